@@ -1,4 +1,5 @@
 use crate::storage;
+use crate::storage::AddOutcome;
 use std::sync::{Arc, RwLock};
 
 use futures::{Future};
@@ -6,66 +7,89 @@ use futures::{Future};
 use hyper::{Body, Response};
 
 use crate::api::alexa::dto::{GenericCall, GenericResult};
-use crate::api::utils::{internal_error_rsp, ok_rsp};
+use crate::metrics::{Metrics, CHANNEL_ALEXA};
+use crate::api::utils::{internal_error_rsp, ok_rsp, storage_unavailable_rsp};
 
 pub struct AlexaController {
-    storage: Arc<RwLock<storage::Storage>>
+    storage: Arc<RwLock<storage::Storage>>,
+    metrics: Arc<Metrics>
 }
 
 
 impl AlexaController {
 
-    pub fn new(storage:Arc<RwLock<storage::Storage>>) -> AlexaController {
+    pub fn new(storage:Arc<RwLock<storage::Storage>>, metrics: Arc<Metrics>) -> AlexaController {
         AlexaController {
-            storage: storage
+            storage,
+            metrics
         }
     }
 
 
-    pub fn create_slap_notification(&self, call: GenericCall) -> Box<Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+    pub fn create_slap_notification(&self, call: GenericCall) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
 
         let for_city_opt = resolve_city(call.clone());
 
         // validate city
         if for_city_opt.is_none() {
             let result_object = GenericResult::city_not_provided();
-            println!("city hasn't been provided");
+            warn!(intent = "create_slap_notification"; "city hasn't been provided");
             return prepare_response(result_object);
         }
 
         let for_city = for_city_opt.unwrap();
         let event = storage::Event::new_slap();
 
-        self.storage.write().unwrap().add_event(event, for_city.clone());
-        let response_object = GenericResult::notification_created(for_city.clone());
+        let event_type = event.event_type.clone();
+        let outcome = match self.storage.write() {
+            Ok(mut storage) => storage.add_event(event, for_city.clone()),
+            Err(_) => return storage_unavailable_rsp()
+        };
+        match outcome {
+            AddOutcome::Rejected | AddOutcome::UnknownDevice => (),
+            _ => self.metrics.notification_created(&event_type, CHANNEL_ALEXA)
+        }
+
+        let response_object = match outcome {
+            AddOutcome::Queued | AddOutcome::Merged | AddOutcome::Pushed => GenericResult::notification_created(for_city.clone()),
+            AddOutcome::DroppedOldest(_) => GenericResult::notification_created_dropping_oldest(&for_city),
+            AddOutcome::Coalesced => GenericResult::notification_coalesced(&for_city),
+            AddOutcome::Rejected => GenericResult::queue_full(&for_city),
+            AddOutcome::UnknownDevice => GenericResult::city_unknown()
+        };
 
-        return prepare_response(response_object);
+        prepare_response(response_object)
     }
 
-    pub fn deliver_notification(&self, call: GenericCall) -> Box<Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+    pub fn deliver_notification(&self, call: GenericCall) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
 
         let for_city_opt = resolve_city(call.clone());
         if for_city_opt.is_none() {
             let result_object = GenericResult::city_not_provided();
-            println!("Failed notification delivery: city hasn't been provided");
+            warn!(intent = "deliver_notification"; "city hasn't been provided");
             return prepare_response(result_object);
         }
 
         let for_city = for_city_opt.unwrap();
-        println!("city value is {}", &for_city);
+        debug!(city = for_city.as_str(); "delivering notification");
 
-        if !self.storage.read().unwrap().is_registered(&for_city) {
+        let mut storage = match self.storage.write() {
+            Ok(storage) => storage,
+            Err(_) => return storage_unavailable_rsp()
+        };
+        if !storage.is_registered(&for_city) {
             let response_object = GenericResult::city_unknown();
             return prepare_response(response_object);
         }
 
-        match self.storage.write().unwrap().pop_event(&for_city) {
+        match storage.pop_event(&for_city, CHANNEL_ALEXA) {
             Some(event) => {
+                self.metrics.notification_delivered(&event.event_type, CHANNEL_ALEXA);
                 let result = GenericResult::for_event(event);
                 prepare_response(result)
             },
             None => {
-                println!("No notifications found for city: {}", &for_city);
+                debug!(city = for_city.as_str(); "no notifications to deliver");
                 let result = GenericResult::no_notifications_found_for(&for_city);
                 prepare_response(result)
             }
@@ -80,11 +104,11 @@ fn resolve_city(call: GenericCall) -> Option<String> {
     Some(city)
 }
 
-fn prepare_response(result: GenericResult) -> Box<Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+fn prepare_response(result: GenericResult) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
     match serde_json::to_string(&result) {
         Ok(json) => ok_rsp(json),
         Err(err) => {
-            println!("ERROR: failed to serialize response for notification creation: {:?}", err);
+            error!(error:% = err; "cannot serialize alexa response");
             internal_error_rsp()
         }
 
@@ -83,8 +83,8 @@ pub struct SlotValue {
 
 impl GenericCall {
 
-    pub fn from(json: &String) -> Result<GenericCall, Error> {
-        let call: GenericCall = serde_json::from_str(&json)?;
+    pub fn from(json: &str) -> Result<GenericCall, Error> {
+        let call: GenericCall = serde_json::from_str(json)?;
         Ok(call)
     }
 
@@ -117,7 +117,46 @@ impl GenericResult {
             response: Response {
                 output_speech: OutputSpeech {
                     type_name: String::from("PlainText"),
-                    text: Some(String::from(format!("notification for {} created", &for_city))),
+                    text: Some(format!("notification for {} created", &for_city)),
+                    ssml: None
+                }
+            }
+        }
+    }
+
+    pub fn notification_created_dropping_oldest(for_city: &String) -> GenericResult {
+        GenericResult {
+            version: String::from("1.0"),
+            response: Response {
+                output_speech: OutputSpeech {
+                    type_name: String::from("PlainText"),
+                    text: Some(format!("notification for {} created, the oldest one was dropped because the queue is full", &for_city)),
+                    ssml: None
+                }
+            }
+        }
+    }
+
+    pub fn notification_coalesced(for_city: &String) -> GenericResult {
+        GenericResult {
+            version: String::from("1.0"),
+            response: Response {
+                output_speech: OutputSpeech {
+                    type_name: String::from("PlainText"),
+                    text: Some(format!("notification for {} merged with the pending one", &for_city)),
+                    ssml: None
+                }
+            }
+        }
+    }
+
+    pub fn queue_full(for_city: &String) -> GenericResult {
+        GenericResult {
+            version: String::from("1.0"),
+            response: Response {
+                output_speech: OutputSpeech {
+                    type_name: String::from("PlainText"),
+                    text: Some(format!("{} has too many pending notifications, try again later", &for_city)),
                     ssml: None
                 }
             }
@@ -156,7 +195,7 @@ impl GenericResult {
             response: Response {
                 output_speech: OutputSpeech {
                     type_name: String::from("PlainText"),
-                    text: Some(String::from(format!("There are no pending notifications for {}", &city))),
+                    text: Some(format!("There are no pending notifications for {}", &city)),
                     ssml: None
                 }
             }
@@ -172,21 +211,23 @@ impl GenericResult {
                     response: Response {
                         output_speech: OutputSpeech {
                             type_name: String::from("PlainText"),
-                            text: Some(String::from(format!("Someone has just slapped you."))),
+                            text: Some(slap_speech(event.sender.as_ref(), event.count)),
                             ssml: None
                         }
                     }
                 }
             },
             EventType::MESSAGE => {
-                let message = event.message.unwrap();
+                // both come from callers, they must not break the SSML around them
+                let message = format!(r###"<emphasis level="strong"> {} </emphasis>"###, ssml_escape(&event.message.unwrap()));
+                let sender = event.sender.as_deref().map(ssml_escape);
                 GenericResult {
                     version: String::from("1.0"),
                     response: Response {
                         output_speech: OutputSpeech {
                             type_name: String::from("SSML"),
                             text: None,
-                            ssml: Some(String::from(format!(r###"<speak>Someone sent you a message: <emphasis level="strong"> {} </emphasis> </speak> "###, &message)))
+                            ssml: Some(format!("<speak>{} </speak> ", message_speech(sender.as_ref(), &message)))
                         }
                     }
                 }
@@ -196,6 +237,49 @@ impl GenericResult {
 
 }
 
+pub fn slap_speech(sender: Option<&String>, count: u32) -> String {
+    match (sender, count) {
+        (Some(sender), 1) => format!("{} has just slapped you.", sender),
+        (None, 1) => String::from("Someone has just slapped you."),
+        (Some(sender), count) => format!("{} slapped you {} times.", sender, count),
+        (None, count) => format!("You were slapped {} times.", count)
+    }
+}
+
+// the message is taken as it is, so it may carry markup of the channel
+pub fn message_speech(sender: Option<&String>, message: &str) -> String {
+    format!("{} sent you a message: {}", sender.map(String::as_str).unwrap_or("Someone"), message)
+}
+
+fn ssml_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&apos;")
+}
+
+#[test]
+fn test_counted_slaps_speech() {
+    let slaps = Event { count: 10, ..Event::new_slap() };
+    let result = GenericResult::for_event(slaps);
+    assert_eq!(result.response.output_speech.text, Some(String::from("You were slapped 10 times.")));
+
+    let slaps = Event { count: 2, ..Event::new_slap().sent_by(Some(String::from("anna"))) };
+    let result = GenericResult::for_event(slaps);
+    assert_eq!(result.response.output_speech.text, Some(String::from("anna slapped you 2 times.")));
+}
+
+#[test]
+fn test_message_speech_emphasizes_the_message() {
+    let message = Event::new_message(String::from("lunch is here")).sent_by(Some(String::from("anna")));
+    let result = GenericResult::for_event(message);
+    assert_eq!(result.response.output_speech.ssml, Some(String::from(r###"<speak>anna sent you a message: <emphasis level="strong"> lunch is here </emphasis> </speak> "###)));
+}
+
+#[test]
+fn test_message_speech_escapes_sender_and_message() {
+    let message = Event::new_message(String::from("salt & <vinegar>")).sent_by(Some(String::from("r&d <bot>")));
+    let result = GenericResult::for_event(message);
+    assert_eq!(result.response.output_speech.ssml, Some(String::from(r###"<speak>r&amp;d &lt;bot&gt; sent you a message: <emphasis level="strong"> salt &amp; &lt;vinegar&gt; </emphasis> </speak> "###)));
+}
+
 #[test]
 fn smoke_test_create_custom_notification() {
 
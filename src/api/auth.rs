@@ -0,0 +1,205 @@
+use hyper::{Body, Request, Response, StatusCode};
+use hyper::header::{HeaderMap, HeaderName, AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE};
+
+use crate::api::middleware::{Flow, Middleware, RequestContext};
+use crate::api::rest::dto::ALL_CITIES;
+use crate::api::rest::routes::RestRoute;
+use crate::api::utils::TEXT_CONTENT_TYPE;
+use crate::config::{ApiKeyConfig, AuthConfig};
+
+const PROTECTED_PREFIX: &str = "/rest-api/";
+const API_KEY_HEADER: &str = "x-api-key";
+const BEARER_PREFIX: &str = "Bearer ";
+
+// the authenticated caller, stored in the request extensions for the controllers
+#[derive(Clone, Debug, PartialEq)]
+pub struct Identity {
+    pub client_id: String,
+    pub cities: Vec<String>,
+    pub can_read_status: bool
+}
+
+impl Identity {
+    pub fn may_send_to(&self, city: &str) -> bool {
+        self.cities.iter().any(|allowed| allowed == city || allowed == ALL_CITIES)
+    }
+}
+
+pub struct ApiKeyAuth {
+    api_keys: Vec<ApiKeyConfig>
+}
+
+impl ApiKeyAuth {
+
+    pub fn new(config: &AuthConfig) -> ApiKeyAuth {
+        ApiKeyAuth {
+            api_keys: config.api_keys.clone()
+        }
+    }
+
+    pub fn authenticate(&self, headers: &HeaderMap) -> Result<Identity, &'static str> {
+        let presented = match presented_key(headers) {
+            Some(key) => key,
+            None => return Err("credentials are missing, provide 'Authorization: Bearer <key>' or 'X-Api-Key: <key>'.")
+        };
+
+        self.api_keys.iter()
+            .find(|api_key| constant_time_eq(api_key.key.as_bytes(), presented.as_bytes()))
+            .map(|api_key| Identity {
+                client_id: api_key.id.clone(),
+                cities: api_key.cities.clone(),
+                can_read_status: api_key.can_read_status
+            })
+            .ok_or("the provided credentials are invalid.")
+    }
+
+}
+
+impl Middleware for ApiKeyAuth {
+    fn on_request(&self, req: Request<Body>, ctx: &mut RequestContext) -> Flow {
+        if self.api_keys.is_empty() || !req.uri().path().starts_with(PROTECTED_PREFIX) {
+            return Flow::Continue(req);
+        }
+
+        let identity = match self.authenticate(req.headers()) {
+            Ok(identity) => identity,
+            Err(reason) => return Flow::Respond(unauthorized_response(reason))
+        };
+
+        ctx.client_id = Some(identity.client_id.clone());
+
+        // city scopes depend on the body and are checked by the controller
+        let route = RestRoute::resolve(req.method(), req.uri().path());
+        if matches!(route, Some(RestRoute::GetStatus) | Some(RestRoute::StreamEvents) | Some(RestRoute::OpenSocket) | Some(RestRoute::NextNotification) | Some(RestRoute::GetDeliveries)) && !identity.can_read_status {
+            return Flow::Respond(forbidden_response(format!("{} is not allowed to read the status.", &identity.client_id)));
+        }
+
+        let mut req = req;
+        req.extensions_mut().insert(identity);
+        Flow::Continue(req)
+    }
+}
+
+fn presented_key(headers: &HeaderMap) -> Option<String> {
+    let bearer = headers.get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| value.starts_with(BEARER_PREFIX))
+        .map(|value| String::from(value[BEARER_PREFIX.len()..].trim()));
+
+    bearer.or_else(|| {
+        headers.get(HeaderName::from_static(API_KEY_HEADER))
+            .and_then(|value| value.to_str().ok())
+            .map(|value| String::from(value.trim()))
+    })
+}
+
+pub fn constant_time_eq(expected: &[u8], presented: &[u8]) -> bool {
+    if expected.len() != presented.len() {
+        return false;
+    }
+
+    expected.iter().zip(presented.iter()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn unauthorized_response(msg: &str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header(WWW_AUTHENTICATE, "Bearer")
+        .header(CONTENT_TYPE, TEXT_CONTENT_TYPE)
+        .body(Body::from(String::from(msg)))
+        .unwrap()
+}
+
+fn forbidden_response(msg: String) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .header(CONTENT_TYPE, TEXT_CONTENT_TYPE)
+        .body(Body::from(msg))
+        .unwrap()
+}
+
+// ------------- there are tests only below this point ------------
+
+#[cfg(test)]
+fn berlin_bot_auth() -> ApiKeyAuth {
+    ApiKeyAuth::new(&AuthConfig {
+        api_keys: vec![ApiKeyConfig {
+            id: String::from("berlin-bot"),
+            key: String::from("s3cr3t"),
+            cities: vec![String::from("BERLIN")],
+            can_read_status: false
+        }]
+    })
+}
+
+#[cfg(test)]
+fn run_auth(auth: &ApiKeyAuth, req: Request<Body>) -> Flow {
+    let mut ctx = RequestContext::from(&req);
+    auth.on_request(req, &mut ctx)
+}
+
+#[cfg(test)]
+fn status_of(flow: Flow) -> StatusCode {
+    match flow {
+        Flow::Respond(rsp) => rsp.status(),
+        Flow::Continue(_) => panic!("request wasn't short-circuited")
+    }
+}
+
+#[test]
+fn test_missing_and_invalid_credentials_are_unauthorized() {
+    let auth = berlin_bot_auth();
+
+    let missing = Request::post("https://auto1.danila.app/rest-api/notifications").body(Body::empty()).unwrap();
+    assert_eq!(status_of(run_auth(&auth, missing)), StatusCode::UNAUTHORIZED);
+
+    let invalid = Request::post("https://auto1.danila.app/rest-api/notifications")
+        .header(AUTHORIZATION, "Bearer guessed")
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(status_of(run_auth(&auth, invalid)), StatusCode::UNAUTHORIZED);
+}
+
+#[test]
+fn test_status_requires_read_scope() {
+    let auth = berlin_bot_auth();
+
+    let req = Request::get("https://auto1.danila.app/rest-api/status?city=BERLIN")
+        .header(API_KEY_HEADER, "s3cr3t")
+        .body(Body::empty())
+        .unwrap();
+
+    assert_eq!(status_of(run_auth(&auth, req)), StatusCode::FORBIDDEN);
+}
+
+#[test]
+fn test_valid_key_attaches_identity() {
+    let auth = berlin_bot_auth();
+
+    let req = Request::post("https://auto1.danila.app/rest-api/notifications")
+        .header(AUTHORIZATION, "Bearer s3cr3t")
+        .body(Body::empty())
+        .unwrap();
+
+    match run_auth(&auth, req) {
+        Flow::Continue(req) => {
+            let identity = req.extensions().get::<Identity>().unwrap();
+            assert_eq!(identity.client_id, "berlin-bot");
+            assert!(identity.may_send_to("BERLIN"));
+            assert!(!identity.may_send_to("KIEV"));
+        },
+        Flow::Respond(_) => panic!("valid key was rejected")
+    }
+}
+
+#[test]
+fn test_alexa_endpoint_is_not_protected() {
+    let auth = berlin_bot_auth();
+
+    let req = Request::post("https://auto1.danila.app/alexa-skill").body(Body::empty()).unwrap();
+
+    match run_auth(&auth, req) {
+        Flow::Continue(_) => (),
+        Flow::Respond(_) => panic!("alexa endpoint must not require api keys")
+    }
+}
@@ -0,0 +1,168 @@
+use hyper::{Body, Method, Request, Response, StatusCode};
+use hyper::header::{HeaderMap, HeaderValue, ALLOW, ORIGIN, VARY, ACCESS_CONTROL_REQUEST_METHOD,
+                    ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_ALLOW_METHODS,
+                    ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_MAX_AGE};
+
+use crate::api::middleware::{Flow, Middleware, RequestContext};
+use crate::config::CorsConfig;
+
+const ANY_ORIGIN: &str = "*";
+
+pub struct Cors {
+    allowed_origins: Vec<String>,
+    allowed_methods: Vec<String>,
+    allowed_headers: Vec<String>,
+    max_age_secs: u64
+}
+
+impl Cors {
+
+    pub fn new(config: &CorsConfig) -> Cors {
+        Cors {
+            allowed_origins: config.allowed_origins.clone(),
+            allowed_methods: config.allowed_methods.iter().map(|method| method.to_uppercase()).collect(),
+            allowed_headers: config.allowed_headers.clone(),
+            max_age_secs: config.max_age_secs
+        }
+    }
+
+    pub fn is_preflight(method: &Method, headers: &HeaderMap) -> bool {
+        method == Method::OPTIONS && headers.contains_key(ORIGIN) && headers.contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+    }
+
+    pub fn preflight(&self, headers: &HeaderMap) -> Response<Body> {
+        let allowed_origin = headers.get(ORIGIN).and_then(|origin| self.allowed_origin(origin));
+        let requested_method = headers.get(ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|method| method.to_str().ok())
+            .map(|method| method.to_uppercase());
+
+        let method_allowed = match requested_method {
+            Some(method) => self.allowed_methods.contains(&method),
+            None => false
+        };
+
+        match allowed_origin {
+            Some(origin) if method_allowed => {
+                Response::builder()
+                    .status(StatusCode::NO_CONTENT)
+                    .header(ACCESS_CONTROL_ALLOW_ORIGIN, origin)
+                    .header(ACCESS_CONTROL_ALLOW_METHODS, self.allowed_methods.join(", ").as_str())
+                    .header(ACCESS_CONTROL_ALLOW_HEADERS, self.allowed_headers.join(", ").as_str())
+                    .header(ACCESS_CONTROL_MAX_AGE, self.max_age_secs.to_string().as_str())
+                    .header(VARY, "Origin")
+                    .body(Body::empty())
+                    .unwrap()
+            },
+            _ => {
+                Response::builder()
+                    .status(StatusCode::FORBIDDEN)
+                    .header(VARY, "Origin")
+                    .body(Body::empty())
+                    .unwrap()
+            }
+        }
+    }
+
+    // plain OPTIONS request, not a CORS preflight
+    pub fn options(&self) -> Response<Body> {
+        let mut methods = self.allowed_methods.clone();
+        methods.push(String::from("OPTIONS"));
+
+        Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .header(ALLOW, methods.join(", ").as_str())
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    pub fn decorate(&self, origin: Option<&HeaderValue>, rsp: &mut Response<Body>) {
+        if let Some(allowed_origin) = origin.and_then(|origin| self.allowed_origin(origin)) {
+            rsp.headers_mut().insert(ACCESS_CONTROL_ALLOW_ORIGIN, allowed_origin);
+            rsp.headers_mut().append(VARY, HeaderValue::from_static("Origin"));
+        }
+    }
+
+    fn allowed_origin(&self, origin: &HeaderValue) -> Option<HeaderValue> {
+        let origin_str = origin.to_str().ok()?;
+
+        if self.allowed_origins.iter().any(|allowed| allowed == ANY_ORIGIN) {
+            Some(HeaderValue::from_static(ANY_ORIGIN))
+        } else if self.allowed_origins.iter().any(|allowed| allowed == origin_str) {
+            Some(origin.clone())
+        } else {
+            None
+        }
+    }
+
+}
+
+impl Middleware for Cors {
+    fn on_request(&self, req: Request<Body>, _ctx: &mut RequestContext) -> Flow {
+        if req.method() != Method::OPTIONS {
+            return Flow::Continue(req);
+        }
+
+        if Cors::is_preflight(req.method(), req.headers()) {
+            Flow::Respond(self.preflight(req.headers()))
+        } else {
+            Flow::Respond(self.options())
+        }
+    }
+
+    fn on_response(&self, rsp: Response<Body>, ctx: &RequestContext) -> Response<Body> {
+        let mut rsp = rsp;
+        self.decorate(ctx.headers.get(ORIGIN), &mut rsp);
+        rsp
+    }
+}
+
+#[cfg(test)]
+fn preflight_headers(origin: &'static str, method: &'static str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(ORIGIN, HeaderValue::from_static(origin));
+    headers.insert(ACCESS_CONTROL_REQUEST_METHOD, HeaderValue::from_static(method));
+    headers
+}
+
+#[test]
+fn test_preflight_for_allowed_origin() {
+    let cors = Cors::new(&CorsConfig {
+        allowed_origins: vec![String::from("https://slap.danila.app")],
+        ..CorsConfig::default()
+    });
+
+    let rsp = cors.preflight(&preflight_headers("https://slap.danila.app", "POST"));
+
+    assert_eq!(rsp.status(), StatusCode::NO_CONTENT);
+    assert_eq!(rsp.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], "https://slap.danila.app");
+    assert_eq!(rsp.headers()[ACCESS_CONTROL_ALLOW_METHODS], "GET, POST");
+    assert_eq!(rsp.headers()[ACCESS_CONTROL_ALLOW_HEADERS], "Content-Type");
+}
+
+#[test]
+fn test_preflight_rejects_unknown_origin_and_method() {
+    let cors = Cors::new(&CorsConfig {
+        allowed_origins: vec![String::from("https://slap.danila.app")],
+        ..CorsConfig::default()
+    });
+
+    let rsp = cors.preflight(&preflight_headers("https://evil.example", "POST"));
+    assert_eq!(rsp.status(), StatusCode::FORBIDDEN);
+    assert!(!rsp.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+
+    let rsp = cors.preflight(&preflight_headers("https://slap.danila.app", "DELETE"));
+    assert_eq!(rsp.status(), StatusCode::FORBIDDEN);
+}
+
+#[test]
+fn test_decorate_with_wildcard_origin() {
+    let cors = Cors::new(&CorsConfig {
+        allowed_origins: vec![String::from(ANY_ORIGIN)],
+        ..CorsConfig::default()
+    });
+    let mut rsp = Response::new(Body::empty());
+
+    cors.decorate(Some(&HeaderValue::from_static("https://anyone.example")), &mut rsp);
+
+    assert_eq!(rsp.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], ANY_ORIGIN);
+}
@@ -1,60 +1,138 @@
 
-use hyper::{Body, Request, Response, Method};
+use hyper::{Body, Method, Request, Response};
 
-use futures::{future, Future, Stream};
-use futures::future::ok;
+use futures::{Future, Stream};
+use futures::future::err;
+use hyper::header::{HeaderMap, HeaderValue, ACCEPT, CONTENT_LENGTH};
 use std::sync::{Arc};
+use std::time::Duration;
 use crate::api::rest::controller::RestController;
-use crate::api::rest::dto::CreateNotificationReqeust;
+use crate::api::rest::dto::CreateNotificationsBody;
+use crate::api::rest::routes::RestRoute;
+use crate::api::rest::sse::{EVENT_STREAM_CONTENT_TYPE, LAST_EVENT_ID_HEADER};
 use crate::api::alexa::controller::AlexaController;
+use crate::api::health::controller::HealthController;
+use crate::api::slack::controller::SlackController;
+use crate::api::auth::Identity;
 use crate::api::alexa::dto::GenericCall;
-use crate::api::utils::{internal_error_rsp, bad_request_rsp, not_found_rsp};
+use crate::api::utils::{internal_error_rsp, bad_request_rsp, not_found_rsp, not_acceptable_rsp, payload_too_large_rsp, JSON_CONTENT_TYPE};
+use crate::config::Config;
+use crate::logging::{in_request, next_request_id, redact_json};
+use crate::metrics::Metrics;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+// id of the request for logs and responses, inserted into the request extensions by the pipeline
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
 
 pub struct DeconstructedRequest {
+    pub request_id: String,
     pub method: hyper::Method,
     pub path: String,
     pub query: Option<String>,
-    pub body: Box<Future<Item=String, Error=hyper::Error> + Send>
+    pub headers: HeaderMap,
+    pub identity: Option<Identity>,
+    pub body: Box<dyn Future<Item=String, Error=BodyError> + Send>
+}
+
+#[derive(Debug)]
+pub enum BodyError {
+    Transport(hyper::Error),
+    TooLarge(usize),
+    InvalidUtf8
 }
 
 
 pub struct Dispatcher {
     rest_controller: Arc<RestController>,
-    alexa_controller: Arc<AlexaController>
+    alexa_controller: Arc<AlexaController>,
+    health_controller: Arc<HealthController>,
+    slack_controller: Arc<SlackController>,
+    metrics: Arc<Metrics>,
+    max_body_size: usize
 }
 
+// probes of the orchestrator, served without authentication
+pub const LIVENESS_PATH: &str = "/health/live";
+pub const READINESS_PATH: &str = "/health/ready";
+pub const BUILD_INFO_PATH: &str = "/version";
+pub const METRICS_PATH: &str = "/metrics";
+pub const ALEXA_PATH: &str = "/alexa-skill";
+// signed by Slack instead of carrying an api key
+pub const SLACK_COMMANDS_PATH: &str = "/slack/commands";
+
+const CREATE_SLAP_INTENT: &str = "create_slap_notification";
+const DELIVER_NOTIFICATION_INTENT: &str = "deliver_notification";
+
 impl Dispatcher {
 
-    pub fn new(rest_controller: RestController, alexa_controller: AlexaController) -> Dispatcher {
+    pub fn new(rest_controller: RestController, alexa_controller: AlexaController, health_controller: HealthController, metrics: Arc<Metrics>, config: &Config) -> Dispatcher {
+        let rest_controller = Arc::new(rest_controller);
         Dispatcher {
-            rest_controller: Arc::new(rest_controller),
-            alexa_controller: Arc::new(alexa_controller)
+            slack_controller: Arc::new(SlackController::new(rest_controller.clone(), &config.slack)),
+            rest_controller,
+            alexa_controller: Arc::new(alexa_controller),
+            health_controller: Arc::new(health_controller),
+            metrics,
+            max_body_size: config.server.max_body_size
         }
     }
 
-    pub fn dispatch(&self, req: Request<Body>) -> Box<Future<Item=Response<Body>, Error=hyper::Error> + Send> {
-        println!("dispatching uri: {}", req.uri());
-        let d_request = DeconstructedRequest::from(req);
+    pub fn dispatch(&self, req: Request<Body>) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+        let request_id = request_id_of(&req);
+        let produces = match RestRoute::resolve(req.method(), req.uri().path()) {
+            Some(RestRoute::StreamEvents) => EVENT_STREAM_CONTENT_TYPE,
+            _ => JSON_CONTENT_TYPE
+        };
+        // the body of a socket request is the upgraded connection, it must not be read
+        if RestRoute::resolve(req.method(), req.uri().path()) == Some(RestRoute::OpenSocket) {
+            let (parts, body) = req.into_parts();
+            let rest_controller = self.rest_controller.clone();
+            let identity = parts.extensions.get::<Identity>().cloned();
+            let result = in_request(&request_id, || rest_controller.open_socket(&parts.headers, body.on_upgrade(), identity, request_id.clone()));
+            return Box::new(result.map(move |rsp| with_request_id(rsp, &request_id)));
+        }
+        let acceptable = accepts(req.headers(), produces);
+        let d_request = DeconstructedRequest::from(req, request_id.clone(), self.max_body_size);
 
-        match d_request.path.as_ref() {
-            "/alexa-skill" => self.dispatch_alexa(d_request),
+        let result = in_request(&request_id, || match (&d_request.method, d_request.path.as_ref()) {
+            (&Method::GET, LIVENESS_PATH) => self.health_controller.get_liveness(),
+            (&Method::GET, READINESS_PATH) => self.health_controller.get_readiness(),
+            (&Method::GET, BUILD_INFO_PATH) => self.health_controller.get_build_info(),
+            (&Method::GET, METRICS_PATH) => self.health_controller.get_metrics(),
+            (_, ALEXA_PATH) => self.dispatch_alexa(d_request),
+            (&Method::POST, SLACK_COMMANDS_PATH) => self.dispatch_slack(d_request),
+            _ if !acceptable => not_acceptable_rsp(produces),
             _ => self.dispatch_rest(d_request)
-        }
+        });
+
+        Box::new(result.map(move |rsp| with_request_id(rsp, &request_id)))
     }
 
-    fn dispatch_rest(&self, req: DeconstructedRequest) -> Box<Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+    fn dispatch_rest(&self, req: DeconstructedRequest) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
         let _rest_controller = self.rest_controller.clone();
 
         let body = req.body;
         let path = req.path;
         let query = req.query;
         let method = req.method;
+        let identity = req.identity;
+        let request_id = req.request_id;
+        let last_event_id = req.headers.get(LAST_EVENT_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from);
 
-        let result = body.and_then( move |str_body| {
-                println!("request body: {}", &str_body);
+        let result = body.then( move |body_result| in_request(&request_id, || {
+                let str_body = match body_result {
+                    Ok(str_body) => str_body,
+                    Err(err) => return body_error_rsp(err)
+                };
+                debug!(body = redact_json(&str_body); "rest request body");
 
-                match (method, path.as_ref()) {
-                    (Method::GET, "/rest-api/status") => {
+                match RestRoute::resolve(&method, &path) {
+                    Some(RestRoute::GetStatus) => {
                         match query {
                             Some(query_params) => {
                                 let city = str::replace(&query_params, "city=", "");
@@ -65,80 +143,219 @@ impl Dispatcher {
                             }
                         }
                     },
-                    (Method::POST, "/rest-api/notifications") => {
-                        let request_object: Result<CreateNotificationReqeust, serde_json::Error> = serde_json::from_str(&str_body);
+                    Some(RestRoute::StreamEvents) => {
+                        match query {
+                            Some(query_params) => {
+                                let city = str::replace(&query_params, "city=", "");
+                                _rest_controller.stream_events_for(&city, last_event_id.as_deref())
+                            },
+                            _ => {
+                                bad_request_rsp(String::from("query parameter 'city' is mandatory but hasn't been provided."))
+                            }
+                        }
+                    },
+                    Some(RestRoute::CreateNotifications) => {
+                        let request_object: Result<CreateNotificationsBody, serde_json::Error> = serde_json::from_str(&str_body);
                         match request_object {
-                            Ok(object) => _rest_controller.create_notification(object),
+                            Ok(CreateNotificationsBody::Single(object)) => _rest_controller.create_notification(object, identity.as_ref()),
+                            Ok(CreateNotificationsBody::Batch(ref objects)) if objects.is_empty() => {
+                                bad_request_rsp(String::from("at least one notification must be provided."))
+                            },
+                            Ok(CreateNotificationsBody::Batch(objects)) => _rest_controller.create_notifications(objects, identity.as_ref()),
                             _ => bad_request_rsp(String::from("cannot deserialize body."))
                          }
-                    }
-                    _ => not_found_rsp()
+                    },
+                    Some(route @ RestRoute::NextNotification) => {
+                        let city = route.path_param(&path, "city").unwrap_or_default();
+                        match query_param(query.as_deref(), "wait").map(|wait| wait.parse::<u64>()) {
+                            None => _rest_controller.next_notification_for(&String::from(city), Duration::from_secs(0), identity.as_ref()),
+                            Some(Ok(wait)) => _rest_controller.next_notification_for(&String::from(city), Duration::from_secs(wait), identity.as_ref()),
+                            Some(Err(_)) => bad_request_rsp(String::from("query parameter 'wait' must be a number of seconds."))
+                        }
+                    },
+                    Some(route @ RestRoute::GetDeliveries) => _rest_controller.get_deliveries_for(&String::from(route.path_param(&path, "city").unwrap_or_default())),
+                    Some(RestRoute::GetOpenApi) => _rest_controller.get_openapi_spec(),
+                    Some(RestRoute::OpenSocket) | None => not_found_rsp()
                 }
-        });
+        }));
 
-        return Box::new(result);
+        Box::new(result)
     }
 
-    fn dispatch_alexa(&self, req: DeconstructedRequest) -> Box<Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    fn dispatch_slack(&self, req: DeconstructedRequest) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+        let slack_controller = self.slack_controller.clone();
+        let request_id = req.request_id;
+        let headers = req.headers;
+
+        let result = req.body.then(move |body_result| in_request(&request_id, || {
+            match body_result {
+                Ok(str_body) => slack_controller.handle_command(&headers, &str_body),
+                Err(err) => body_error_rsp(err)
+            }
+        }));
+
+        Box::new(result)
+    }
+
+    fn dispatch_alexa(&self, req: DeconstructedRequest) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
         let _alexa_controller = self.alexa_controller.clone();
+        let metrics = self.metrics.clone();
+        let request_id = req.request_id;
 
-        let result = req.body.and_then( move |str_body| {
-            println!("request body: {}", &str_body);
+        let result = req.body.then( move |body_result| in_request(&request_id, || {
+            let str_body = match body_result {
+                Ok(str_body) => str_body,
+                Err(err) => return body_error_rsp(err)
+            };
+            debug!(body = redact_json(&str_body); "alexa request body");
             let parsed_result = GenericCall::from(&str_body);
 
             match parsed_result {
-                Ok(call) => match call.request.intent.name.as_ref() {
-                    "create_slap_notification" => _alexa_controller.create_slap_notification(call),
-                    "deliver_notification" => _alexa_controller.deliver_notification(call),
-                    _ => not_found_rsp()
+                Ok(call) => {
+                    info!(alexa_request_id = call.request.request_id.as_str(), intent = call.request.intent.name.as_str(); "alexa intent received");
+                    metrics.alexa_intent(intent_label(&call.request.intent.name));
+                    match call.request.intent.name.as_ref() {
+                        CREATE_SLAP_INTENT => _alexa_controller.create_slap_notification(call),
+                        DELIVER_NOTIFICATION_INTENT => _alexa_controller.deliver_notification(call),
+                        _ => not_found_rsp()
+                    }
                 },
                 Err(err) => {
-                    println!("alexa request deserialisation error: {:?}", err);
+                    warn!(error:% = err; "cannot deserialize alexa request");
                     internal_error_rsp()
                 }
             }
-        });
+        }));
 
-        return Box::new(result);
+        Box::new(result)
     }
 
 }
 
 impl DeconstructedRequest {
-    pub fn new(method: hyper::Method, path: String, query: Option<String>, body: Box<Future<Item=String, Error=hyper::Error> + Send>) -> DeconstructedRequest {
+    pub fn new(request_id: String, method: hyper::Method, path: String, query: Option<String>, headers: HeaderMap, identity: Option<Identity>, body: Box<dyn Future<Item=String, Error=BodyError> + Send>) -> DeconstructedRequest {
         DeconstructedRequest {
-            method: method,
-            path: path,
-            query: query,
-            body: body
+            request_id,
+            method,
+            path,
+            query,
+            headers,
+            identity,
+            body
         }
     }
 
-    pub fn from(req: Request<Body>) -> DeconstructedRequest {
+    pub fn from(req: Request<Body>, request_id: String, max_body_size: usize) -> DeconstructedRequest {
         let (parts, body) = req.into_parts();
         let uri = parts.uri;
         let method = parts.method;
         let path = String::from(uri.path());
-        let query = match uri.query() {
-            Some(query_str) => Some(String::from(query_str)),
-            _ => None
-        };
+        let query = uri.query().map(String::from);
+        let identity = parts.extensions.get::<Identity>().cloned();
+
+        // refuse announced oversized bodies without reading them at all
+        let declared_length = parts.headers.get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<usize>().ok());
+        if let Some(length) = declared_length {
+            if length > max_body_size {
+                return DeconstructedRequest::new(request_id, method, path, query, parts.headers, identity, Box::new(err(BodyError::TooLarge(max_body_size))));
+            }
+        }
+
         let raw_body = body
-            .fold(Vec::new(), |mut acc, chunk| {
+            .map_err(BodyError::Transport)
+            .fold(Vec::new(), move |mut acc, chunk| {
+                if acc.len() + chunk.len() > max_body_size {
+                    return Err(BodyError::TooLarge(max_body_size));
+                }
                 acc.extend_from_slice(&chunk);
-                future::ok::<Vec<u8>, hyper::Error>(acc)
+                Ok(acc)
             })
             .and_then( move |acc| {
-                ok(String::from_utf8(acc).unwrap())
+                String::from_utf8(acc).map_err(|_| BodyError::InvalidUtf8)
             });
 
         let result_body = Box::new(raw_body);
 
-        return DeconstructedRequest::new(method, path, query, result_body);
+        DeconstructedRequest::new(request_id, method, path, query, parts.headers, identity, result_body)
     }
 }
 
+// bounded route label for metrics and logs, raw paths would explode the label space
+pub fn route_label(method: &Method, path: &str) -> &'static str {
+    if let Some(route) = RestRoute::resolve(method, path) {
+        return route.path();
+    }
 
+    [LIVENESS_PATH, READINESS_PATH, BUILD_INFO_PATH, METRICS_PATH, ALEXA_PATH, SLACK_COMMANDS_PATH].iter()
+        .find(|known| **known == path)
+        .cloned()
+        .unwrap_or("unmatched")
+}
 
+// intent names come from the unauthenticated skill endpoint, unknown ones share one label
+pub fn intent_label(intent: &str) -> &'static str {
+    [CREATE_SLAP_INTENT, DELIVER_NOTIFICATION_INTENT].iter()
+        .find(|known| **known == intent)
+        .cloned()
+        .unwrap_or("other")
+}
 
+// the id assigned by the pipeline, else a well-formed one sent by the caller, else a new one
+pub fn request_id_of(req: &Request<Body>) -> String {
+    if let Some(RequestId(request_id)) = req.extensions().get::<RequestId>() {
+        return request_id.clone();
+    }
 
+    req.headers().get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| is_valid_request_id(value))
+        .map(String::from)
+        .unwrap_or_else(next_request_id)
+}
+
+// ids end up in log lines, so anything that could forge or break a line is replaced
+fn is_valid_request_id(value: &str) -> bool {
+    !value.is_empty() && value.len() <= MAX_REQUEST_ID_LENGTH
+        && value.chars().all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c))
+}
+
+pub fn with_request_id(rsp: Response<Body>, request_id: &str) -> Response<Body> {
+    let mut rsp = rsp;
+    if let Ok(value) = HeaderValue::from_str(request_id) {
+        rsp.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    rsp
+}
+
+// requests without an Accept header take whatever we answer
+fn accepts(headers: &HeaderMap, produces: &str) -> bool {
+    let accept = match headers.get(ACCEPT).and_then(|value| value.to_str().ok()) {
+        Some(accept) => accept,
+        None => return true
+    };
+
+    accept.split(',')
+        .map(|media_range| media_range.split(';').next().unwrap_or("").trim())
+        .any(|media_type| media_type == produces || media_type == "*/*" || (media_type.ends_with("/*") && produces.starts_with(&media_type[..media_type.len() - 1])))
+}
+
+fn query_param<'a>(query: Option<&'a str>, name: &str) -> Option<&'a str> {
+    query?.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+fn body_error_rsp(body_error: BodyError) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+    match body_error {
+        BodyError::Transport(transport_error) => Box::new(err(transport_error)),
+        BodyError::TooLarge(max_body_size) => payload_too_large_rsp(format!("request body must not exceed {} bytes.", max_body_size)),
+        BodyError::InvalidUtf8 => bad_request_rsp(String::from("request body is not valid UTF-8."))
+    }
+}
@@ -0,0 +1,114 @@
+use crate::storage;
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use futures::Future;
+
+use hyper::{Body, Response};
+
+use crate::api::health::dto::{BuildInfo, HealthResponse, STATUS_UP};
+use crate::api::utils::{internal_error_rsp, metrics_rsp, ok_rsp, service_unavailable_rsp};
+use crate::config::Config;
+use crate::metrics::Metrics;
+
+// operational endpoints for the orchestrator and monitoring
+pub struct HealthController {
+    storage: Arc<RwLock<storage::Storage>>,
+    metrics: Arc<Metrics>,
+    features: Vec<String>
+}
+
+impl HealthController {
+
+    pub fn new(storage: Arc<RwLock<storage::Storage>>, metrics: Arc<Metrics>, config: &Config) -> HealthController {
+        HealthController {
+            storage,
+            metrics,
+            features: enabled_features(config)
+        }
+    }
+
+    // the process is up and able to answer
+    pub fn get_liveness(&self) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+        prepare_response(HealthResponse::up())
+    }
+
+    // the process can serve notifications
+    pub fn get_readiness(&self) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+        let mut checks = BTreeMap::new();
+        checks.insert(String::from("storage"), self.check_storage());
+
+        prepare_response(HealthResponse::from_checks(checks))
+    }
+
+    pub fn get_build_info(&self) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+        let build_info = BuildInfo {
+            name: String::from(env!("CARGO_PKG_NAME")),
+            version: String::from(env!("CARGO_PKG_VERSION")),
+            profile: String::from(if cfg!(debug_assertions) { "debug" } else { "release" }),
+            features: self.features.clone()
+        };
+
+        match serde_json::to_string(&build_info) {
+            Ok(json) => ok_rsp(json),
+            Err(err) => {
+                error!(error:% = err; "cannot serialize build info");
+                internal_error_rsp()
+            }
+        }
+    }
+
+    pub fn get_metrics(&self) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+        match self.storage.read() {
+            Ok(storage) => metrics_rsp(self.metrics.render(&storage)),
+            Err(_) => internal_error_rsp()
+        }
+    }
+
+    // the in-memory queues are the only backend so far
+    fn check_storage(&self) -> String {
+        match self.storage.read() {
+            Ok(storage) if storage.get_devices().is_empty() => String::from("no devices are registered"),
+            Ok(_) => String::from(STATUS_UP),
+            Err(_) => String::from("storage lock is poisoned")
+        }
+    }
+
+}
+
+fn enabled_features(config: &Config) -> Vec<String> {
+    let features = [
+        ("tls", config.server.tls.is_some()),
+        ("api_keys", !config.auth.api_keys.is_empty()),
+        ("cors", !config.cors.allowed_origins.is_empty()),
+        ("client_rate_limit", config.rate_limit.per_client.is_some()),
+        ("city_rate_limit", config.rate_limit.per_city.is_some()),
+        ("queue_limit", config.storage.max_queue_length.is_some()),
+        ("slap_coalescing", config.storage.coalesce_slaps != storage::SlapCoalescing::Off),
+        ("webhooks", !config.webhooks.endpoints.is_empty()),
+        ("proactive_events", !config.proactive_events.users.is_empty()),
+        ("chat", !config.chat.webhooks.is_empty()),
+        ("slack_commands", config.slack.signing_secret.is_some()),
+        ("mail_delivery", !config.mail.recipients.is_empty()),
+        ("mail_ingest", config.mail.ingest.is_some()),
+        ("mqtt", config.mqtt.broker.is_some()),
+        ("delivery_routes", !config.delivery.routes.is_empty())
+    ];
+
+    features.iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| String::from(*name))
+        .collect()
+}
+
+fn prepare_response(health: HealthResponse) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+    let is_up = health.is_up();
+    match serde_json::to_string(&health) {
+        Ok(json) if is_up => ok_rsp(json),
+        Ok(json) => service_unavailable_rsp(json),
+        Err(err) => {
+            error!(error:% = err; "cannot serialize health response");
+            internal_error_rsp()
+        }
+    }
+}
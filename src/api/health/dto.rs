@@ -0,0 +1,41 @@
+use std::collections::BTreeMap;
+
+pub const STATUS_UP: &str = "UP";
+pub const STATUS_DOWN: &str = "DOWN";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HealthResponse {
+    pub status: String,
+    pub checks: BTreeMap<String, String>
+}
+
+impl HealthResponse {
+    pub fn up() -> HealthResponse {
+        HealthResponse {
+            status: String::from(STATUS_UP),
+            checks: BTreeMap::new()
+        }
+    }
+
+    // DOWN as soon as one check is not UP
+    pub fn from_checks(checks: BTreeMap<String, String>) -> HealthResponse {
+        let status = if checks.values().all(|check| check == STATUS_UP) { STATUS_UP } else { STATUS_DOWN };
+
+        HealthResponse {
+            status: String::from(status),
+            checks
+        }
+    }
+
+    pub fn is_up(&self) -> bool {
+        self.status == STATUS_UP
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BuildInfo {
+    pub name: String,
+    pub version: String,
+    pub profile: String,
+    pub features: Vec<String>
+}
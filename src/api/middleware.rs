@@ -0,0 +1,299 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
+
+use futures::Future;
+use futures::future::ok;
+use hyper::{Body, Method, Request, Response, Version};
+use hyper::body::Payload;
+use hyper::header::HeaderMap;
+
+use crate::api::dispatcher::{request_id_of, route_label, with_request_id, Dispatcher, RequestId};
+use crate::api::ratelimit::RemoteAddr;
+use crate::logging::{clf_timestamp, in_request, rfc3339_timestamp, AccessLogFormat, ACCESS_LOG_TARGET};
+use crate::metrics::Metrics;
+
+// request data shared between the request and the response hooks of all layers
+pub struct RequestContext {
+    pub request_id: String,
+    pub method: Method,
+    pub path: String,
+    // path and query as requested, for the access log
+    pub target: String,
+    pub version: Version,
+    pub headers: HeaderMap,
+    pub remote_addr: Option<SocketAddr>,
+    // set by the authentication layer once the caller is known
+    pub client_id: Option<String>,
+    pub received_at: SystemTime,
+    pub started_at: Instant
+}
+
+impl RequestContext {
+    pub fn from(req: &Request<Body>) -> RequestContext {
+        RequestContext {
+            request_id: request_id_of(req),
+            method: req.method().clone(),
+            path: String::from(req.uri().path()),
+            target: req.uri().path_and_query().map(|target| String::from(target.as_str())).unwrap_or_else(|| String::from("/")),
+            version: req.version(),
+            headers: req.headers().clone(),
+            remote_addr: req.extensions().get::<RemoteAddr>().map(|RemoteAddr(addr)| *addr),
+            client_id: None,
+            received_at: SystemTime::now(),
+            started_at: Instant::now()
+        }
+    }
+}
+
+pub enum Flow {
+    Continue(Request<Body>),
+    Respond(Response<Body>)
+}
+
+// request hooks run in registration order, response hooks in reverse order.
+// A layer short-circuits by returning a response from on_request; that response
+// then only passes the response hooks of the layers registered before it.
+pub trait Middleware: Send + Sync {
+    fn on_request(&self, req: Request<Body>, _ctx: &mut RequestContext) -> Flow {
+        Flow::Continue(req)
+    }
+
+    fn on_response(&self, rsp: Response<Body>, _ctx: &RequestContext) -> Response<Body> {
+        rsp
+    }
+}
+
+pub struct Pipeline {
+    layers: Arc<Vec<Box<dyn Middleware>>>,
+    dispatcher: Arc<Dispatcher>
+}
+
+impl Pipeline {
+
+    pub fn new(dispatcher: Dispatcher, layers: Vec<Box<dyn Middleware>>) -> Pipeline {
+        Pipeline {
+            layers: Arc::new(layers),
+            dispatcher: Arc::new(dispatcher)
+        }
+    }
+
+    pub fn handle(&self, req: Request<Body>) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+        let mut ctx = RequestContext::from(&req);
+        let mut req = req;
+        req.extensions_mut().insert(RequestId(ctx.request_id.clone()));
+        let request_id = ctx.request_id.clone();
+
+        let flow = in_request(&request_id, || {
+            for (position, layer) in self.layers.iter().enumerate() {
+                req = match layer.on_request(req, &mut ctx) {
+                    Flow::Continue(req) => req,
+                    Flow::Respond(rsp) => {
+                        let rsp = with_request_id(rsp, &ctx.request_id);
+                        return Flow::Respond(run_response_hooks(&self.layers[..position], rsp, &ctx));
+                    }
+                };
+            }
+            Flow::Continue(req)
+        });
+
+        let req = match flow {
+            Flow::Continue(req) => req,
+            Flow::Respond(rsp) => return Box::new(ok(rsp))
+        };
+
+        let layers = self.layers.clone();
+        let result = self.dispatcher.dispatch(req)
+            .map(move |rsp| in_request(&request_id, || run_response_hooks(&layers, rsp, &ctx)));
+
+        Box::new(result)
+    }
+
+}
+
+fn run_response_hooks(layers: &[Box<dyn Middleware>], rsp: Response<Body>, ctx: &RequestContext) -> Response<Body> {
+    layers.iter().rev().fold(rsp, |rsp, layer| layer.on_response(rsp, ctx))
+}
+
+// one access log line per request, written as is next to the other log records
+pub struct AccessLog {
+    format: AccessLogFormat
+}
+
+impl AccessLog {
+    pub fn new(format: AccessLogFormat) -> AccessLog {
+        AccessLog {
+            format
+        }
+    }
+}
+
+impl Middleware for AccessLog {
+    fn on_response(&self, rsp: Response<Body>, ctx: &RequestContext) -> Response<Body> {
+        let line = access_line(self.format, ctx, rsp.status().as_u16(), rsp.body().content_length());
+        info!(target: ACCESS_LOG_TARGET, "{}", line);
+        rsp
+    }
+}
+
+fn access_line(format: AccessLogFormat, ctx: &RequestContext, status: u16, bytes: Option<u64>) -> String {
+    let remote = ctx.remote_addr.map(|addr| addr.ip().to_string());
+    let latency_ms = ctx.started_at.elapsed().as_millis() as u64;
+
+    match format {
+        // common log format, followed by the request id and the latency in milliseconds
+        AccessLogFormat::Common => format!("{} - {} [{}] \"{} {} {:?}\" {} {} {} {}",
+            remote.as_deref().unwrap_or("-"),
+            ctx.client_id.as_deref().unwrap_or("-"),
+            clf_timestamp(ctx.received_at),
+            ctx.method,
+            ctx.target,
+            ctx.version,
+            status,
+            bytes.map(|bytes| bytes.to_string()).unwrap_or_else(|| String::from("-")),
+            ctx.request_id,
+            latency_ms),
+        AccessLogFormat::Json => json!({
+            "ts": rfc3339_timestamp(ctx.received_at),
+            "request_id": ctx.request_id,
+            "remote": remote,
+            "client": ctx.client_id,
+            "method": ctx.method.as_str(),
+            "path": ctx.target,
+            "route": route_label(&ctx.method, &ctx.path),
+            "protocol": format!("{:?}", ctx.version),
+            "status": status,
+            "bytes": bytes,
+            "latency_ms": latency_ms
+        }).to_string()
+    }
+}
+
+pub struct MetricsLayer {
+    metrics: Arc<Metrics>
+}
+
+impl MetricsLayer {
+    pub fn new(metrics: Arc<Metrics>) -> MetricsLayer {
+        MetricsLayer {
+            metrics
+        }
+    }
+}
+
+impl Middleware for MetricsLayer {
+    fn on_response(&self, rsp: Response<Body>, ctx: &RequestContext) -> Response<Body> {
+        let route = route_label(&ctx.method, &ctx.path);
+        self.metrics.observe_request(route, ctx.method.as_str(), rsp.status().as_u16(), ctx.started_at.elapsed());
+        rsp
+    }
+}
+
+// ------------- there are tests only below this point ------------
+
+#[cfg(test)]
+use std::sync::{Mutex, RwLock};
+#[cfg(test)]
+use hyper::StatusCode;
+#[cfg(test)]
+use crate::storage::Storage;
+#[cfg(test)]
+use crate::config::Config;
+
+#[cfg(test)]
+struct RecordingLayer {
+    name: &'static str,
+    short_circuit: bool,
+    journal: Arc<Mutex<Vec<String>>>
+}
+
+#[cfg(test)]
+impl Middleware for RecordingLayer {
+    fn on_request(&self, req: Request<Body>, _ctx: &mut RequestContext) -> Flow {
+        self.journal.lock().unwrap().push(format!("request {}", self.name));
+        if self.short_circuit {
+            let rsp = Response::builder().status(StatusCode::FORBIDDEN).body(Body::empty()).unwrap();
+            return Flow::Respond(rsp);
+        }
+        Flow::Continue(req)
+    }
+
+    fn on_response(&self, rsp: Response<Body>, _ctx: &RequestContext) -> Response<Body> {
+        self.journal.lock().unwrap().push(format!("response {}", self.name));
+        rsp
+    }
+}
+
+#[cfg(test)]
+fn recording_pipeline(short_circuit_at: Option<&'static str>, journal: Arc<Mutex<Vec<String>>>) -> Pipeline {
+    let storage = Arc::new(RwLock::new(Storage::new()));
+    let dispatcher = crate::create_dispatcher(storage, Arc::new(crate::metrics::Metrics::new()), &Config::default());
+
+    let layers: Vec<Box<dyn Middleware>> = ["outer", "middle", "inner"].iter()
+        .map(|name| Box::new(RecordingLayer {
+            name,
+            short_circuit: short_circuit_at == Some(*name),
+            journal: journal.clone()
+        }) as Box<dyn Middleware>)
+        .collect();
+
+    Pipeline::new(dispatcher, layers)
+}
+
+#[cfg(test)]
+fn status_request() -> Request<Body> {
+    Request::builder()
+        .uri("https://auto1.danila.app/rest-api/status?city=BERLIN")
+        .body(Body::empty())
+        .unwrap()
+}
+
+#[test]
+fn test_layers_wrap_dispatcher_in_order() {
+    let journal = Arc::new(Mutex::new(Vec::new()));
+    let pipeline = recording_pipeline(None, journal.clone());
+
+    let rsp = pipeline.handle(status_request()).wait().unwrap();
+
+    assert_eq!(rsp.status(), StatusCode::OK);
+    assert_eq!(*journal.lock().unwrap(), vec![
+        "request outer", "request middle", "request inner",
+        "response inner", "response middle", "response outer"
+    ]);
+}
+
+#[test]
+fn test_short_circuit_skips_inner_layers_and_dispatcher() {
+    let journal = Arc::new(Mutex::new(Vec::new()));
+    let pipeline = recording_pipeline(Some("middle"), journal.clone());
+
+    let rsp = pipeline.handle(status_request()).wait().unwrap();
+
+    assert_eq!(rsp.status(), StatusCode::FORBIDDEN);
+    assert_eq!(*journal.lock().unwrap(), vec![
+        "request outer", "request middle",
+        "response outer"
+    ]);
+}
+
+#[test]
+fn test_access_lines_in_common_and_json_format() {
+    let mut req = Request::get("https://auto1.danila.app/rest-api/status?city=BERLIN").body(Body::empty()).unwrap();
+    req.extensions_mut().insert(RemoteAddr("10.0.0.1:50000".parse().unwrap()));
+    req.extensions_mut().insert(RequestId(String::from("5bac9e36-000001")));
+    let mut ctx = RequestContext::from(&req);
+    ctx.client_id = Some(String::from("berlin-bot"));
+    ctx.received_at = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_538_043_174);
+
+    let common = access_line(AccessLogFormat::Common, &ctx, 200, Some(17));
+    assert!(common.starts_with("10.0.0.1 - berlin-bot [27/Sep/2018:10:12:54 +0000] \"GET /rest-api/status?city=BERLIN HTTP/1.1\" 200 17 5bac9e36-000001 "));
+
+    let json: serde_json::Value = serde_json::from_str(&access_line(AccessLogFormat::Json, &ctx, 201, None)).unwrap();
+    assert_eq!(json["request_id"], "5bac9e36-000001");
+    assert_eq!(json["remote"], "10.0.0.1");
+    assert_eq!(json["client"], "berlin-bot");
+    assert_eq!(json["route"], "/rest-api/status");
+    assert_eq!(json["status"], 201);
+    assert_eq!(json["bytes"], serde_json::Value::Null);
+    assert_eq!(json["ts"], "2018-09-27T10:12:54.000Z");
+}
@@ -1,4 +1,10 @@
 pub mod rest;
 pub mod alexa;
+pub mod slack;
+pub mod health;
+pub mod auth;
+pub mod ratelimit;
+pub mod cors;
 pub mod dispatcher;
+pub mod middleware;
 pub mod utils;
@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hyper::{Body, Request};
+
+use crate::api::auth::Identity;
+use crate::api::middleware::{Flow, Middleware, RequestContext};
+use crate::api::utils::too_many_requests_response;
+use crate::config::BucketConfig;
+
+#[cfg(test)]
+use hyper::StatusCode;
+#[cfg(test)]
+use hyper::header::RETRY_AFTER;
+#[cfg(test)]
+use crate::api::utils::retry_after_secs;
+
+const LIMITED_PREFIX: &str = "/rest-api/";
+const PRUNE_THRESHOLD: usize = 10_000;
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Duration;
+}
+
+pub struct SystemClock {
+    origin: Instant
+}
+
+impl SystemClock {
+    pub fn new() -> SystemClock {
+        SystemClock {
+            origin: Instant::now()
+        }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.origin.elapsed()
+    }
+}
+
+// peer address of the connection, inserted into the request extensions by the server
+#[derive(Clone, Copy, Debug)]
+pub struct RemoteAddr(pub SocketAddr);
+
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Duration
+}
+
+// a limiter without a bucket config lets everything through
+pub struct RateLimiter {
+    bucket: Option<BucketConfig>,
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+    clock: Arc<dyn Clock>
+}
+
+impl RateLimiter {
+
+    pub fn new(bucket: Option<BucketConfig>, clock: Arc<dyn Clock>) -> RateLimiter {
+        RateLimiter {
+            bucket,
+            buckets: Mutex::new(HashMap::new()),
+            clock
+        }
+    }
+
+    // takes one token from the bucket of the key or tells how long to wait for it
+    pub fn try_acquire(&self, key: &str) -> Result<(), Duration> {
+        let config = match &self.bucket {
+            Some(config) => config,
+            None => return Ok(())
+        };
+
+        let capacity = config.capacity as f64;
+        let rate = config.refill_per_minute as f64 / 60.0;
+        let now = self.clock.now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() > PRUNE_THRESHOLD {
+            buckets.retain(|_, bucket| bucket.tokens + rate * (now - bucket.updated_at).as_secs_f64() < capacity);
+        }
+
+        let bucket = buckets.entry(String::from(key)).or_insert(TokenBucket {
+            tokens: capacity,
+            updated_at: now
+        });
+
+        let elapsed = (now - bucket.updated_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+        bucket.updated_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else if rate > 0.0 {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        } else {
+            Err(Duration::from_secs(u64::from(u32::MAX)))
+        }
+    }
+
+}
+
+// limits every REST call per api key, or per peer address for anonymous callers
+pub struct ClientRateLimit {
+    limiter: RateLimiter
+}
+
+impl ClientRateLimit {
+    pub fn new(limiter: RateLimiter) -> ClientRateLimit {
+        ClientRateLimit {
+            limiter
+        }
+    }
+}
+
+impl Middleware for ClientRateLimit {
+    fn on_request(&self, req: Request<Body>, _ctx: &mut RequestContext) -> Flow {
+        if !req.uri().path().starts_with(LIMITED_PREFIX) {
+            return Flow::Continue(req);
+        }
+
+        let client = match (req.extensions().get::<Identity>(), req.extensions().get::<RemoteAddr>()) {
+            (Some(identity), _) => format!("client:{}", &identity.client_id),
+            (None, Some(RemoteAddr(addr))) => format!("ip:{}", addr.ip()),
+            (None, None) => String::from("anonymous")
+        };
+
+        match self.limiter.try_acquire(&client) {
+            Ok(()) => Flow::Continue(req),
+            Err(wait) => Flow::Respond(too_many_requests_response(wait, format!("rate limit for {} exceeded.", &client)))
+        }
+    }
+}
+
+// ------------- there are tests only below this point ------------
+
+#[cfg(test)]
+pub struct ManualClock {
+    now: Mutex<Duration>
+}
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn new() -> ManualClock {
+        ManualClock {
+            now: Mutex::new(Duration::from_secs(0))
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        *self.now.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+fn limiter_with(capacity: u32, refill_per_minute: u32, clock: Arc<ManualClock>) -> RateLimiter {
+    RateLimiter::new(Some(BucketConfig { capacity, refill_per_minute }), clock)
+}
+
+#[test]
+fn test_bucket_refills_with_time() {
+    let clock = Arc::new(ManualClock::new());
+    let limiter = limiter_with(2, 6, clock.clone());
+
+    assert!(limiter.try_acquire("MILAN").is_ok());
+    assert!(limiter.try_acquire("MILAN").is_ok());
+    assert_eq!(limiter.try_acquire("MILAN"), Err(Duration::from_secs(10)));
+
+    clock.advance(Duration::from_secs(9));
+    let wait = limiter.try_acquire("MILAN").unwrap_err();
+    assert_eq!(retry_after_secs(wait), 1);
+
+    clock.advance(Duration::from_secs(1));
+    assert!(limiter.try_acquire("MILAN").is_ok());
+    assert!(limiter.try_acquire("MILAN").is_err());
+}
+
+#[test]
+fn test_buckets_are_independent_per_key() {
+    let clock = Arc::new(ManualClock::new());
+    let limiter = limiter_with(1, 1, clock.clone());
+
+    assert!(limiter.try_acquire("MILAN").is_ok());
+    assert!(limiter.try_acquire("MILAN").is_err());
+    assert!(limiter.try_acquire("BERLIN").is_ok());
+}
+
+#[test]
+fn test_bucket_never_exceeds_capacity() {
+    let clock = Arc::new(ManualClock::new());
+    let limiter = limiter_with(2, 60, clock.clone());
+
+    clock.advance(Duration::from_secs(3600));
+
+    assert!(limiter.try_acquire("KIEV").is_ok());
+    assert!(limiter.try_acquire("KIEV").is_ok());
+    assert!(limiter.try_acquire("KIEV").is_err());
+}
+
+#[test]
+fn test_client_limit_keys_by_remote_address() {
+    let clock = Arc::new(ManualClock::new());
+    let layer = ClientRateLimit::new(limiter_with(1, 60, clock.clone()));
+    let request_from = |addr: &str| {
+        let mut req = Request::get("https://auto1.danila.app/rest-api/status?city=BERLIN").body(Body::empty()).unwrap();
+        req.extensions_mut().insert(RemoteAddr(addr.parse().unwrap()));
+        req
+    };
+    let run = |req: Request<Body>| {
+        let mut ctx = RequestContext::from(&req);
+        match layer.on_request(req, &mut ctx) {
+            Flow::Continue(_) => None,
+            Flow::Respond(rsp) => Some(rsp)
+        }
+    };
+
+    assert!(run(request_from("10.0.0.1:50000")).is_none());
+    assert!(run(request_from("10.0.0.2:50000")).is_none());
+
+    let rsp = run(request_from("10.0.0.1:50001")).unwrap();
+    assert_eq!(rsp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(rsp.headers()[RETRY_AFTER], "1");
+
+    clock.advance(Duration::from_secs(1));
+    assert!(run(request_from("10.0.0.1:50001")).is_none());
+}
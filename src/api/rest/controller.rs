@@ -1,79 +1,359 @@
 use crate::storage;
+use crate::storage::AddOutcome;
 
 use std::sync::{Arc, RwLock};
 use crate::futures::Future;
+use crate::futures::future::ok;
 
-use crate::api::rest::dto::{StatusResponse, CreateNotificationReqeust};
-use crate::api::utils::{bad_request_rsp, created_rsp, internal_error_rsp, ok_rsp};
+use crate::api::rest::dto::{DeliveriesResponse, NextNotificationResponse, StatusResponse, CreateNotificationReqeust, CityTarget, ALL_CITIES, BatchCreateResponse, CreateResult};
+use crate::api::rest::openapi;
+use crate::api::rest::sse::{change_message, event_stream_response, snapshot_message};
+use crate::api::rest::ws::{accept_key, serve, switching_protocols_response, upgrade_required_response, HandshakeError, Session};
+use crate::feed::{ChangeId, ChangeKind};
+use crate::api::auth::Identity;
+use crate::metrics::{Metrics, CHANNEL_REST};
+use crate::api::ratelimit::RateLimiter;
+use crate::api::utils::{bad_request_rsp, no_content_rsp, created_rsp, forbidden_rsp, internal_error_rsp, ok_rsp, multi_status_rsp, too_many_requests_rsp, created_with_overflow_rsp, insufficient_storage_rsp, storage_unavailable_rsp, retry_after_secs};
 
+use std::time::{Duration, Instant};
 use hyper::{Body, Response};
+use hyper::header::HeaderMap;
+use hyper::upgrade::OnUpgrade;
+use futures::Stream;
+use tokio::timer::Delay;
 
 
 
 pub struct RestController {
-    storage: Arc<RwLock<storage::Storage>>
+    storage: Arc<RwLock<storage::Storage>>,
+    city_limiter: RateLimiter,
+    metrics: Arc<Metrics>,
+    heartbeat: Duration,
+    max_poll_wait: Duration
+}
+
+enum CreationError {
+    Invalid(String),
+    Forbidden(String),
+    RateLimited(String, Duration),
+    QueueFull(String),
+    Unavailable
+}
+
+impl CreationError {
+    fn status(&self) -> u16 {
+        match self {
+            CreationError::Invalid(_) => 400,
+            CreationError::Forbidden(_) => 403,
+            CreationError::RateLimited(_, _) => 429,
+            CreationError::QueueFull(_) => 507,
+            CreationError::Unavailable => 503
+        }
+    }
+
+    fn message(self) -> String {
+        match self {
+            CreationError::Invalid(msg) => msg,
+            CreationError::Forbidden(msg) => msg,
+            CreationError::RateLimited(msg, _) => msg,
+            CreationError::QueueFull(msg) => msg,
+            CreationError::Unavailable => String::from("the notification storage is unavailable.")
+        }
+    }
 }
 
 impl RestController {
 
-    pub fn new(storage:Arc<RwLock<storage::Storage>>) -> RestController {
+    pub fn new(storage:Arc<RwLock<storage::Storage>>, city_limiter: RateLimiter, metrics: Arc<Metrics>, heartbeat: Duration, max_poll_wait: Duration) -> RestController {
         RestController {
-            storage: storage
+            storage,
+            city_limiter,
+            metrics,
+            heartbeat,
+            max_poll_wait
+        }
+    }
+
+    pub fn get_notifications_for(&self, device: &String) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+        debug!(device = device.as_str(); "reading queue size");
+
+        match self.storage.read() {
+            Ok(storage) => prepare_response(storage.size(device)),
+            Err(_) => storage_unavailable_rsp()
+        }
+    }
+
+    pub fn get_deliveries_for(&self, device: &String) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+        let storage = match self.storage.read() {
+            Ok(storage) => storage,
+            Err(_) => return storage_unavailable_rsp()
+        };
+        if !storage.is_registered(device) {
+            return bad_request_rsp(format!("unknown city {}, supported cities are {}.", device, storage.get_supported_cities_as_str()));
+        }
+
+        let response_object = DeliveriesResponse::new(device, &storage.deliveries().for_city(device));
+        match serde_json::to_string(&response_object) {
+            Ok(json) => ok_rsp(json),
+            Err(err) => {
+                error!(error:% = err; "cannot serialize deliveries response");
+                internal_error_rsp()
+            }
         }
     }
 
-    pub fn get_notifications_for(&self, device: &String) -> Box<Future<Item=Response<Body>, Error=hyper::Error> + Send> {
-        println!("DEBUG: received GET notifications request for device: {}", device);
+    pub fn stream_events_for(&self, device: &String, last_event_id: Option<&str>) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+        // holding the lock keeps changes from slipping in between the snapshot and the subscription
+        let storage = match self.storage.read() {
+            Ok(storage) => storage,
+            Err(_) => return storage_unavailable_rsp()
+        };
+        if !storage.is_registered(device) {
+            return bad_request_rsp(format!("unknown city {}, supported cities are {}.", device, storage.get_supported_cities_as_str()));
+        }
 
-        let count = self.storage.read().unwrap().size(&device);
+        let subscription = storage.feed().subscribe(device, last_event_id.and_then(ChangeId::parse));
+        let head = match &subscription.missed {
+            Some(missed) => missed.iter().map(change_message).collect(),
+            None => vec![snapshot_message(device, storage.size(device), subscription.last_id)]
+        };
+        debug!(device = device.as_str(), resumed = subscription.missed.is_some(); "event stream opened");
 
-        return prepare_response(count);
+        Box::new(ok(event_stream_response(head, subscription, self.heartbeat)))
     }
 
-    pub fn create_notification(&self, req: CreateNotificationReqeust) -> Box<Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+    // dequeues the next event, waiting up to `wait` for one when the queue is empty
+    pub fn next_notification_for(&self, device: &String, wait: Duration, consumer: Option<&Identity>) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+        // taking a notification is as much within the scope of the key as sending one
+        if let Some(identity) = consumer {
+            if !identity.may_send_to(device) {
+                return forbidden_rsp(format!("{} is not allowed to take notifications of {}.", &identity.client_id, device));
+            }
+        }
+
+        let mut storage = match self.storage.write() {
+            Ok(storage) => storage,
+            Err(_) => return storage_unavailable_rsp()
+        };
+        if !storage.is_registered(device) {
+            return bad_request_rsp(format!("unknown city {}, supported cities are {}.", device, storage.get_supported_cities_as_str()));
+        }
+
+        if let Some(event) = storage.pop_event(device, CHANNEL_REST) {
+            self.metrics.notification_delivered(&event.event_type, CHANNEL_REST);
+            return prepare_next_response(device, &event);
+        }
+
+        let wait = wait.min(self.max_poll_wait);
+        if wait == Duration::from_secs(0) {
+            return no_content_rsp();
+        }
+
+        // subscribed under the lock, an event added right after the empty pop is not missed
+        let subscription = storage.feed().subscribe(device, None);
+        drop(storage);
+        debug!(device = device.as_str(), wait_ms = wait.as_millis() as u64; "waiting for the next notification");
+
+        let storage = self.storage.clone();
+        let city = device.clone();
+        let next = subscription.changes
+            .filter(|change| change.kind == ChangeKind::Added)
+            .filter_map(move |_| storage.write().ok()?.pop_event(&city, CHANNEL_REST))
+            .into_future()
+            .map(|(event, _)| event)
+            .map_err(|_| ());
+        let timeout = Delay::new(Instant::now() + wait)
+            .map(|_| None)
+            .map_err(|_| ());
+
+        // other consumers may win the race for an added event, the loser keeps waiting
+        let metrics = self.metrics.clone();
+        let city = device.clone();
+        Box::new(next.select(timeout).then(move |result| match result {
+            Ok((Some(event), _)) => {
+                metrics.notification_delivered(&event.event_type, CHANNEL_REST);
+                prepare_next_response(&city, &event)
+            },
+            _ => no_content_rsp()
+        }))
+    }
+
+    // answers the handshake, the session runs on its own task once hyper hands over the connection
+    pub fn open_socket(&self, headers: &HeaderMap, on_upgrade: OnUpgrade, consumer: Option<Identity>, request_id: String) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+        let accept = match accept_key(headers) {
+            Ok(accept) => accept,
+            Err(HandshakeError::UpgradeRequired) => return Box::new(ok(upgrade_required_response())),
+            Err(HandshakeError::InvalidKey) => return bad_request_rsp(String::from("header 'Sec-WebSocket-Key' is missing or malformed."))
+        };
+
+        // subscribed before the handshake is answered, so no change between the two is missed
+        let changes = match self.storage.read() {
+            Ok(storage) => storage.feed().subscribe_all(),
+            Err(_) => return storage_unavailable_rsp()
+        };
+        let session = Session::new(self.storage.clone(), self.metrics.clone(), consumer);
+        let heartbeat = self.heartbeat;
+        tokio::spawn(on_upgrade
+            .map_err(|err| warn!(error:% = err; "websocket upgrade failed"))
+            .and_then(move |upgraded| serve(upgraded, session, changes, heartbeat, request_id)));
+        debug!("websocket opened");
+
+        Box::new(ok(switching_protocols_response(accept)))
+    }
+
+    pub fn get_openapi_spec(&self) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+        ok_rsp(openapi::spec().to_string())
+    }
+
+    pub fn create_notification(&self, req: CreateNotificationReqeust, sender: Option<&Identity>) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+        match req.for_city.single_city() {
+            Some(for_city) => match self.create_for_city(&req, for_city.clone(), sender, CHANNEL_REST) {
+                Ok(None) => created_rsp(),
+                Ok(Some(overflow)) => created_with_overflow_rsp(overflow),
+                Err(CreationError::Invalid(msg)) => bad_request_rsp(msg),
+                Err(CreationError::Forbidden(msg)) => forbidden_rsp(msg),
+                Err(CreationError::RateLimited(msg, wait)) => too_many_requests_rsp(wait, msg),
+                Err(CreationError::QueueFull(msg)) => insufficient_storage_rsp(msg),
+                Err(CreationError::Unavailable) => storage_unavailable_rsp()
+            },
+            None => self.create_notifications(vec![req], sender)
+        }
+    }
+
+    pub fn create_notifications(&self, reqs: Vec<CreateNotificationReqeust>, sender: Option<&Identity>) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+        let results = reqs.iter()
+            .flat_map(|req| self.create_in_cities(req, sender, CHANNEL_REST))
+            .collect();
+
+        prepare_batch_response(BatchCreateResponse::new(results))
+    }
+
+    // the outcome for every city the request targets, channel labels the created notifications in the metrics
+    pub fn create_in_cities(&self, req: &CreateNotificationReqeust, sender: Option<&Identity>, channel: &'static str) -> Vec<CreateResult> {
+        let cities = match self.resolve_cities(&req.for_city) {
+            Ok(cities) if cities.is_empty() => Err(CreationError::Invalid(String::from("for_city must name at least one city."))),
+            result => result
+        };
+        let cities = match cities {
+            Ok(cities) => cities,
+            Err(error) => return vec![CreateResult::failed(req.type_name.clone(), String::new(), error.status(), error.message())]
+        };
+
+        cities.into_iter()
+            .map(|city| match self.create_for_city(req, city.clone(), sender, channel) {
+                Ok(overflow) => CreateResult::created(req.type_name.clone(), city, overflow),
+                Err(error) => CreateResult::failed(req.type_name.clone(), city, error.status(), error.message())
+            })
+            .collect()
+    }
+
+    fn resolve_cities(&self, target: &CityTarget) -> Result<Vec<String>, CreationError> {
+        let requested = match target {
+            CityTarget::One(city) => vec![city.clone()],
+            CityTarget::Many(cities) => cities.clone()
+        };
+
+        let mut cities: Vec<String> = Vec::new();
+        for city in requested {
+            let expanded = if city == ALL_CITIES {
+                self.storage.read().map_err(|_| CreationError::Unavailable)?.get_devices()
+            } else {
+                vec![city]
+            };
+
+            for city in expanded {
+                if !cities.contains(&city) {
+                    cities.push(city);
+                }
+            }
+        }
+
+        Ok(cities)
+    }
+
+    // on success tells how the overflow policy made room in a full queue, if it had to
+    fn create_for_city(&self, req: &CreateNotificationReqeust, for_city: String, sender: Option<&Identity>, channel: &'static str) -> Result<Option<&'static str>, CreationError> {
         let event_type = req.type_name.clone();
-        let for_city = req.for_city;
-        let valid_city = self.storage.read().unwrap().is_registered(&for_city);
+        let storage = self.storage.read().map_err(|_| CreationError::Unavailable)?;
 
         // validate parameter value
-        if !valid_city {
-            let supported_cities = self.storage.read().unwrap().get_supported_cities_as_str();
-            return bad_request_rsp(format!("The city {} is not supported. Supported cities are: {}.", &for_city, &supported_cities));
+        if !storage.is_registered(&for_city) {
+            let supported_cities = storage.get_supported_cities_as_str();
+            return Err(CreationError::Invalid(format!("The city {} is not supported. Supported cities are: {}.", &for_city, &supported_cities)));
+        }
+
+        // validate the scope of the api key
+        if let Some(identity) = sender {
+            if !identity.may_send_to(&for_city) {
+                return Err(CreationError::Forbidden(format!("{} is not allowed to send notifications to {}.", &identity.client_id, &for_city)));
+            }
         }
 
+        drop(storage);
+        let sender_id = sender.map(|identity| identity.client_id.clone());
+
         // process valid creation request
-        match event_type.as_ref() {
-            "SLAP" => self.create_slap_msg(for_city),
+        let event = match event_type.as_ref() {
+            "SLAP" => storage::Event::new_slap(),
             "MESSAGE" => {
-                match req.message_text {
-                    Some(text) => self.create_text_msg(for_city, text),
-                    None => bad_request_rsp(String::from("message_text property must not be missed if notification type is MESSAGE."))
+                match req.message_text.clone() {
+                    Some(text) => storage::Event::new_message(text),
+                    None => return Err(CreationError::Invalid(String::from("message_text property must not be missed if notification type is MESSAGE.")))
                 }
             },
-            _ => bad_request_rsp(format!("The event type '{}' is not supported. Supported types are: SLAP, MESSAGE.", &event_type))
+            _ => return Err(CreationError::Invalid(format!("The event type '{}' is not supported. Supported types are: SLAP, MESSAGE.", &event_type)))
+        };
+
+        // only valid requests count against the limit of the city
+        if let Err(wait) = self.city_limiter.try_acquire(&for_city) {
+            let msg = format!("Too many notifications for {}, retry in {} seconds.", &for_city, retry_after_secs(wait));
+            return Err(CreationError::RateLimited(msg, wait));
         }
-    }
 
-    fn create_text_msg(&self, for_city: String, text: String) -> Box<Future<Item=Response<Body>, Error=hyper::Error> + Send> {
-        let event = storage::Event::new_message(text);
-        self.storage.write().unwrap().add_event(event, for_city);
-        created_rsp()
-    }
+        let event_type = event.event_type.clone();
+        let result = match self.storage.write().map_err(|_| CreationError::Unavailable)?.add_event(event.sent_by(sender_id), for_city.clone()) {
+            AddOutcome::Queued | AddOutcome::Merged | AddOutcome::Pushed => Ok(None),
+            AddOutcome::DroppedOldest(_) => Ok(Some("dropped_oldest")),
+            AddOutcome::Coalesced => Ok(Some("coalesced")),
+            AddOutcome::Rejected => Err(CreationError::QueueFull(format!("The notification queue of {} is full.", &for_city))),
+            AddOutcome::UnknownDevice => Err(CreationError::Invalid(format!("The city {} is not supported.", &for_city)))
+        };
 
-    fn create_slap_msg(&self, for_city: String) -> Box<Future<Item=Response<Body>, Error=hyper::Error> + Send> {
-        let event = storage::Event::new_slap();
-        self.storage.write().unwrap().add_event(event, for_city);
-        created_rsp()
+        if result.is_ok() {
+            self.metrics.notification_created(&event_type, channel);
+        }
+        result
     }
 
 }
 
-fn prepare_response(num: usize) -> Box<Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+fn prepare_response(num: usize) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
     let response_object = StatusResponse::new(num);
     match serde_json::to_string(&response_object) {
         Ok(json) => ok_rsp(json),
         Err(err) => {
-            println!("ERROR: failed to serialize response for notification creation: {:?}", err);
+            error!(error:% = err; "cannot serialize status response");
+            internal_error_rsp()
+        }
+
+    }
+}
+
+fn prepare_next_response(city: &str, event: &storage::Event) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+    match serde_json::to_string(&NextNotificationResponse::new(city, event)) {
+        Ok(json) => ok_rsp(json),
+        Err(err) => {
+            error!(error:% = err; "cannot serialize next notification");
+            internal_error_rsp()
+        }
+    }
+}
+
+fn prepare_batch_response(response_object: BatchCreateResponse) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+    match serde_json::to_string(&response_object) {
+        Ok(json) => multi_status_rsp(json),
+        Err(err) => {
+            error!(error:% = err; "cannot serialize batch creation response");
             internal_error_rsp()
         }
 
@@ -1,3 +1,9 @@
+use std::collections::BTreeMap;
+
+use crate::delivery::{DeliveryRecord, DeliveryStatus};
+use crate::feed::QueueChange;
+use crate::storage::Event;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct StatusResponse {
     pub message_num: usize
@@ -6,7 +12,7 @@ pub struct StatusResponse {
 impl StatusResponse {
     pub fn new(message_num: usize) -> StatusResponse {
         StatusResponse {
-            message_num: message_num
+            message_num
         }
     }
 }
@@ -14,6 +20,253 @@ impl StatusResponse {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CreateNotificationReqeust {
     pub type_name: String,
-    pub for_city: String,
+    pub for_city: CityTarget,
     pub message_text: Option<String>
 }
+
+// "BERLIN", "*" for every registered city, or ["BERLIN", "KIEV"]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum CityTarget {
+    One(String),
+    Many(Vec<String>)
+}
+
+pub const ALL_CITIES: &str = "*";
+
+impl CityTarget {
+    pub fn single_city(&self) -> Option<&String> {
+        match self {
+            CityTarget::One(city) if city != ALL_CITIES => Some(city),
+            _ => None
+        }
+    }
+}
+
+impl From<String> for CityTarget {
+    fn from(city: String) -> CityTarget {
+        CityTarget::One(city)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum CreateNotificationsBody {
+    Batch(Vec<CreateNotificationReqeust>),
+    Single(CreateNotificationReqeust)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BatchCreateResponse {
+    pub results: Vec<CreateResult>
+}
+
+impl BatchCreateResponse {
+    pub fn new(results: Vec<CreateResult>) -> BatchCreateResponse {
+        BatchCreateResponse {
+            results
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CreateResult {
+    pub type_name: String,
+    pub for_city: String,
+    pub status: u16,
+    pub error: Option<String>,
+    pub overflow: Option<String>
+}
+
+impl CreateResult {
+    pub fn created(type_name: String, for_city: String, overflow: Option<&str>) -> CreateResult {
+        CreateResult {
+            type_name,
+            for_city,
+            status: 201,
+            error: None,
+            overflow: overflow.map(String::from)
+        }
+    }
+
+    pub fn failed(type_name: String, for_city: String, status: u16, error: String) -> CreateResult {
+        CreateResult {
+            type_name,
+            for_city,
+            status,
+            error: Some(error),
+            overflow: None
+        }
+    }
+}
+
+// data of a server-sent event, type_name is empty for the snapshot of a queue
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QueueChangeEvent {
+    pub city: String,
+    pub type_name: Option<String>,
+    pub message_num: usize
+}
+
+impl QueueChangeEvent {
+    pub fn snapshot(city: &str, message_num: usize) -> QueueChangeEvent {
+        QueueChangeEvent {
+            city: String::from(city),
+            type_name: None,
+            message_num
+        }
+    }
+}
+
+impl<'a> From<&'a QueueChange> for QueueChangeEvent {
+    fn from(change: &'a QueueChange) -> QueueChangeEvent {
+        QueueChangeEvent {
+            city: change.city.clone(),
+            type_name: Some(String::from(change.event.event_type.name())),
+            message_num: change.queue_size
+        }
+    }
+}
+
+// the latest notifications of a city, oldest first, and how they fared on every channel
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeliveriesResponse {
+    pub city: String,
+    pub deliveries: Vec<DeliveryEntry>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeliveryEntry {
+    // the id of the added event of the event stream
+    pub id: String,
+    pub type_name: String,
+    pub channels: BTreeMap<String, ChannelDelivery>,
+    pub taken_by: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChannelDelivery {
+    pub status: String,
+    pub reason: Option<String>
+}
+
+impl DeliveriesResponse {
+    pub fn new(city: &str, records: &[DeliveryRecord]) -> DeliveriesResponse {
+        let deliveries = records.iter()
+            .map(|record| DeliveryEntry {
+                id: record.id.to_string(),
+                type_name: String::from(record.event_type.name()),
+                channels: record.channels.iter().map(|(channel, status)| (channel.clone(), ChannelDelivery::new(status))).collect(),
+                taken_by: record.taken_by.clone()
+            })
+            .collect();
+        DeliveriesResponse { city: String::from(city), deliveries }
+    }
+}
+
+impl ChannelDelivery {
+    pub fn new(status: &DeliveryStatus) -> ChannelDelivery {
+        match status {
+            DeliveryStatus::Pending => ChannelDelivery { status: String::from("pending"), reason: None },
+            DeliveryStatus::Delivered => ChannelDelivery { status: String::from("delivered"), reason: None },
+            DeliveryStatus::Failed(reason) => ChannelDelivery { status: String::from("failed"), reason: Some(reason.clone()) }
+        }
+    }
+}
+
+// event dequeued by a consumer polling a city
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NextNotificationResponse {
+    pub city: String,
+    pub type_name: String,
+    pub message_text: Option<String>,
+    pub sender: Option<String>,
+    pub count: u32
+}
+
+impl NextNotificationResponse {
+    pub fn new(city: &str, event: &Event) -> NextNotificationResponse {
+        NextNotificationResponse {
+            city: String::from(city),
+            type_name: String::from(event.event_type.name()),
+            message_text: event.message.clone(),
+            sender: event.sender.clone(),
+            count: event.count
+        }
+    }
+}
+
+// command of a WebSocket client: subscribe with cities, or ack with the id of a pushed notification
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SocketCommand {
+    pub action: String,
+    pub cities: Option<Vec<String>>,
+    pub id: Option<u64>
+}
+
+pub const SUBSCRIBE_ACTION: &str = "subscribe";
+pub const ACK_ACTION: &str = "ack";
+
+// pending event pushed over a WebSocket, it stays queued until the client acks its id
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SocketNotification {
+    pub kind: String,
+    pub id: u64,
+    pub city: String,
+    pub type_name: String,
+    pub message_text: Option<String>,
+    pub sender: Option<String>,
+    pub count: u32
+}
+
+impl SocketNotification {
+    pub fn new(id: u64, city: &str, event: &Event) -> SocketNotification {
+        SocketNotification {
+            kind: String::from("notification"),
+            id,
+            city: String::from(city),
+            type_name: String::from(event.event_type.name()),
+            message_text: event.message.clone(),
+            sender: event.sender.clone(),
+            count: event.count
+        }
+    }
+}
+
+// answer of the server to a command
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SocketReply {
+    pub kind: String,
+    pub id: Option<u64>,
+    pub cities: Option<Vec<String>>,
+    pub error: Option<String>
+}
+
+impl SocketReply {
+    pub fn subscribed(cities: Vec<String>) -> SocketReply {
+        SocketReply {
+            kind: String::from("subscribed"),
+            id: None,
+            cities: Some(cities),
+            error: None
+        }
+    }
+
+    pub fn acked(id: u64) -> SocketReply {
+        SocketReply {
+            kind: String::from("acked"),
+            id: Some(id),
+            cities: None,
+            error: None
+        }
+    }
+
+    pub fn error(error: String) -> SocketReply {
+        SocketReply {
+            kind: String::from("error"),
+            id: None,
+            cities: None,
+            error: Some(error)
+        }
+    }
+}
@@ -1,2 +1,6 @@
 pub mod dto;
 pub mod controller;
+pub mod routes;
+pub mod openapi;
+pub mod sse;
+pub mod ws;
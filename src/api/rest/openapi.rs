@@ -0,0 +1,566 @@
+use serde_json::{Map, Value};
+
+use crate::api::rest::dto::{NextNotificationResponse, StatusResponse, CreateNotificationReqeust, CityTarget, CreateNotificationsBody, BatchCreateResponse, CreateResult, QueueChangeEvent, SocketCommand, SocketNotification, SocketReply, DeliveriesResponse, DeliveryEntry, ChannelDelivery};
+use crate::api::rest::routes::{RestRoute, REST_ROUTES};
+
+pub trait ApiSchema {
+    fn schema_name() -> &'static str;
+    fn schema() -> Value;
+}
+
+impl ApiSchema for StatusResponse {
+    fn schema_name() -> &'static str { "StatusResponse" }
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["message_num"],
+            "properties": {
+                "message_num": { "type": "integer", "minimum": 0, "description": "number of pending notifications for the city" }
+            }
+        })
+    }
+}
+
+impl ApiSchema for CityTarget {
+    fn schema_name() -> &'static str { "CityTarget" }
+
+    fn schema() -> Value {
+        json!({
+            "description": "a registered city, \"*\" for every registered city, or a list of cities",
+            "oneOf": [
+                { "type": "string", "example": "BERLIN" },
+                { "type": "array", "items": { "type": "string" }, "example": ["BERLIN", "KIEV"] }
+            ]
+        })
+    }
+}
+
+impl ApiSchema for CreateNotificationReqeust {
+    fn schema_name() -> &'static str { "CreateNotificationRequest" }
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["type_name", "for_city"],
+            "properties": {
+                "type_name": { "type": "string", "enum": ["SLAP", "MESSAGE"] },
+                "for_city": reference::<CityTarget>(),
+                "message_text": { "type": "string", "nullable": true, "description": "mandatory when type_name is MESSAGE" }
+            }
+        })
+    }
+}
+
+impl ApiSchema for CreateNotificationsBody {
+    fn schema_name() -> &'static str { "CreateNotificationsBody" }
+
+    fn schema() -> Value {
+        json!({
+            "oneOf": [
+                reference::<CreateNotificationReqeust>(),
+                { "type": "array", "minItems": 1, "items": reference::<CreateNotificationReqeust>() }
+            ]
+        })
+    }
+}
+
+impl ApiSchema for CreateResult {
+    fn schema_name() -> &'static str { "CreateResult" }
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["type_name", "for_city", "status"],
+            "properties": {
+                "type_name": { "type": "string" },
+                "for_city": { "type": "string" },
+                "status": { "type": "integer", "description": "status the single-city request would have returned" },
+                "error": { "type": "string", "nullable": true },
+                "overflow": { "type": "string", "nullable": true, "enum": ["dropped_oldest", "coalesced"], "description": "set when the queue of the city was full and the overflow policy made room" }
+            }
+        })
+    }
+}
+
+impl ApiSchema for BatchCreateResponse {
+    fn schema_name() -> &'static str { "BatchCreateResponse" }
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["results"],
+            "properties": {
+                "results": { "type": "array", "items": reference::<CreateResult>() }
+            }
+        })
+    }
+}
+
+impl ApiSchema for QueueChangeEvent {
+    fn schema_name() -> &'static str { "QueueChangeEvent" }
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["city", "type_name", "message_num"],
+            "properties": {
+                "city": { "type": "string" },
+                "type_name": { "type": "string", "nullable": true, "enum": ["SLAP", "MESSAGE", null], "description": "type of the added, delivered or expired notification, null in a snapshot" },
+                "message_num": { "type": "integer", "minimum": 0, "description": "number of pending notifications after the change" }
+            }
+        })
+    }
+}
+
+impl ApiSchema for NextNotificationResponse {
+    fn schema_name() -> &'static str { "NextNotificationResponse" }
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["city", "type_name", "count"],
+            "properties": {
+                "city": { "type": "string" },
+                "type_name": { "type": "string", "enum": ["SLAP", "MESSAGE"] },
+                "message_text": { "type": "string", "nullable": true },
+                "sender": { "type": "string", "nullable": true },
+                "count": { "type": "integer", "minimum": 1, "description": "number of SLAPs counted into this one" }
+            }
+        })
+    }
+}
+
+impl ApiSchema for DeliveriesResponse {
+    fn schema_name() -> &'static str { "DeliveriesResponse" }
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["city", "deliveries"],
+            "properties": {
+                "city": { "type": "string" },
+                "deliveries": { "type": "array", "items": reference::<DeliveryEntry>(), "description": "the latest notifications of the city, oldest first; kept while queued or on their way through a channel, then for the last 100 notifications of the city" }
+            }
+        })
+    }
+}
+
+impl ApiSchema for DeliveryEntry {
+    fn schema_name() -> &'static str { "DeliveryEntry" }
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["id", "type_name", "channels"],
+            "properties": {
+                "id": { "type": "string", "description": "id of the added event of the event stream" },
+                "type_name": { "type": "string", "enum": ["SLAP", "MESSAGE"] },
+                "channels": { "type": "object", "additionalProperties": reference::<ChannelDelivery>(), "description": "by channel name: queue, proactive_events, chat, mail, webhooks or mqtt" },
+                "taken_by": { "type": "string", "nullable": true, "description": "the consumer that took it out of the queue: alexa, rest or websocket" }
+            }
+        })
+    }
+}
+
+impl ApiSchema for ChannelDelivery {
+    fn schema_name() -> &'static str { "ChannelDelivery" }
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["status"],
+            "properties": {
+                "status": { "type": "string", "enum": ["pending", "delivered", "failed"] },
+                "reason": { "type": "string", "nullable": true, "description": "why the delivery failed" }
+            }
+        })
+    }
+}
+
+impl ApiSchema for SocketCommand {
+    fn schema_name() -> &'static str { "SocketCommand" }
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["action"],
+            "properties": {
+                "action": { "type": "string", "enum": ["subscribe", "ack"] },
+                "cities": { "type": "array", "items": { "type": "string" }, "nullable": true, "description": "cities to follow, mandatory for subscribe" },
+                "id": { "type": "integer", "nullable": true, "description": "id of the pushed notification, mandatory for ack" }
+            }
+        })
+    }
+}
+
+impl ApiSchema for SocketNotification {
+    fn schema_name() -> &'static str { "SocketNotification" }
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["kind", "id", "city", "type_name", "count"],
+            "properties": {
+                "kind": { "type": "string", "enum": ["notification"] },
+                "id": { "type": "integer", "description": "acknowledge it to dequeue the notification" },
+                "city": { "type": "string" },
+                "type_name": { "type": "string", "enum": ["SLAP", "MESSAGE"] },
+                "message_text": { "type": "string", "nullable": true },
+                "sender": { "type": "string", "nullable": true },
+                "count": { "type": "integer", "minimum": 1, "description": "number of SLAPs counted into this one" }
+            }
+        })
+    }
+}
+
+impl ApiSchema for SocketReply {
+    fn schema_name() -> &'static str { "SocketReply" }
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["kind"],
+            "properties": {
+                "kind": { "type": "string", "enum": ["subscribed", "acked", "error"] },
+                "id": { "type": "integer", "nullable": true, "description": "the acked notification" },
+                "cities": { "type": "array", "items": { "type": "string" }, "nullable": true, "description": "every city followed after a subscribe" },
+                "error": { "type": "string", "nullable": true }
+            }
+        })
+    }
+}
+
+pub fn reference<T: ApiSchema>() -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", T::schema_name()) })
+}
+
+fn json_content<T: ApiSchema>(description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": { "application/json": { "schema": reference::<T>() } }
+    })
+}
+
+fn error_response() -> Value {
+    json!({ "$ref": "#/components/responses/Error" })
+}
+
+fn rate_limited_response() -> Value {
+    json!({ "$ref": "#/components/responses/RateLimited" })
+}
+
+fn request_id_parameter() -> Value {
+    json!({ "$ref": "#/components/parameters/RequestId" })
+}
+
+fn request_id_header() -> Value {
+    json!({ "$ref": "#/components/headers/RequestId" })
+}
+
+fn operation(route: RestRoute) -> Value {
+    match route {
+        RestRoute::GetStatus => json!({
+            "operationId": "getStatus",
+            "summary": "number of pending notifications for a city",
+            "parameters": [
+                { "name": "city", "in": "query", "required": true, "schema": { "type": "string" } },
+                request_id_parameter()
+            ],
+            "responses": {
+                "200": json_content::<StatusResponse>("pending notifications counter"),
+                "400": error_response(),
+                "401": error_response(),
+                "403": error_response(),
+                "406": error_response(),
+                "429": rate_limited_response()
+            }
+        }),
+        RestRoute::StreamEvents => json!({
+            "operationId": "streamEvents",
+            "summary": "server-sent events for every change of the queue of a city",
+            "description": "Events are named snapshot, added, delivered or expired and carry a QueueChangeEvent as data. The stream opens with a snapshot, or with the missed events when Last-Event-ID can still be resumed. Comment lines are sent as heartbeats.",
+            "parameters": [
+                { "name": "city", "in": "query", "required": true, "schema": { "type": "string" } },
+                { "name": "Last-Event-ID", "in": "header", "required": false, "schema": { "type": "string" } },
+                request_id_parameter()
+            ],
+            "responses": {
+                "200": {
+                    "description": "event stream, see the QueueChangeEvent schema for the data of every event",
+                    "content": { "text/event-stream": { "schema": { "type": "string" } } }
+                },
+                "400": error_response(),
+                "401": error_response(),
+                "403": error_response(),
+                "406": error_response(),
+                "429": rate_limited_response()
+            }
+        }),
+        RestRoute::OpenSocket => json!({
+            "operationId": "openSocket",
+            "summary": "WebSocket pushing the pending notifications of the subscribed cities",
+            "description": "Clients send SocketCommand text messages. After a subscribe the oldest pending notification of every city is pushed as SocketNotification, it stays queued until the client acks its id, then the next one follows. Every command is answered with a SocketReply. Reading the notifications requires the permission to read the status, and only cities in the scope of the api key can be subscribed.",
+            "parameters": [ request_id_parameter() ],
+            "responses": {
+                "101": {
+                    "description": "switched to the WebSocket protocol",
+                    "headers": { "X-Request-Id": request_id_header() }
+                },
+                "400": error_response(),
+                "401": error_response(),
+                "403": error_response(),
+                "426": error_response(),
+                "429": rate_limited_response()
+            }
+        }),
+        RestRoute::CreateNotifications => json!({
+            "operationId": "createNotifications",
+            "summary": "create a notification for one city, or fan out a batch / broadcast",
+            "parameters": [ request_id_parameter() ],
+            "requestBody": {
+                "required": true,
+                "content": { "application/json": { "schema": reference::<CreateNotificationsBody>() } }
+            },
+            "responses": {
+                "201": {
+                    "description": "notification created for the single requested city",
+                    "headers": {
+                        "X-Request-Id": request_id_header(),
+                        "X-Queue-Overflow": { "description": "dropped_oldest or coalesced when the queue of the city was full", "schema": { "type": "string" } }
+                    }
+                },
+                "207": json_content::<BatchCreateResponse>("per item results of a batch or multi-city request"),
+                "400": error_response(),
+                "401": error_response(),
+                "403": error_response(),
+                "406": error_response(),
+                "413": error_response(),
+                "429": rate_limited_response(),
+                "507": error_response()
+            }
+        }),
+        RestRoute::NextNotification => json!({
+            "operationId": "nextNotification",
+            "summary": "dequeue the next notification of a city, optionally waiting for one",
+            "description": "The returned notification is delivered and gone from the queue. Without a pending notification the call waits up to wait seconds, bounded by the server configuration, and answers 204 when none arrived. An api key needs to read the status and have the city in its scope.",
+            "parameters": [
+                { "name": "city", "in": "path", "required": true, "schema": { "type": "string" } },
+                { "name": "wait", "in": "query", "required": false, "schema": { "type": "integer", "minimum": 0, "default": 0 }, "description": "seconds to wait for a notification" },
+                request_id_parameter()
+            ],
+            "responses": {
+                "200": json_content::<NextNotificationResponse>("the dequeued notification"),
+                "204": { "description": "no notification arrived in time", "headers": { "X-Request-Id": request_id_header() } },
+                "400": error_response(),
+                "401": error_response(),
+                "403": error_response(),
+                "406": error_response(),
+                "429": rate_limited_response()
+            }
+        }),
+        RestRoute::GetDeliveries => json!({
+            "operationId": "getDeliveries",
+            "summary": "how the latest notifications of a city fared on every delivery channel",
+            "description": "A notification is queued unless its delivery routes leave out the queue channel; the queue channel is delivered once a consumer took it and failed when it expired. Push channels are listed as the delivery routes sent the notification to them. The statuses are kept while a notification is queued or on its way through a channel, afterwards only for the last 100 notifications of the city.",
+            "parameters": [
+                { "name": "city", "in": "path", "required": true, "schema": { "type": "string" } },
+                request_id_parameter()
+            ],
+            "responses": {
+                "200": json_content::<DeliveriesResponse>("the delivery statuses"),
+                "400": error_response(),
+                "401": error_response(),
+                "403": error_response(),
+                "406": error_response(),
+                "429": rate_limited_response()
+            }
+        }),
+        RestRoute::GetOpenApi => json!({
+            "operationId": "getOpenApi",
+            "summary": "this document",
+            "parameters": [ request_id_parameter() ],
+            "responses": {
+                "200": { "description": "OpenAPI 3 document", "content": { "application/json": { "schema": { "type": "object" } } } },
+                "401": error_response(),
+                "406": error_response(),
+                "429": rate_limited_response()
+            }
+        })
+    }
+}
+
+fn schemas() -> Value {
+    let mut schemas = Map::new();
+    schemas.insert(String::from(StatusResponse::schema_name()), StatusResponse::schema());
+    schemas.insert(String::from(CityTarget::schema_name()), CityTarget::schema());
+    schemas.insert(String::from(CreateNotificationReqeust::schema_name()), CreateNotificationReqeust::schema());
+    schemas.insert(String::from(CreateNotificationsBody::schema_name()), CreateNotificationsBody::schema());
+    schemas.insert(String::from(CreateResult::schema_name()), CreateResult::schema());
+    schemas.insert(String::from(BatchCreateResponse::schema_name()), BatchCreateResponse::schema());
+    schemas.insert(String::from(QueueChangeEvent::schema_name()), QueueChangeEvent::schema());
+    schemas.insert(String::from(NextNotificationResponse::schema_name()), NextNotificationResponse::schema());
+    schemas.insert(String::from(DeliveriesResponse::schema_name()), DeliveriesResponse::schema());
+    schemas.insert(String::from(DeliveryEntry::schema_name()), DeliveryEntry::schema());
+    schemas.insert(String::from(ChannelDelivery::schema_name()), ChannelDelivery::schema());
+    schemas.insert(String::from(SocketCommand::schema_name()), SocketCommand::schema());
+    schemas.insert(String::from(SocketNotification::schema_name()), SocketNotification::schema());
+    schemas.insert(String::from(SocketReply::schema_name()), SocketReply::schema());
+    Value::Object(schemas)
+}
+
+pub fn spec() -> Value {
+    let mut paths = Map::new();
+    for route in REST_ROUTES.iter() {
+        let method = route.method().as_str().to_lowercase();
+        let path_item = paths.entry(String::from(route.path())).or_insert_with(|| json!({}));
+        path_item[method] = operation(*route);
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Danila notification service",
+            "version": env!("CARGO_PKG_VERSION")
+        },
+        "paths": Value::Object(paths),
+        "security": [ { "bearer": [] }, { "apiKey": [] } ],
+        "components": {
+            "securitySchemes": {
+                "bearer": { "type": "http", "scheme": "bearer", "description": "api key from the service configuration" },
+                "apiKey": { "type": "apiKey", "in": "header", "name": "X-Api-Key" }
+            },
+            "schemas": schemas(),
+            "parameters": {
+                "RequestId": {
+                    "name": "X-Request-Id", "in": "header", "required": false,
+                    "description": "correlates the call with the service logs, generated when missing or malformed",
+                    "schema": { "type": "string", "maxLength": 128, "pattern": "^[A-Za-z0-9._:-]+$" }
+                }
+            },
+            "headers": {
+                "RequestId": { "description": "id of the request as it appears in the service logs", "schema": { "type": "string" } }
+            },
+            "responses": {
+                "Error": {
+                    "description": "human readable reason of the failure, empty for 404 and 500",
+                    "headers": { "X-Request-Id": request_id_header() },
+                    "content": { "text/plain": { "schema": { "type": "string" } } }
+                },
+                "RateLimited": {
+                    "description": "the caller or the target city exceeded its rate limit",
+                    "headers": {
+                        "X-Request-Id": request_id_header(),
+                        "Retry-After": { "description": "seconds to wait before retrying", "schema": { "type": "integer" } }
+                    },
+                    "content": { "text/plain": { "schema": { "type": "string" } } }
+                }
+            }
+        }
+    })
+}
+
+// ------------- there are tests only below this point ------------
+
+#[cfg(test)]
+use std::sync::{Arc, RwLock};
+#[cfg(test)]
+use futures::future;
+#[cfg(test)]
+use hyper::{Body, Method, Request, StatusCode};
+#[cfg(test)]
+use hyper::header::{CONNECTION, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_VERSION, UPGRADE};
+#[cfg(test)]
+use crate::storage::Storage;
+#[cfg(test)]
+use crate::config::Config;
+
+#[cfg(test)]
+fn property_names(object: &Value) -> Vec<String> {
+    let mut names: Vec<String> = object.as_object().unwrap().keys().cloned().collect();
+    names.sort();
+    names
+}
+
+#[cfg(test)]
+fn assert_schema_matches<T: ApiSchema + serde::Serialize>(sample: &T) {
+    let serialized = serde_json::to_value(sample).unwrap();
+    let schema = T::schema();
+    assert_eq!(property_names(&schema["properties"]), property_names(&serialized), "schema of {} drifted from the DTO", T::schema_name());
+}
+
+#[test]
+fn test_spec_documents_every_rest_route() {
+    let spec = spec();
+
+    let mut documented_operations = 0;
+    for (_, path_item) in spec["paths"].as_object().unwrap() {
+        documented_operations += path_item.as_object().unwrap().len();
+    }
+    assert_eq!(documented_operations, REST_ROUTES.len());
+
+    for route in REST_ROUTES.iter() {
+        let method = route.method().as_str().to_lowercase();
+        assert!(spec["paths"][route.path()][&method].is_object(), "{:?} is not documented", route);
+    }
+}
+
+#[cfg(test)]
+fn example_request(operation_id: &str, method: &str, path: &str) -> Request<Body> {
+    let uri = format!("https://auto1.danila.app{}", path.replace("{city}", "BERLIN"));
+    let mut req = Request::builder();
+    req.method(Method::from_bytes(method.to_uppercase().as_bytes()).unwrap());
+    match operation_id {
+        "getStatus" | "streamEvents" => req.uri(format!("{}?city=BERLIN", uri)).body(Body::empty()),
+        "openSocket" => req.uri(uri)
+            .header(UPGRADE, "websocket")
+            .header(CONNECTION, "Upgrade")
+            .header(SEC_WEBSOCKET_VERSION, "13")
+            .header(SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+            .body(Body::empty()),
+        "createNotifications" => req.uri(uri).body(Body::from(r#"{"type_name":"SLAP","for_city":"BERLIN","message_text":null}"#)),
+        _ => req.uri(uri).body(Body::empty())
+    }.unwrap()
+}
+
+#[test]
+fn test_documented_operations_are_served() {
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let storage = Arc::new(RwLock::new(Storage::new()));
+    let dispatcher = Arc::new(crate::create_dispatcher(storage, Arc::new(crate::metrics::Metrics::new()), &Config::default()));
+    let spec = spec();
+
+    for (path, path_item) in spec["paths"].as_object().unwrap() {
+        for (method, operation) in path_item.as_object().unwrap() {
+            let operation_id = operation["operationId"].as_str().unwrap();
+            let req = example_request(operation_id, method, path);
+
+            // the socket upgrade is spawned while dispatching, so it has to run on the runtime
+            let dispatcher = dispatcher.clone();
+            let response = runtime.block_on(future::lazy(move || dispatcher.dispatch(req))).unwrap();
+            let status = response.status();
+            assert!(status.is_success() || status == StatusCode::SWITCHING_PROTOCOLS, "{} {} refused its example with {}", method, path, status);
+            assert!(operation["responses"][status.as_str()].is_object(), "{} {} answered undocumented status {}", method, path, status);
+        }
+    }
+}
+
+#[test]
+fn test_schemas_match_dtos() {
+    assert_schema_matches(&StatusResponse::new(1));
+    assert_schema_matches(&CreateNotificationReqeust {
+        type_name: String::from("MESSAGE"),
+        for_city: CityTarget::One(String::from("BERLIN")),
+        message_text: Some(String::from("text"))
+    });
+    assert_schema_matches(&CreateResult::failed(String::from("SLAP"), String::from("PARIS"), 400, String::from("error")));
+    assert_schema_matches(&BatchCreateResponse::new(vec![]));
+    assert_schema_matches(&QueueChangeEvent::snapshot("BERLIN", 2));
+    assert_schema_matches(&NextNotificationResponse::new("BERLIN", &crate::storage::Event::new_slap()));
+    assert_schema_matches(&DeliveriesResponse::new("BERLIN", &[]));
+    assert_schema_matches(&DeliveryEntry { id: String::from("1-1"), type_name: String::from("SLAP"), channels: Default::default(), taken_by: Some(String::from("alexa")) });
+    assert_schema_matches(&ChannelDelivery::new(&crate::delivery::DeliveryStatus::Failed(String::from("expired"))));
+    assert_schema_matches(&SocketCommand { action: String::from("ack"), cities: None, id: Some(1) });
+    assert_schema_matches(&SocketNotification::new(1, "BERLIN", &crate::storage::Event::new_slap()));
+    assert_schema_matches(&SocketReply::acked(1));
+}
@@ -0,0 +1,94 @@
+use hyper::Method;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RestRoute {
+    GetStatus,
+    StreamEvents,
+    OpenSocket,
+    CreateNotifications,
+    NextNotification,
+    GetDeliveries,
+    GetOpenApi
+}
+
+pub const REST_ROUTES: [RestRoute; 7] = [
+    RestRoute::GetStatus,
+    RestRoute::StreamEvents,
+    RestRoute::OpenSocket,
+    RestRoute::CreateNotifications,
+    RestRoute::NextNotification,
+    RestRoute::GetDeliveries,
+    RestRoute::GetOpenApi
+];
+
+impl RestRoute {
+    pub fn method(&self) -> Method {
+        match self {
+            RestRoute::GetStatus => Method::GET,
+            RestRoute::StreamEvents => Method::GET,
+            RestRoute::OpenSocket => Method::GET,
+            RestRoute::CreateNotifications => Method::POST,
+            RestRoute::NextNotification => Method::POST,
+            RestRoute::GetDeliveries => Method::GET,
+            RestRoute::GetOpenApi => Method::GET
+        }
+    }
+
+    pub fn path(&self) -> &'static str {
+        match self {
+            RestRoute::GetStatus => "/rest-api/status",
+            RestRoute::StreamEvents => "/rest-api/events",
+            RestRoute::OpenSocket => "/rest-api/socket",
+            RestRoute::CreateNotifications => "/rest-api/notifications",
+            RestRoute::NextNotification => "/rest-api/cities/{city}/notifications/next",
+            RestRoute::GetDeliveries => "/rest-api/cities/{city}/deliveries",
+            RestRoute::GetOpenApi => "/rest-api/openapi.json"
+        }
+    }
+
+    // paths are templates, a {name} segment matches any non-empty segment
+    pub fn resolve(method: &Method, path: &str) -> Option<RestRoute> {
+        REST_ROUTES.iter()
+            .find(|route| route.method() == *method && route.params(path).is_some())
+            .cloned()
+    }
+
+    // value of the {name} segment of a path served by this route
+    pub fn path_param<'a>(&self, path: &'a str, name: &str) -> Option<&'a str> {
+        self.params(path)?.into_iter()
+            .find(|(param, _)| *param == name)
+            .map(|(_, value)| value)
+    }
+
+    // the params of the path, None when the route does not serve it
+    fn params<'a>(&self, path: &'a str) -> Option<Vec<(&'static str, &'a str)>> {
+        let template: Vec<&'static str> = self.path().split('/').collect();
+        let segments: Vec<&'a str> = path.split('/').collect();
+        if template.len() != segments.len() {
+            return None;
+        }
+
+        let mut params = Vec::new();
+        for (expected, segment) in template.into_iter().zip(segments) {
+            if expected.starts_with('{') && expected.ends_with('}') && !segment.is_empty() {
+                params.push((&expected[1..expected.len() - 1], segment));
+            } else if expected != segment {
+                return None;
+            }
+        }
+        Some(params)
+    }
+}
+
+// ------------- there are tests only below this point ------------
+
+#[test]
+fn test_templated_paths_resolve_and_expose_their_params() {
+    let route = RestRoute::resolve(&Method::POST, "/rest-api/cities/BERLIN/notifications/next");
+
+    assert_eq!(route, Some(RestRoute::NextNotification));
+    assert_eq!(route.unwrap().path_param("/rest-api/cities/BERLIN/notifications/next", "city"), Some("BERLIN"));
+    assert_eq!(RestRoute::resolve(&Method::POST, "/rest-api/cities//notifications/next"), None);
+    assert_eq!(RestRoute::resolve(&Method::POST, "/rest-api/cities/BERLIN/notifications"), None);
+    assert_eq!(RestRoute::resolve(&Method::GET, "/rest-api/status"), Some(RestRoute::GetStatus));
+}
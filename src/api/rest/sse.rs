@@ -0,0 +1,153 @@
+use std::io;
+use std::time::Duration;
+
+use futures::{stream, Stream};
+use hyper::{Body, Chunk, Response, StatusCode};
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
+use tokio::timer::Interval;
+
+use crate::api::rest::dto::QueueChangeEvent;
+use crate::feed::{ChangeId, QueueChange, Subscription};
+
+pub const EVENT_STREAM_CONTENT_TYPE: &str = "text/event-stream";
+pub const LAST_EVENT_ID_HEADER: &str = "last-event-id";
+
+// how long browsers wait before reconnecting a dropped stream
+const RETRY_MILLIS: u64 = 3000;
+
+enum Item {
+    Message(String),
+    Heartbeat,
+    Closed
+}
+
+pub fn snapshot_message(city: &str, queue_size: usize, id: ChangeId) -> String {
+    message(&id, "snapshot", &QueueChangeEvent::snapshot(city, queue_size))
+}
+
+pub fn change_message(change: &QueueChange) -> String {
+    message(&change.id, change.kind.name(), &QueueChangeEvent::from(change))
+}
+
+fn message(id: &ChangeId, event: &str, data: &QueueChangeEvent) -> String {
+    let data = serde_json::to_string(data).unwrap_or_default();
+    format!("id: {}\nevent: {}\ndata: {}\n\n", id, event, data)
+}
+
+// the stream starts with what the subscriber missed (or a snapshot of the queue),
+// follows the feed and ends when the feed closes or the client goes away
+pub fn event_stream_response(head: Vec<String>, subscription: Subscription, heartbeat: Duration) -> Response<Body> {
+    let preamble = stream::iter_ok(Some(format!("retry: {}\n\n", RETRY_MILLIS)).into_iter().chain(head).map(Item::Message));
+
+    let changes = subscription.changes
+        .map(|change| Item::Message(change_message(&change)))
+        .chain(stream::once(Ok(Item::Closed)))
+        .map_err(|_| io::Error::other("feed failed"));
+    let heartbeats = Interval::new_interval(heartbeat)
+        .map(|_| Item::Heartbeat)
+        .map_err(io::Error::other);
+
+    let body = preamble
+        .chain(changes.select(heartbeats))
+        .take_while(|item| Ok(!matches!(item, Item::Closed)))
+        .map(|item| match item {
+            Item::Message(message) => Chunk::from(message),
+            _ => Chunk::from(": heartbeat\n\n")
+        });
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, EVENT_STREAM_CONTENT_TYPE)
+        .header(CACHE_CONTROL, "no-cache")
+        .body(Body::wrap_stream(body))
+        .unwrap()
+}
+
+// ------------- there are tests only below this point ------------
+
+#[cfg(test)]
+use std::io::{Read, Write};
+#[cfg(test)]
+use std::net::TcpStream;
+#[cfg(test)]
+use std::sync::{Arc, RwLock};
+#[cfg(test)]
+use futures::Future;
+#[cfg(test)]
+use crate::config::Config;
+#[cfg(test)]
+use crate::storage::{Event, Storage};
+
+#[cfg(test)]
+fn open_stream(addr: std::net::SocketAddr, last_event_id: Option<&str>) -> TcpStream {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let resume = last_event_id.map(|id| format!("Last-Event-ID: {}\r\n", id)).unwrap_or_default();
+    write!(stream, "GET /rest-api/events?city=BERLIN HTTP/1.1\r\nHost: localhost\r\nAccept: text/event-stream\r\n{}\r\n", resume).unwrap();
+    stream
+}
+
+#[cfg(test)]
+fn read_until(stream: &mut TcpStream, received: &mut String, marker: &str) {
+    let mut buffer = [0u8; 1024];
+    while !received.contains(marker) {
+        let read = stream.read(&mut buffer).unwrap_or_else(|err| panic!("no {:?} in {:?}: {}", marker, received, err));
+        assert!(read > 0, "stream closed before {:?} in {:?}", marker, received);
+        received.push_str(&String::from_utf8_lossy(&buffer[..read]));
+    }
+}
+
+#[test]
+fn test_stream_pushes_changes_heartbeats_and_resumes() {
+    // given
+    let storage = Arc::new(RwLock::new(Storage::new()));
+    let feed = storage.read().unwrap().feed();
+    let mut config = Config::default();
+    config.server.address = String::from("127.0.0.1:0");
+    config.server.heartbeat_secs = 1;
+    let pipeline = Arc::new(crate::create_pipeline(storage.clone(), Arc::new(crate::metrics::Metrics::new()), &config));
+    let server = crate::server::bind(&config.server, pipeline, futures::future::empty()).unwrap();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.spawn(server.future);
+
+    // when
+    let mut first = open_stream(server.local_addr, None);
+    let mut received = String::new();
+    read_until(&mut first, &mut received, "event: snapshot\n");
+
+    // then
+    assert!(received.starts_with("HTTP/1.1 200"));
+    assert!(received.to_lowercase().contains("content-type: text/event-stream"));
+    assert!(received.contains("retry: 3000\n"));
+    read_until(&mut first, &mut received, "\"message_num\":0}\n\n");
+    let snapshot_id = received.lines().find(|line| line.starts_with("id: ")).unwrap()[4..].to_string();
+
+    // when
+    storage.write().unwrap().add_event(Event::new_slap(), String::from("BERLIN"));
+    storage.write().unwrap().add_event(Event::new_slap(), String::from("KIEV"));
+
+    // then
+    read_until(&mut first, &mut received, "event: added\ndata: {\"city\":\"BERLIN\",\"type_name\":\"SLAP\",\"message_num\":1}\n\n");
+    read_until(&mut first, &mut received, ": heartbeat\n\n");
+    assert!(!received.contains("KIEV"));
+
+    // a reconnecting client only gets what it missed
+    let mut resumed = open_stream(server.local_addr, Some(&snapshot_id));
+    let mut replayed = String::new();
+    read_until(&mut resumed, &mut replayed, "event: added\n");
+    assert!(!replayed.contains("event: snapshot"));
+
+    // disconnected clients are dropped, closing the feed ends the remaining streams
+    drop(resumed);
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    while feed.subscriber_count() > 1 && std::time::Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    assert_eq!(feed.subscriber_count(), 1);
+    storage.write().unwrap().pop_event(&String::from("BERLIN"), crate::metrics::CHANNEL_REST);
+    read_until(&mut first, &mut received, "event: delivered\n");
+    feed.close();
+    read_until(&mut first, &mut received, "\r\n0\r\n\r\n");
+
+    runtime.shutdown_now().wait().unwrap();
+}
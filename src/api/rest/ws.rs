@@ -0,0 +1,386 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use futures::{stream, Future, Stream};
+use futures::sync::mpsc::{unbounded, UnboundedReceiver};
+use hyper::{Body, Response, StatusCode};
+use hyper::header::{HeaderMap, HeaderName, CONNECTION, CONTENT_TYPE, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_VERSION, UPGRADE};
+use hyper::upgrade::Upgraded;
+use serde::Serialize;
+use tokio::codec::Framed;
+use tokio::timer::Interval;
+use websocket_base::codec::ws::{Context, MessageCodec};
+use websocket_base::header::{WebSocketAccept, WebSocketKey};
+use websocket_base::message::OwnedMessage;
+use websocket_base::result::WebSocketError;
+
+use crate::api::auth::Identity;
+use crate::api::rest::dto::{SocketCommand, SocketNotification, SocketReply, ACK_ACTION, SUBSCRIBE_ACTION};
+use crate::api::utils::TEXT_CONTENT_TYPE;
+use crate::feed::QueueChange;
+use crate::logging::in_request;
+use crate::metrics::{Metrics, CHANNEL_WEBSOCKET};
+use crate::storage::{Event, Storage};
+
+const WEBSOCKET_VERSION: &str = "13";
+const STORAGE_UNAVAILABLE: &str = "the notification storage is unavailable.";
+
+pub enum HandshakeError {
+    UpgradeRequired,
+    InvalidKey
+}
+
+enum Input {
+    Frame(OwnedMessage),
+    Change(QueueChange),
+    Heartbeat,
+    Closed
+}
+
+// the Sec-WebSocket-Accept answering a valid upgrade request
+pub fn accept_key(headers: &HeaderMap) -> Result<String, HandshakeError> {
+    if !has_token(headers, UPGRADE, "websocket") || !has_token(headers, CONNECTION, "upgrade") || !has_token(headers, SEC_WEBSOCKET_VERSION, WEBSOCKET_VERSION) {
+        return Err(HandshakeError::UpgradeRequired);
+    }
+
+    headers.get(SEC_WEBSOCKET_KEY)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<WebSocketKey>().ok())
+        .map(|key| WebSocketAccept::new(&key).serialize())
+        .ok_or(HandshakeError::InvalidKey)
+}
+
+fn has_token(headers: &HeaderMap, name: HeaderName, token: &str) -> bool {
+    headers.get_all(name).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(token))
+}
+
+pub fn switching_protocols_response(accept: String) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(UPGRADE, "websocket")
+        .header(CONNECTION, "Upgrade")
+        .header(SEC_WEBSOCKET_ACCEPT, accept)
+        .body(Body::empty())
+        .unwrap()
+}
+
+pub fn upgrade_required_response() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::UPGRADE_REQUIRED)
+        .header(UPGRADE, "websocket")
+        .header(SEC_WEBSOCKET_VERSION, WEBSOCKET_VERSION)
+        .header(CONTENT_TYPE, TEXT_CONTENT_TYPE)
+        .body(Body::from("this endpoint only speaks WebSocket."))
+        .unwrap()
+}
+
+// one client connection. Every subscribed city has at most one pushed event the client has
+// not acked yet, it is the head of the queue of the city and only dequeued by the ack.
+pub struct Session {
+    storage: Arc<RwLock<Storage>>,
+    metrics: Arc<Metrics>,
+    // the api key of the client, None without authentication
+    consumer: Option<Identity>,
+    cities: Vec<String>,
+    shown: HashMap<String, (u64, Event)>,
+    last_id: u64
+}
+
+impl Session {
+
+    pub fn new(storage: Arc<RwLock<Storage>>, metrics: Arc<Metrics>, consumer: Option<Identity>) -> Session {
+        Session {
+            storage,
+            metrics,
+            consumer,
+            cities: Vec::new(),
+            shown: HashMap::new(),
+            last_id: 0
+        }
+    }
+
+    pub fn on_command(&mut self, text: &str) -> Vec<String> {
+        match serde_json::from_str::<SocketCommand>(text) {
+            Ok(SocketCommand { ref action, cities: Some(ref cities), .. }) if action == SUBSCRIBE_ACTION => self.subscribe(cities),
+            Ok(SocketCommand { ref action, id: Some(id), .. }) if action == ACK_ACTION => self.ack(id),
+            Ok(_) => vec![to_json(&SocketReply::error(String::from("action must be subscribe with cities or ack with an id.")))],
+            Err(_) => vec![to_json(&SocketReply::error(String::from("cannot deserialize command.")))]
+        }
+    }
+
+    pub fn on_change(&mut self, change: &QueueChange) -> Vec<String> {
+        if !self.cities.contains(&change.city) {
+            return Vec::new();
+        }
+        self.show_next(&change.city)
+    }
+
+    fn subscribe(&mut self, cities: &[String]) -> Vec<String> {
+        if cities.is_empty() {
+            return vec![to_json(&SocketReply::error(String::from("at least one city must be provided.")))];
+        }
+        {
+            let storage = match self.storage.read() {
+                Ok(storage) => storage,
+                Err(_) => return vec![to_json(&SocketReply::error(String::from(STORAGE_UNAVAILABLE)))]
+            };
+            if let Some(unknown) = cities.iter().find(|city| !storage.is_registered(city)) {
+                return vec![to_json(&SocketReply::error(format!("unknown city {}, supported cities are {}.", unknown, storage.get_supported_cities_as_str())))];
+            }
+        }
+        // acks dequeue, so only the cities the api key may send to can be subscribed
+        if let Some(identity) = &self.consumer {
+            if let Some(refused) = cities.iter().find(|city| !identity.may_send_to(city)) {
+                return vec![to_json(&SocketReply::error(format!("{} is not allowed to take notifications of {}.", &identity.client_id, refused)))];
+            }
+        }
+
+        for city in cities {
+            if !self.cities.contains(city) {
+                self.cities.push(city.clone());
+            }
+        }
+        debug!(cities:? = self.cities; "websocket subscribed");
+
+        let mut messages = vec![to_json(&SocketReply::subscribed(self.cities.clone()))];
+        for city in cities {
+            messages.extend(self.show_next(city));
+        }
+        messages
+    }
+
+    fn ack(&mut self, id: u64) -> Vec<String> {
+        let city = match self.shown.iter().find(|(_, (shown_id, _))| *shown_id == id) {
+            Some((city, _)) => city.clone(),
+            None => return vec![to_json(&SocketReply::error(format!("notification {} is not pending on this connection.", id)))]
+        };
+
+        let (_, shown) = self.shown.remove(&city).unwrap();
+        let delivered = match self.storage.write() {
+            Ok(mut storage) => storage.pop_event_if(&city, &shown, CHANNEL_WEBSOCKET),
+            Err(_) => return vec![to_json(&SocketReply::error(String::from(STORAGE_UNAVAILABLE)))]
+        };
+        let mut messages = match delivered {
+            Some(event) => {
+                self.metrics.notification_delivered(&event.event_type, CHANNEL_WEBSOCKET);
+                vec![to_json(&SocketReply::acked(id))]
+            },
+            None => vec![to_json(&SocketReply::error(format!("notification {} has changed or was delivered elsewhere.", id)))]
+        };
+        messages.extend(self.show_next(&city));
+        messages
+    }
+
+    // pushes the head of the queue unless the client already has it
+    fn show_next(&mut self, city: &String) -> Vec<String> {
+        let head = match self.storage.read() {
+            Ok(storage) => storage.peek_event(city),
+            Err(_) => return Vec::new()
+        };
+        match head {
+            Some(event) if self.shown.get(city).map(|(_, shown)| shown) != Some(&event) => {
+                self.last_id += 1;
+                let message = to_json(&SocketNotification::new(self.last_id, city, &event));
+                self.shown.insert(city.clone(), (self.last_id, event));
+                vec![message]
+            },
+            Some(_) => Vec::new(),
+            None => {
+                self.shown.remove(city);
+                Vec::new()
+            }
+        }
+    }
+
+}
+
+fn to_json<T: Serialize>(message: &T) -> String {
+    serde_json::to_string(message).unwrap_or_default()
+}
+
+// runs the session until the client closes, the connection breaks or the feed closes
+pub fn serve(upgraded: Upgraded, mut session: Session, changes: UnboundedReceiver<QueueChange>, heartbeat: Duration, request_id: String) -> impl Future<Item=(), Error=()> {
+    let (sink, frames) = Framed::new(upgraded, MessageCodec::default(Context::Server)).split();
+    let (outgoing, queued) = unbounded();
+
+    let frames = frames
+        .then(|frame| match frame {
+            Ok(OwnedMessage::Close(_)) => Ok(Input::Closed),
+            Ok(frame) => Ok(Input::Frame(frame)),
+            Err(err) => {
+                debug!(error:% = err; "websocket failed");
+                Ok(Input::Closed)
+            }
+        })
+        .chain(stream::once(Ok(Input::Closed)));
+    let changes = changes
+        .map(Input::Change)
+        .chain(stream::once(Ok(Input::Closed)));
+    let heartbeats = Interval::new_interval(heartbeat)
+        .map(|_| Input::Heartbeat)
+        .map_err(|_| ());
+
+    let replies = outgoing.clone();
+    let reader = frames.select(changes).select(heartbeats)
+        .take_while(|input| Ok(!matches!(input, Input::Closed)))
+        .for_each(move |input| {
+            let messages = in_request(&request_id, || match input {
+                Input::Frame(OwnedMessage::Text(text)) => session.on_command(&text).into_iter().map(OwnedMessage::Text).collect(),
+                Input::Frame(OwnedMessage::Binary(_)) => vec![OwnedMessage::Text(to_json(&SocketReply::error(String::from("commands must be sent as text."))))],
+                Input::Frame(OwnedMessage::Ping(data)) => vec![OwnedMessage::Pong(data)],
+                Input::Change(change) => session.on_change(&change).into_iter().map(OwnedMessage::Text).collect(),
+                Input::Heartbeat => vec![OwnedMessage::Ping(Vec::new())],
+                _ => Vec::new()
+            });
+            for message in messages {
+                let _ = replies.unbounded_send(message);
+            }
+            Ok(())
+        })
+        .then(move |_| {
+            let _ = outgoing.unbounded_send(OwnedMessage::Close(None));
+            Ok(())
+        });
+
+    // the writer ends once the reader dropped its senders
+    let writer = queued
+        .map_err(|_| WebSocketError::NoDataAvailable)
+        .forward(sink)
+        .then(|result| {
+            if let Err(err) = result {
+                debug!(error:% = err; "cannot write to websocket");
+            }
+            Ok(())
+        });
+
+    reader.join(writer).map(|_| debug!("websocket closed"))
+}
+
+// ------------- there are tests only below this point ------------
+
+#[cfg(test)]
+use std::io::{Read, Write};
+#[cfg(test)]
+use std::net::TcpStream;
+#[cfg(test)]
+use websocket_base::dataframe::DataFrame;
+#[cfg(test)]
+use websocket_base::ws::Message;
+#[cfg(test)]
+use crate::config::Config;
+
+#[cfg(test)]
+fn open_socket(addr: std::net::SocketAddr) -> TcpStream {
+    let mut socket = TcpStream::connect(addr).unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    write!(socket, "GET /rest-api/socket HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                    Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n").unwrap();
+
+    // byte by byte, frames may follow the head right away
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        socket.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8(head).unwrap();
+    assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+    assert!(head.to_lowercase().contains("sec-websocket-accept: s3pplmbitxaq9kygzzhzrbk+xoo="), "{}", head);
+    socket
+}
+
+#[cfg(test)]
+fn send(socket: &mut TcpStream, message: OwnedMessage) {
+    message.serialize(socket, true).unwrap();
+}
+
+#[cfg(test)]
+fn receive(socket: &mut TcpStream) -> OwnedMessage {
+    let frame = DataFrame::read_dataframe(socket, false).unwrap();
+    OwnedMessage::from_dataframes(vec![frame]).unwrap()
+}
+
+#[cfg(test)]
+fn receive_json(socket: &mut TcpStream) -> serde_json::Value {
+    loop {
+        match receive(socket) {
+            OwnedMessage::Text(text) => return serde_json::from_str(&text).unwrap(),
+            OwnedMessage::Ping(_) => continue,
+            other => panic!("expected a text message, got {:?}", other)
+        }
+    }
+}
+
+#[test]
+fn test_socket_pushes_pending_events_and_dequeues_on_ack() {
+    // given
+    let storage = Arc::new(RwLock::new(Storage::new()));
+    storage.write().unwrap().add_event(Event::new_slap(), String::from("BERLIN"));
+    storage.write().unwrap().add_event(Event::new_message(String::from("lunch is here")), String::from("BERLIN"));
+    let mut config = Config::default();
+    config.server.address = String::from("127.0.0.1:0");
+    let pipeline = Arc::new(crate::create_pipeline(storage.clone(), Arc::new(crate::metrics::Metrics::new()), &config));
+    let server = crate::server::bind(&config.server, pipeline, futures::future::empty()).unwrap();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.spawn(server.future);
+    let mut socket = open_socket(server.local_addr);
+
+    // when
+    send(&mut socket, OwnedMessage::Text(String::from(r#"{"action":"subscribe","cities":["BERLIN","KIEV"]}"#)));
+
+    // then
+    assert_eq!(receive_json(&mut socket), json!({"kind": "subscribed", "id": null, "cities": ["BERLIN", "KIEV"], "error": null}));
+    let slap = receive_json(&mut socket);
+    assert_eq!((slap["id"].as_u64(), slap["city"].as_str(), slap["type_name"].as_str()), (Some(1), Some("BERLIN"), Some("SLAP")));
+
+    // when
+    send(&mut socket, OwnedMessage::Text(String::from(r#"{"action":"ack","id":1}"#)));
+
+    // then
+    assert_eq!(receive_json(&mut socket)["kind"], "acked");
+    let message = receive_json(&mut socket);
+    assert_eq!((message["id"].as_u64(), message["message_text"].as_str()), (Some(2), Some("lunch is here")));
+    assert_eq!(storage.read().unwrap().size(&String::from("BERLIN")), 1);
+
+    // events queued later are pushed as well
+    storage.write().unwrap().add_event(Event::new_slap(), String::from("KIEV"));
+    let kiev = receive_json(&mut socket);
+    assert_eq!((kiev["id"].as_u64(), kiev["city"].as_str()), (Some(3), Some("KIEV")));
+
+    // an event another channel delivered first cannot be acked anymore
+    storage.write().unwrap().pop_event(&String::from("BERLIN"), crate::metrics::CHANNEL_REST);
+    send(&mut socket, OwnedMessage::Text(String::from(r#"{"action":"ack","id":2}"#)));
+    // depending on whether the change reached the session first, it was withdrawn or fails to dequeue
+    assert_eq!(receive_json(&mut socket)["kind"], "error");
+
+    send(&mut socket, OwnedMessage::Text(String::from(r#"{"action":"subscribe","cities":["PARIS"]}"#)));
+    assert_eq!(receive_json(&mut socket)["kind"], "error");
+    send(&mut socket, OwnedMessage::Ping(b"tray".to_vec()));
+    assert_eq!(receive(&mut socket), OwnedMessage::Pong(b"tray".to_vec()));
+    send(&mut socket, OwnedMessage::Close(None));
+    assert_eq!(receive(&mut socket), OwnedMessage::Close(None));
+    assert_eq!(storage.read().unwrap().size(&String::from("KIEV")), 1);
+
+    runtime.shutdown_now().wait().unwrap();
+}
+
+#[test]
+fn test_socket_subscribes_only_to_cities_of_the_api_key() {
+    let storage = Arc::new(RwLock::new(Storage::new()));
+    storage.write().unwrap().add_event(Event::new_slap(), String::from("KIEV"));
+    let identity = Identity { client_id: String::from("berlin-tray"), cities: vec![String::from("BERLIN")], can_read_status: true };
+    let mut session = Session::new(storage.clone(), Arc::new(Metrics::new()), Some(identity));
+
+    let replies = session.on_command(r#"{"action":"subscribe","cities":["BERLIN","KIEV"]}"#);
+    let reply: serde_json::Value = serde_json::from_str(&replies[0]).unwrap();
+    assert_eq!((replies.len(), reply["kind"].as_str()), (1, Some("error")));
+    assert_eq!(reply["error"], "berlin-tray is not allowed to take notifications of KIEV.");
+
+    let replies = session.on_command(r#"{"action":"subscribe","cities":["BERLIN"]}"#);
+    assert_eq!(replies, vec![to_json(&SocketReply::subscribed(vec![String::from("BERLIN")]))]);
+    assert_eq!(storage.read().unwrap().size(&String::from("KIEV")), 1);
+}
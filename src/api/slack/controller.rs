@@ -0,0 +1,115 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::Future;
+use hmac::{Hmac, Mac};
+use hyper::{Body, Response};
+use hyper::header::HeaderMap;
+use sha2::Sha256;
+
+use crate::api::auth::{constant_time_eq, Identity};
+use crate::api::rest::controller::RestController;
+use crate::api::rest::dto::ALL_CITIES;
+use crate::api::slack::dto::{SlashCommand, SlashCommandReply};
+use crate::api::utils::{internal_error_rsp, not_found_rsp, ok_rsp, unauthorized_rsp};
+use crate::config::SlackConfig;
+use crate::metrics::CHANNEL_SLACK;
+
+pub const TIMESTAMP_HEADER: &str = "x-slack-request-timestamp";
+// "v0=" and the hex HMAC of "v0:{timestamp}:{body}" keyed with the signing secret of the app
+pub const SIGNATURE_HEADER: &str = "x-slack-signature";
+
+// slash commands from team chat, answered only to the one who typed them
+pub struct SlackController {
+    rest_controller: Arc<RestController>,
+    signing_secret: Option<String>,
+    max_clock_skew: Duration
+}
+
+impl SlackController {
+
+    pub fn new(rest_controller: Arc<RestController>, config: &SlackConfig) -> SlackController {
+        SlackController {
+            rest_controller,
+            signing_secret: config.signing_secret.clone(),
+            max_clock_skew: Duration::from_secs(config.max_clock_skew_secs)
+        }
+    }
+
+    pub fn handle_command(&self, headers: &HeaderMap, body: &str) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+        // the endpoint stays closed until a signing secret is configured
+        let signing_secret = match &self.signing_secret {
+            Some(signing_secret) => signing_secret,
+            None => return not_found_rsp()
+        };
+        if let Err(reason) = self.verify(signing_secret, headers, body) {
+            warn!(reason = reason; "slash command refused");
+            return unauthorized_rsp(String::from(reason));
+        }
+
+        let command = SlashCommand::from_form(body);
+        info!(command = command.command.as_str(), user = command.user_name.as_str(); "slash command received");
+        let reply = match command.to_request() {
+            Ok(req) => {
+                // the workspace is trusted as a whole, the user is shown as the sender
+                let sender = Identity {
+                    client_id: command.user_name.clone(),
+                    cities: vec![String::from(ALL_CITIES)],
+                    can_read_status: false
+                };
+                SlashCommandReply::for_results(&self.rest_controller.create_in_cities(&req, Some(&sender), CHANNEL_SLACK))
+            },
+            Err(usage) => SlashCommandReply::ephemeral(usage)
+        };
+
+        match serde_json::to_string(&reply) {
+            Ok(json) => ok_rsp(json),
+            Err(err) => {
+                error!(error:% = err; "cannot serialize slash command reply");
+                internal_error_rsp()
+            }
+        }
+    }
+
+    // old timestamps are refused so a captured request cannot be replayed
+    fn verify(&self, signing_secret: &str, headers: &HeaderMap, body: &str) -> Result<(), &'static str> {
+        let timestamp = headers.get(TIMESTAMP_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .ok_or("the request timestamp is missing.")?;
+        let sent_at = timestamp.parse::<u64>().map_err(|_| "the request timestamp is invalid.")?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0);
+        if now.abs_diff(sent_at) > self.max_clock_skew.as_secs() {
+            return Err("the request timestamp is too far from now.");
+        }
+
+        let presented = headers.get(SIGNATURE_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .ok_or("the request signature is missing.")?;
+        if !constant_time_eq(sign(signing_secret, timestamp, body).as_bytes(), presented.as_bytes()) {
+            return Err("the request signature is invalid.");
+        }
+        Ok(())
+    }
+
+}
+
+pub fn sign(signing_secret: &str, timestamp: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(signing_secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(b"v0:");
+    mac.update(timestamp.as_bytes());
+    mac.update(b":");
+    mac.update(body.as_bytes());
+    let digest: String = mac.finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("v0={}", digest)
+}
+
+// ------------- there are tests only below this point ------------
+
+#[test]
+fn test_signature_follows_the_slack_scheme() {
+    // the example of the Slack documentation on verifying requests
+    let body = "token=xyzz0WbapA4vBCDEFasx0q6G&team_id=T1DC2JH3J&team_domain=testteamnow&channel_id=G8PSS9T3V&channel_name=foobar&user_id=U2CERLKJA&user_name=roadrunner&command=%2Fwebhook-collect&text=&response_url=https%3A%2F%2Fhooks.slack.com%2Fcommands%2FT1DC2JH3J%2F397700885554%2F96rGlfmibIGlgcZRskXaIFfN&trigger_id=398738663015.47445629121.803a0bc887a14d10d2c447fce8b6703c";
+    let signature = sign("8f742231b10e8888abcd99yyyzzz85a5", "1531420618", body);
+
+    assert_eq!(signature, "v0=a2114d57b48eac39b9ad189dd8316235a7b4a8d21a10bd27519666489c69b503");
+}
@@ -0,0 +1,128 @@
+use crate::api::rest::dto::{CreateNotificationReqeust, CreateResult, ALL_CITIES};
+
+pub const SLAP_COMMAND: &str = "/slap";
+pub const MESSAGE_COMMAND: &str = "/msg";
+
+// the fields of a slash-command form post that matter here
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SlashCommand {
+    pub command: String,
+    pub text: String,
+    pub user_name: String
+}
+
+impl SlashCommand {
+    pub fn from_form(body: &str) -> SlashCommand {
+        let mut command = SlashCommand::default();
+        for (name, value) in body.split('&').filter_map(|pair| pair.split_once('=')) {
+            match name {
+                "command" => command.command = form_decode(value),
+                "text" => command.text = form_decode(value),
+                "user_name" => command.user_name = form_decode(value),
+                _ => ()
+            }
+        }
+        command
+    }
+
+    // "/slap berlin" and "/msg kiev lunch is here", "all" or "*" target every city
+    pub fn to_request(&self) -> Result<CreateNotificationReqeust, String> {
+        let text = self.text.trim();
+        let (city, message) = match text.split_once(char::is_whitespace) {
+            Some((city, message)) => (city, message.trim()),
+            None => (text, "")
+        };
+        let for_city = match city {
+            "all" | ALL_CITIES => String::from(ALL_CITIES),
+            city => city.to_uppercase()
+        };
+
+        match self.command.as_str() {
+            SLAP_COMMAND if !city.is_empty() => Ok(CreateNotificationReqeust { type_name: String::from("SLAP"), for_city: for_city.into(), message_text: None }),
+            SLAP_COMMAND => Err(format!("Usage: {} <city>", SLAP_COMMAND)),
+            MESSAGE_COMMAND if !city.is_empty() && !message.is_empty() => {
+                Ok(CreateNotificationReqeust { type_name: String::from("MESSAGE"), for_city: for_city.into(), message_text: Some(String::from(message)) })
+            },
+            MESSAGE_COMMAND => Err(format!("Usage: {} <city> <message>", MESSAGE_COMMAND)),
+            other => Err(format!("The command {} is not supported. Supported commands are: {}, {}.", other, SLAP_COMMAND, MESSAGE_COMMAND))
+        }
+    }
+}
+
+// shown to the sender only
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SlashCommandReply {
+    pub response_type: String,
+    pub text: String
+}
+
+impl SlashCommandReply {
+    pub fn ephemeral(text: String) -> SlashCommandReply {
+        SlashCommandReply {
+            response_type: String::from("ephemeral"),
+            text
+        }
+    }
+
+    // one line per city
+    pub fn for_results(results: &[CreateResult]) -> SlashCommandReply {
+        let lines: Vec<String> = results.iter()
+            .map(|result| match (&result.error, result.type_name.as_str()) {
+                (Some(error), _) => error.clone(),
+                (None, "SLAP") => format!("Slapped {}.", result.for_city),
+                (None, _) => format!("Sent your message to {}.", result.for_city)
+            })
+            .collect();
+        SlashCommandReply::ephemeral(lines.join("\n"))
+    }
+}
+
+// application/x-www-form-urlencoded, malformed escapes are kept as they are
+fn form_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 3)
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            },
+            (b'+', _) => {
+                decoded.push(b' ');
+                i += 1;
+            },
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// ------------- there are tests only below this point ------------
+
+#[test]
+fn test_slash_commands_are_parsed_from_the_form() {
+    let slap = SlashCommand::from_form("token=x&team_id=T1&user_name=anna&command=%2Fslap&text=berlin&response_url=https%3A%2F%2Fhooks.slack.com%2Fcommands%2F1");
+    assert_eq!(slap, SlashCommand { command: String::from("/slap"), text: String::from("berlin"), user_name: String::from("anna") });
+    let request = slap.to_request().unwrap();
+    assert_eq!((request.type_name.as_str(), request.for_city.single_city().map(String::as_str)), ("SLAP", Some("BERLIN")));
+
+    let message = SlashCommand::from_form("command=%2Fmsg&text=kiev+lunch+is+here+%F0%9F%8D%95&user_name=anna").to_request().unwrap();
+    assert_eq!(message.for_city.single_city().map(String::as_str), Some("KIEV"));
+    assert_eq!(message.message_text.as_deref(), Some("lunch is here \u{1F355}"));
+
+    let broadcast = SlashCommand::from_form("command=%2Fslap&text=all").to_request().unwrap();
+    assert_eq!(broadcast.for_city.single_city(), None);
+
+    assert_eq!(SlashCommand::from_form("command=%2Fmsg&text=kiev").to_request().unwrap_err(), "Usage: /msg <city> <message>");
+    assert_eq!(SlashCommand::from_form("command=%2Fslap&text=").to_request().unwrap_err(), "Usage: /slap <city>");
+    assert!(SlashCommand::from_form("command=%2Fpoke&text=kiev").to_request().is_err());
+    assert_eq!(form_decode("100%+sure%+1%2"), "100% sure% 1%2");
+}
@@ -2,40 +2,156 @@
 use crate::futures::Future;
 use crate::futures::future::ok;
 
+use std::time::Duration;
+
 use hyper::{Body, Response, StatusCode};
+use hyper::header::{CONTENT_TYPE, RETRY_AFTER};
+
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+pub const TEXT_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+pub const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+pub const QUEUE_OVERFLOW_HEADER: &str = "x-queue-overflow";
+
+pub fn ok_rsp(json: String) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+    Box::new(ok(Response::builder()
+                .status(StatusCode::OK)
+                .header(CONTENT_TYPE, JSON_CONTENT_TYPE)
+                .body(Body::from(json))
+                .unwrap()))
+}
 
-pub fn ok_rsp(json: String) -> Box<Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+pub fn metrics_rsp(text: String) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
     Box::new(ok(Response::builder()
                 .status(StatusCode::OK)
+                .header(CONTENT_TYPE, METRICS_CONTENT_TYPE)
+                .body(Body::from(text))
+                .unwrap()))
+}
+
+pub fn multi_status_rsp(json: String) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+    Box::new(ok(Response::builder()
+                .status(StatusCode::MULTI_STATUS)
+                .header(CONTENT_TYPE, JSON_CONTENT_TYPE)
                 .body(Body::from(json))
                 .unwrap()))
 }
 
-pub fn created_rsp() -> Box<Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+pub fn created_rsp() -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
     Box::new(ok(Response::builder()
                 .status(StatusCode::CREATED)
                 .body(Body::empty())
                 .unwrap()))
 }
 
-pub fn bad_request_rsp(msg: String) -> Box<Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+// 201 for a notification that only fit into a full queue because of the overflow policy
+pub fn created_with_overflow_rsp(overflow: &'static str) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+    Box::new(ok(Response::builder()
+                .status(StatusCode::CREATED)
+                .header(QUEUE_OVERFLOW_HEADER, overflow)
+                .body(Body::empty())
+                .unwrap()))
+}
+
+pub fn no_content_rsp() -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+    Box::new(ok(Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(Body::empty())
+                .unwrap()))
+}
+
+pub fn bad_request_rsp(msg: String) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
     Box::new(ok(Response::builder()
                 .status(StatusCode::BAD_REQUEST)
+                .header(CONTENT_TYPE, TEXT_CONTENT_TYPE)
                 .body(Body::from(msg))
                 .unwrap()))
 }
 
+pub fn unauthorized_rsp(msg: String) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+    Box::new(ok(Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .header(CONTENT_TYPE, TEXT_CONTENT_TYPE)
+                .body(Body::from(msg))
+                .unwrap()))
+}
 
-pub fn internal_error_rsp() -> Box<Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+pub fn forbidden_rsp(msg: String) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+    Box::new(ok(Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .header(CONTENT_TYPE, TEXT_CONTENT_TYPE)
+                .body(Body::from(msg))
+                .unwrap()))
+}
+
+pub fn payload_too_large_rsp(msg: String) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+    Box::new(ok(Response::builder()
+                .status(StatusCode::PAYLOAD_TOO_LARGE)
+                .header(CONTENT_TYPE, TEXT_CONTENT_TYPE)
+                .body(Body::from(msg))
+                .unwrap()))
+}
+
+pub fn too_many_requests_rsp(wait: Duration, msg: String) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+    Box::new(ok(too_many_requests_response(wait, msg)))
+}
+
+// unboxed for the middlewares, which answer with a plain response
+pub fn too_many_requests_response(wait: Duration, msg: String) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .header(RETRY_AFTER, retry_after_secs(wait).to_string().as_str())
+        .header(CONTENT_TYPE, TEXT_CONTENT_TYPE)
+        .body(Body::from(msg))
+        .unwrap()
+}
+
+pub fn retry_after_secs(wait: Duration) -> u64 {
+    wait.as_secs_f64().ceil().max(1.0) as u64
+}
+
+pub fn insufficient_storage_rsp(msg: String) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+    Box::new(ok(Response::builder()
+                .status(StatusCode::INSUFFICIENT_STORAGE)
+                .header(CONTENT_TYPE, TEXT_CONTENT_TYPE)
+                .body(Body::from(msg))
+                .unwrap()))
+}
+
+pub fn service_unavailable_rsp(json: String) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+    Box::new(ok(Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .header(CONTENT_TYPE, JSON_CONTENT_TYPE)
+                .body(Body::from(json))
+                .unwrap()))
+}
+
+// the storage lock was poisoned by a panic, readiness reports the same
+pub fn storage_unavailable_rsp() -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+    Box::new(ok(Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .header(CONTENT_TYPE, TEXT_CONTENT_TYPE)
+                .body(Body::from("the notification storage is unavailable."))
+                .unwrap()))
+}
+
+pub fn internal_error_rsp() -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
     Box::new(ok(Response::builder()
                 .status(StatusCode::INTERNAL_SERVER_ERROR)
                 .body(Body::empty())
                 .unwrap()))
 }
 
-pub fn not_found_rsp() -> Box<Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+pub fn not_found_rsp() -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
     Box::new(ok(Response::builder()
                 .status(StatusCode::NOT_FOUND)
                 .body(Body::empty())
                 .unwrap()))
 }
+
+pub fn not_acceptable_rsp(produces: &str) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+    Box::new(ok(Response::builder()
+                .status(StatusCode::NOT_ACCEPTABLE)
+                .header(CONTENT_TYPE, TEXT_CONTENT_TYPE)
+                .body(Body::from(format!("responses are only available as {}.", produces)))
+                .unwrap()))
+}
@@ -0,0 +1,180 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::Future;
+use hyper::{Body, Method, Request, Uri};
+use hyper::header::CONTENT_TYPE;
+
+use crate::api::alexa::dto::{message_speech, slap_speech};
+use crate::api::utils::JSON_CONTENT_TYPE;
+use crate::client::{send, with_retries, Failure, HttpClient};
+use crate::config::ChatConfig;
+use crate::delivery::DeliveryChannel;
+use crate::feed::QueueChange;
+use crate::metrics::CHANNEL_CHAT;
+use crate::storage::{Event, EventType};
+
+// the payload Slack and Mattermost incoming webhooks both accept
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatMessage {
+    pub text: String
+}
+
+impl ChatMessage {
+    // says what the Echo would say, the emphasis of a message becomes bold;
+    // sender and message are escaped so they cannot mention channels or open links
+    pub fn for_event(event: &Event) -> ChatMessage {
+        let sender = event.sender.as_deref().map(chat_escape);
+        let text = match event.event_type {
+            EventType::SLAP => slap_speech(sender.as_ref(), event.count),
+            EventType::MESSAGE => message_speech(sender.as_ref(), &bold(&chat_escape(event.message.as_deref().unwrap_or_default())))
+        };
+        ChatMessage { text }
+    }
+}
+
+// the control characters of the Slack message format
+fn chat_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+// asterisks cannot be escaped, a text containing one stays plain instead of breaking the bold
+fn bold(text: &str) -> String {
+    if text.is_empty() || text.contains('*') {
+        String::from(text)
+    } else {
+        format!("*{}*", text)
+    }
+}
+
+// forwards the notifications added for a device to the chat channel of its office
+pub struct ChatBridge {
+    channels: BTreeMap<String, Uri>,
+    max_attempts: u32,
+    initial_backoff: Duration,
+    timeout: Duration,
+    client: HttpClient
+}
+
+impl ChatBridge {
+
+    pub fn new(config: &ChatConfig, client: HttpClient) -> Result<ChatBridge, String> {
+        let channels = config.webhooks.iter()
+            .map(|(device, url)| url.parse::<Uri>()
+                .map(|uri| (device.clone(), uri))
+                .map_err(|err| format!("invalid chat webhook url of {}: {}", device, err)))
+            .collect::<Result<BTreeMap<_, _>, String>>()?;
+
+        Ok(ChatBridge {
+            channels,
+            max_attempts: config.max_attempts.max(1),
+            initial_backoff: Duration::from_millis(config.initial_backoff_millis),
+            timeout: Duration::from_secs(config.timeout_secs),
+            client
+        })
+    }
+
+    fn attempt(&self, device: &str, body: &str) -> impl Future<Item=(), Error=Failure> {
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(self.channels[device].clone())
+            .header(CONTENT_TYPE, JSON_CONTENT_TYPE)
+            .body(Body::from(String::from(body)))
+            .unwrap();
+
+        send(&self.client, req, self.timeout)
+            .map_err(Failure::retryable)
+            .and_then(|rsp| match rsp.status() {
+                status if status.is_success() => Ok(()),
+                status => Err(Failure::of_status(status))
+            })
+    }
+
+}
+
+impl DeliveryChannel for ChatBridge {
+    fn name(&self) -> &'static str {
+        CHANNEL_CHAT
+    }
+
+    fn serves(&self, city: &str) -> bool {
+        self.channels.contains_key(city)
+    }
+
+    fn deliver(self: Arc<Self>, change: &QueueChange) -> Box<dyn Future<Item=(), Error=Failure> + Send> {
+        let body = serde_json::to_string(&ChatMessage::for_event(&change.event)).unwrap_or_default();
+        let call = format!("chat webhook {}", change.city);
+        let (bridge, device) = (self.clone(), change.city.clone());
+
+        Box::new(with_retries(call, self.max_attempts, self.initial_backoff, move |_| bridge.attempt(&device, &body))
+            .map(|_| ())
+            .map_err(|(_, failure)| failure))
+    }
+}
+
+// ------------- there are tests only below this point ------------
+
+#[cfg(test)]
+use crate::delivery::DeliveryRouter;
+#[cfg(test)]
+use crate::feed::{ChangeKind, Feed};
+#[cfg(test)]
+use crate::testing::{stand_in, wait_until, Recorded};
+
+#[test]
+fn test_chat_text_mirrors_the_speech() {
+    let slaps = Event { count: 3, ..Event::new_slap().sent_by(Some(String::from("anna"))) };
+    assert_eq!(ChatMessage::for_event(&slaps).text, "anna slapped you 3 times.");
+    assert_eq!(ChatMessage::for_event(&Event::new_slap()).text, "Someone has just slapped you.");
+
+    let message = Event::new_message(String::from("lunch is here"));
+    assert_eq!(ChatMessage::for_event(&message).text, "Someone sent you a message: *lunch is here*");
+}
+
+#[test]
+fn test_chat_text_cannot_mention_or_break_the_bold() {
+    let mention = Event::new_message(String::from("<!channel> lunch & <http://x|cake>")).sent_by(Some(String::from("<@U024BE7LH>")));
+    assert_eq!(ChatMessage::for_event(&mention).text, "&lt;@U024BE7LH&gt; sent you a message: *&lt;!channel&gt; lunch &amp; &lt;http://x|cake&gt;*");
+
+    let stars = Event::new_message(String::from("2 * 3 = 6"));
+    assert_eq!(ChatMessage::for_event(&stars).text, "Someone sent you a message: 2 * 3 = 6");
+
+    let slaps = Event::new_slap().sent_by(Some(String::from("<!here>")));
+    assert_eq!(ChatMessage::for_event(&slaps).text, "&lt;!here&gt; has just slapped you.");
+}
+
+#[test]
+fn test_added_notifications_are_posted_to_the_webhook_of_their_device() {
+    // given
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let (addr, received) = stand_in(&mut runtime, vec![502]);
+    let mut webhooks = BTreeMap::new();
+    webhooks.insert(String::from("BERLIN"), format!("http://{}/hooks/berlin", addr));
+    webhooks.insert(String::from("KIEV"), format!("http://{}/hooks/kiev", addr));
+    let config = ChatConfig { webhooks, initial_backoff_millis: 10, ..ChatConfig::default() };
+    let bridge = Arc::new(ChatBridge::new(&config, crate::client::https_client().unwrap()).unwrap());
+    let feed = Feed::new();
+    runtime.spawn(Arc::new(DeliveryRouter::for_channel(bridge)).run(feed.subscribe_all()));
+
+    // when
+    feed.publish("MILAN", ChangeKind::Added, &Event::new_slap(), 1);
+    feed.publish("BERLIN", ChangeKind::Delivered, &Event::new_slap(), 0);
+    feed.publish("BERLIN", ChangeKind::Added, &Event::new_message(String::from("lunch is here")).sent_by(Some(String::from("anna"))), 1);
+
+    // then the bad gateway was retried
+    wait_until(|| received.lock().unwrap().len() == 2);
+    let Recorded { path, body, .. } = received.lock().unwrap()[1].clone();
+    let message: ChatMessage = serde_json::from_str(&body).unwrap();
+    assert_eq!((path.as_str(), message.text.as_str()), ("/hooks/berlin", "anna sent you a message: *lunch is here*"));
+
+    // when
+    feed.publish("KIEV", ChangeKind::Added, &Event::new_slap(), 1);
+
+    // then
+    wait_until(|| received.lock().unwrap().len() == 3);
+    assert_eq!(received.lock().unwrap()[2].path, "/hooks/kiev");
+
+    feed.close();
+    runtime.shutdown_now().wait().unwrap();
+}
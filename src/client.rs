@@ -0,0 +1,75 @@
+use std::time::{Duration, Instant};
+
+use futures::{future, Future};
+use futures::future::Loop;
+use hyper::{Body, Client, Request, Response, StatusCode};
+use hyper::client::HttpConnector;
+use hyper_tls::HttpsConnector;
+use tokio::timer::{Delay, Timeout};
+
+// threads resolving host names of outgoing calls
+const DNS_THREADS: usize = 2;
+
+// client of the outgoing calls of the push channels, speaks both HTTP and HTTPS
+pub type HttpClient = Client<HttpsConnector<HttpConnector>, Body>;
+
+pub fn https_client() -> Result<HttpClient, String> {
+    let connector = HttpsConnector::new(DNS_THREADS).map_err(|err| format!("cannot initialize TLS for outgoing calls: {}", err))?;
+    Ok(Client::builder().build(connector))
+}
+
+// the response, or why there is none
+pub fn send(client: &HttpClient, req: Request<Body>, timeout: Duration) -> impl Future<Item=Response<Body>, Error=String> {
+    Timeout::new(client.request(req), timeout).map_err(move |err| {
+        if err.is_elapsed() {
+            return format!("no response within {} ms", timeout.as_millis());
+        }
+        match err.into_inner() {
+            Some(err) => err.to_string(),
+            None => String::from("timer failed")
+        }
+    })
+}
+
+// why an outgoing call failed, and whether calling again may succeed
+#[derive(Debug)]
+pub struct Failure {
+    pub reason: String,
+    pub retryable: bool
+}
+
+impl Failure {
+    pub fn retryable(reason: String) -> Failure {
+        Failure { reason, retryable: true }
+    }
+
+    pub fn permanent(reason: String) -> Failure {
+        Failure { reason, retryable: false }
+    }
+
+    // a receiver refusing the request itself won't accept it the next time either
+    pub fn of_status(status: StatusCode) -> Failure {
+        let retryable = status.is_server_error() || status == StatusCode::REQUEST_TIMEOUT || status == StatusCode::TOO_MANY_REQUESTS;
+        Failure { reason: format!("answered {}", status), retryable }
+    }
+}
+
+// calls until a call succeeds, fails for good or max_attempts ran out, the backoff doubles per attempt.
+// Resolves to the number of attempts made.
+pub fn with_retries<F, A>(call: String, max_attempts: u32, initial_backoff: Duration, mut attempt: F) -> impl Future<Item=u32, Error=(u32, Failure)>
+    where F: FnMut(u32) -> A, A: Future<Item=(), Error=Failure> + Send + 'static {
+    future::loop_fn(1, move |number| {
+        let call = call.clone();
+        attempt(number).then(move |result| -> Box<dyn Future<Item=Loop<u32, u32>, Error=(u32, Failure)> + Send> {
+            match result {
+                Ok(()) => Box::new(future::ok(Loop::Break(number))),
+                Err(failure) if failure.retryable && number < max_attempts => {
+                    let backoff = initial_backoff * 2u32.saturating_pow(number - 1);
+                    warn!(call = call.as_str(), attempt = number, error = failure.reason.as_str(), retry_in_ms = backoff.as_millis() as u64; "outgoing call failed");
+                    Box::new(Delay::new(Instant::now() + backoff).then(move |_| Ok(Loop::Continue(number + 1))))
+                },
+                Err(failure) => Box::new(future::err((number, failure)))
+            }
+        })
+    })
+}
@@ -0,0 +1,439 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+
+use crate::logging::{AccessLogFormat, LogFormat, LogLevel};
+use crate::storage::{OverflowPolicy, SlapCoalescing};
+use crate::webhooks::WebhookEvent;
+
+const CONFIG_PATH_VARIABLE: &str = "DANILA_CONFIG";
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub cors: CorsConfig,
+    pub auth: AuthConfig,
+    pub rate_limit: RateLimitConfig,
+    pub storage: StorageConfig,
+    pub logging: LoggingConfig,
+    pub webhooks: WebhooksConfig,
+    pub proactive_events: ProactiveEventsConfig,
+    pub chat: ChatConfig,
+    pub slack: SlackConfig,
+    pub mail: MailConfig,
+    pub mqtt: MqttConfig,
+    pub delivery: DeliveryConfig
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub address: String,
+    pub max_body_size: usize,
+    pub tls: Option<TlsConfig>,
+    // how long in-flight requests may take to finish after a shutdown signal
+    pub shutdown_timeout_secs: u64,
+    // comment lines keep idle event streams open through proxies
+    pub heartbeat_secs: u64,
+    // upper bound of the wait of a long-polling consumer
+    pub max_poll_wait_secs: u64
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            address: String::from("127.0.0.1:3000"),
+            max_body_size: 64 * 1024,
+            tls: None,
+            shutdown_timeout_secs: 30,
+            heartbeat_secs: 15,
+            max_poll_wait_secs: 30
+        }
+    }
+}
+
+// PEM encoded certificate chain and PKCS#8 key; with redirect_from set, plain HTTP
+// requests to that address are redirected to the HTTPS address
+#[derive(Deserialize, Debug, Clone)]
+pub struct TlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+    #[serde(default)]
+    pub redirect_from: Option<String>
+}
+
+// CORS stays disabled until at least one origin is allowed, "*" allows any origin
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CorsConfig {
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    pub max_age_secs: u64
+}
+
+impl Default for CorsConfig {
+    fn default() -> CorsConfig {
+        CorsConfig {
+            allowed_origins: Vec::new(),
+            allowed_methods: vec![String::from("GET"), String::from("POST")],
+            allowed_headers: vec![String::from("Content-Type")],
+            max_age_secs: 600
+        }
+    }
+}
+
+// the REST API stays open until at least one key is configured
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct AuthConfig {
+    pub api_keys: Vec<ApiKeyConfig>
+}
+
+// cities may contain "*" to allow sending to every registered city
+#[derive(Deserialize, Debug, Clone)]
+pub struct ApiKeyConfig {
+    pub id: String,
+    pub key: String,
+    #[serde(default)]
+    pub cities: Vec<String>,
+    #[serde(default)]
+    pub can_read_status: bool
+}
+
+// a missing bucket disables that limit, both are off unless configured
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub per_client: Option<BucketConfig>,
+    pub per_city: Option<BucketConfig>
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct BucketConfig {
+    pub capacity: u32,
+    pub refill_per_minute: u32
+}
+
+// a missing max_queue_length keeps the queues unbounded, as Storage::new() does
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct StorageConfig {
+    pub max_queue_length: Option<usize>,
+    pub overflow_policy: OverflowPolicy,
+    pub coalesce_slaps: SlapCoalescing
+}
+
+impl Default for StorageConfig {
+    fn default() -> StorageConfig {
+        StorageConfig {
+            max_queue_length: None,
+            overflow_policy: OverflowPolicy::Reject,
+            coalesce_slaps: SlapCoalescing::Off
+        }
+    }
+}
+
+// deliveries still failing after max_attempts are dead-lettered, the backoff doubles per attempt
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct WebhooksConfig {
+    pub endpoints: Vec<WebhookConfig>,
+    pub max_attempts: u32,
+    pub initial_backoff_millis: u64,
+    pub timeout_secs: u64,
+    // dead letters are appended here as JSON lines, else they are only logged
+    pub dead_letter_path: Option<String>
+}
+
+impl Default for WebhooksConfig {
+    fn default() -> WebhooksConfig {
+        WebhooksConfig {
+            endpoints: Vec::new(),
+            max_attempts: 5,
+            initial_backoff_millis: 1000,
+            timeout_secs: 10,
+            dead_letter_path: None
+        }
+    }
+}
+
+// empty cities or events subscribe to every city or event
+#[derive(Deserialize, Debug, Clone)]
+pub struct WebhookConfig {
+    pub id: String,
+    pub url: String,
+    pub secret: String,
+    #[serde(default)]
+    pub cities: Vec<String>,
+    #[serde(default)]
+    pub events: Vec<WebhookEvent>
+}
+
+// users maps a city to the Alexa user ids of its Echo devices, nothing is pushed while it is empty
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ProactiveEventsConfig {
+    pub client_id: String,
+    pub client_secret: String,
+    pub token_url: String,
+    // the regional endpoint of the skill, e.g. https://api.eu.amazonalexa.com
+    pub api_url: String,
+    pub stage: ProactiveEventsStage,
+    pub users: BTreeMap<String, Vec<String>>,
+    pub max_attempts: u32,
+    pub initial_backoff_millis: u64,
+    pub timeout_secs: u64,
+    // Alexa accepts between 5 minutes and 24 hours
+    pub expiry_minutes: u64
+}
+
+impl Default for ProactiveEventsConfig {
+    fn default() -> ProactiveEventsConfig {
+        ProactiveEventsConfig {
+            client_id: String::new(),
+            client_secret: String::new(),
+            token_url: String::from("https://api.amazon.com/auth/o2/token"),
+            api_url: String::from("https://api.amazonalexa.com"),
+            stage: ProactiveEventsStage::Development,
+            users: BTreeMap::new(),
+            max_attempts: 5,
+            initial_backoff_millis: 1000,
+            timeout_secs: 10,
+            expiry_minutes: 60
+        }
+    }
+}
+
+// a skill pushes to the development stage until it is certified
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProactiveEventsStage {
+    Development,
+    Live
+}
+
+// webhooks maps a device to the Slack or Mattermost incoming webhook of its office
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ChatConfig {
+    pub webhooks: BTreeMap<String, String>,
+    pub max_attempts: u32,
+    pub initial_backoff_millis: u64,
+    pub timeout_secs: u64
+}
+
+impl Default for ChatConfig {
+    fn default() -> ChatConfig {
+        ChatConfig {
+            webhooks: BTreeMap::new(),
+            max_attempts: 3,
+            initial_backoff_millis: 1000,
+            timeout_secs: 10
+        }
+    }
+}
+
+// slash commands are refused until the signing secret of the Slack app is configured
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SlackConfig {
+    pub signing_secret: Option<String>,
+    pub max_clock_skew_secs: u64
+}
+
+impl Default for SlackConfig {
+    fn default() -> SlackConfig {
+        SlackConfig {
+            signing_secret: None,
+            max_clock_skew_secs: 300
+        }
+    }
+}
+
+// recipients maps a city to the addresses mailed for every notification added to it;
+// the relay is trusted to take the mails without TLS or authentication, e.g. a local MTA
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct MailConfig {
+    pub relay: String,
+    pub from: String,
+    // greets and introduces the service in SMTP conversations
+    pub hostname: String,
+    pub recipients: BTreeMap<String, Vec<String>>,
+    pub max_attempts: u32,
+    pub initial_backoff_millis: u64,
+    pub timeout_secs: u64,
+    pub ingest: Option<MailIngestConfig>
+}
+
+impl Default for MailConfig {
+    fn default() -> MailConfig {
+        MailConfig {
+            relay: String::from("127.0.0.1:25"),
+            from: String::from("danila@localhost"),
+            hostname: String::from("localhost"),
+            recipients: BTreeMap::new(),
+            max_attempts: 5,
+            initial_backoff_millis: 1000,
+            timeout_secs: 30,
+            ingest: None
+        }
+    }
+}
+
+// mails to {city}@domain become MESSAGE events of the city, any domain is taken without one;
+// the listener authenticates nobody: the sender is the unverified From: header and the mails pass
+// neither api key scopes nor rate limits, so it should only be reachable by a trusted relay
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct MailIngestConfig {
+    pub address: String,
+    pub domain: Option<String>,
+    pub max_message_size: usize
+}
+
+impl Default for MailIngestConfig {
+    fn default() -> MailIngestConfig {
+        MailIngestConfig {
+            address: String::from("127.0.0.1:2525"),
+            domain: None,
+            max_message_size: 256 * 1024
+        }
+    }
+}
+
+// the bridge connects to the broker as a client, it stays off while no broker is set;
+// it trusts whoever may publish to the create topics of the broker: the sender is the unverified
+// one of the payload and the creates pass neither api key scopes nor rate limits
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct MqttConfig {
+    pub broker: Option<String>,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub keep_alive_secs: u16,
+    // notifications go to {topic_prefix}/{city}, they are created on {topic_prefix}/{city}/create
+    pub topic_prefix: String,
+    pub reconnect_millis: u64
+}
+
+impl Default for MqttConfig {
+    fn default() -> MqttConfig {
+        MqttConfig {
+            broker: None,
+            client_id: String::from("danila"),
+            username: None,
+            password: None,
+            keep_alive_secs: 30,
+            topic_prefix: String::from("notifications"),
+            reconnect_millis: 5000
+        }
+    }
+}
+
+// without routes every notification is queued and every push channel takes the notifications of the
+// cities it serves; with routes a notification goes to the channels of every route matching it, the
+// queue included only when one of them names it. A notification no route matches is only queued.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct DeliveryConfig {
+    pub routes: Vec<RouteConfig>
+}
+
+// empty cities or types match every city or type, e.g. MESSAGE to BERLIN goes to queue and chat.
+// The queue channel feeds Alexa, the REST polls, the event streams and the sockets.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct RouteConfig {
+    pub cities: Vec<String>,
+    pub types: Vec<String>,
+    pub channels: Vec<String>
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LoggingConfig {
+    pub level: LogLevel,
+    pub format: LogFormat,
+    pub access_log: AccessLogFormat
+}
+
+impl Default for LoggingConfig {
+    fn default() -> LoggingConfig {
+        LoggingConfig {
+            level: LogLevel::Info,
+            format: LogFormat::Text,
+            access_log: AccessLogFormat::Common
+        }
+    }
+}
+
+impl Config {
+
+    // the path is taken from the first command line argument or from DANILA_CONFIG,
+    // without either of them the defaults are used
+    pub fn load() -> Result<Config, String> {
+        let path = env::args().nth(1).or_else(|| env::var(CONFIG_PATH_VARIABLE).ok());
+
+        match path {
+            Some(path) => Config::from_file(&path),
+            None => Ok(Config::default())
+        }
+    }
+
+    pub fn from_file(path: &str) -> Result<Config, String> {
+        let json = fs::read_to_string(path)
+            .map_err(|err| format!("cannot read config file {}: {}", path, err))?;
+
+        Config::from_json(&json)
+            .map_err(|err| format!("cannot parse config file {}: {}", path, err))
+    }
+
+    pub fn from_json(json: &str) -> Result<Config, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+}
+
+#[test]
+fn test_partial_config_keeps_defaults() {
+    let config = Config::from_json("{}").unwrap();
+    assert_eq!(config.server.max_body_size, 64 * 1024);
+    assert!(config.rate_limit.per_client.is_none());
+    assert!(config.rate_limit.per_city.is_none());
+
+    let config = Config::from_json(r###"{"server":{"max_body_size":10}}"###).unwrap();
+    assert_eq!(config.server.max_body_size, 10);
+
+    let config = Config::from_json(r###"{"storage":{"overflow_policy":"drop_oldest"}}"###).unwrap();
+    assert_eq!(config.storage.max_queue_length, None);
+    assert_eq!(config.storage.overflow_policy, OverflowPolicy::DropOldest);
+    assert_eq!(config.logging.level, LogLevel::Info);
+
+    let config = Config::from_json(r###"{"logging":{"format":"json","access_log":"json"}}"###).unwrap();
+    assert_eq!(config.logging.format, LogFormat::Json);
+    assert_eq!(config.logging.access_log, AccessLogFormat::Json);
+
+    let config = Config::from_json(r###"{"webhooks":{"endpoints":[{"id":"chat","url":"http://localhost:9000/hook","secret":"s3cr3t","events":["created"]}]}}"###).unwrap();
+    assert_eq!(config.webhooks.max_attempts, 5);
+    assert_eq!(config.webhooks.endpoints[0].events, vec![WebhookEvent::Created]);
+    assert!(config.webhooks.endpoints[0].cities.is_empty());
+
+    let config = Config::from_json(r###"{"proactive_events":{"client_id":"id","client_secret":"secret","stage":"live","users":{"BERLIN":["amzn1.ask.account.1"]}}}"###).unwrap();
+    assert_eq!(config.proactive_events.stage, ProactiveEventsStage::Live);
+    assert_eq!(config.proactive_events.token_url, "https://api.amazon.com/auth/o2/token");
+    assert_eq!(config.proactive_events.users["BERLIN"], vec![String::from("amzn1.ask.account.1")]);
+
+    let config = Config::from_json(r###"{"mail":{"ingest":{"domain":"danila.example"}}}"###).unwrap();
+    assert_eq!(config.mail.ingest.unwrap().address, "127.0.0.1:2525");
+    assert!(config.mail.recipients.is_empty());
+
+    let config = Config::from_json(r###"{"mqtt":{"broker":"localhost:1883"}}"###).unwrap();
+    assert_eq!(config.mqtt.topic_prefix, "notifications");
+    assert_eq!(config.mqtt.keep_alive_secs, 30);
+
+    let config = Config::from_json(r###"{"delivery":{"routes":[{"cities":["BERLIN"],"channels":["chat"]}]}}"###).unwrap();
+    assert!(config.delivery.routes[0].types.is_empty());
+}
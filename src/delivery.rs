@@ -0,0 +1,451 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use futures::{Future, Stream};
+use futures::sync::mpsc::UnboundedReceiver;
+
+use crate::client::Failure;
+use crate::config::DeliveryConfig;
+use crate::feed::{ChangeId, ChangeKind, QueueChange};
+use crate::metrics::Metrics;
+use crate::storage::{Event, EventType};
+
+// the queue of the city, Alexa, the REST polls, the event streams and the sockets take notifications from there
+pub const QUEUE_CHANNEL: &str = "queue";
+// per city, the statuses of older notifications no channel is on anymore are forgotten
+const SETTLED_PER_CITY: usize = 100;
+
+// a way to reach people that pushes every added notification of the cities it serves
+pub trait DeliveryChannel: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // whether anyone in the city is reached through this channel
+    fn serves(&self, city: &str) -> bool;
+
+    // resolves once the notification is delivered or given up, retries included
+    fn deliver(self: Arc<Self>, change: &QueueChange) -> Box<dyn Future<Item=(), Error=Failure> + Send>;
+
+    // whether the channel is also told when a notification leaves the queue, only additions are pushed by default
+    fn follows(&self, _kind: ChangeKind) -> bool {
+        false
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    Failed(String)
+}
+
+// how one added notification fares on every channel it was sent to
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeliveryRecord {
+    pub id: ChangeId,
+    pub city: String,
+    pub event_type: EventType,
+    pub channels: BTreeMap<String, DeliveryStatus>,
+    // the consumer that took it out of the queue
+    pub taken_by: Option<String>
+}
+
+impl DeliveryRecord {
+    fn new(id: ChangeId, city: &str, event: &Event) -> DeliveryRecord {
+        DeliveryRecord { id, city: String::from(city), event_type: event.event_type.clone(), channels: BTreeMap::new(), taken_by: None }
+    }
+
+    // neither queued nor on its way through a channel
+    fn is_settled(&self) -> bool {
+        self.channels.values().all(|status| *status != DeliveryStatus::Pending)
+    }
+}
+
+#[derive(Debug, Default)]
+struct CityLog {
+    // by the sequence of the change id, oldest first
+    records: BTreeMap<u64, DeliveryRecord>,
+    // the ids of the added changes behind every queued event, oldest first;
+    // an event merged into the tail carries the ids of every change merged into it
+    queued: VecDeque<Vec<ChangeId>>
+}
+
+impl CityLog {
+    fn add(&mut self, record: DeliveryRecord) {
+        self.records.insert(record.id.sequence, record);
+        self.forget_settled();
+    }
+
+    // a record already forgotten stays forgotten
+    fn set_status(&mut self, id: ChangeId, channel: &str, status: DeliveryStatus) {
+        if let Some(record) = self.records.get_mut(&id.sequence).filter(|record| record.id == id) {
+            record.channels.insert(String::from(channel), status);
+        }
+        self.forget_settled();
+    }
+
+    fn forget_settled(&mut self) {
+        let settled: Vec<u64> = self.records.values().filter(|record| record.is_settled()).map(|record| record.id.sequence).collect();
+        for sequence in settled.iter().take(settled.len().saturating_sub(SETTLED_PER_CITY)) {
+            self.records.remove(sequence);
+        }
+    }
+}
+
+// delivery statuses of the notifications of every city by change id, the storage keeps the queue channel up to date.
+// A notification is kept while it is queued or a channel is still on it, afterwards only the last
+// SETTLED_PER_CITY notifications of its city are.
+#[derive(Debug, Default)]
+pub struct DeliveryLog {
+    cities: Mutex<HashMap<String, CityLog>>
+}
+
+impl DeliveryLog {
+
+    pub fn new() -> DeliveryLog {
+        DeliveryLog::default()
+    }
+
+    pub fn queued(&self, id: ChangeId, city: &str, event: &Event, merged: bool) {
+        let mut cities = self.cities.lock().unwrap();
+        let log = cities.entry(String::from(city)).or_default();
+        let mut record = DeliveryRecord::new(id, city, event);
+        record.channels.insert(String::from(QUEUE_CHANNEL), DeliveryStatus::Pending);
+        log.add(record);
+
+        match log.queued.back_mut() {
+            Some(tail) if merged => tail.push(id),
+            _ => log.queued.push_back(vec![id])
+        }
+    }
+
+    // the routes of the notification don't name the queue, the router adds the channels they name
+    pub fn pushed(&self, id: ChangeId, city: &str, event: &Event) {
+        self.cities.lock().unwrap().entry(String::from(city)).or_default().add(DeliveryRecord::new(id, city, event));
+    }
+
+    // the oldest event of the city left the queue, taken by a consumer or pushed out
+    pub fn dequeued(&self, city: &str, status: DeliveryStatus, taken_by: Option<&str>) {
+        let mut cities = self.cities.lock().unwrap();
+        let log = match cities.get_mut(city) {
+            Some(log) => log,
+            None => return
+        };
+        for id in log.queued.pop_front().unwrap_or_default() {
+            if let Some(record) = log.records.get_mut(&id.sequence).filter(|record| record.id == id) {
+                record.taken_by = taken_by.map(String::from);
+            }
+            log.set_status(id, QUEUE_CHANNEL, status.clone());
+        }
+    }
+
+    pub fn update(&self, city: &str, id: ChangeId, channel: &str, status: DeliveryStatus) {
+        if let Some(log) = self.cities.lock().unwrap().get_mut(city) {
+            log.set_status(id, channel, status);
+        }
+    }
+
+    // oldest first
+    pub fn for_city(&self, city: &str) -> Vec<DeliveryRecord> {
+        match self.cities.lock().unwrap().get(city) {
+            Some(log) => log.records.values().cloned().collect(),
+            None => Vec::new()
+        }
+    }
+
+    #[cfg(test)]
+    pub fn record(&self, id: ChangeId) -> Option<DeliveryRecord> {
+        self.cities.lock().unwrap().values()
+            .filter_map(|log| log.records.get(&id.sequence))
+            .find(|record| record.id == id)
+            .cloned()
+    }
+
+}
+
+// empty cities or types match every city or type
+#[derive(Debug, Clone)]
+struct Route {
+    cities: Vec<String>,
+    types: Vec<String>,
+    channels: Vec<String>
+}
+
+impl Route {
+    fn matches(&self, city: &str, event_type: &EventType) -> bool {
+        (self.cities.is_empty() || self.cities.iter().any(|routed| routed == city))
+            && (self.types.is_empty() || self.types.iter().any(|type_name| type_name == event_type.name()))
+    }
+}
+
+// the routes of the delivery config, the storage asks them whether to queue a notification
+#[derive(Debug, Clone, Default)]
+pub struct DeliveryRoutes {
+    routes: Vec<Route>
+}
+
+impl DeliveryRoutes {
+
+    pub fn new(config: &DeliveryConfig) -> DeliveryRoutes {
+        let routes = config.routes.iter()
+            .map(|route| Route {
+                cities: route.cities.iter().map(|city| city.to_uppercase()).collect(),
+                types: route.types.iter().map(|type_name| type_name.to_uppercase()).collect(),
+                channels: route.channels.clone()
+            })
+            .collect();
+        DeliveryRoutes { routes }
+    }
+
+    // a notification no route matches is still queued, so a gap in the routes loses nothing
+    pub fn queues(&self, city: &str, event_type: &EventType) -> bool {
+        let mut matching = self.routes.iter().filter(|route| route.matches(city, event_type)).peekable();
+        matching.peek().is_none() || matching.any(|route| route.channels.iter().any(|name| name == QUEUE_CHANNEL))
+    }
+
+    // every channel takes everything without routes
+    fn lead_to(&self, change: &QueueChange, channel: &str) -> bool {
+        self.routes.is_empty() || self.routes.iter().any(|route| route.matches(&change.city, &change.event.event_type) && route.channels.iter().any(|name| name == channel))
+    }
+
+}
+
+// fans every added notification out to the channels its routes name, to every channel without routes
+pub struct DeliveryRouter {
+    channels: Vec<Arc<dyn DeliveryChannel>>,
+    routes: DeliveryRoutes,
+    log: Arc<DeliveryLog>,
+    metrics: Arc<Metrics>
+}
+
+impl DeliveryRouter {
+
+    // a route naming a channel that isn't set up fails the startup
+    pub fn new(channels: Vec<Arc<dyn DeliveryChannel>>, config: &DeliveryConfig, log: Arc<DeliveryLog>, metrics: Arc<Metrics>) -> Result<DeliveryRouter, String> {
+        for route in config.routes.iter() {
+            if let Some(unknown) = route.channels.iter().find(|name| *name != QUEUE_CHANNEL && !channels.iter().any(|channel| channel.name() == name.as_str())) {
+                return Err(format!("a route names the channel {} which is not set up", unknown));
+            }
+        }
+
+        Ok(DeliveryRouter { channels, routes: DeliveryRoutes::new(config), log, metrics })
+    }
+
+    // routes everything to the one channel
+    #[cfg(test)]
+    pub fn for_channel(channel: Arc<dyn DeliveryChannel>) -> DeliveryRouter {
+        DeliveryRouter { channels: vec![channel], routes: DeliveryRoutes::default(), log: Arc::new(DeliveryLog::new()), metrics: Arc::new(Metrics::new()) }
+    }
+
+    // runs until the feed closes, every delivery runs on its own task;
+    // only the additions are tracked in the log, the other changes are told as they come
+    pub fn run(self: Arc<Self>, changes: UnboundedReceiver<QueueChange>) -> impl Future<Item=(), Error=()> {
+        changes.for_each(move |change| {
+            for channel in self.channels_for(&change) {
+                if change.kind.is_new() {
+                    self.log.update(&change.city, change.id, channel.name(), DeliveryStatus::Pending);
+                    tokio::spawn(self.clone().deliver(channel, change.clone()));
+                } else {
+                    tokio::spawn(channel.deliver(&change).then(|_| Ok(())));
+                }
+            }
+            Ok(())
+        })
+    }
+
+    // the changes of a notification go where the notification went
+    fn channels_for(&self, change: &QueueChange) -> Vec<Arc<dyn DeliveryChannel>> {
+        self.channels.iter()
+            .filter(|channel| change.kind.is_new() || channel.follows(change.kind))
+            .filter(|channel| channel.serves(&change.city))
+            .filter(|channel| self.routes.lead_to(change, channel.name()))
+            .cloned()
+            .collect()
+    }
+
+    fn deliver(self: Arc<Self>, channel: Arc<dyn DeliveryChannel>, change: QueueChange) -> impl Future<Item=(), Error=()> {
+        let name = channel.name();
+        channel.deliver(&change).then(move |result| {
+            let status = match result {
+                Ok(()) => {
+                    debug!(channel = name, city = change.city.as_str(), id:% = change.id; "notification delivered");
+                    self.metrics.notification_delivered(&change.event.event_type, name);
+                    DeliveryStatus::Delivered
+                },
+                Err(failure) => {
+                    error!(channel = name, city = change.city.as_str(), id:% = change.id, error = failure.reason.as_str(); "cannot deliver notification");
+                    DeliveryStatus::Failed(failure.reason)
+                }
+            };
+            self.log.update(&change.city, change.id, name, status);
+            Ok(())
+        })
+    }
+
+}
+
+// ------------- there are tests only below this point ------------
+
+#[cfg(test)]
+use futures::future;
+#[cfg(test)]
+use crate::config::RouteConfig;
+#[cfg(test)]
+use crate::storage::{AddOutcome, Storage};
+#[cfg(test)]
+use crate::testing::wait_until;
+
+// serves the given cities and answers with the given outcomes in turn, delivered once they ran out
+#[cfg(test)]
+struct FakeChannel {
+    name: &'static str,
+    cities: Vec<&'static str>,
+    outcomes: Mutex<VecDeque<Result<(), Failure>>>,
+    delivered: Mutex<Vec<ChangeId>>
+}
+
+#[cfg(test)]
+impl FakeChannel {
+    fn new(name: &'static str, cities: Vec<&'static str>) -> Arc<FakeChannel> {
+        FakeChannel::failing(name, cities, Vec::new())
+    }
+
+    fn failing(name: &'static str, cities: Vec<&'static str>, outcomes: Vec<Result<(), Failure>>) -> Arc<FakeChannel> {
+        Arc::new(FakeChannel { name, cities, outcomes: Mutex::new(VecDeque::from(outcomes)), delivered: Mutex::new(Vec::new()) })
+    }
+
+    fn delivered(&self) -> Vec<ChangeId> {
+        self.delivered.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl DeliveryChannel for FakeChannel {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn serves(&self, city: &str) -> bool {
+        self.cities.contains(&city)
+    }
+
+    fn deliver(self: Arc<Self>, change: &QueueChange) -> Box<dyn Future<Item=(), Error=Failure> + Send> {
+        let outcome = self.outcomes.lock().unwrap().pop_front().unwrap_or(Ok(()));
+        if outcome.is_ok() {
+            self.delivered.lock().unwrap().push(change.id);
+        }
+        Box::new(future::result(outcome))
+    }
+}
+
+#[cfg(test)]
+fn route(cities: &[&str], types: &[&str], channels: &[&str]) -> RouteConfig {
+    let strings = |values: &[&str]| values.iter().map(|value| String::from(*value)).collect();
+    RouteConfig { cities: strings(cities), types: strings(types), channels: strings(channels) }
+}
+
+#[test]
+fn test_notifications_are_routed_per_city_and_type() {
+    // given
+    let config = DeliveryConfig { routes: vec![route(&["berlin"], &["message"], &["proactive_events", "chat", "queue"]), route(&[], &["SLAP"], &["mail"])] };
+    let mut storage = Storage::new().with_routes(DeliveryRoutes::new(&config));
+    let alexa = FakeChannel::new("proactive_events", vec!["BERLIN", "KIEV"]);
+    let slack = FakeChannel::failing("chat", vec!["BERLIN", "KIEV"], vec![Err(Failure::permanent(String::from("answered 404 Not Found")))]);
+    let mail = FakeChannel::new("mail", vec!["BERLIN"]);
+    let channels: Vec<Arc<dyn DeliveryChannel>> = vec![alexa.clone(), slack.clone(), mail.clone()];
+    let metrics = Arc::new(Metrics::new());
+    let router = Arc::new(DeliveryRouter::new(channels, &config, storage.deliveries(), metrics.clone()).unwrap());
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.spawn(router.run(storage.feed().subscribe_all()));
+
+    // when
+    storage.add_event(Event::new_message(String::from("lunch is here")), String::from("BERLIN"));
+    storage.add_event(Event::new_message(String::from("lunch is here")), String::from("KIEV"));
+    let slap = storage.add_event(Event::new_slap(), String::from("BERLIN"));
+
+    // then
+    let log = storage.deliveries();
+    let ids: Vec<ChangeId> = log.for_city("BERLIN").iter().map(|record| record.id).collect();
+    wait_until(|| alexa.delivered().len() == 1 && mail.delivered().len() == 1);
+    assert_eq!((alexa.delivered(), mail.delivered()), (vec![ids[0]], vec![ids[1]]));
+    assert!(slack.delivered().is_empty());
+
+    wait_until(|| log.record(ids[0]).unwrap().channels.iter().all(|(name, status)| name == QUEUE_CHANNEL || *status != DeliveryStatus::Pending));
+    let message = log.record(ids[0]).unwrap();
+    assert_eq!(message.channels.keys().collect::<Vec<_>>(), vec!["chat", "proactive_events", "queue"]);
+    assert_eq!(message.channels["proactive_events"], DeliveryStatus::Delivered);
+    assert_eq!(message.channels["chat"], DeliveryStatus::Failed(String::from("answered 404 Not Found")));
+    assert_eq!(message.channels["queue"], DeliveryStatus::Pending);
+    assert_eq!(log.for_city("KIEV")[0].channels.len(), 1);
+
+    // the route of the SLAP doesn't name the queue, a message no route matches is only queued
+    assert_eq!((slap, storage.size(&String::from("BERLIN")), storage.size(&String::from("KIEV"))), (AddOutcome::Pushed, 1, 1));
+    wait_until(|| log.record(ids[1]).unwrap().channels.get("mail") == Some(&DeliveryStatus::Delivered));
+    assert_eq!(log.record(ids[1]).unwrap().channels.len(), 1);
+    let text = metrics.render(&storage);
+    assert!(text.contains("notifications_delivered_total{type=\"MESSAGE\",channel=\"proactive_events\"} 1\n"));
+    assert!(text.contains("notifications_delivered_total{type=\"SLAP\",channel=\"mail\"} 1\n"));
+    assert!(!text.contains("channel=\"chat\""));
+
+    storage.feed().close();
+    runtime.shutdown_now().wait().unwrap();
+}
+
+#[test]
+fn test_every_serving_channel_takes_notifications_without_routes() {
+    let chat = FakeChannel::new("chat", vec!["BERLIN"]);
+    let mail = FakeChannel::new("mail", vec!["KIEV"]);
+    let router = DeliveryRouter::new(vec![chat, mail], &DeliveryConfig::default(), Arc::new(DeliveryLog::new()), Arc::new(Metrics::new())).unwrap();
+    let change = QueueChange { id: ChangeId { epoch: 1, sequence: 1 }, city: String::from("KIEV"), kind: ChangeKind::Added, event: Event::new_slap(), queue_size: 1 };
+
+    let names: Vec<&str> = router.channels_for(&change).iter().map(|channel| channel.name()).collect();
+    assert_eq!(names, vec!["mail"]);
+    let delivered = QueueChange { kind: ChangeKind::Delivered, ..change };
+    assert!(router.channels_for(&delivered).is_empty());
+
+    let config = DeliveryConfig { routes: vec![route(&[], &[], &["queue", "sms"])] };
+    let err = DeliveryRouter::new(vec![FakeChannel::new("chat", vec![])], &config, Arc::new(DeliveryLog::new()), Arc::new(Metrics::new())).err();
+    assert_eq!(err.as_deref(), Some("a route names the channel sms which is not set up"));
+}
+
+#[test]
+fn test_the_queue_channel_follows_consumers_and_overflows() {
+    let mut storage = Storage::new()
+        .with_queue_limit(Some(2), crate::storage::OverflowPolicy::DropOldest)
+        .with_slap_coalescing(crate::storage::SlapCoalescing::All);
+    let berlin = String::from("BERLIN");
+    storage.add_event(Event::new_message(String::from("first")), berlin.clone());
+    storage.add_event(Event::new_slap(), berlin.clone());
+    storage.add_event(Event::new_slap(), berlin.clone());
+    storage.add_event(Event::new_message(String::from("third")), berlin.clone());
+    storage.pop_event(&berlin, crate::metrics::CHANNEL_ALEXA);
+
+    let records = storage.deliveries().for_city("BERLIN");
+    let statuses: Vec<DeliveryStatus> = records.iter().map(|record| record.channels[QUEUE_CHANNEL].clone()).collect();
+    assert_eq!(statuses, vec![
+        DeliveryStatus::Failed(String::from("expired")),
+        DeliveryStatus::Delivered,
+        DeliveryStatus::Delivered,
+        DeliveryStatus::Pending
+    ]);
+    let takers: Vec<Option<&str>> = records.iter().map(|record| record.taken_by.as_deref()).collect();
+    assert_eq!(takers, vec![None, Some("alexa"), Some("alexa"), None]);
+}
+
+#[test]
+fn test_statuses_are_kept_per_city_while_notifications_are_queued() {
+    let mut storage = Storage::new();
+    let berlin = String::from("BERLIN");
+    storage.add_event(Event::new_message(String::from("waiting")), String::from("KIEV"));
+    for _ in 0..SETTLED_PER_CITY + 10 {
+        storage.add_event(Event::new_slap(), berlin.clone());
+    }
+    for _ in 0..SETTLED_PER_CITY + 5 {
+        storage.pop_event(&berlin, crate::metrics::CHANNEL_REST);
+    }
+
+    let log = storage.deliveries();
+    let settled = log.for_city("BERLIN").iter().filter(|record| record.channels[QUEUE_CHANNEL] == DeliveryStatus::Delivered).count();
+    let queued = log.for_city("BERLIN").iter().filter(|record| record.channels[QUEUE_CHANNEL] == DeliveryStatus::Pending).count();
+    assert_eq!((settled, queued), (SETTLED_PER_CITY, 5));
+    assert_eq!(log.for_city("KIEV")[0].channels[QUEUE_CHANNEL], DeliveryStatus::Pending);
+}
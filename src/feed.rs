@@ -0,0 +1,290 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+
+use crate::storage::Event;
+
+// changes kept per city for subscribers resuming after a reconnect
+const HISTORY_PER_CITY: usize = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChangeKind {
+    Added,
+    Delivered,
+    // pushed out of a full queue without being delivered
+    Expired,
+    // handed to the push channels of its routes only, it never entered the queue
+    Pushed
+}
+
+impl ChangeKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChangeKind::Added => "added",
+            ChangeKind::Delivered => "delivered",
+            ChangeKind::Expired => "expired",
+            ChangeKind::Pushed => "pushed"
+        }
+    }
+
+    // a new notification, whether queued or not
+    pub fn is_new(&self) -> bool {
+        *self == ChangeKind::Added || *self == ChangeKind::Pushed
+    }
+}
+
+// ids are only comparable within one feed, the epoch tells feeds of different runs apart
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChangeId {
+    pub epoch: u64,
+    pub sequence: u64
+}
+
+impl ChangeId {
+    pub fn parse(value: &str) -> Option<ChangeId> {
+        let mut parts = value.trim().splitn(2, '-');
+        let epoch = parts.next()?.parse().ok()?;
+        let sequence = parts.next()?.parse().ok()?;
+        Some(ChangeId { epoch, sequence })
+    }
+}
+
+impl fmt::Display for ChangeId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.epoch, self.sequence)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueueChange {
+    pub id: ChangeId,
+    pub city: String,
+    pub kind: ChangeKind,
+    pub event: Event,
+    pub queue_size: usize
+}
+
+pub struct Subscription {
+    // the last change published before subscribing
+    pub last_id: ChangeId,
+    // changes after the requested id, None when they are no longer known
+    pub missed: Option<Vec<QueueChange>>,
+    pub changes: UnboundedReceiver<QueueChange>
+}
+
+struct Subscriber {
+    // None follows every city
+    city: Option<String>,
+    sender: UnboundedSender<QueueChange>
+}
+
+#[derive(Default)]
+struct CityHistory {
+    changes: VecDeque<QueueChange>,
+    // sequence of the newest change pushed out of the history
+    forgotten_up_to: u64
+}
+
+struct FeedState {
+    sequence: u64,
+    closed: bool,
+    history: HashMap<String, CityHistory>,
+    subscribers: Vec<Subscriber>
+}
+
+// fans queue changes of the storage out to streaming subscribers
+pub struct Feed {
+    epoch: u64,
+    state: Mutex<FeedState>
+}
+
+impl Feed {
+
+    pub fn new() -> Feed {
+        Feed {
+            epoch: SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0),
+            state: Mutex::new(FeedState {
+                sequence: 0,
+                closed: false,
+                history: HashMap::new(),
+                subscribers: Vec::new()
+            })
+        }
+    }
+
+    pub fn publish(&self, city: &str, kind: ChangeKind, event: &Event, queue_size: usize) -> ChangeId {
+        self.publish_with(city, kind, event, queue_size, |_| ())
+    }
+
+    // before_sending is given the id of the change before any subscriber can see it
+    pub fn publish_with<F: FnOnce(ChangeId)>(&self, city: &str, kind: ChangeKind, event: &Event, queue_size: usize, before_sending: F) -> ChangeId {
+        let mut state = self.state.lock().unwrap();
+        state.sequence += 1;
+        let change = QueueChange {
+            id: ChangeId { epoch: self.epoch, sequence: state.sequence },
+            city: String::from(city),
+            kind,
+            event: event.clone(),
+            queue_size
+        };
+
+        // the queue of the city didn't change, only those following every city are told
+        let queue_changed = kind != ChangeKind::Pushed;
+        if queue_changed {
+            let history = state.history.entry(String::from(city)).or_default();
+            history.changes.push_back(change.clone());
+            if history.changes.len() > HISTORY_PER_CITY {
+                if let Some(forgotten) = history.changes.pop_front() {
+                    history.forgotten_up_to = forgotten.id.sequence;
+                }
+            }
+        }
+
+        before_sending(change.id);
+
+        // a failed send means the subscriber has gone away
+        state.subscribers.retain(|subscriber| subscriber.city.as_deref().is_some_and(|subscribed| subscribed != city || !queue_changed) || subscriber.sender.unbounded_send(change.clone()).is_ok());
+
+        change.id
+    }
+
+    pub fn subscribe(&self, city: &str, resume_after: Option<ChangeId>) -> Subscription {
+        let mut state = self.state.lock().unwrap();
+        let last_id = ChangeId { epoch: self.epoch, sequence: state.sequence };
+
+        let missed = resume_after
+            .filter(|after| after.epoch == self.epoch && after.sequence <= state.sequence)
+            .and_then(|after| match state.history.get(city) {
+                Some(history) if history.forgotten_up_to > after.sequence => None,
+                Some(history) => Some(history.changes.iter().filter(|change| change.id.sequence > after.sequence).cloned().collect()),
+                None => Some(Vec::new())
+            });
+
+        let (sender, changes) = unbounded();
+        if !state.closed {
+            state.subscribers.retain(|subscriber| !subscriber.sender.is_closed());
+            state.subscribers.push(Subscriber { city: Some(String::from(city)), sender });
+        }
+
+        Subscription {
+            last_id,
+            missed,
+            changes
+        }
+    }
+
+    // changes of every city from now on, for channels following several cities at once
+    pub fn subscribe_all(&self) -> UnboundedReceiver<QueueChange> {
+        let mut state = self.state.lock().unwrap();
+        let (sender, changes) = unbounded();
+        if !state.closed {
+            state.subscribers.retain(|subscriber| !subscriber.sender.is_closed());
+            state.subscribers.push(Subscriber { city: None, sender });
+        }
+        changes
+    }
+
+    // ends every subscription, streams finish once they drained their changes
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        state.subscribers.clear();
+    }
+
+    #[cfg(test)]
+    pub fn subscriber_count(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        state.subscribers.retain(|subscriber| !subscriber.sender.is_closed());
+        state.subscribers.len()
+    }
+
+}
+
+impl fmt::Debug for Feed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.state.lock().unwrap();
+        f.debug_struct("Feed")
+            .field("epoch", &self.epoch)
+            .field("sequence", &state.sequence)
+            .field("subscribers", &state.subscribers.len())
+            .finish()
+    }
+}
+
+// ------------- there are tests only below this point ------------
+
+#[cfg(test)]
+use futures::{Future, Stream};
+
+#[test]
+fn test_subscribers_only_get_changes_of_their_city() {
+    let feed = Feed::new();
+    let berlin = feed.subscribe("BERLIN", None);
+    let _kiev = feed.subscribe("KIEV", None);
+    let everything = feed.subscribe_all();
+
+    feed.publish("BERLIN", ChangeKind::Added, &Event::new_slap(), 1);
+    feed.publish("KIEV", ChangeKind::Added, &Event::new_message(String::from("lunch")), 1);
+    feed.publish("BERLIN", ChangeKind::Delivered, &Event::new_slap(), 0);
+    feed.publish("BERLIN", ChangeKind::Pushed, &Event::new_slap(), 0);
+    feed.close();
+
+    let received: Vec<QueueChange> = berlin.changes.collect().wait().unwrap();
+    assert_eq!(received.iter().map(|change| (change.kind, change.queue_size)).collect::<Vec<_>>(),
+               vec![(ChangeKind::Added, 1), (ChangeKind::Delivered, 0)]);
+    assert!(berlin.missed.is_none());
+    assert_eq!(everything.collect().wait().unwrap().len(), 4);
+}
+
+#[test]
+fn test_the_change_is_recorded_before_it_is_sent() {
+    let feed = Feed::new();
+    let everything = feed.subscribe_all();
+    let mut recorded = Vec::new();
+
+    let id = feed.publish_with("BERLIN", ChangeKind::Pushed, &Event::new_slap(), 0, |id| recorded.push(id));
+    feed.close();
+
+    assert_eq!(recorded, vec![id]);
+    assert_eq!(everything.collect().wait().unwrap()[0].id, id);
+}
+
+#[test]
+fn test_resume_replays_missed_changes_while_they_are_known() {
+    let feed = Feed::new();
+    let first = feed.publish("BERLIN", ChangeKind::Added, &Event::new_slap(), 1);
+    feed.publish("BERLIN", ChangeKind::Added, &Event::new_slap(), 2);
+    feed.publish("KIEV", ChangeKind::Added, &Event::new_slap(), 1);
+
+    let resumed = feed.subscribe("BERLIN", Some(first));
+    let missed = resumed.missed.unwrap();
+    assert_eq!(missed.len(), 1);
+    assert_eq!(missed[0].queue_size, 2);
+    assert_eq!(resumed.last_id.sequence, 3);
+
+    // ids of another run or from the future cannot be resumed
+    assert!(feed.subscribe("BERLIN", Some(ChangeId { epoch: first.epoch + 1, ..first })).missed.is_none());
+    assert!(feed.subscribe("BERLIN", Some(ChangeId { sequence: 42, ..first })).missed.is_none());
+
+    for size in 0..HISTORY_PER_CITY {
+        feed.publish("BERLIN", ChangeKind::Added, &Event::new_slap(), size);
+    }
+    assert!(feed.subscribe("BERLIN", Some(first)).missed.is_none());
+}
+
+#[test]
+fn test_dropped_subscribers_are_pruned() {
+    let feed = Feed::new();
+    let kept = feed.subscribe("BERLIN", None);
+    drop(feed.subscribe("BERLIN", None));
+
+    assert_eq!(feed.subscriber_count(), 1);
+    drop(kept);
+    feed.publish("BERLIN", ChangeKind::Added, &Event::new_slap(), 1);
+    assert_eq!(feed.subscriber_count(), 0);
+    assert_eq!(ChangeId::parse(" 1538043174-7"), Some(ChangeId { epoch: 1_538_043_174, sequence: 7 }));
+    assert_eq!(ChangeId::parse("7"), None);
+}
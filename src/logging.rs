@@ -0,0 +1,353 @@
+use std::cell::RefCell;
+use std::fmt::Write as FmtWrite;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use log::kv::{Key, Value, VisitSource};
+use serde_json::{Map, Number};
+
+pub const REDACTED: &str = "[redacted]";
+// records of this target are access log lines, already formatted by the access log layer
+pub const ACCESS_LOG_TARGET: &str = "access";
+
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+// the epoch was a Thursday
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+
+// json keys and log fields whose values never reach the log
+const SENSITIVE_KEYS: [&str; 8] = ["apiaccesstoken", "accesstoken", "token", "key", "authorization", "message", "message_text", "messagetext"];
+
+static REQUEST_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    static CURRENT_REQUEST_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    Text,
+    Json
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogFormat {
+    Common,
+    Json
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace
+}
+
+impl LogLevel {
+    fn filter(self) -> LevelFilter {
+        match self {
+            LogLevel::Error => LevelFilter::Error,
+            LogLevel::Warn => LevelFilter::Warn,
+            LogLevel::Info => LevelFilter::Info,
+            LogLevel::Debug => LevelFilter::Debug,
+            LogLevel::Trace => LevelFilter::Trace
+        }
+    }
+}
+
+// one line per record on stdout, errors and warnings go to stderr.
+// Dependencies are chatty below info, so they never log more than that.
+struct Logger {
+    level: LevelFilter,
+    format: LogFormat
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let own = metadata.target().split("::").next() == module_path!().split("::").next();
+        let level = if own { self.level } else { self.level.min(LevelFilter::Info) };
+        metadata.level() <= level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        if record.target() == ACCESS_LOG_TARGET {
+            let _ = writeln!(std::io::stdout(), "{}", record.args());
+            return;
+        }
+
+        let line = format_record(self.format, rfc3339_timestamp(SystemTime::now()), record);
+        if record.level() <= Level::Warn {
+            let _ = writeln!(std::io::stderr(), "{}", line);
+        } else {
+            let _ = writeln!(std::io::stdout(), "{}", line);
+        }
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stdout().flush();
+    }
+}
+
+pub fn init(level: LogLevel, format: LogFormat) -> Result<(), String> {
+    log::set_boxed_logger(Box::new(Logger { level: level.filter(), format }))
+        .map_err(|err| format!("cannot install the logger: {}", err))?;
+    log::set_max_level(level.filter());
+    Ok(())
+}
+
+// unique within the process and unlikely to repeat across restarts
+pub fn next_request_id() -> String {
+    let started = SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0);
+    let sequence = REQUEST_COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{:08x}-{:06x}", started & 0xffff_ffff, sequence & 0xff_ffff)
+}
+
+// every record logged by the closure carries the request id. Request handling runs
+// synchronously inside the futures of a request, so a thread local is enough.
+pub fn in_request<T, F: FnOnce() -> T>(request_id: &str, handle: F) -> T {
+    let previous = CURRENT_REQUEST_ID.with(|current| current.replace(Some(String::from(request_id))));
+    let _restore = RestoreRequestId(previous);
+    handle()
+}
+
+struct RestoreRequestId(Option<String>);
+
+impl Drop for RestoreRequestId {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        CURRENT_REQUEST_ID.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+pub fn is_sensitive(key: &str) -> bool {
+    let key = key.to_lowercase();
+    SENSITIVE_KEYS.contains(&key.as_str())
+}
+
+// request bodies are only logged with tokens and message texts blanked out
+pub fn redact_json(body: &str) -> String {
+    if body.trim().is_empty() {
+        return String::new();
+    }
+
+    match serde_json::from_str::<serde_json::Value>(body) {
+        Ok(mut value) => {
+            redact_value(&mut value);
+            value.to_string()
+        },
+        Err(_) => String::from(REDACTED)
+    }
+}
+
+fn redact_value(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(fields) => {
+            for (key, field) in fields.iter_mut() {
+                if is_sensitive(key) {
+                    *field = serde_json::Value::from(REDACTED);
+                } else {
+                    redact_value(field);
+                }
+            }
+        },
+        serde_json::Value::Array(items) => items.iter_mut().for_each(redact_value),
+        _ => {}
+    }
+}
+
+fn format_record(format: LogFormat, timestamp: String, record: &Record) -> String {
+    let mut fields = Fields(Vec::new());
+    let _ = record.key_values().visit(&mut fields);
+    if !fields.0.iter().any(|(key, _)| key == "request_id") {
+        if let Some(request_id) = CURRENT_REQUEST_ID.with(|current| current.borrow().clone()) {
+            fields.0.insert(0, (String::from("request_id"), serde_json::Value::from(request_id)));
+        }
+    }
+
+    match format {
+        LogFormat::Text => {
+            let mut line = format!("{} {:<5} {}: {}", timestamp, record.level(), record.target(), record.args());
+            for (key, value) in fields.0 {
+                let value = value_to_text(&value);
+                if value.contains(' ') || value.contains('"') || value.is_empty() {
+                    let _ = write!(line, " {}={:?}", key, value);
+                } else {
+                    let _ = write!(line, " {}={}", key, value);
+                }
+            }
+            line
+        },
+        LogFormat::Json => {
+            let mut object = Map::new();
+            object.insert(String::from("ts"), serde_json::Value::from(timestamp));
+            object.insert(String::from("level"), serde_json::Value::from(record.level().as_str()));
+            object.insert(String::from("target"), serde_json::Value::from(record.target()));
+            object.insert(String::from("msg"), serde_json::Value::from(record.args().to_string()));
+            for (key, value) in fields.0 {
+                object.insert(key, value);
+            }
+            serde_json::Value::Object(object).to_string()
+        }
+    }
+}
+
+struct Fields(Vec<(String, serde_json::Value)>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        let value = if is_sensitive(key.as_str()) {
+            serde_json::Value::from(REDACTED)
+        } else if let Some(flag) = value.to_bool() {
+            serde_json::Value::Bool(flag)
+        } else if let Some(number) = value.to_u64() {
+            serde_json::Value::from(number)
+        } else if let Some(number) = value.to_i64() {
+            serde_json::Value::from(number)
+        } else if let Some(number) = value.to_f64().and_then(Number::from_f64) {
+            serde_json::Value::Number(number)
+        } else {
+            serde_json::Value::from(value.to_string())
+        };
+
+        self.0.push((String::from(key.as_str()), value));
+        Ok(())
+    }
+}
+
+fn value_to_text(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(text) => text.clone(),
+        other => other.to_string()
+    }
+}
+
+pub fn rfc3339_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    rfc3339(since_epoch.as_secs(), since_epoch.subsec_millis())
+}
+
+pub fn clf_timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0);
+    let (year, month, day) = civil_date(secs);
+    let seconds_of_day = secs % 86_400;
+
+    format!("{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000",
+            day, MONTHS[month as usize - 1], year, seconds_of_day / 3600, seconds_of_day / 60 % 60, seconds_of_day % 60)
+}
+
+// the Date header of an email
+pub fn rfc5322_date(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0);
+    let (year, month, day) = civil_date(secs);
+    let seconds_of_day = secs % 86_400;
+
+    format!("{}, {:02} {} {:04} {:02}:{:02}:{:02} +0000",
+            WEEKDAYS[(secs / 86_400 % 7) as usize], day, MONTHS[month as usize - 1], year, seconds_of_day / 3600, seconds_of_day / 60 % 60, seconds_of_day % 60)
+}
+
+fn rfc3339(secs: u64, millis: u32) -> String {
+    let (year, month, day) = civil_date(secs);
+    let seconds_of_day = secs % 86_400;
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year, month, day, seconds_of_day / 3600, seconds_of_day / 60 % 60, seconds_of_day % 60, millis)
+}
+
+// civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+fn civil_date(secs: u64) -> (i64, i64, i64) {
+    let days = (secs / 86_400) as i64;
+
+    let shifted = days + 719_468;
+    let era = shifted.div_euclid(146_097);
+    let day_of_era = shifted - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+// ------------- there are tests only below this point ------------
+
+#[cfg(test)]
+fn format_with(format: LogFormat, fields: &[(&str, Value)]) -> String {
+    let kvs: Vec<(&str, Value)> = fields.to_vec();
+    format_record(format, String::from("2018-09-27T10:12:54.000Z"), &Record::builder()
+        .args(format_args!("request handled"))
+        .level(Level::Info)
+        .target("danila::access")
+        .key_values(&kvs)
+        .build())
+}
+
+#[test]
+fn test_text_and_json_lines_carry_the_fields() {
+    let fields = [("request_id", Value::from("5bac9e36-000001")), ("route", Value::from("/rest-api/status")), ("status", Value::from(200u16)), ("latency_ms", Value::from(3u64))];
+
+    let text = format_with(LogFormat::Text, &fields);
+    assert_eq!(text, "2018-09-27T10:12:54.000Z INFO  danila::access: request handled request_id=5bac9e36-000001 route=/rest-api/status status=200 latency_ms=3");
+
+    let json: serde_json::Value = serde_json::from_str(&format_with(LogFormat::Json, &fields)).unwrap();
+    assert_eq!(json["level"], "INFO");
+    assert_eq!(json["msg"], "request handled");
+    assert_eq!(json["route"], "/rest-api/status");
+    assert_eq!(json["status"], 200);
+    assert_eq!(json["latency_ms"], 3);
+}
+
+#[test]
+fn test_sensitive_fields_are_redacted() {
+    let line = format_with(LogFormat::Text, &[("message_text", Value::from("lunch is here")), ("city", Value::from("KIEV"))]);
+    assert!(line.ends_with("message_text=[redacted] city=KIEV"));
+
+    let body = r###"{"context":{"System":{"apiAccessToken":"eyJ0eXAi"}},"request":{"intent":{"slots":{"message":{"name":"message","value":"lunch"}}}},"for_city":"KIEV"}"###;
+    let redacted = redact_json(body);
+    assert!(!redacted.contains("eyJ0eXAi"));
+    assert!(!redacted.contains("lunch"));
+    assert!(redacted.contains("\"for_city\":\"KIEV\""));
+    assert_eq!(redact_json("not json at all"), REDACTED);
+    assert_eq!(redact_json(""), "");
+}
+
+#[test]
+fn test_timestamps_are_rfc3339() {
+    assert_eq!(rfc3339(0, 0), "1970-01-01T00:00:00.000Z");
+    assert_eq!(rfc3339(1_538_043_174, 42), "2018-09-27T10:12:54.042Z");
+    assert_eq!(rfc3339(951_782_400, 0), "2000-02-29T00:00:00.000Z");
+    assert_eq!(clf_timestamp(UNIX_EPOCH + std::time::Duration::from_secs(1_538_043_174)), "27/Sep/2018:10:12:54 +0000");
+    assert_eq!(rfc5322_date(UNIX_EPOCH + std::time::Duration::from_secs(1_538_043_174)), "Thu, 27 Sep 2018 10:12:54 +0000");
+}
+
+#[test]
+fn test_records_inside_a_request_carry_its_id() {
+    let outside = format_with(LogFormat::Text, &[("city", Value::from("KIEV"))]);
+    assert!(!outside.contains("request_id"));
+
+    let inside = in_request("5bac9e36-000007", || format_with(LogFormat::Text, &[("city", Value::from("KIEV"))]));
+    assert!(inside.ends_with("request handled request_id=5bac9e36-000007 city=KIEV"));
+
+    let nested = in_request("outer", || {
+        in_request("inner", || ());
+        format_with(LogFormat::Json, &[])
+    });
+    let json: serde_json::Value = serde_json::from_str(&nested).unwrap();
+    assert_eq!(json["request_id"], "outer");
+}
+
+#[test]
+fn test_request_ids_are_unique() {
+    assert_ne!(next_request_id(), next_request_id());
+}
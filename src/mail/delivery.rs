@@ -0,0 +1,337 @@
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use futures::{future, stream, Future, Sink, Stream};
+use futures::future::Loop;
+use tokio::codec::Framed;
+use tokio::net::TcpStream;
+use tokio::timer::Timeout;
+
+use crate::api::alexa::dto::{message_speech, slap_speech};
+use crate::client::{with_retries, Failure};
+use crate::config::MailConfig;
+use crate::delivery::DeliveryChannel;
+use crate::feed::QueueChange;
+use crate::logging::rfc5322_date;
+use crate::mail::SmtpCodec;
+use crate::metrics::CHANNEL_MAIL;
+use crate::storage::EventType;
+
+type Connection = Framed<TcpStream, SmtpCodec>;
+
+// mails every notification added to a city to the addresses of the city
+pub struct Mailer {
+    relay: SocketAddr,
+    from: String,
+    hostname: String,
+    recipients: Vec<(String, Vec<String>)>,
+    max_attempts: u32,
+    initial_backoff: Duration,
+    timeout: Duration
+}
+
+impl Mailer {
+
+    // the relay is resolved once, a relay that can't be resolved fails the startup
+    pub fn new(config: &MailConfig) -> Result<Mailer, String> {
+        let relay = config.relay.to_socket_addrs()
+            .map_err(|err| format!("cannot resolve the mail relay {}: {}", config.relay, err))?
+            .next()
+            .ok_or_else(|| format!("the mail relay {} has no address", config.relay))?;
+
+        Ok(Mailer {
+            relay,
+            from: config.from.clone(),
+            hostname: config.hostname.clone(),
+            recipients: config.recipients.iter().map(|(city, addresses)| (city.clone(), addresses.clone())).collect(),
+            max_attempts: config.max_attempts.max(1),
+            initial_backoff: Duration::from_millis(config.initial_backoff_millis),
+            timeout: Duration::from_secs(config.timeout_secs)
+        })
+    }
+
+    fn recipients_of(&self, city: &str) -> Option<&Vec<String>> {
+        self.recipients.iter()
+            .find(|(recipients_city, _)| recipients_city == city)
+            .map(|(_, addresses)| addresses)
+            .filter(|addresses| !addresses.is_empty())
+    }
+
+    // one mail to all recipients the relay accepts, the relay takes it once it accepted the data;
+    // recipients refused for good are skipped, the mail fails only when none is left
+    fn transaction(&self, recipients: Vec<String>, lines: Vec<String>) -> impl Future<Item=(), Error=Failure> {
+        let ehlo = format!("EHLO {}", self.hostname);
+        let mail_from = format!("MAIL FROM:<{}>", self.from);
+        let timeout = self.timeout;
+
+        let transaction = TcpStream::connect(&self.relay)
+            .map_err(|err| Failure::retryable(format!("cannot connect to the relay: {}", err)))
+            .and_then(|stream| expect(Framed::new(stream, SmtpCodec), None, 220))
+            .and_then(move |connection| expect(connection, Some(ehlo), 250))
+            .and_then(move |connection| expect(connection, Some(mail_from), 250))
+            .and_then(move |connection| stream::iter_ok(recipients)
+                .fold((connection, 0, None), |(connection, accepted, last_refusal), recipient| rcpt(connection, recipient)
+                    .map(move |(connection, refusal)| match refusal {
+                        Some(refusal) => (connection, accepted, Some(refusal)),
+                        None => (connection, accepted + 1, last_refusal)
+                    })))
+            .and_then(|(connection, accepted, refusal)| match (accepted, refusal) {
+                (0, Some(refusal)) => Err(refusal),
+                _ => Ok(connection)
+            })
+            .and_then(|connection| expect(connection, Some(String::from("DATA")), 354))
+            .and_then(move |connection| connection.send_all(stream::iter_ok::<_, std::io::Error>(lines))
+                .map(|(connection, _)| connection)
+                .map_err(|err| Failure::retryable(err.to_string())))
+            .and_then(|connection| expect(connection, Some(String::from(".")), 250))
+            // the mail is accepted, a failing goodbye doesn't change that
+            .and_then(|connection| expect(connection, Some(String::from("QUIT")), 221).then(|_| Ok(())));
+
+        Timeout::new(transaction, timeout).map_err(move |err| {
+            if err.is_elapsed() {
+                return Failure::retryable(format!("no answer within {} ms", timeout.as_millis()));
+            }
+            err.into_inner().unwrap_or_else(|| Failure::retryable(String::from("timer failed")))
+        })
+    }
+
+    // headers and body of the mail, dot-stuffed for the DATA command
+    fn compose(&self, change: &QueueChange, recipients: &[String]) -> Vec<String> {
+        let event = &change.event;
+        let (subject, text) = match event.event_type {
+            EventType::SLAP => (format!("Slap for {}", change.city), slap_speech(event.sender.as_ref(), event.count)),
+            EventType::MESSAGE => (format!("Message for {}", change.city), message_speech(event.sender.as_ref(), event.message.as_deref().unwrap_or_default()))
+        };
+
+        let mut lines = vec![
+            format!("From: {}", self.from),
+            format!("To: {}", recipients.join(", ")),
+            format!("Subject: {}", subject),
+            format!("Date: {}", rfc5322_date(SystemTime::now())),
+            format!("Message-ID: <{}@{}>", change.id, self.hostname),
+            String::from("MIME-Version: 1.0"),
+            String::from("Content-Type: text/plain; charset=utf-8"),
+            String::from("Content-Transfer-Encoding: 8bit"),
+            String::new()
+        ];
+        lines.extend(text.lines().map(String::from));
+
+        lines.into_iter()
+            .map(|line| if line.starts_with('.') { format!(".{}", line) } else { line })
+            .collect()
+    }
+
+}
+
+impl DeliveryChannel for Mailer {
+    fn name(&self) -> &'static str {
+        CHANNEL_MAIL
+    }
+
+    fn serves(&self, city: &str) -> bool {
+        self.recipients_of(city).is_some()
+    }
+
+    fn deliver(self: Arc<Self>, change: &QueueChange) -> Box<dyn Future<Item=(), Error=Failure> + Send> {
+        let recipients = self.recipients_of(&change.city).cloned().unwrap_or_default();
+        let lines = self.compose(change, &recipients);
+        let call = format!("mail to {}", change.city);
+        let mailer = self.clone();
+
+        Box::new(with_retries(call, self.max_attempts, self.initial_backoff, move |_| mailer.transaction(recipients.clone(), lines.clone()))
+            .map(|_| ())
+            .map_err(|(_, failure)| failure))
+    }
+}
+
+// sends the command, if any, and reads the reply; 4xx replies are worth another attempt, 5xx replies are final
+fn expect(connection: Connection, command: Option<String>, code: u16) -> Box<dyn Future<Item=Connection, Error=Failure> + Send> {
+    let verb = command.as_ref().and_then(|command| command.split(' ').next()).unwrap_or("greeting").to_string();
+    let sent: Box<dyn Future<Item=Connection, Error=Failure> + Send> = match command {
+        Some(command) => Box::new(connection.send(command).map_err(|err| Failure::retryable(err.to_string()))),
+        None => Box::new(future::ok(connection))
+    };
+
+    Box::new(sent
+        .and_then(read_reply)
+        .and_then(move |(reply_code, text, connection)| {
+            if reply_code == code {
+                return Ok(connection);
+            }
+            let reason = format!("relay answered {} {} to {}", reply_code, text, verb);
+            match reply_code {
+                400..=499 => Err(Failure::retryable(reason)),
+                _ => Err(Failure::permanent(reason))
+            }
+        }))
+}
+
+// a recipient refused for good is only logged, a temporary refusal fails the transaction before any data was sent
+fn rcpt(connection: Connection, recipient: String) -> impl Future<Item=(Connection, Option<Failure>), Error=Failure> {
+    connection.send(format!("RCPT TO:<{}>", recipient))
+        .map_err(|err| Failure::retryable(err.to_string()))
+        .and_then(read_reply)
+        .and_then(move |(reply_code, text, connection)| {
+            let reason = format!("relay answered {} {} to RCPT", reply_code, text);
+            match reply_code {
+                250 | 251 => Ok((connection, None)),
+                400..=499 => Err(Failure::retryable(reason)),
+                _ => {
+                    warn!(recipient = recipient.as_str(), error = reason.as_str(); "recipient refused, mailing the others");
+                    Ok((connection, Some(Failure::permanent(reason))))
+                }
+            }
+        })
+}
+
+// a reply ends with the line that has a space after its code
+fn read_reply(connection: Connection) -> impl Future<Item=(u16, String, Connection), Error=Failure> {
+    future::loop_fn((connection, Vec::new()), |(connection, mut text)| {
+        connection.into_future()
+            .map_err(|(err, _)| Failure::retryable(err.to_string()))
+            .and_then(|(line, connection)| {
+                let line = line.ok_or_else(|| Failure::retryable(String::from("the relay closed the connection")))?;
+                let code = line.get(..3).and_then(|code| code.parse::<u16>().ok())
+                    .ok_or_else(|| Failure::retryable(format!("malformed reply: {}", line)))?;
+                text.push(String::from(line.get(4..).unwrap_or_default()));
+                match line.as_bytes().get(3) {
+                    Some(b'-') => Ok(Loop::Continue((connection, text))),
+                    _ => Ok(Loop::Break((code, text.join(" "), connection)))
+                }
+            })
+    })
+}
+
+// ------------- there are tests only below this point ------------
+
+#[cfg(test)]
+use std::collections::{BTreeMap, VecDeque};
+#[cfg(test)]
+use std::io::{BufRead, BufReader, Write};
+#[cfg(test)]
+use std::sync::Mutex;
+#[cfg(test)]
+use crate::delivery::DeliveryRouter;
+#[cfg(test)]
+use crate::feed::{ChangeId, ChangeKind, Feed};
+#[cfg(test)]
+use crate::storage::Event;
+#[cfg(test)]
+use crate::testing::wait_until;
+
+#[cfg(test)]
+type Received = Arc<Mutex<Vec<(Vec<String>, Vec<String>)>>>;
+
+// a relay answering RCPT with the given codes in turn, 250 once they ran out;
+// records the accepted recipients and the data of every accepted mail
+#[cfg(test)]
+fn stand_in_relay(rcpt_codes: Vec<u16>) -> (SocketAddr, Received) {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let recorder = received.clone();
+    let rcpt_codes = Arc::new(Mutex::new(VecDeque::from(rcpt_codes)));
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut writer = stream.unwrap();
+            let mut reader = BufReader::new(writer.try_clone().unwrap());
+            let (mut recipients, mut data, mut in_data) = (Vec::new(), Vec::new(), false);
+            writer.write_all(b"220-relay.local ESMTP\r\n220 ready\r\n").unwrap();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 0 {
+                let command = line.trim_end().to_string();
+                line.clear();
+                let reply = match command.split(' ').next().unwrap() {
+                    "." if in_data => {
+                        in_data = false;
+                        recorder.lock().unwrap().push((recipients.clone(), data.clone()));
+                        String::from("250 queued")
+                    },
+                    _ if in_data => {
+                        data.push(command);
+                        continue;
+                    },
+                    "EHLO" => String::from("250-relay.local\r\n250 8BITMIME"),
+                    "RCPT" => {
+                        let code = rcpt_codes.lock().unwrap().pop_front().unwrap_or(250);
+                        if code == 250 {
+                            recipients.push(command.clone());
+                        }
+                        format!("{} recipient", code)
+                    },
+                    "DATA" => {
+                        in_data = true;
+                        String::from("354 go ahead")
+                    },
+                    "QUIT" => {
+                        writer.write_all(b"221 bye\r\n").unwrap();
+                        break;
+                    },
+                    _ => String::from("250 ok")
+                };
+                writer.write_all(format!("{}\r\n", reply).as_bytes()).unwrap();
+            }
+        }
+    });
+    (addr, received)
+}
+
+#[test]
+fn test_added_notifications_are_mailed_to_the_addresses_of_their_city() {
+    // given
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let (addr, received) = stand_in_relay(vec![451, 250, 250, 550, 250, 550, 550]);
+    let mut recipients = BTreeMap::new();
+    recipients.insert(String::from("BERLIN"), vec![String::from("office@berlin.example"), String::from("anna@berlin.example")]);
+    let config = MailConfig {
+        relay: addr.to_string(),
+        recipients,
+        initial_backoff_millis: 10,
+        ..MailConfig::default()
+    };
+    let mailer = Arc::new(Mailer::new(&config).unwrap());
+    let feed = Feed::new();
+    runtime.spawn(Arc::new(DeliveryRouter::for_channel(mailer)).run(feed.subscribe_all()));
+
+    // when
+    feed.publish("KIEV", ChangeKind::Added, &Event::new_slap(), 1);
+    feed.publish("BERLIN", ChangeKind::Added, &Event::new_message(String::from(".hidden lunch is here")).sent_by(Some(String::from("anna"))), 1);
+
+    // then the greylisting relay was asked again
+    wait_until(|| received.lock().unwrap().len() == 1);
+    let (to, data) = received.lock().unwrap()[0].clone();
+    assert_eq!(to, vec!["RCPT TO:<office@berlin.example>", "RCPT TO:<anna@berlin.example>"]);
+    assert!(data.contains(&String::from("Subject: Message for BERLIN")));
+    assert!(data.contains(&String::from("To: office@berlin.example, anna@berlin.example")));
+    assert_eq!(data.last().unwrap(), "anna sent you a message: .hidden lunch is here");
+
+    // when a recipient is refused for good
+    feed.publish("BERLIN", ChangeKind::Added, &Event::new_slap(), 2);
+
+    // then the others still get the mail
+    wait_until(|| received.lock().unwrap().len() == 2);
+    assert_eq!(received.lock().unwrap()[1].0, vec!["RCPT TO:<anna@berlin.example>"]);
+
+    // when all recipients are refused for good
+    feed.publish("BERLIN", ChangeKind::Added, &Event::new_slap(), 3);
+
+    // then the mail is given up
+    std::thread::sleep(Duration::from_millis(300));
+    assert_eq!(received.lock().unwrap().len(), 2);
+
+    feed.close();
+    runtime.shutdown_now().wait().unwrap();
+}
+
+#[test]
+fn test_leading_dots_are_stuffed() {
+    let mailer = Mailer::new(&MailConfig::default()).unwrap();
+    let change = QueueChange { id: ChangeId { epoch: 1538043174, sequence: 7 }, city: String::from("BERLIN"), kind: ChangeKind::Added, event: Event::new_message(String::from("...\nthat's all")), queue_size: 1 };
+
+    let lines = mailer.compose(&change, &[String::from("office@berlin.example")]);
+
+    assert_eq!(&lines[lines.len() - 1], "that's all");
+    assert_eq!(&lines[lines.len() - 2], "Someone sent you a message: ...");
+    assert_eq!(lines[4], "Message-ID: <1538043174-7@localhost>");
+}
@@ -0,0 +1,320 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use futures::{future, stream, Future, Sink, Stream};
+use futures::future::{Either, Loop};
+use mail_parser::MessageParser;
+use tokio::codec::Framed;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::timer::Timeout;
+
+use crate::config::{MailConfig, MailIngestConfig};
+use crate::mail::{path_of, SmtpCodec};
+use crate::metrics::{Metrics, CHANNEL_MAIL};
+use crate::storage::{AddOutcome, Event, Storage};
+
+// a client may not hold a connection longer than this
+const SESSION_TIMEOUT: Duration = Duration::from_secs(300);
+const STORAGE_UNAVAILABLE: &str = "451 the notification storage is unavailable";
+
+// takes mails to {city}@domain and queues their text as a MESSAGE for the city
+pub struct MailIngest {
+    storage: Arc<RwLock<Storage>>,
+    metrics: Arc<Metrics>,
+    hostname: String,
+    domain: Option<String>,
+    max_message_size: usize
+}
+
+impl MailIngest {
+
+    pub fn new(storage: Arc<RwLock<Storage>>, metrics: Arc<Metrics>, config: &MailConfig, ingest: &MailIngestConfig) -> MailIngest {
+        MailIngest {
+            storage,
+            metrics,
+            hostname: config.hostname.clone(),
+            domain: ingest.domain.clone(),
+            max_message_size: ingest.max_message_size
+        }
+    }
+
+    pub fn bind(address: &str) -> Result<TcpListener, String> {
+        let addr: SocketAddr = address.parse().map_err(|err| format!("invalid address {}: {}", address, err))?;
+        TcpListener::bind(&addr).map_err(|err| format!("cannot bind {}: {}", addr, err))
+    }
+
+    // runs until the listener fails, every client is served on its own task
+    pub fn serve(self: Arc<Self>, listener: TcpListener) -> impl Future<Item=(), Error=()> {
+        listener.incoming()
+            .map_err(|err| error!(error:% = err; "cannot accept mail clients"))
+            .for_each(move |stream| {
+                let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+                let session = Timeout::new(self.clone().converse(stream), SESSION_TIMEOUT)
+                    .map_err(move |err| warn!(peer = peer.as_str(), error:% = err; "mail session ended"));
+                tokio::spawn(session);
+                Ok(())
+            })
+    }
+
+    fn converse(self: Arc<Self>, stream: TcpStream) -> impl Future<Item=(), Error=std::io::Error> {
+        let greeting = format!("220 {} ESMTP", self.hostname);
+        let conversation = Conversation::new(self);
+
+        Framed::new(stream, SmtpCodec).send(greeting).and_then(|connection| {
+            future::loop_fn((connection, conversation), |(connection, mut conversation)| {
+                connection.into_future()
+                    .map_err(|(err, _)| err)
+                    .and_then(move |(line, connection)| {
+                        let line = match line {
+                            Some(line) => line,
+                            None => return Either::A(future::ok(Loop::Break(())))
+                        };
+                        let replies = conversation.on_line(line);
+                        Either::B(connection.send_all(stream::iter_ok::<_, std::io::Error>(replies))
+                            .map(move |(connection, _)| match conversation.closed {
+                                true => Loop::Break(()),
+                                false => Loop::Continue((connection, conversation))
+                            }))
+                    })
+            })
+        })
+    }
+
+    // the reply to the end of the data
+    fn queue(&self, cities: &[String], raw: &[u8]) -> String {
+        let message = match MessageParser::default().parse(raw) {
+            Some(message) => message,
+            None => return String::from("554 the mail cannot be parsed")
+        };
+        let text = message.body_text(0)
+            .map(|text| text.trim().to_string())
+            .filter(|text| !text.is_empty())
+            .or_else(|| message.subject().map(|subject| subject.trim().to_string()).filter(|subject| !subject.is_empty()));
+        let text = match text {
+            Some(text) => text,
+            None => return String::from("554 the mail has no text")
+        };
+        let sender = message.from()
+            .and_then(|from| from.first())
+            .and_then(|from| from.name().or(from.address()))
+            .map(String::from);
+
+        // the sender is whatever the From: header claims, see MailIngestConfig
+        let mut queued = Vec::new();
+        for city in cities {
+            let event = Event::new_message(text.clone()).sent_by(sender.clone());
+            let event_type = event.event_type.clone();
+            let outcome = match self.storage.write() {
+                Ok(mut storage) => storage.add_event(event, city.clone()),
+                Err(_) => return String::from(STORAGE_UNAVAILABLE)
+            };
+            match outcome {
+                AddOutcome::Rejected | AddOutcome::UnknownDevice => (),
+                _ => {
+                    self.metrics.notification_created(&event_type, CHANNEL_MAIL);
+                    queued.push(city.as_str())
+                }
+            }
+        }
+
+        info!(cities = queued.join(",").as_str(), sender:? = sender; "mail queued");
+        match queued.is_empty() {
+            true => String::from("452 the notification queue is full"),
+            false => format!("250 queued for {}", queued.join(", "))
+        }
+    }
+
+    // the city of a recipient of our domain, fails with the reply when the storage can't tell
+    fn city_of(&self, recipient: &str) -> Result<Option<String>, String> {
+        let (local, domain) = recipient.rsplit_once('@').unwrap_or((recipient, ""));
+        if let Some(expected) = &self.domain {
+            if !domain.eq_ignore_ascii_case(expected) {
+                return Ok(None);
+            }
+        }
+        let city = local.to_uppercase();
+        match self.storage.read() {
+            Ok(storage) if storage.is_registered(&city) => Ok(Some(city)),
+            Ok(_) => Ok(None),
+            Err(_) => Err(String::from(STORAGE_UNAVAILABLE))
+        }
+    }
+
+}
+
+// the state of one client connection, it speaks just enough SMTP to take mails
+struct Conversation {
+    ingest: Arc<MailIngest>,
+    reverse_path: Option<String>,
+    cities: Vec<String>,
+    // Some while the mail is read, dot-unstuffed with CRLF line ends
+    data: Option<Vec<u8>>,
+    closed: bool
+}
+
+impl Conversation {
+
+    fn new(ingest: Arc<MailIngest>) -> Conversation {
+        Conversation { ingest, reverse_path: None, cities: Vec::new(), data: None, closed: false }
+    }
+
+    fn on_line(&mut self, line: String) -> Vec<String> {
+        if let Some(data) = self.data.as_mut() {
+            if line == "." {
+                let data = self.data.take().unwrap_or_default();
+                let reply = match data.len() > self.ingest.max_message_size {
+                    true => String::from("552 the mail is too large"),
+                    false => self.ingest.queue(&self.cities, &data)
+                };
+                self.reset();
+                return vec![reply];
+            }
+            // the size is checked at the end, the client won't listen before that
+            if data.len() <= self.ingest.max_message_size {
+                data.extend_from_slice(line.strip_prefix('.').unwrap_or(&line).as_bytes());
+                data.extend_from_slice(b"\r\n");
+            }
+            return Vec::new();
+        }
+
+        let (verb, argument) = line.split_once(' ').unwrap_or((&line, ""));
+        let reply = match verb.to_uppercase().as_str() {
+            "HELO" | "EHLO" => format!("250 {}", self.ingest.hostname),
+            "MAIL" => match path_of(argument) {
+                Some(reverse_path) => {
+                    self.reset();
+                    self.reverse_path = Some(String::from(reverse_path));
+                    String::from("250 OK")
+                },
+                None => String::from("501 expected MAIL FROM:<address>")
+            },
+            "RCPT" if self.reverse_path.is_none() => String::from("503 MAIL first"),
+            "RCPT" => match path_of(argument).map(|recipient| (recipient, self.ingest.city_of(recipient))) {
+                Some((_, Ok(Some(city)))) => {
+                    if !self.cities.contains(&city) {
+                        self.cities.push(city);
+                    }
+                    String::from("250 OK")
+                },
+                Some((recipient, Ok(None))) => format!("550 no city takes mails for {}", recipient),
+                Some((_, Err(reply))) => reply,
+                None => String::from("501 expected RCPT TO:<address>")
+            },
+            "DATA" if self.cities.is_empty() => String::from("503 RCPT first"),
+            "DATA" => {
+                self.data = Some(Vec::new());
+                String::from("354 end data with <CR><LF>.<CR><LF>")
+            },
+            "RSET" => {
+                self.reset();
+                String::from("250 OK")
+            },
+            "NOOP" => String::from("250 OK"),
+            "QUIT" => {
+                self.closed = true;
+                format!("221 {} closing", self.ingest.hostname)
+            },
+            _ => String::from("502 command not implemented")
+        };
+        vec![reply]
+    }
+
+    fn reset(&mut self) {
+        self.reverse_path = None;
+        self.cities.clear();
+        self.data = None;
+    }
+
+}
+
+// ------------- there are tests only below this point ------------
+
+#[cfg(test)]
+use std::io::{BufRead, BufReader, Write};
+
+#[cfg(test)]
+fn converse(client: &mut std::net::TcpStream, replies: &mut BufReader<std::net::TcpStream>, line: &str) -> String {
+    client.write_all(format!("{}\r\n", line).as_bytes()).unwrap();
+    let mut reply = String::new();
+    replies.read_line(&mut reply).unwrap();
+    reply
+}
+
+#[cfg(test)]
+fn ingest(storage: Arc<RwLock<Storage>>, domain: Option<&str>) -> MailIngest {
+    let ingest = MailIngestConfig { domain: domain.map(String::from), max_message_size: 1024, ..MailIngestConfig::default() };
+    MailIngest::new(storage, Arc::new(Metrics::new()), &MailConfig::default(), &ingest)
+}
+
+#[test]
+fn test_mails_to_a_city_are_queued_as_messages() {
+    // given
+    let storage = Arc::new(RwLock::new(Storage::new()));
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let listener = MailIngest::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mail_ingest = Arc::new(ingest(storage.clone(), Some("danila.example")));
+    runtime.spawn(mail_ingest.clone().serve(listener));
+
+    // when
+    let mut client = std::net::TcpStream::connect(addr).unwrap();
+    let mut replies = BufReader::new(client.try_clone().unwrap());
+    let mut greeting = String::new();
+    replies.read_line(&mut greeting).unwrap();
+
+    // then
+    assert!(greeting.starts_with("220 "));
+    assert!(converse(&mut client, &mut replies, "EHLO client.example").starts_with("250 "));
+    assert!(converse(&mut client, &mut replies, "RCPT TO:<berlin@danila.example>").starts_with("503 "));
+    assert!(converse(&mut client, &mut replies, "MAIL FROM:<anna@example.com>").starts_with("250 "));
+    assert!(converse(&mut client, &mut replies, "RCPT TO:<paris@danila.example>").starts_with("550 "));
+    assert!(converse(&mut client, &mut replies, "RCPT TO:<berlin@elsewhere.example>").starts_with("550 "));
+    assert!(converse(&mut client, &mut replies, "RCPT TO:<Berlin@danila.example>").starts_with("250 "));
+    assert!(converse(&mut client, &mut replies, "RCPT TO:<kiev@danila.example>").starts_with("250 "));
+    assert!(converse(&mut client, &mut replies, "DATA").starts_with("354 "));
+    client.write_all(b"From: Anna <anna@example.com>\r\nSubject: food\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n..lunch is here\r\n").unwrap();
+    assert_eq!(converse(&mut client, &mut replies, "."), "250 queued for BERLIN, KIEV\r\n");
+    assert!(converse(&mut client, &mut replies, "QUIT").starts_with("221 "));
+
+    let event = storage.write().unwrap().pop_event(&String::from("BERLIN"), crate::metrics::CHANNEL_REST).unwrap();
+    assert_eq!((event.message.as_deref(), event.sender.as_deref()), (Some(".lunch is here"), Some("Anna")));
+    assert_eq!(storage.read().unwrap().size(&String::from("KIEV")), 1);
+    assert!(mail_ingest.metrics.render(&storage.read().unwrap()).contains("notifications_created_total{type=\"MESSAGE\",channel=\"mail\"} 2\n"));
+
+    runtime.shutdown_now().wait().unwrap();
+}
+
+#[test]
+fn test_oversized_and_empty_mails_are_refused() {
+    let storage = Arc::new(RwLock::new(Storage::new()));
+    let mut conversation = Conversation::new(Arc::new(ingest(storage.clone(), None)));
+    for line in ["HELO client", "MAIL FROM:<anna@example.com>", "RCPT TO:<milan@anywhere.example>", "DATA"].iter() {
+        conversation.on_line(String::from(*line));
+    }
+
+    for _ in 0..100 {
+        assert!(conversation.on_line(String::from("far too long for a slap")).is_empty());
+    }
+    assert!(conversation.on_line(String::from("."))[0].starts_with("552 "));
+
+    for line in ["MAIL FROM:<anna@example.com>", "RCPT TO:<milan@anywhere.example>", "DATA", "Subject: ", ""].iter() {
+        conversation.on_line(String::from(*line));
+    }
+    assert!(conversation.on_line(String::from("."))[0].starts_with("554 "));
+    assert_eq!(storage.read().unwrap().size(&String::from("MILAN")), 0);
+}
+
+#[test]
+fn test_recipients_are_deferred_while_the_storage_is_poisoned() {
+    let storage = Arc::new(RwLock::new(Storage::new()));
+    let mut conversation = Conversation::new(Arc::new(ingest(storage.clone(), None)));
+    let poisoning_storage = storage.clone();
+    let _ = std::thread::spawn(move || {
+        let _guard = poisoning_storage.write().unwrap();
+        panic!("poison the storage lock");
+    }).join();
+
+    conversation.on_line(String::from("MAIL FROM:<anna@example.com>"));
+    assert!(conversation.on_line(String::from("RCPT TO:<milan@anywhere.example>"))[0].starts_with("451 "));
+}
@@ -0,0 +1,50 @@
+use std::io;
+
+use bytes::BytesMut;
+use tokio::codec::{Decoder, Encoder};
+
+pub mod delivery;
+pub mod ingest;
+
+// RFC 5321 allows 1000 octets, some clients don't care
+const MAX_LINE_LENGTH: usize = 8 * 1024;
+
+// lines of an SMTP conversation, CRLF on the wire; mails needn't be UTF-8, so lines are decoded lossily
+#[derive(Default)]
+pub struct SmtpCodec;
+
+impl Decoder for SmtpCodec {
+    type Item = String;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<String>, io::Error> {
+        match buf.iter().position(|byte| *byte == b'\n') {
+            Some(end) => {
+                let line = buf.split_to(end + 1);
+                let line = line[..end].strip_suffix(b"\r").unwrap_or(&line[..end]);
+                Ok(Some(String::from_utf8_lossy(line).into_owned()))
+            },
+            None if buf.len() > MAX_LINE_LENGTH => Err(io::Error::new(io::ErrorKind::InvalidData, "line too long")),
+            None => Ok(None)
+        }
+    }
+}
+
+impl Encoder for SmtpCodec {
+    type Item = String;
+    type Error = io::Error;
+
+    fn encode(&mut self, line: String, buf: &mut BytesMut) -> Result<(), io::Error> {
+        buf.reserve(line.len() + 2);
+        buf.extend_from_slice(line.as_bytes());
+        buf.extend_from_slice(b"\r\n");
+        Ok(())
+    }
+}
+
+// the address between the angle brackets of "MAIL FROM:<...>" or "RCPT TO:<...>"
+pub fn path_of(argument: &str) -> Option<&str> {
+    let start = argument.find('<')?;
+    let end = start + argument[start..].find('>')?;
+    Some(&argument[start + 1..end])
+}
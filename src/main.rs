@@ -1,47 +1,217 @@
 #[macro_use]
 extern crate serde_derive;
 extern crate serde;
+#[macro_use]
 extern crate serde_json;
 extern crate hyper;
 extern crate futures;
+extern crate tokio;
+extern crate native_tls;
+extern crate tokio_tls;
+extern crate hyper_tls;
+#[macro_use]
+extern crate log;
+#[cfg(test)]
+extern crate openssl;
 
 mod api;
+mod chat;
+mod client;
+mod config;
+mod delivery;
+mod feed;
+mod logging;
+mod mail;
+mod metrics;
+mod mqtt;
+mod proactive_events;
+mod server;
 mod storage;
+#[cfg(test)]
+mod testing;
+mod webhooks;
 
 use std::sync::{Arc, RwLock};
-use futures::{future, Future, Stream};
-use hyper::{Body, Request, Server, Method, Response, StatusCode};
-use hyper::service::service_fn;
 
-use crate::api::rest::dto::StatusResponse;
+use futures::Future;
+#[cfg(test)]
+use hyper::{Body, Request};
+
+#[cfg(test)]
+use futures::{future, Stream};
+#[cfg(test)]
+use hyper::{Method, Response, StatusCode};
+#[cfg(test)]
+use hyper::header::{HeaderValue, ACCEPT, AUTHORIZATION, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_REQUEST_METHOD, CONTENT_TYPE, ORIGIN, RETRY_AFTER};
+#[cfg(test)]
+use crate::api::rest::dto::{StatusResponse, BatchCreateResponse};
+
+fn create_dispatcher(storage: Arc<RwLock<storage::Storage>>, metrics: Arc<metrics::Metrics>, config: &config::Config) -> api::dispatcher::Dispatcher {
+    let alexa_controller = api::alexa::controller::AlexaController::new(storage.clone(), metrics.clone());
+    let city_limiter = api::ratelimit::RateLimiter::new(config.rate_limit.per_city.clone(), Arc::new(api::ratelimit::SystemClock::new()));
+    let rest_controller = api::rest::controller::RestController::new(storage.clone(), city_limiter, metrics.clone(), std::time::Duration::from_secs(config.server.heartbeat_secs), std::time::Duration::from_secs(config.server.max_poll_wait_secs));
+
+    let health_controller = api::health::controller::HealthController::new(storage.clone(), metrics.clone(), config);
+
+    api::dispatcher::Dispatcher::new(rest_controller, alexa_controller, health_controller, metrics, config)
+}
+
+fn create_pipeline(storage: Arc<RwLock<storage::Storage>>, metrics: Arc<metrics::Metrics>, config: &config::Config) -> api::middleware::Pipeline {
+    let client_limiter = api::ratelimit::RateLimiter::new(config.rate_limit.per_client.clone(), Arc::new(api::ratelimit::SystemClock::new()));
+    let dispatcher = create_dispatcher(storage, metrics, config);
+    let layers: Vec<Box<dyn api::middleware::Middleware>> = vec![
+        Box::new(api::middleware::AccessLog::new(config.logging.access_log)),
+        Box::new(api::middleware::MetricsLayer::new(dispatcher.metrics())),
+        Box::new(api::cors::Cors::new(&config.cors)),
+        Box::new(api::auth::ApiKeyAuth::new(&config.auth)),
+        Box::new(api::ratelimit::ClientRateLimit::new(client_limiter))
+    ];
+
+    api::middleware::Pipeline::new(dispatcher, layers)
+}
+
+// None while no webhook is registered
+fn create_webhooks(config: &config::Config) -> Result<Option<Arc<webhooks::Webhooks>>, String> {
+    if config.webhooks.endpoints.is_empty() {
+        return Ok(None);
+    }
+    let webhooks = webhooks::Webhooks::new(&config.webhooks, client::https_client()?)?;
+    Ok(Some(Arc::new(webhooks)))
+}
+
+// None while no Echo device is mapped to an Alexa user
+fn create_proactive_events(config: &config::Config) -> Result<Option<Arc<proactive_events::ProactiveEvents>>, String> {
+    if config.proactive_events.users.is_empty() {
+        return Ok(None);
+    }
+    let proactive_events = proactive_events::ProactiveEvents::new(&config.proactive_events, client::https_client()?)?;
+    Ok(Some(Arc::new(proactive_events)))
+}
+
+// None while no device forwards to a chat
+fn create_chat_bridge(config: &config::Config) -> Result<Option<Arc<chat::ChatBridge>>, String> {
+    if config.chat.webhooks.is_empty() {
+        return Ok(None);
+    }
+    let bridge = chat::ChatBridge::new(&config.chat, client::https_client()?)?;
+    Ok(Some(Arc::new(bridge)))
+}
+
+// None while no city has mail recipients
+fn create_mailer(config: &config::Config) -> Result<Option<Arc<mail::delivery::Mailer>>, String> {
+    if config.mail.recipients.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(Arc::new(mail::delivery::Mailer::new(&config.mail)?)))
+}
 
-fn create_dispatcher(storage: Arc<RwLock<storage::Storage>>) -> api::dispatcher::Dispatcher {
-    let alexa_controller = api::alexa::controller::AlexaController::new(storage.clone());
-    let rest_controller = api::rest::controller::RestController::new(storage.clone());
+// the push channels that are set up, fanned out to by the delivery routes
+fn create_delivery_router(config: &config::Config, deliveries: Arc<delivery::DeliveryLog>, metrics: Arc<metrics::Metrics>, mqtt_bridge: Option<Arc<mqtt::bridge::MqttBridge>>) -> Result<delivery::DeliveryRouter, String> {
+    let mut channels: Vec<Arc<dyn delivery::DeliveryChannel>> = Vec::new();
+    if let Some(mqtt_bridge) = mqtt_bridge {
+        channels.push(mqtt_bridge);
+    }
+    if let Some(webhooks) = create_webhooks(config).map_err(|err| format!("webhooks: {}", err))? {
+        channels.push(webhooks);
+    }
+    if let Some(proactive_events) = create_proactive_events(config).map_err(|err| format!("proactive events: {}", err))? {
+        channels.push(proactive_events);
+    }
+    if let Some(chat_bridge) = create_chat_bridge(config).map_err(|err| format!("chat bridge: {}", err))? {
+        channels.push(chat_bridge);
+    }
+    if let Some(mailer) = create_mailer(config).map_err(|err| format!("mail delivery: {}", err))? {
+        channels.push(mailer);
+    }
+    delivery::DeliveryRouter::new(channels, &config.delivery, deliveries, metrics)
+}
 
-    api::dispatcher::Dispatcher::new(rest_controller, alexa_controller)
+// None while no broker is set
+fn create_mqtt_bridge(config: &config::Config, storage: Arc<RwLock<storage::Storage>>, metrics: Arc<metrics::Metrics>) -> Result<Option<Arc<mqtt::bridge::MqttBridge>>, String> {
+    if config.mqtt.broker.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(Arc::new(mqtt::bridge::MqttBridge::new(storage, metrics, &config.mqtt)?)))
 }
 
 fn main() {
-    let storage = Arc::new(RwLock::new(storage::Storage::new()));
-    let dispatcher = Arc::new(create_dispatcher(storage.clone()));
+    let config = match config::Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("configuration error: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(err) = logging::init(config.logging.level, config.logging.format) {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
 
-    let new_svc = move || {
-        let _dispatcher = dispatcher.clone();
-        let _storage = storage.clone();
-        service_fn( move |req: Request<Body>| {
-            _dispatcher.dispatch(req)
-        })
+    let storage = storage::Storage::new()
+        .with_queue_limit(config.storage.max_queue_length, config.storage.overflow_policy)
+        .with_slap_coalescing(config.storage.coalesce_slaps)
+        .with_routes(delivery::DeliveryRoutes::new(&config.delivery));
+    let storage = Arc::new(RwLock::new(storage));
+    // one set of metrics for everything that creates or delivers notifications
+    let metrics = Arc::new(metrics::Metrics::new());
+    let pipeline = Arc::new(create_pipeline(storage.clone(), metrics.clone(), &config));
+    let mqtt_bridge = match create_mqtt_bridge(&config, storage.clone(), metrics.clone()) {
+        Ok(mqtt_bridge) => mqtt_bridge,
+        Err(err) => {
+            error!(error:% = err; "cannot set up the MQTT bridge");
+            std::process::exit(1);
+        }
+    };
+    let delivery_router = match create_delivery_router(&config, storage.read().unwrap().deliveries(), metrics.clone(), mqtt_bridge.clone()) {
+        Ok(delivery_router) => Arc::new(delivery_router),
+        Err(err) => {
+            error!(error:% = err; "cannot set up the delivery channels");
+            std::process::exit(1);
+        }
+    };
+    let mail_ingest = match config.mail.ingest.as_ref().map(|ingest| mail::ingest::MailIngest::bind(&ingest.address)).transpose() {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!(error:% = err; "cannot start the mail ingest");
+            std::process::exit(1);
+        }
     };
 
-    let addr = ([127, 0, 0, 1], 3000).into();
-    let server = Server::bind(&addr).serve(new_svc).map_err(|e| {
-        eprintln!("server error: {}", e)
-    });
+    // open event streams end with the shutdown, so they don't hold up draining
+    let feed = storage.read().unwrap().feed();
+    let shutdown = server::termination_signal().inspect(move |_| feed.close());
+
+    let server = match server::bind(&config.server, pipeline, shutdown) {
+        Ok(server) => server,
+        Err(err) => {
+            error!(error:% = err; "cannot start the server");
+            std::process::exit(1);
+        }
+    };
+
+    info!(address:% = server.local_addr; "serving");
+    if let Some(redirect_addr) = server.redirect_addr {
+        info!(address:% = redirect_addr; "redirecting plain HTTP to HTTPS");
+    }
 
-    // Run this server for... forever!
-    hyper::rt::run(server);
+    // Run this server until SIGINT or SIGTERM, connections still open after draining are dropped
+    let mut runtime = tokio::runtime::Runtime::new().expect("cannot start the runtime");
+    runtime.spawn(delivery_router.run(storage.read().unwrap().feed().subscribe_all()));
+    if let Some(mqtt_bridge) = mqtt_bridge {
+        runtime.spawn(mqtt_bridge.run(storage.read().unwrap().feed().subscribe_all()));
+    }
+    if let (Some(listener), Some(ingest)) = (mail_ingest, config.mail.ingest.as_ref()) {
+        info!(address:% = ingest.address; "taking mails");
+        runtime.spawn(Arc::new(mail::ingest::MailIngest::new(storage.clone(), metrics, &config.mail, ingest)).serve(listener));
+    }
+    let _ = runtime.block_on(server.future);
+    let _ = runtime.shutdown_now().wait();
 
+    match storage.write() {
+        Ok(mut storage) => storage.flush(),
+        Err(_) => error!("storage lock is poisoned, nothing has been flushed")
+    };
 }
 
 // ------------- there are tests only below this point ------------
@@ -51,7 +221,7 @@ fn main() {
 fn smoke_test_slap_rest_creation() {
     // given
     let storage = Arc::new(RwLock::new(storage::Storage::new()));
-    let dispatcher = create_dispatcher(storage.clone());
+    let dispatcher = create_dispatcher(storage.clone(), Arc::new(metrics::Metrics::new()), &config::Config::default());
     let for_city = String::from("BERLIN");
 
     // when
@@ -70,7 +240,7 @@ fn smoke_test_slap_rest_creation() {
 fn smoke_test_message_rest_creation() {
     // given
     let storage = Arc::new(RwLock::new(storage::Storage::new()));
-    let dispatcher = create_dispatcher(storage.clone());
+    let dispatcher = create_dispatcher(storage.clone(), Arc::new(metrics::Metrics::new()), &config::Config::default());
     let for_city = String::from("BERLIN");
     let message = String::from("test message text");
 
@@ -84,7 +254,7 @@ fn smoke_test_message_rest_creation() {
     let berlin_queue_size = storage.clone().read().unwrap().size(&for_city);
     assert_eq!(berlin_queue_size, 1);
 
-    match storage.clone().write().unwrap().pop_event(&for_city) {
+    match storage.clone().write().unwrap().pop_event(&for_city, metrics::CHANNEL_REST) {
         Some(event) => {
             assert!(event.message.clone().is_some());
             assert_eq!(message, event.message.unwrap());
@@ -101,7 +271,7 @@ fn smoke_test_message_rest_creation() {
 fn smoke_test_get_notifications() {
     // given
     let storage = Arc::new(RwLock::new(storage::Storage::new()));
-    let dispatcher = create_dispatcher(storage.clone());
+    let dispatcher = create_dispatcher(storage.clone(), Arc::new(metrics::Metrics::new()), &config::Config::default());
     let for_city = String::from("BERLIN");
 
     let event = storage::Event::new_slap();
@@ -123,7 +293,7 @@ fn smoke_test_get_notifications() {
 fn smoke_test_create_slap_notification() {
     // given
     let storage = Arc::new(RwLock::new(storage::Storage::new()));
-    let dispatcher = create_dispatcher(storage.clone());
+    let dispatcher = create_dispatcher(storage.clone(), Arc::new(metrics::Metrics::new()), &config::Config::default());
     let city = String::from("BERLIN");
 
     // when
@@ -139,7 +309,7 @@ fn smoke_test_create_slap_notification() {
 fn smoke_test_create_and_retrieve_slap() {
     // given
     let storage = Arc::new(RwLock::new(storage::Storage::new()));
-    let dispatcher = create_dispatcher(storage.clone());
+    let dispatcher = create_dispatcher(storage.clone(), Arc::new(metrics::Metrics::new()), &config::Config::default());
     let city = String::from("BERLIN");
 
     // STEP 1: create notification for Berlin
@@ -157,12 +327,523 @@ fn smoke_test_create_and_retrieve_slap() {
     assert_eq!(berlin_queue_size, 0);
 }
 
-fn deliver_notification_for(city: &String, dispatcher: &api::dispatcher::Dispatcher) {
+#[test]
+fn smoke_test_broadcast_slap_to_all_cities() {
+    // given
+    let storage = Arc::new(RwLock::new(storage::Storage::new()));
+    let dispatcher = create_dispatcher(storage.clone(), Arc::new(metrics::Metrics::new()), &config::Config::default());
+    let json = r###"{"type_name":"SLAP","for_city":"*","message_text":null}"###;
+
+    // when
+    let req = build_request_for_notification_creation(String::from(json));
+
+    // then
+    let response = dispatcher.dispatch(req).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::MULTI_STATUS);
+
+    let response_object: BatchCreateResponse = serde_json::from_str(&consume_body(response)).unwrap();
+    assert_eq!(response_object.results.len(), 4);
+    assert!(response_object.results.iter().all(|result| result.status == 201));
+
+    for city in storage.read().unwrap().get_devices() {
+        assert_eq!(storage.read().unwrap().size(&city), 1);
+    }
+}
+
+#[test]
+fn smoke_test_batch_creation_reports_per_item_results() {
+    // given
+    let storage = Arc::new(RwLock::new(storage::Storage::new()));
+    let dispatcher = create_dispatcher(storage.clone(), Arc::new(metrics::Metrics::new()), &config::Config::default());
+    let json = r###"[
+        {"type_name":"MESSAGE","for_city":["BERLIN","KIEV","PARIS"],"message_text":"lunch is here"},
+        {"type_name":"MESSAGE","for_city":"MILAN","message_text":null}
+    ]"###;
+
+    // when
+    let req = build_request_for_notification_creation(String::from(json));
+
+    // then
+    let response = dispatcher.dispatch(req).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::MULTI_STATUS);
+
+    let response_object: BatchCreateResponse = serde_json::from_str(&consume_body(response)).unwrap();
+    let statuses: Vec<(String, u16)> = response_object.results.iter()
+        .map(|result| (result.for_city.clone(), result.status))
+        .collect();
+    assert_eq!(statuses, vec![
+        (String::from("BERLIN"), 201),
+        (String::from("KIEV"), 201),
+        (String::from("PARIS"), 400),
+        (String::from("MILAN"), 400)
+    ]);
+    assert!(response_object.results[2].error.is_some());
+
+    assert_eq!(storage.read().unwrap().size(&String::from("BERLIN")), 1);
+    assert_eq!(storage.read().unwrap().size(&String::from("KIEV")), 1);
+    assert_eq!(storage.read().unwrap().size(&String::from("MILAN")), 0);
+}
+
+#[test]
+fn test_oversized_body_is_rejected() {
+    // given
+    let storage = Arc::new(RwLock::new(storage::Storage::new()));
+    let mut config = config::Config::default();
+    config.server.max_body_size = 16;
+    let dispatcher = create_dispatcher(storage.clone(), Arc::new(metrics::Metrics::new()), &config);
+    let json = r###"{"type_name":"SLAP","for_city":"BERLIN","message_text":null}"###;
+
+    // when
+    let mut announced = build_request_for_notification_creation(String::from(json));
+    announced.headers_mut().insert(hyper::header::CONTENT_LENGTH, hyper::header::HeaderValue::from(json.len()));
+
+    let (mut sender, body) = Body::channel();
+    let streamed = Request::builder()
+        .uri("https://auto1.danila.app/rest-api/notifications")
+        .method(Method::POST)
+        .body(body)
+        .unwrap();
+    std::thread::spawn(move || {
+        for chunk in json.as_bytes().chunks(8) {
+            if future::poll_fn(|| sender.poll_ready()).wait().is_err() {
+                return;
+            }
+            let _ = sender.send_data(hyper::Chunk::from(chunk.to_vec()));
+        }
+    });
+
+    // then
+    let response = dispatcher.dispatch(announced).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+    let response = dispatcher.dispatch(streamed).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+    assert_eq!(storage.read().unwrap().size(&String::from("BERLIN")), 0);
+}
+
+#[test]
+fn test_invalid_utf8_body_is_bad_request() {
+    // given
+    let storage = Arc::new(RwLock::new(storage::Storage::new()));
+    let dispatcher = create_dispatcher(storage.clone(), Arc::new(metrics::Metrics::new()), &config::Config::default());
+
+    // when
+    let rest_req = Request::builder()
+        .uri("https://auto1.danila.app/rest-api/notifications")
+        .method(Method::POST)
+        .body(Body::from(vec![0x7b, 0xff, 0xfe, 0x7d]))
+        .unwrap();
+    let alexa_req = Request::builder()
+        .uri("https://auto1.danila.app/alexa-skill")
+        .method(Method::POST)
+        .body(Body::from(vec![0x7b, 0xff, 0xfe, 0x7d]))
+        .unwrap();
+
+    // then
+    let response = dispatcher.dispatch(rest_req).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = dispatcher.dispatch(alexa_req).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[test]
+fn test_cors_for_browser_clients() {
+    // given
+    let storage = Arc::new(RwLock::new(storage::Storage::new()));
+    let mut config = config::Config::default();
+    config.cors.allowed_origins = vec![String::from("https://slap.danila.app")];
+    let pipeline = create_pipeline(storage.clone(), Arc::new(metrics::Metrics::new()), &config);
+
+    // when
+    let preflight = Request::builder()
+        .method(Method::OPTIONS)
+        .uri("https://auto1.danila.app/rest-api/notifications")
+        .header(ORIGIN, "https://slap.danila.app")
+        .header(ACCESS_CONTROL_REQUEST_METHOD, "POST")
+        .body(Body::empty())
+        .unwrap();
+    let mut status_req = build_request_for_get_notifications(String::from("BERLIN"));
+    status_req.headers_mut().insert(ORIGIN, HeaderValue::from_static("https://slap.danila.app"));
+
+    // then
+    let response = pipeline.handle(preflight).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(response.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], "https://slap.danila.app");
+
+    let response = pipeline.handle(status_req).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], "https://slap.danila.app");
+    assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+}
+
+#[test]
+fn test_rest_api_negotiates_json_only() {
+    // given
+    let storage = Arc::new(RwLock::new(storage::Storage::new()));
+    let dispatcher = create_dispatcher(storage.clone(), Arc::new(metrics::Metrics::new()), &config::Config::default());
+
+    // when
+    let mut html_req = build_request_for_get_notifications(String::from("BERLIN"));
+    html_req.headers_mut().insert(ACCEPT, HeaderValue::from_static("text/html"));
+    let mut browser_req = build_request_for_get_notifications(String::from("BERLIN"));
+    browser_req.headers_mut().insert(ACCEPT, HeaderValue::from_static("text/html, application/json;q=0.9"));
+
+    // then
+    let response = dispatcher.dispatch(html_req).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_ACCEPTABLE);
+
+    let response = dispatcher.dispatch(browser_req).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[test]
+fn test_api_key_scopes_limit_target_cities() {
+    // given
+    let storage = Arc::new(RwLock::new(storage::Storage::new()));
+    let config = config::Config::from_json(r###"{"auth":{"api_keys":[
+        {"id":"berlin-bot","key":"s3cr3t","cities":["BERLIN"]}
+    ]}}"###).unwrap();
+    let pipeline = create_pipeline(storage.clone(), Arc::new(metrics::Metrics::new()), &config);
+
+    // when
+    let anonymous_req = build_request_for_slap_notification_creation(String::from("BERLIN"));
+    let mut forbidden_req = build_request_for_slap_notification_creation(String::from("KIEV"));
+    forbidden_req.headers_mut().insert(AUTHORIZATION, HeaderValue::from_static("Bearer s3cr3t"));
+    let mut allowed_req = build_request_for_slap_notification_creation(String::from("BERLIN"));
+    allowed_req.headers_mut().insert(AUTHORIZATION, HeaderValue::from_static("Bearer s3cr3t"));
+
+    // then
+    let response = pipeline.handle(anonymous_req).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = pipeline.handle(forbidden_req).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(storage.read().unwrap().size(&String::from("KIEV")), 0);
+
+    let response = pipeline.handle(allowed_req).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let event = storage.write().unwrap().pop_event(&String::from("BERLIN"), metrics::CHANNEL_REST).unwrap();
+    assert_eq!(event.sender, Some(String::from("berlin-bot")));
+}
+
+#[test]
+fn test_rate_limits_per_city_and_per_client() {
+    // given
+    let storage = Arc::new(RwLock::new(storage::Storage::new()));
+    let config = config::Config::from_json(r###"{"rate_limit":{
+        "per_client":{"capacity":3,"refill_per_minute":1},
+        "per_city":{"capacity":1,"refill_per_minute":1}
+    }}"###).unwrap();
+    let pipeline = create_pipeline(storage.clone(), Arc::new(metrics::Metrics::new()), &config);
+
+    // when
+    let first_for_berlin = pipeline.handle(build_request_for_slap_notification_creation(String::from("BERLIN"))).wait().unwrap();
+    let second_for_berlin = pipeline.handle(build_request_for_slap_notification_creation(String::from("BERLIN"))).wait().unwrap();
+    let first_for_kiev = pipeline.handle(build_request_for_slap_notification_creation(String::from("KIEV"))).wait().unwrap();
+    let over_client_limit = pipeline.handle(build_request_for_slap_notification_creation(String::from("MILAN"))).wait().unwrap();
+
+    // then
+    assert_eq!(first_for_berlin.status(), StatusCode::CREATED);
+    assert_eq!(second_for_berlin.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(second_for_berlin.headers()[RETRY_AFTER], "60");
+    assert_eq!(first_for_kiev.status(), StatusCode::CREATED);
+    assert_eq!(over_client_limit.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    assert_eq!(storage.read().unwrap().size(&String::from("BERLIN")), 1);
+    assert_eq!(storage.read().unwrap().size(&String::from("MILAN")), 0);
+}
+
+#[test]
+fn test_request_id_is_accepted_or_generated_and_returned() {
+    // given
+    let storage = Arc::new(RwLock::new(storage::Storage::new()));
+    let config = config::Config::from_json(r###"{"auth":{"api_keys":[
+        {"id":"berlin-bot","key":"s3cr3t","cities":["BERLIN"]}
+    ]}}"###).unwrap();
+    let pipeline = create_pipeline(storage.clone(), Arc::new(metrics::Metrics::new()), &config);
+    let dispatcher = create_dispatcher(storage, Arc::new(metrics::Metrics::new()), &config);
+    let request_id = api::dispatcher::REQUEST_ID_HEADER;
+
+    // when
+    let mut traced_req = build_request_for_slap_notification_creation(String::from("BERLIN"));
+    traced_req.headers_mut().insert(request_id, HeaderValue::from_static("trace-42"));
+    let mut forged_req = build_request_for_slap_notification_creation(String::from("BERLIN"));
+    forged_req.headers_mut().insert(request_id, HeaderValue::from_static("x\" status=200"));
+    let mut alexa_req = build_request_for_skill_api(String::from(DELIVER_NOTIFICATION_BODY));
+    alexa_req.headers_mut().insert(request_id, HeaderValue::from_static("alexa-7"));
+
+    // then the caller's id comes back even when auth rejects the request
+    let response = pipeline.handle(traced_req).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(response.headers()[request_id], "trace-42");
+
+    let response = pipeline.handle(forged_req).wait().unwrap();
+    let generated = response.headers()[request_id].to_str().unwrap();
+    assert_ne!(generated, "x\" status=200");
+    assert!(!generated.is_empty());
+
+    let response = dispatcher.dispatch(alexa_req).wait().unwrap();
+    assert_eq!(response.headers()[request_id], "alexa-7");
+
+    let response = dispatcher.dispatch(Request::get("https://auto1.danila.app/health/live").body(Body::empty()).unwrap()).wait().unwrap();
+    assert!(response.headers().contains_key(request_id));
+}
+
+#[test]
+fn test_full_queue_outcome_is_reported() {
+    // given
+    let rejecting = storage::Storage::new().with_queue_limit(Some(1), storage::OverflowPolicy::Reject);
+    let rejecting = Arc::new(RwLock::new(rejecting));
+    let dropping = storage::Storage::new().with_queue_limit(Some(1), storage::OverflowPolicy::DropOldest);
+    let dropping = Arc::new(RwLock::new(dropping));
+    let rejecting_dispatcher = create_dispatcher(rejecting.clone(), Arc::new(metrics::Metrics::new()), &config::Config::default());
+    let dropping_dispatcher = create_dispatcher(dropping.clone(), Arc::new(metrics::Metrics::new()), &config::Config::default());
+
+    // when
+    for dispatcher in [&rejecting_dispatcher, &dropping_dispatcher].iter() {
+        let req = build_request_for_message_notification_creation(String::from("BERLIN"), String::from("first"));
+        assert_eq!(dispatcher.dispatch(req).wait().unwrap().status(), StatusCode::CREATED);
+    }
+
+    let rejected = rejecting_dispatcher.dispatch(build_request_for_slap_notification_creation(String::from("BERLIN"))).wait().unwrap();
+    let dropped = dropping_dispatcher.dispatch(build_request_for_slap_notification_creation(String::from("BERLIN"))).wait().unwrap();
+
+    // then
+    assert_eq!(rejected.status(), StatusCode::INSUFFICIENT_STORAGE);
+    assert_eq!(dropped.status(), StatusCode::CREATED);
+    assert_eq!(dropped.headers()[api::utils::QUEUE_OVERFLOW_HEADER], "dropped_oldest");
+
+    let remaining = dropping.write().unwrap().pop_event(&String::from("BERLIN"), metrics::CHANNEL_REST).unwrap();
+    assert_eq!(remaining.event_type, storage::EventType::SLAP);
+}
+
+#[test]
+fn test_probes_skip_auth_and_report_storage_state() {
+    // given
+    let storage = Arc::new(RwLock::new(storage::Storage::new()));
+    let config = config::Config::from_json(r###"{"auth":{"api_keys":[
+        {"id":"berlin-bot","key":"s3cr3t","cities":["BERLIN"]}
+    ]}}"###).unwrap();
+    let pipeline = create_pipeline(storage.clone(), Arc::new(metrics::Metrics::new()), &config);
+    let probe = |path: &str| Request::get(format!("https://auto1.danila.app{}", path)).body(Body::empty()).unwrap();
+
+    // then
+    let response = pipeline.handle(probe(api::dispatcher::LIVENESS_PATH)).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = pipeline.handle(probe(api::dispatcher::READINESS_PATH)).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = pipeline.handle(probe(api::dispatcher::BUILD_INFO_PATH)).wait().unwrap();
+    let build_info: api::health::dto::BuildInfo = serde_json::from_str(&consume_body(response)).unwrap();
+    assert_eq!(build_info.version, env!("CARGO_PKG_VERSION"));
+    assert!(build_info.features.contains(&String::from("api_keys")));
+
+    // when a writer panics while holding the lock
+    let poisoning_storage = storage.clone();
+    let _ = std::thread::spawn(move || {
+        let _guard = poisoning_storage.write().unwrap();
+        panic!("poison the storage lock");
+    }).join();
+
+    // then
+    let response = pipeline.handle(probe(api::dispatcher::LIVENESS_PATH)).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = pipeline.handle(probe(api::dispatcher::READINESS_PATH)).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let health: api::health::dto::HealthResponse = serde_json::from_str(&consume_body(response)).unwrap();
+    assert_eq!(health.checks["storage"], "storage lock is poisoned");
+
+    let mut create_req = build_request_for_slap_notification_creation(String::from("BERLIN"));
+    create_req.headers_mut().insert(AUTHORIZATION, HeaderValue::from_static("Bearer s3cr3t"));
+    let response = pipeline.handle(create_req).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    let response = pipeline.handle(build_request_for_skill_api(String::from(DELIVER_NOTIFICATION_BODY))).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[test]
+fn test_metrics_count_requests_notifications_and_intents() {
+    // given
+    let storage = Arc::new(RwLock::new(storage::Storage::new()));
+    let pipeline = create_pipeline(storage, Arc::new(metrics::Metrics::new()), &config::Config::default());
+
+    // when
+    let response = pipeline.handle(build_request_for_slap_notification_creation(String::from("BERLIN"))).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let response = pipeline.handle(build_request_for_message_notification_creation(String::from("KIEV"), String::from("lunch"))).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    pipeline.handle(build_request_for_skill_api(String::from(DELIVER_NOTIFICATION_BODY))).wait().unwrap();
+    let made_up_intent = DELIVER_NOTIFICATION_BODY.replace(r#""name":"deliver_notification""#, r#""name":"made_up_intent""#);
+    pipeline.handle(build_request_for_skill_api(made_up_intent)).wait().unwrap();
+
+    let response = pipeline.handle(Request::get("https://auto1.danila.app/metrics").body(Body::empty()).unwrap()).wait().unwrap();
 
+    // then
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[CONTENT_TYPE], api::utils::METRICS_CONTENT_TYPE);
+    let text = consume_body(response);
+    assert!(text.contains("http_requests_total{route=\"/rest-api/notifications\",method=\"POST\",status=\"201\"} 2\n"));
+    assert!(text.contains("http_request_duration_seconds_count{route=\"/alexa-skill\",status=\"200\"} 1\n"));
+    assert!(text.contains("notifications_created_total{type=\"SLAP\",channel=\"rest\"} 1\n"));
+    assert!(text.contains("notifications_created_total{type=\"MESSAGE\",channel=\"rest\"} 1\n"));
+    assert!(text.contains("notifications_delivered_total{type=\"SLAP\",channel=\"alexa\"} 1\n"));
+    assert!(text.contains("alexa_intents_total{intent=\"deliver_notification\"} 1\n"));
+    assert!(text.contains("alexa_intents_total{intent=\"other\"} 1\n"));
+    assert!(!text.contains("made_up_intent"));
+    assert!(text.contains("notification_queue_size{device=\"BERLIN\"} 0\n"));
+    assert!(text.contains("notification_queue_size{device=\"KIEV\"} 1\n"));
+}
 
-let raw_body = r###"{"version":"1.0","session":{"new":true,"sessionId":"amzn1.echo-api.session.cc4447e1-2363-4067-a557-8c5c8a04f4e5","application":{"applicationId":"amzn1.ask.skill.9f4ef1dd-cee9-40e5-b01d-30b9f4ecce7f"},"user":{"userId":"amzn1.ask.account.AGWKPG3JM4Z364AYKKSAGHKL6CYWMJKOAZGXG5CPXYX2Y7UKWZTH6XELFWPICBCWZP7OF5VEBSQTQ4UMCVE7EVRWN2PUKBLMJGU3GD22HZSRVU6TTDMUN2PJ5M7TWKAQOT7VBFKZJLBICK3WVIXOGDF7YHXTWWWKC75D2ONSL4JOLRUFFY2JKEAP5U44TCLJJBQDDFJMFGUG5WY"}},"context":{"System":{"application":{"applicationId":"amzn1.ask.skill.9f4ef1dd-cee9-40e5-b01d-30b9f4ecce7f"},"user":{"userId":"amzn1.ask.account.AGWKPG3JM4Z364AYKKSAGHKL6CYWMJKOAZGXG5CPXYX2Y7UKWZTH6XELFWPICBCWZP7OF5VEBSQTQ4UMCVE7EVRWN2PUKBLMJGU3GD22HZSRVU6TTDMUN2PJ5M7TWKAQOT7VBFKZJLBICK3WVIXOGDF7YHXTWWWKC75D2ONSL4JOLRUFFY2JKEAP5U44TCLJJBQDDFJMFGUG5WY"},"device":{"deviceId":"amzn1.ask.device.AFBBPRUJRVKP4BAHNQW4BS6FJZP32LOYQO2AYRVRMCKP7D3U5BHCS35VMMAPWMZEHJMDZTQJ5Z7EMJDRWXCADDHYR4OOCL7BTJ44MIZB2EFMCE2WM7DZ4QJDFMVNKAIXQ7OPW6UJDJGCJBKSE2IUOIPRJASFASF7CYBLYIMA725YQFMRGJPBO","supportedInterfaces":{}},"apiEndpoint":"https://api.amazonalexa.com","apiAccessToken":"eyJ0eXAiOiJKV1QiLCJhbGciOiJSUzI1NiIsImtpZCI6IjEifQ.eyJhdWQiOiJodHRwczovL2FwaS5hbWF6b25hbGV4YS5jb20iLCJpc3MiOiJBbGV4YVNraWxsS2l0Iiwic3ViIjoiYW16bjEuYXNrLnNraWxsLjlmNGVmMWRkLWNlZTktNDBlNS1iMDFkLTMwYjlmNGVjY2U3ZiIsImV4cCI6MTUzODA0Njc3NCwiaWF0IjoxNTM4MDQzMTc0LCJuYmYiOjE1MzgwNDMxNzQsInByaXZhdGVDbGFpbXMiOnsiY29uc2VudFRva2VuIjpudWxsLCJkZXZpY2VJZCI6ImFtem4xLmFzay5kZXZpY2UuQUZCQlBSVUpSVktQNEJBSE5RVzRCUzZGSlpQMzJMT1lRTzJBWVJWUk1DS1A3RDNVNUJIQ1MzNVZNTUFQV01aRUhKTURaVFFKNVo3RU1KRFJXWENBRERIWVI0T09DTDdCVEo0NE1JWkIyRUZNQ0UyV003RFo0UUpERk1WTktBSVhRN09QVzZVSkRKR0NKQktTRTJJVU9JUFJKQVNGQVNGN0NZQkxZSU1BNzI1WVFGTVJHSlBCTyIsInVzZXJJZCI6ImFtem4xLmFzay5hY2NvdW50LkFHV0tQRzNKTTRaMzY0QVlLS1NBR0hLTDZDWVdNSktPQVpHWEc1Q1BYWVgyWTdVS1daVEg2WEVMRldQSUNCQ1daUDdPRjVWRUJTUVRRNFVNQ1ZFN0VWUldOMlBVS0JMTUpHVTNHRDIySFpTUlZVNlRURE1VTjJQSjVNN1RXS0FRT1Q3VkJGS1pKTEJJQ0szV1ZJWE9HREY3WUhYVFdXV0tDNzVEMk9OU0w0Sk9MUlVGRlkySktFQVA1VTQ0VENMSkpCUURERkpNRkdVRzVXWSJ9fQ.Atpu3ZcEb3T96hJ80Bv8crmbqNdMn_gHAwd8IpD_6HfblYxlEqSSulnfBpKfX4rY2t4Xup4b_XITTYYEty-sKn0cWACOzh0q3LXo2TkA-mXLjr2Px5w6C-9EHxXlW5k8Wjeg1li2A-zAD-0YAFmNRxiSwQFtKOX7r5kgC8GUJluJPoAjYHje4YsC3n6-Vgv0hpx6-x5OFIXY1RDuIFyOEY69GtE57vDlTgSclTSQ-xovddOYinAkcKPBV7c-hOzq4hjWlduGt7J2MPuA1Gjwv0G_skFfpPymsokI2pGZylTOWoilfonu-QU768vvNUwtgwZAapoyeZkUlaySfwtxuA"}},"request":{"type":"IntentRequest","requestId":"amzn1.echo-api.request.e4cc1710-ee0c-4c13-83c6-22ebe882d64c","timestamp":"2018-09-27T10:12:54Z","locale":"en-US","intent":{"name":"deliver_notification","confirmationStatus":"NONE","slots":{"city":{"name":"city","value":"Berlin","resolutions":{"resolutionsPerAuthority":[{"authority":"amzn1.er-authority.echo-sdk.amzn1.ask.skill.9f4ef1dd-cee9-40e5-b01d-30b9f4ecce7f.city","status":{"code":"ER_SUCCESS_MATCH"},"values":[{"value":{"name":"BERLIN","id":"0"}}]}]},"confirmationStatus":"NONE"}}}}}"###;
+#[test]
+fn test_next_notification_is_dequeued_or_waited_for() {
+    // given
+    let storage = Arc::new(RwLock::new(storage::Storage::new()));
+    let dispatcher = create_dispatcher(storage.clone(), Arc::new(metrics::Metrics::new()), &config::Config::default());
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let req = build_request_for_message_notification_creation(String::from("BERLIN"), String::from("lunch is here"));
+    assert_eq!(dispatcher.dispatch(req).wait().unwrap().status(), StatusCode::CREATED);
 
-    let raw_body_from_city = raw_body.replace("city_example", &city);
+    // when
+    let response = runtime.block_on(dispatcher.dispatch(build_request_for_next_notification("BERLIN", ""))).unwrap();
+
+    // then
+    assert_eq!(response.status(), StatusCode::OK);
+    let next: api::rest::dto::NextNotificationResponse = serde_json::from_str(&consume_body(response)).unwrap();
+    assert_eq!((next.type_name.as_str(), next.message_text), ("MESSAGE", Some(String::from("lunch is here"))));
+    assert_eq!(runtime.block_on(dispatcher.dispatch(build_request_for_next_notification("BERLIN", ""))).unwrap().status(), StatusCode::NO_CONTENT);
+    assert_eq!(runtime.block_on(dispatcher.dispatch(build_request_for_next_notification("PARIS", ""))).unwrap().status(), StatusCode::BAD_REQUEST);
+    assert_eq!(runtime.block_on(dispatcher.dispatch(build_request_for_next_notification("BERLIN", "?wait=soon"))).unwrap().status(), StatusCode::BAD_REQUEST);
+
+    // when
+    let started = std::time::Instant::now();
+    let adding_storage = storage.clone();
+    let adding = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        adding_storage.write().unwrap().add_event(storage::Event::new_slap(), String::from("BERLIN"));
+    });
+    let response = runtime.block_on(dispatcher.dispatch(build_request_for_next_notification("BERLIN", "?wait=5"))).unwrap();
+    adding.join().unwrap();
+
+    // then
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    assert_eq!(storage.read().unwrap().size(&String::from("BERLIN")), 0);
+
+    let started = std::time::Instant::now();
+    let response = runtime.block_on(dispatcher.dispatch(build_request_for_next_notification("BERLIN", "?wait=1"))).unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert!(started.elapsed() >= std::time::Duration::from_secs(1));
+}
+
+#[test]
+fn test_next_notification_is_limited_to_the_cities_of_the_api_key() {
+    // given
+    let storage = Arc::new(RwLock::new(storage::Storage::new()));
+    let config = config::Config::from_json(r###"{"auth":{"api_keys":[
+        {"id":"berlin-bot","key":"s3cr3t","cities":["BERLIN"],"can_read_status":true}
+    ]}}"###).unwrap();
+    let pipeline = create_pipeline(storage.clone(), Arc::new(metrics::Metrics::new()), &config);
+    storage.write().unwrap().add_event(storage::Event::new_slap(), String::from("KIEV"));
+    let with_key = |city: &str| {
+        let mut req = build_request_for_next_notification(city, "");
+        req.headers_mut().insert(AUTHORIZATION, HeaderValue::from_static("Bearer s3cr3t"));
+        req
+    };
+
+    // when
+    let response = pipeline.handle(with_key("KIEV")).wait().unwrap();
+
+    // then
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(storage.read().unwrap().size(&String::from("KIEV")), 1);
+    assert_eq!(pipeline.handle(with_key("BERLIN")).wait().unwrap().status(), StatusCode::NO_CONTENT);
+}
+
+#[test]
+fn test_signed_slash_commands_create_notifications() {
+    // given
+    let storage = Arc::new(RwLock::new(storage::Storage::new()));
+    let config = config::Config::from_json(r###"{"slack":{"signing_secret":"s3cr3t"},"auth":{"api_keys":[{"id":"bot","key":"k"}]}}"###).unwrap();
+    let pipeline = create_pipeline(storage.clone(), Arc::new(metrics::Metrics::new()), &config);
+
+    // when
+    let slap = pipeline.handle(build_request_for_slash_command("s3cr3t", "command=%2Fslap&text=berlin&user_name=anna")).wait().unwrap();
+    let message = pipeline.handle(build_request_for_slash_command("s3cr3t", "command=%2Fmsg&text=kiev+lunch+is+here&user_name=anna")).wait().unwrap();
+    let usage = pipeline.handle(build_request_for_slash_command("s3cr3t", "command=%2Fmsg&text=kiev&user_name=anna")).wait().unwrap();
+    let unknown_city = pipeline.handle(build_request_for_slash_command("s3cr3t", "command=%2Fslap&text=paris&user_name=anna")).wait().unwrap();
+    let forged = pipeline.handle(build_request_for_slash_command("guessed", "command=%2Fslap&text=milan&user_name=mallory")).wait().unwrap();
+    let mut replayed = build_request_for_slash_command("s3cr3t", "command=%2Fslap&text=milan&user_name=mallory");
+    let stale = (std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() - 3600).to_string();
+    let stale_signature = api::slack::controller::sign("s3cr3t", &stale, "command=%2Fslap&text=milan&user_name=mallory");
+    replayed.headers_mut().insert(api::slack::controller::TIMESTAMP_HEADER, HeaderValue::from_str(&stale).unwrap());
+    replayed.headers_mut().insert(api::slack::controller::SIGNATURE_HEADER, HeaderValue::from_str(&stale_signature).unwrap());
+    let replayed = pipeline.handle(replayed).wait().unwrap();
+    let mut unsigned = build_request_for_slash_command("s3cr3t", "command=%2Fslap&text=milan&user_name=mallory");
+    unsigned.headers_mut().remove(api::slack::controller::SIGNATURE_HEADER);
+    let unsigned = pipeline.handle(unsigned).wait().unwrap();
+
+    // then
+    assert_eq!(slap.status(), StatusCode::OK);
+    let reply: api::slack::dto::SlashCommandReply = serde_json::from_str(&consume_body(slap)).unwrap();
+    assert_eq!((reply.response_type.as_str(), reply.text.as_str()), ("ephemeral", "Slapped BERLIN."));
+    let reply: api::slack::dto::SlashCommandReply = serde_json::from_str(&consume_body(message)).unwrap();
+    assert_eq!(reply.text, "Sent your message to KIEV.");
+    let reply: api::slack::dto::SlashCommandReply = serde_json::from_str(&consume_body(usage)).unwrap();
+    assert_eq!(reply.text, "Usage: /msg <city> <message>");
+    let reply: api::slack::dto::SlashCommandReply = serde_json::from_str(&consume_body(unknown_city)).unwrap();
+    assert!(reply.text.starts_with("The city PARIS is not supported."));
+    assert_eq!(forged.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(replayed.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(unsigned.status(), StatusCode::UNAUTHORIZED);
+
+    let event = storage.write().unwrap().pop_event(&String::from("KIEV"), metrics::CHANNEL_REST).unwrap();
+    assert_eq!((event.message, event.sender), (Some(String::from("lunch is here")), Some(String::from("anna"))));
+    assert_eq!(storage.read().unwrap().size(&String::from("BERLIN")), 1);
+    assert_eq!(storage.read().unwrap().size(&String::from("MILAN")), 0);
+
+    // when no signing secret is configured
+    let pipeline = create_pipeline(storage.clone(), Arc::new(metrics::Metrics::new()), &config::Config::default());
+    let response = pipeline.handle(build_request_for_slash_command("s3cr3t", "command=%2Fslap&text=berlin")).wait().unwrap();
+
+    // then
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn test_deliveries_follow_the_alexa_skill_taking_notifications() {
+    // given
+    let storage = Arc::new(RwLock::new(storage::Storage::new()));
+    let dispatcher = create_dispatcher(storage.clone(), Arc::new(metrics::Metrics::new()), &config::Config::default());
+    create_notification_for("BERLIN", &dispatcher);
+    let req = build_request_for_message_notification_creation(String::from("BERLIN"), String::from("lunch is here"));
+    assert_eq!(dispatcher.dispatch(req).wait().unwrap().status(), StatusCode::CREATED);
+
+    // when
+    deliver_notification_for("BERLIN", &dispatcher);
+    let response = dispatcher.dispatch(build_request_for_deliveries("BERLIN")).wait().unwrap();
+
+    // then
+    assert_eq!(response.status(), StatusCode::OK);
+    let deliveries: api::rest::dto::DeliveriesResponse = serde_json::from_str(&consume_body(response)).unwrap();
+    let statuses: Vec<(&str, &str)> = deliveries.deliveries.iter()
+        .map(|entry| (entry.type_name.as_str(), entry.channels["queue"].status.as_str()))
+        .collect();
+    assert_eq!(statuses, vec![("SLAP", "delivered"), ("MESSAGE", "pending")]);
+    assert_eq!(dispatcher.dispatch(build_request_for_deliveries("PARIS")).wait().unwrap().status(), StatusCode::BAD_REQUEST);
+}
+
+#[cfg(test)]
+const DELIVER_NOTIFICATION_BODY: &str = r###"{"version":"1.0","session":{"new":true,"sessionId":"amzn1.echo-api.session.cc4447e1-2363-4067-a557-8c5c8a04f4e5","application":{"applicationId":"amzn1.ask.skill.9f4ef1dd-cee9-40e5-b01d-30b9f4ecce7f"},"user":{"userId":"amzn1.ask.account.AGWKPG3JM4Z364AYKKSAGHKL6CYWMJKOAZGXG5CPXYX2Y7UKWZTH6XELFWPICBCWZP7OF5VEBSQTQ4UMCVE7EVRWN2PUKBLMJGU3GD22HZSRVU6TTDMUN2PJ5M7TWKAQOT7VBFKZJLBICK3WVIXOGDF7YHXTWWWKC75D2ONSL4JOLRUFFY2JKEAP5U44TCLJJBQDDFJMFGUG5WY"}},"context":{"System":{"application":{"applicationId":"amzn1.ask.skill.9f4ef1dd-cee9-40e5-b01d-30b9f4ecce7f"},"user":{"userId":"amzn1.ask.account.AGWKPG3JM4Z364AYKKSAGHKL6CYWMJKOAZGXG5CPXYX2Y7UKWZTH6XELFWPICBCWZP7OF5VEBSQTQ4UMCVE7EVRWN2PUKBLMJGU3GD22HZSRVU6TTDMUN2PJ5M7TWKAQOT7VBFKZJLBICK3WVIXOGDF7YHXTWWWKC75D2ONSL4JOLRUFFY2JKEAP5U44TCLJJBQDDFJMFGUG5WY"},"device":{"deviceId":"amzn1.ask.device.AFBBPRUJRVKP4BAHNQW4BS6FJZP32LOYQO2AYRVRMCKP7D3U5BHCS35VMMAPWMZEHJMDZTQJ5Z7EMJDRWXCADDHYR4OOCL7BTJ44MIZB2EFMCE2WM7DZ4QJDFMVNKAIXQ7OPW6UJDJGCJBKSE2IUOIPRJASFASF7CYBLYIMA725YQFMRGJPBO","supportedInterfaces":{}},"apiEndpoint":"https://api.amazonalexa.com","apiAccessToken":"eyJ0eXAiOiJKV1QiLCJhbGciOiJSUzI1NiIsImtpZCI6IjEifQ.eyJhdWQiOiJodHRwczovL2FwaS5hbWF6b25hbGV4YS5jb20iLCJpc3MiOiJBbGV4YVNraWxsS2l0Iiwic3ViIjoiYW16bjEuYXNrLnNraWxsLjlmNGVmMWRkLWNlZTktNDBlNS1iMDFkLTMwYjlmNGVjY2U3ZiIsImV4cCI6MTUzODA0Njc3NCwiaWF0IjoxNTM4MDQzMTc0LCJuYmYiOjE1MzgwNDMxNzQsInByaXZhdGVDbGFpbXMiOnsiY29uc2VudFRva2VuIjpudWxsLCJkZXZpY2VJZCI6ImFtem4xLmFzay5kZXZpY2UuQUZCQlBSVUpSVktQNEJBSE5RVzRCUzZGSlpQMzJMT1lRTzJBWVJWUk1DS1A3RDNVNUJIQ1MzNVZNTUFQV01aRUhKTURaVFFKNVo3RU1KRFJXWENBRERIWVI0T09DTDdCVEo0NE1JWkIyRUZNQ0UyV003RFo0UUpERk1WTktBSVhRN09QVzZVSkRKR0NKQktTRTJJVU9JUFJKQVNGQVNGN0NZQkxZSU1BNzI1WVFGTVJHSlBCTyIsInVzZXJJZCI6ImFtem4xLmFzay5hY2NvdW50LkFHV0tQRzNKTTRaMzY0QVlLS1NBR0hLTDZDWVdNSktPQVpHWEc1Q1BYWVgyWTdVS1daVEg2WEVMRldQSUNCQ1daUDdPRjVWRUJTUVRRNFVNQ1ZFN0VWUldOMlBVS0JMTUpHVTNHRDIySFpTUlZVNlRURE1VTjJQSjVNN1RXS0FRT1Q3VkJGS1pKTEJJQ0szV1ZJWE9HREY3WUhYVFdXV0tDNzVEMk9OU0w0Sk9MUlVGRlkySktFQVA1VTQ0VENMSkpCUURERkpNRkdVRzVXWSJ9fQ.Atpu3ZcEb3T96hJ80Bv8crmbqNdMn_gHAwd8IpD_6HfblYxlEqSSulnfBpKfX4rY2t4Xup4b_XITTYYEty-sKn0cWACOzh0q3LXo2TkA-mXLjr2Px5w6C-9EHxXlW5k8Wjeg1li2A-zAD-0YAFmNRxiSwQFtKOX7r5kgC8GUJluJPoAjYHje4YsC3n6-Vgv0hpx6-x5OFIXY1RDuIFyOEY69GtE57vDlTgSclTSQ-xovddOYinAkcKPBV7c-hOzq4hjWlduGt7J2MPuA1Gjwv0G_skFfpPymsokI2pGZylTOWoilfonu-QU768vvNUwtgwZAapoyeZkUlaySfwtxuA"}},"request":{"type":"IntentRequest","requestId":"amzn1.echo-api.request.e4cc1710-ee0c-4c13-83c6-22ebe882d64c","timestamp":"2018-09-27T10:12:54Z","locale":"en-US","intent":{"name":"deliver_notification","confirmationStatus":"NONE","slots":{"city":{"name":"city","value":"Berlin","resolutions":{"resolutionsPerAuthority":[{"authority":"amzn1.er-authority.echo-sdk.amzn1.ask.skill.9f4ef1dd-cee9-40e5-b01d-30b9f4ecce7f.city","status":{"code":"ER_SUCCESS_MATCH"},"values":[{"value":{"name":"BERLIN","id":"0"}}]}]},"confirmationStatus":"NONE"}}}}}"###;
+
+#[cfg(test)]
+fn deliver_notification_for(city: &str, dispatcher: &api::dispatcher::Dispatcher) {
+    let raw_body_from_city = DELIVER_NOTIFICATION_BODY.replace("city_example", city);
 
     let req = build_request_for_skill_api(raw_body_from_city);
 
@@ -170,11 +851,12 @@ let raw_body = r###"{"version":"1.0","session":{"new":true,"sessionId":"amzn1.ec
 
 }
 
-fn create_notification_for(city: &String, dispatcher: &api::dispatcher::Dispatcher) {
+#[cfg(test)]
+fn create_notification_for(city: &str, dispatcher: &api::dispatcher::Dispatcher) {
 
     let raw_body = r###"{"version":"1.0","session":{"new":true,"sessionId":"amzn1.echo-api.session.c9add14f-1b3d-40ad-a7e3-f2452e3c2f47","application":{"applicationId":"amzn1.ask.skill.9f4ef1dd-cee9-40e5-b01d-30b9f4ecce7f"},"user":{"userId":"amzn1.ask.account.AGWKPG3JM4Z364AYKKSAGHKL6CYWMJKOAZGXG5CPXYX2Y7UKWZTH6XELFWPICBCWZP7OF5VEBSQTQ4UMCVE7EVRWN2PUKBLMJGU3GD22HZSRVU6TTDMUN2PJ5M7TWKAQOT7VBFKZJLBICK3WVIXOGDF7YHXTWWWKC75D2ONSL4JOLRUFFY2JKEAP5U44TCLJJBQDDFJMFGUG5WY"}},"context":{"System":{"application":{"applicationId":"amzn1.ask.skill.9f4ef1dd-cee9-40e5-b01d-30b9f4ecce7f"},"user":{"userId":"amzn1.ask.account.AGWKPG3JM4Z364AYKKSAGHKL6CYWMJKOAZGXG5CPXYX2Y7UKWZTH6XELFWPICBCWZP7OF5VEBSQTQ4UMCVE7EVRWN2PUKBLMJGU3GD22HZSRVU6TTDMUN2PJ5M7TWKAQOT7VBFKZJLBICK3WVIXOGDF7YHXTWWWKC75D2ONSL4JOLRUFFY2JKEAP5U44TCLJJBQDDFJMFGUG5WY"},"device":{"deviceId":"amzn1.ask.device.AFBBPRUJRVKP4BAHNQW4BS6FJZP32LOYQO2AYRVRMCKP7D3U5BHCS35VMMAPWMZEHJMDZTQJ5Z7EMJDRWXCADDHYR4OOCL7BTJ44MIZB2EFMCE2WM7DZ4QJDFMVNKAIXQ7OPW6UJDJGCJBKSE2IUOIPRJASFASF7CYBLYIMA725YQFMRGJPBO","supportedInterfaces":{}},"apiEndpoint":"https://api.amazonalexa.com","apiAccessToken":"eyJ0eXAiOiJKV1QiLCJhbGciOiJSUzI1NiIsImtpZCI6IjEifQ.eyJhdWQiOiJodHRwczovL2FwaS5hbWF6b25hbGV4YS5jb20iLCJpc3MiOiJBbGV4YVNraWxsS2l0Iiwic3ViIjoiYW16bjEuYXNrLnNraWxsLjlmNGVmMWRkLWNlZTktNDBlNS1iMDFkLTMwYjlmNGVjY2U3ZiIsImV4cCI6MTUzODA0NjgzOCwiaWF0IjoxNTM4MDQzMjM4LCJuYmYiOjE1MzgwNDMyMzgsInByaXZhdGVDbGFpbXMiOnsiY29uc2VudFRva2VuIjpudWxsLCJkZXZpY2VJZCI6ImFtem4xLmFzay5kZXZpY2UuQUZCQlBSVUpSVktQNEJBSE5RVzRCUzZGSlpQMzJMT1lRTzJBWVJWUk1DS1A3RDNVNUJIQ1MzNVZNTUFQV01aRUhKTURaVFFKNVo3RU1KRFJXWENBRERIWVI0T09DTDdCVEo0NE1JWkIyRUZNQ0UyV003RFo0UUpERk1WTktBSVhRN09QVzZVSkRKR0NKQktTRTJJVU9JUFJKQVNGQVNGN0NZQkxZSU1BNzI1WVFGTVJHSlBCTyIsInVzZXJJZCI6ImFtem4xLmFzay5hY2NvdW50LkFHV0tQRzNKTTRaMzY0QVlLS1NBR0hLTDZDWVdNSktPQVpHWEc1Q1BYWVgyWTdVS1daVEg2WEVMRldQSUNCQ1daUDdPRjVWRUJTUVRRNFVNQ1ZFN0VWUldOMlBVS0JMTUpHVTNHRDIySFpTUlZVNlRURE1VTjJQSjVNN1RXS0FRT1Q3VkJGS1pKTEJJQ0szV1ZJWE9HREY3WUhYVFdXV0tDNzVEMk9OU0w0Sk9MUlVGRlkySktFQVA1VTQ0VENMSkpCUURERkpNRkdVRzVXWSJ9fQ.B5Y7wjEtxv6sH8lOaaf-jVps5yulE-EwpT84GESxd7WjPBfS7iJIjnmkmKatPpbfxRfwte_HerIW0sLKiJ2S9LJI_mg1_9t_iTiymW-ecacwHOjQeAKYRGXBhHfv41D1j_3gVouNe7cNUK8eckUDm5_o_1AjIaDLhqc9FJiNaphBYlJeyB2Mc_NjpKvFgtnS7yqcRiqESA_6imOZwHyVDS02Iq_3H2qvow9ZLfi09QTOjK3AVBkWtdif14ZD89d-jUuGVXZsvxCxB09sRoOkAQ--AZC1t2mm_AWxWsyLhfRinY6nJh4Y5RMfssBYZPfHD_HT8-aM8NsZ4p0r5SnGag"}},"request":{"type":"IntentRequest","requestId":"amzn1.echo-api.request.1fd8560b-185f-493e-b944-d2d860064e86","timestamp":"2018-09-27T10:13:58Z","locale":"en-US","intent":{"name":"create_slap_notification","confirmationStatus":"NONE","slots":{"city":{"name":"city","value":"Berlin","resolutions":{"resolutionsPerAuthority":[{"authority":"amzn1.er-authority.echo-sdk.amzn1.ask.skill.9f4ef1dd-cee9-40e5-b01d-30b9f4ecce7f.city","status":{"code":"ER_SUCCESS_MATCH"},"values":[{"value":{"name":"BERLIN","id":"0"}}]}]},"confirmationStatus":"NONE"}}}}}"###;
 
-    let raw_body_with_city = raw_body.replace("city_example", &city);
+    let raw_body_with_city = raw_body.replace("city_example", city);
 
     let req = build_request_for_skill_api(raw_body_with_city);
 
@@ -182,6 +864,7 @@ fn create_notification_for(city: &String, dispatcher: &api::dispatcher::Dispatch
 
 }
 
+#[cfg(test)]
 fn build_request_for_get_notifications(city: String) -> Request<Body> {
     Request::builder()
         .method(Method::GET)
@@ -190,6 +873,25 @@ fn build_request_for_get_notifications(city: String) -> Request<Body> {
         .unwrap()
 }
 
+#[cfg(test)]
+fn build_request_for_deliveries(city: &str) -> Request<Body> {
+    Request::builder()
+        .method(Method::GET)
+        .uri(format!("https://auto1.danila.app/rest-api/cities/{}/deliveries", city))
+        .body(Body::empty())
+        .unwrap()
+}
+
+#[cfg(test)]
+fn build_request_for_next_notification(city: &str, query: &str) -> Request<Body> {
+    Request::builder()
+        .method(Method::POST)
+        .uri(format!("https://auto1.danila.app/rest-api/cities/{}/notifications/next{}", city, query))
+        .body(Body::empty())
+        .unwrap()
+}
+
+#[cfg(test)]
 fn build_request_for_skill_api(body: String) -> Request<Body> {
     Request::builder()
         .uri("https://auto1.danila.app/alexa-skill")
@@ -197,10 +899,33 @@ fn build_request_for_skill_api(body: String) -> Request<Body> {
         .unwrap()
 }
 
+#[cfg(test)]
+fn build_request_for_slash_command(signing_secret: &str, form: &str) -> Request<Body> {
+    let timestamp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs().to_string();
+    Request::builder()
+        .uri("https://auto1.danila.app/slack/commands")
+        .method(Method::POST)
+        .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .header(api::slack::controller::TIMESTAMP_HEADER, timestamp.as_str())
+        .header(api::slack::controller::SIGNATURE_HEADER, api::slack::controller::sign(signing_secret, &timestamp, form).as_str())
+        .body(Body::from(String::from(form)))
+        .unwrap()
+}
+
+#[cfg(test)]
+fn build_request_for_notification_creation(json: String) -> Request<Body> {
+    Request::builder()
+        .uri("https://auto1.danila.app/rest-api/notifications")
+        .method(Method::POST)
+        .body(Body::from(json))
+        .unwrap()
+}
+
+#[cfg(test)]
 fn build_request_for_slap_notification_creation(for_city: String) -> Request<Body> {
     let request_obj = api::rest::dto::CreateNotificationReqeust {
         type_name: String::from("SLAP"),
-        for_city: for_city,
+        for_city: for_city.into(),
         message_text: None
     };
     let json = serde_json::to_string(&request_obj).unwrap();
@@ -212,10 +937,11 @@ fn build_request_for_slap_notification_creation(for_city: String) -> Request<Bod
         .unwrap()
 }
 
+#[cfg(test)]
 fn build_request_for_message_notification_creation(for_city: String, message: String) -> Request<Body> {
     let request_obj = api::rest::dto::CreateNotificationReqeust {
         type_name: String::from("MESSAGE"),
-        for_city: for_city,
+        for_city: for_city.into(),
         message_text: Some(message)
     };
     let json = serde_json::to_string(&request_obj).unwrap();
@@ -228,6 +954,7 @@ fn build_request_for_message_notification_creation(for_city: String, message: St
 }
 
 
+#[cfg(test)]
 fn consume_body(rsp: Response<Body>) -> String {
      let result = rsp.into_body()
             .fold(Vec::new(), |mut acc, chunk| {
@@ -0,0 +1,225 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::storage::{EventType, Storage};
+
+pub const CHANNEL_REST: &str = "rest";
+pub const CHANNEL_ALEXA: &str = "alexa";
+pub const CHANNEL_WEBSOCKET: &str = "websocket";
+pub const CHANNEL_SLACK: &str = "slack";
+pub const CHANNEL_MQTT: &str = "mqtt";
+pub const CHANNEL_MAIL: &str = "mail";
+pub const CHANNEL_WEBHOOKS: &str = "webhooks";
+pub const CHANNEL_CHAT: &str = "chat";
+pub const CHANNEL_PROACTIVE_EVENTS: &str = "proactive_events";
+
+const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+struct CounterVec {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, u64>>
+}
+
+impl CounterVec {
+    fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> CounterVec {
+        CounterVec {
+            name,
+            help,
+            labels,
+            values: Mutex::new(BTreeMap::new())
+        }
+    }
+
+    fn inc(&self, label_values: &[&str]) {
+        let key = label_values.iter().map(|value| String::from(*value)).collect();
+        *self.values.lock().unwrap().entry(key).or_insert(0) += 1;
+    }
+
+    #[cfg(test)]
+    fn get(&self, label_values: &[&str]) -> u64 {
+        let key: Vec<String> = label_values.iter().map(|value| String::from(*value)).collect();
+        self.values.lock().unwrap().get(&key).cloned().unwrap_or(0)
+    }
+
+    fn render(&self, out: &mut String) {
+        write_header(out, self.name, self.help, "counter");
+        for (label_values, value) in self.values.lock().unwrap().iter() {
+            let _ = writeln!(out, "{}{} {}", self.name, format_labels(self.labels, label_values, None), value);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64
+}
+
+struct HistogramVec {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, Histogram>>
+}
+
+impl HistogramVec {
+    fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> HistogramVec {
+        HistogramVec {
+            name,
+            help,
+            labels,
+            values: Mutex::new(BTreeMap::new())
+        }
+    }
+
+    fn observe(&self, label_values: &[&str], seconds: f64) {
+        let key = label_values.iter().map(|value| String::from(*value)).collect();
+        let mut values = self.values.lock().unwrap();
+        let histogram = values.entry(key).or_default();
+
+        for (position, bound) in LATENCY_BUCKETS.iter().enumerate() {
+            if seconds <= *bound {
+                histogram.buckets[position] += 1;
+            }
+        }
+        histogram.sum += seconds;
+        histogram.count += 1;
+    }
+
+    fn render(&self, out: &mut String) {
+        write_header(out, self.name, self.help, "histogram");
+        for (label_values, histogram) in self.values.lock().unwrap().iter() {
+            for (position, bound) in LATENCY_BUCKETS.iter().enumerate() {
+                let le = bound.to_string();
+                let _ = writeln!(out, "{}_bucket{} {}", self.name, format_labels(self.labels, label_values, Some(&le)), histogram.buckets[position]);
+            }
+            let _ = writeln!(out, "{}_bucket{} {}", self.name, format_labels(self.labels, label_values, Some("+Inf")), histogram.count);
+            let _ = writeln!(out, "{}_sum{} {}", self.name, format_labels(self.labels, label_values, None), histogram.sum);
+            let _ = writeln!(out, "{}_count{} {}", self.name, format_labels(self.labels, label_values, None), histogram.count);
+        }
+    }
+}
+
+// process wide metrics, rendered in the Prometheus text format
+pub struct Metrics {
+    requests: CounterVec,
+    request_duration: HistogramVec,
+    created: CounterVec,
+    delivered: CounterVec,
+    alexa_intents: CounterVec
+}
+
+impl Metrics {
+
+    pub fn new() -> Metrics {
+        Metrics {
+            requests: CounterVec::new("http_requests_total", "Handled HTTP requests.", &["route", "method", "status"]),
+            request_duration: HistogramVec::new("http_request_duration_seconds", "Time to produce the response head.", &["route", "status"]),
+            created: CounterVec::new("notifications_created_total", "Notifications accepted, queued or pushed to their channels.", &["type", "channel"]),
+            delivered: CounterVec::new("notifications_delivered_total", "Notifications taken out of a queue or pushed by a delivery channel.", &["type", "channel"]),
+            alexa_intents: CounterVec::new("alexa_intents_total", "Alexa intents received.", &["intent"])
+        }
+    }
+
+    pub fn observe_request(&self, route: &str, method: &str, status: u16, elapsed: Duration) {
+        let status = status.to_string();
+        self.requests.inc(&[route, method, &status]);
+        self.request_duration.observe(&[route, &status], elapsed.as_secs_f64());
+    }
+
+    pub fn notification_created(&self, event_type: &EventType, channel: &str) {
+        self.created.inc(&[event_type.name(), channel]);
+    }
+
+    pub fn notification_delivered(&self, event_type: &EventType, channel: &str) {
+        self.delivered.inc(&[event_type.name(), channel]);
+    }
+
+    pub fn alexa_intent(&self, intent: &str) {
+        self.alexa_intents.inc(&[intent]);
+    }
+
+    pub fn render(&self, storage: &Storage) -> String {
+        let mut out = String::new();
+        self.requests.render(&mut out);
+        self.request_duration.render(&mut out);
+        self.created.render(&mut out);
+        self.delivered.render(&mut out);
+        self.alexa_intents.render(&mut out);
+
+        write_header(&mut out, "notification_queue_size", "Pending notifications per device.", "gauge");
+        for device in storage.get_devices() {
+            let _ = writeln!(out, "notification_queue_size{{device=\"{}\"}} {}", escape(&device), storage.size(&device));
+        }
+
+        out
+    }
+
+}
+
+fn write_header(out: &mut String, name: &str, help: &str, metric_type: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, metric_type);
+}
+
+fn format_labels(names: &[&str], values: &[String], le: Option<&str>) -> String {
+    let mut pairs: Vec<String> = names.iter().zip(values.iter())
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+
+    format!("{{{}}}", pairs.join(","))
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+// ------------- there are tests only below this point ------------
+
+#[cfg(test)]
+use crate::storage::Event;
+
+#[test]
+fn test_render_counters_histograms_and_queue_sizes() {
+    let metrics = Metrics::new();
+    let mut storage = Storage::new();
+    storage.add_event(Event::new_slap(), String::from("BERLIN"));
+
+    metrics.observe_request("/rest-api/status", "GET", 200, Duration::from_millis(20));
+    metrics.observe_request("/rest-api/status", "GET", 200, Duration::from_millis(200));
+    metrics.notification_created(&EventType::SLAP, CHANNEL_REST);
+    metrics.alexa_intent("deliver_notification");
+
+    let text = metrics.render(&storage);
+
+    assert!(text.contains("http_requests_total{route=\"/rest-api/status\",method=\"GET\",status=\"200\"} 2\n"));
+    assert!(text.contains("http_request_duration_seconds_bucket{route=\"/rest-api/status\",status=\"200\",le=\"0.025\"} 1\n"));
+    assert!(text.contains("http_request_duration_seconds_bucket{route=\"/rest-api/status\",status=\"200\",le=\"+Inf\"} 2\n"));
+    assert!(text.contains("http_request_duration_seconds_count{route=\"/rest-api/status\",status=\"200\"} 2\n"));
+    assert!(text.contains("notifications_created_total{type=\"SLAP\",channel=\"rest\"} 1\n"));
+    assert!(text.contains("alexa_intents_total{intent=\"deliver_notification\"} 1\n"));
+    assert!(text.contains("notification_queue_size{device=\"BERLIN\"} 1\n"));
+    assert!(text.contains("notification_queue_size{device=\"KIEV\"} 0\n"));
+    assert!(text.contains("# TYPE notifications_delivered_total counter\n"));
+}
+
+#[test]
+fn test_counters_are_kept_per_label_set() {
+    let metrics = Metrics::new();
+
+    metrics.notification_delivered(&EventType::SLAP, CHANNEL_ALEXA);
+    metrics.notification_delivered(&EventType::MESSAGE, CHANNEL_ALEXA);
+    metrics.notification_delivered(&EventType::MESSAGE, CHANNEL_ALEXA);
+
+    assert_eq!(metrics.delivered.get(&["SLAP", CHANNEL_ALEXA]), 1);
+    assert_eq!(metrics.delivered.get(&["MESSAGE", CHANNEL_ALEXA]), 2);
+    assert_eq!(metrics.delivered.get(&["MESSAGE", CHANNEL_REST]), 0);
+}
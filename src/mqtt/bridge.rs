@@ -0,0 +1,533 @@
+use std::collections::VecDeque;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use futures::{future, Future, Sink, Stream};
+use futures::future::Loop;
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::sync::oneshot;
+use tokio::codec::Framed;
+use tokio::net::TcpStream;
+use tokio::timer::{timeout, Delay, Interval, Timeout};
+
+use crate::api::rest::dto::NextNotificationResponse;
+use crate::client::Failure;
+use crate::config::MqttConfig;
+use crate::delivery::DeliveryChannel;
+use crate::feed::QueueChange;
+use crate::metrics::{Metrics, CHANNEL_MQTT};
+use crate::mqtt::codec::{Connect, MqttCodec, Packet, Publish};
+use crate::storage::{AddOutcome, Event, Storage};
+
+const AT_LEAST_ONCE: u8 = 1;
+const SUBSCRIPTION_REFUSED: u8 = 0x80;
+// unacknowledged notifications kept for redelivery, the oldest are given up beyond that
+const MAX_INFLIGHT: usize = 1000;
+
+// the payload of {topic_prefix}/{city}/create, an empty one is a SLAP; the sender is taken
+// as it is, see MqttConfig
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct MqttCreateRequest {
+    pub type_name: Option<String>,
+    pub message_text: Option<String>,
+    pub sender: Option<String>
+}
+
+impl MqttCreateRequest {
+    pub fn from_payload(payload: &[u8]) -> Result<MqttCreateRequest, String> {
+        if payload.iter().all(u8::is_ascii_whitespace) {
+            return Ok(MqttCreateRequest::default());
+        }
+        serde_json::from_slice(payload).map_err(|err| format!("the payload is not a notification: {}", err))
+    }
+
+    pub fn to_event(&self) -> Result<Event, String> {
+        let event = match (self.type_name.as_deref().unwrap_or("SLAP"), &self.message_text) {
+            ("SLAP", _) => Event::new_slap(),
+            ("MESSAGE", Some(text)) if !text.trim().is_empty() => Event::new_message(text.clone()),
+            ("MESSAGE", _) => return Err(String::from("a MESSAGE needs a message_text")),
+            (other, _) => return Err(format!("the event type '{}' is not supported", other))
+        };
+        Ok(event.sent_by(self.sender.clone()))
+    }
+}
+
+// a client of an MQTT broker for devices that don't speak HTTP: it publishes the notifications
+// routed to it to {topic_prefix}/{city} and the queue sizes retained to
+// {topic_prefix}/{city}/queue_size, and queues what is published to {topic_prefix}/{city}/create
+pub struct MqttBridge {
+    storage: Arc<RwLock<Storage>>,
+    metrics: Arc<Metrics>,
+    broker: SocketAddr,
+    client_id: String,
+    username: Option<String>,
+    password: Option<String>,
+    keep_alive: Duration,
+    topic_prefix: String,
+    reconnect_delay: Duration,
+    session: Mutex<Session>
+}
+
+#[derive(Default)]
+struct Session {
+    // None while disconnected
+    outgoing: Option<UnboundedSender<Packet>>,
+    last_packet_id: u16,
+    // QoS 1 notifications the broker hasn't acknowledged, in the order they were published
+    inflight: VecDeque<Inflight>
+}
+
+// told once the broker acknowledged the notification, dropped when it is given up
+struct Inflight {
+    publish: Publish,
+    acked: oneshot::Sender<()>
+}
+
+impl Session {
+    fn next_packet_id(&mut self) -> u16 {
+        // 0 is not a valid packet id
+        self.last_packet_id = self.last_packet_id.checked_add(1).unwrap_or(1);
+        self.last_packet_id
+    }
+
+    // dropped while disconnected, what matters is sent again on connect
+    fn send(&self, packet: Packet) {
+        if let Some(outgoing) = &self.outgoing {
+            let _ = outgoing.unbounded_send(packet);
+        }
+    }
+}
+
+impl MqttBridge {
+
+    // the broker is resolved once, a broker that can't be resolved fails the startup
+    pub fn new(storage: Arc<RwLock<Storage>>, metrics: Arc<Metrics>, config: &MqttConfig) -> Result<MqttBridge, String> {
+        let address = config.broker.as_ref().ok_or_else(|| String::from("no MQTT broker is configured"))?;
+        let broker = address.to_socket_addrs()
+            .map_err(|err| format!("cannot resolve the MQTT broker {}: {}", address, err))?
+            .next()
+            .ok_or_else(|| format!("the MQTT broker {} has no address", address))?;
+
+        Ok(MqttBridge {
+            storage,
+            metrics,
+            broker,
+            client_id: config.client_id.clone(),
+            username: config.username.clone(),
+            password: config.password.clone(),
+            keep_alive: Duration::from_secs(u64::from(config.keep_alive_secs.max(1))),
+            topic_prefix: config.topic_prefix.trim_end_matches('/').to_string(),
+            reconnect_delay: Duration::from_millis(config.reconnect_millis),
+            session: Mutex::new(Session::default())
+        })
+    }
+
+    // runs until the feed closes, the broker is reconnected whenever the connection is lost;
+    // the feed keeps the queue sizes up to date, the notifications come through the delivery router
+    pub fn run(self: Arc<Self>, changes: UnboundedReceiver<QueueChange>) -> impl Future<Item=(), Error=()> {
+        let bridge = self.clone();
+        let publisher = changes.for_each(move |change| {
+            bridge.on_change(&change);
+            Ok(())
+        });
+        publisher.select(self.stay_connected()).then(|_| Ok(()))
+    }
+
+    fn stay_connected(self: Arc<Self>) -> impl Future<Item=(), Error=()> {
+        future::loop_fn(self, |bridge| {
+            bridge.clone().connection().then(move |result| {
+                bridge.session.lock().unwrap().outgoing = None;
+                match result {
+                    Ok(()) => warn!(broker:% = bridge.broker; "MQTT broker closed the connection"),
+                    Err(err) => warn!(broker:% = bridge.broker, error:% = err; "MQTT connection lost")
+                }
+                Delay::new(Instant::now() + bridge.reconnect_delay)
+                    .then(move |_| Ok::<_, ()>(Loop::<(), _>::Continue(bridge)))
+            })
+        })
+    }
+
+    // ends when either side closes or the broker stops answering pings
+    fn connection(self: Arc<Self>) -> impl Future<Item=(), Error=io::Error> {
+        let connect = Packet::Connect(Connect {
+            client_id: self.client_id.clone(),
+            keep_alive: self.keep_alive.as_secs() as u16,
+            clean_session: true,
+            username: self.username.clone(),
+            password: self.password.clone()
+        });
+        let handshake = TcpStream::connect(&self.broker)
+            .and_then(|stream| Framed::new(stream, MqttCodec).send(connect))
+            .and_then(|connection| connection.into_future().map_err(|(err, _)| err))
+            .and_then(|(reply, connection)| match reply {
+                Some(Packet::ConnAck { code: 0, .. }) => Ok(connection),
+                Some(Packet::ConnAck { code, .. }) => Err(io::Error::new(io::ErrorKind::ConnectionRefused, format!("the broker refused the connection with code {}", code))),
+                _ => Err(io::Error::new(io::ErrorKind::InvalidData, "the broker did not acknowledge the connection"))
+            });
+
+        Timeout::new(handshake, self.keep_alive)
+            .map_err(|err| timed_out(err, "the broker did not acknowledge the connection in time"))
+            .and_then(move |connection| {
+                info!(broker:% = self.broker; "connected to the MQTT broker");
+                let (sink, packets) = connection.split();
+                let (outgoing, queued) = unbounded();
+                self.attach(outgoing.clone());
+
+                let writer = queued
+                    .map_err(|_| io::Error::other("outgoing packets ended"))
+                    .forward(sink)
+                    .map(|_| ());
+                let pings = Interval::new(Instant::now() + self.keep_alive, self.keep_alive)
+                    .map_err(io::Error::other)
+                    .for_each(move |_| outgoing.unbounded_send(Packet::PingReq)
+                        .map_err(|_| io::Error::other("outgoing packets ended")));
+                // the broker answers the pings, a connection silent for longer is dead
+                let bridge = self.clone();
+                let reader = Timeout::new(packets, self.keep_alive * 3 / 2)
+                    .map_err(|err| timed_out(err, "the broker stopped answering"))
+                    .for_each(move |packet| {
+                        bridge.on_packet(packet);
+                        Ok(())
+                    });
+
+                reader.select(writer).map(|_| ()).map_err(|(err, _)| err)
+                    .select(pings).map(|_| ()).map_err(|(err, _)| err)
+            })
+    }
+
+    // subscribes, resends what wasn't acknowledged and republishes the queue sizes,
+    // all before any packet of a new change since the session stays locked
+    fn attach(&self, outgoing: UnboundedSender<Packet>) {
+        let mut session = self.session.lock().unwrap();
+        session.outgoing = Some(outgoing);
+        let packet_id = session.next_packet_id();
+        session.send(Packet::Subscribe { packet_id, filters: vec![(format!("{}/+/create", self.topic_prefix), AT_LEAST_ONCE)] });
+
+        for inflight in session.inflight.iter() {
+            session.send(Packet::Publish(Publish { dup: true, ..inflight.publish.clone() }));
+        }
+        let storage = match self.storage.read() {
+            Ok(storage) => storage,
+            Err(_) => {
+                error!("storage lock is poisoned, the queue sizes are not republished");
+                return;
+            }
+        };
+        for city in storage.get_devices() {
+            let queue_size = self.queue_size(&mut session, &city, storage.size(&city));
+            session.send(queue_size);
+        }
+    }
+
+    fn on_change(&self, change: &QueueChange) {
+        let mut session = self.session.lock().unwrap();
+        let queue_size = self.queue_size(&mut session, &change.city, change.queue_size);
+        session.send(queue_size);
+    }
+
+    // kept for redelivery until the broker acknowledges it, even while disconnected
+    fn publish(&self, change: &QueueChange) -> oneshot::Receiver<()> {
+        let mut session = self.session.lock().unwrap();
+        let notification = NextNotificationResponse::new(&change.city, &change.event);
+        let publish = Publish {
+            topic: format!("{}/{}", self.topic_prefix, change.city),
+            packet_id: Some(session.next_packet_id()),
+            payload: serde_json::to_vec(&notification).unwrap_or_default(),
+            retain: false,
+            dup: false
+        };
+        if session.inflight.len() >= MAX_INFLIGHT {
+            if let Some(dropped) = session.inflight.pop_front() {
+                warn!(topic = dropped.publish.topic.as_str(); "unacknowledged MQTT notification given up");
+            }
+        }
+        let (acked, on_ack) = oneshot::channel();
+        session.inflight.push_back(Inflight { publish: publish.clone(), acked });
+        session.send(Packet::Publish(publish));
+        on_ack
+    }
+
+    // retained so a device learns the size as soon as it subscribes; at QoS 1 but never
+    // resent, every connect publishes the current sizes anyway
+    fn queue_size(&self, session: &mut Session, city: &str, size: usize) -> Packet {
+        Packet::Publish(Publish {
+            topic: format!("{}/{}/queue_size", self.topic_prefix, city),
+            packet_id: Some(session.next_packet_id()),
+            payload: size.to_string().into_bytes(),
+            retain: true,
+            dup: false
+        })
+    }
+
+    fn on_packet(&self, packet: Packet) {
+        match packet {
+            Packet::PubAck(packet_id) => {
+                let mut session = self.session.lock().unwrap();
+                let position = session.inflight.iter().position(|inflight| inflight.publish.packet_id == Some(packet_id));
+                if let Some(inflight) = position.and_then(|position| session.inflight.remove(position)) {
+                    let _ = inflight.acked.send(());
+                }
+            },
+            Packet::Publish(publish) => {
+                self.create(&publish);
+                // acknowledged even when refused, a redelivery would be refused again
+                if let Some(packet_id) = publish.packet_id {
+                    self.session.lock().unwrap().send(Packet::PubAck(packet_id));
+                }
+            },
+            Packet::SubAck { codes, .. } if codes.contains(&SUBSCRIPTION_REFUSED) => error!(broker:% = self.broker; "the MQTT broker refused the subscription"),
+            Packet::SubAck { .. } | Packet::PingResp => (),
+            other => debug!(packet:? = other; "unexpected MQTT packet ignored")
+        }
+    }
+
+    // a redelivered create is queued twice, that is the price of QoS 1
+    fn create(&self, publish: &Publish) {
+        let city = publish.topic.strip_prefix(self.topic_prefix.as_str())
+            .and_then(|topic| topic.strip_prefix('/'))
+            .and_then(|topic| topic.strip_suffix("/create"))
+            .filter(|city| !city.is_empty() && !city.contains('/'))
+            .map(str::to_uppercase);
+        let city = match city {
+            Some(city) => city,
+            None => {
+                debug!(topic = publish.topic.as_str(); "MQTT publish to an unknown topic ignored");
+                return;
+            }
+        };
+
+        let event = match MqttCreateRequest::from_payload(&publish.payload).and_then(|req| req.to_event()) {
+            Ok(event) => event,
+            Err(reason) => {
+                warn!(city = city.as_str(), reason = reason.as_str(); "MQTT notification refused");
+                return;
+            }
+        };
+        let event_type = event.event_type.clone();
+        let outcome = match self.storage.write() {
+            Ok(mut storage) => storage.add_event(event, city.clone()),
+            Err(_) => {
+                error!(city = city.as_str(); "storage lock is poisoned, MQTT notification refused");
+                return;
+            }
+        };
+        match outcome {
+            AddOutcome::Rejected => warn!(city = city.as_str(); "MQTT notification refused, the queue is full"),
+            AddOutcome::UnknownDevice => warn!(city = city.as_str(); "MQTT notification for an unknown city refused"),
+            outcome => {
+                self.metrics.notification_created(&event_type, CHANNEL_MQTT);
+                info!(city = city.as_str(), outcome:? = outcome; "MQTT notification queued")
+            }
+        }
+    }
+
+}
+
+impl DeliveryChannel for MqttBridge {
+    fn name(&self) -> &'static str {
+        CHANNEL_MQTT
+    }
+
+    // every city has its topic
+    fn serves(&self, _city: &str) -> bool {
+        true
+    }
+
+    // resolves once the broker acknowledged the notification
+    fn deliver(self: Arc<Self>, change: &QueueChange) -> Box<dyn Future<Item=(), Error=Failure> + Send> {
+        Box::new(self.publish(change)
+            .map_err(|_| Failure::permanent(String::from("given up before the broker acknowledged it"))))
+    }
+}
+
+fn timed_out(err: timeout::Error<io::Error>, reason: &str) -> io::Error {
+    match err.into_inner() {
+        Some(err) => err,
+        None => io::Error::new(io::ErrorKind::TimedOut, reason)
+    }
+}
+
+// ------------- there are tests only below this point ------------
+
+#[cfg(test)]
+use std::io::{Read, Write};
+#[cfg(test)]
+use bytes::BytesMut;
+#[cfg(test)]
+use tokio::codec::{Decoder, Encoder};
+#[cfg(test)]
+use crate::config::DeliveryConfig;
+#[cfg(test)]
+use crate::delivery::{DeliveryRouter, DeliveryStatus};
+#[cfg(test)]
+use crate::testing::wait_until;
+
+#[cfg(test)]
+fn read_packet(stream: &mut std::net::TcpStream, buf: &mut BytesMut) -> Packet {
+    loop {
+        if let Some(packet) = MqttCodec.decode(buf).unwrap() {
+            return packet;
+        }
+        let mut chunk = [0u8; 1024];
+        match stream.read(&mut chunk).unwrap() {
+            0 => panic!("the bridge closed the connection"),
+            read => buf.extend_from_slice(&chunk[..read])
+        }
+    }
+}
+
+#[cfg(test)]
+fn write_packet(stream: &mut std::net::TcpStream, packet: Packet) {
+    let mut buf = BytesMut::new();
+    MqttCodec.encode(packet, &mut buf).unwrap();
+    stream.write_all(&buf).unwrap();
+}
+
+// takes the next connection of the bridge up to its subscription
+#[cfg(test)]
+fn accept_bridge(listener: &std::net::TcpListener) -> (std::net::TcpStream, BytesMut, Connect, Vec<(String, u8)>) {
+    let (mut stream, _) = listener.accept().unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buf = BytesMut::new();
+
+    let connect = match read_packet(&mut stream, &mut buf) {
+        Packet::Connect(connect) => connect,
+        other => panic!("expected CONNECT, got {:?}", other)
+    };
+    write_packet(&mut stream, Packet::ConnAck { session_present: false, code: 0 });
+    let filters = match read_packet(&mut stream, &mut buf) {
+        Packet::Subscribe { packet_id, filters } => {
+            write_packet(&mut stream, Packet::SubAck { packet_id, codes: vec![AT_LEAST_ONCE] });
+            filters
+        },
+        other => panic!("expected SUBSCRIBE, got {:?}", other)
+    };
+    (stream, buf, connect, filters)
+}
+
+#[cfg(test)]
+fn is_published_to(packet: &Packet, topic: &str) -> bool {
+    match packet {
+        Packet::Publish(publish) => publish.topic == topic,
+        _ => false
+    }
+}
+
+#[test]
+fn test_notifications_are_bridged_at_least_once() {
+    // given
+    let storage = Arc::new(RwLock::new(Storage::new()));
+    storage.write().unwrap().add_event(Event::new_slap(), String::from("KIEV"));
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let config = MqttConfig { broker: Some(listener.local_addr().unwrap().to_string()), reconnect_millis: 10, ..MqttConfig::default() };
+    let metrics = Arc::new(Metrics::new());
+    let bridge = Arc::new(MqttBridge::new(storage.clone(), metrics.clone(), &config).unwrap());
+
+    // a local broker that takes a create, then drops the connection before acknowledging the notification
+    let broker = std::thread::spawn(move || {
+        let (mut first, mut buf, connect, filters) = accept_bridge(&listener);
+        let create = Publish {
+            topic: String::from("notifications/berlin/create"),
+            packet_id: Some(42),
+            payload: br#"{"type_name":"MESSAGE","message_text":"lunch is here","sender":"anna"}"#.to_vec(),
+            retain: false,
+            dup: false
+        };
+        write_packet(&mut first, Packet::Publish(create));
+        let mut received = Vec::new();
+        while !received.iter().any(|packet| is_published_to(packet, "notifications/BERLIN")) {
+            received.push(read_packet(&mut first, &mut buf));
+        }
+        drop(first);
+
+        let (mut second, mut buf, _, _) = accept_bridge(&listener);
+        let mut redelivered = Vec::new();
+        while !redelivered.iter().any(|packet| is_published_to(packet, "notifications/BERLIN/queue_size")) {
+            redelivered.push(read_packet(&mut second, &mut buf));
+        }
+        (connect, filters, received, redelivered, second)
+    });
+
+    // when
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.spawn(bridge.clone().run(storage.read().unwrap().feed().subscribe_all()));
+    let router = DeliveryRouter::new(vec![bridge], &DeliveryConfig::default(), storage.read().unwrap().deliveries(), metrics.clone()).unwrap();
+    runtime.spawn(Arc::new(router).run(storage.read().unwrap().feed().subscribe_all()));
+    let (connect, filters, received, redelivered, mut second) = broker.join().unwrap();
+
+    // then
+    assert_eq!((connect.client_id.as_str(), connect.clean_session, connect.keep_alive), ("danila", true, 30));
+    assert_eq!(filters, vec![(String::from("notifications/+/create"), AT_LEAST_ONCE)]);
+    assert!(received.contains(&Packet::PubAck(42)));
+    let kiev = received.iter().find_map(|packet| match packet {
+        Packet::Publish(publish) if publish.topic == "notifications/KIEV/queue_size" => Some(publish.clone()),
+        _ => None
+    }).unwrap();
+    assert_eq!((kiev.payload.as_slice(), kiev.retain, kiev.packet_id.is_some()), (&b"1"[..], true, true));
+
+    let notification = match received.last() {
+        Some(Packet::Publish(publish)) => publish.clone(),
+        other => panic!("expected the notification, got {:?}", other)
+    };
+    assert!(notification.packet_id.is_some() && !notification.dup && !notification.retain);
+    let payload: NextNotificationResponse = serde_json::from_slice(&notification.payload).unwrap();
+    assert_eq!((payload.city.as_str(), payload.type_name.as_str()), ("BERLIN", "MESSAGE"));
+    assert_eq!((payload.message_text.as_deref(), payload.sender.as_deref()), (Some("lunch is here"), Some("anna")));
+
+    assert!(redelivered.contains(&Packet::Publish(Publish { dup: true, ..notification })));
+    assert!(redelivered.iter().any(|packet| match packet {
+        Packet::Publish(publish) => publish.topic == "notifications/BERLIN/queue_size" && publish.payload == b"1" && publish.retain,
+        _ => false
+    }));
+    assert_eq!(storage.read().unwrap().peek_event(&String::from("BERLIN")).unwrap().message.as_deref(), Some("lunch is here"));
+
+    // when the broker acknowledges the redelivery
+    write_packet(&mut second, Packet::PubAck(notification.packet_id.unwrap()));
+
+    // then
+    let deliveries = storage.read().unwrap().deliveries();
+    wait_until(|| deliveries.for_city("BERLIN")[0].channels.get("mqtt") == Some(&DeliveryStatus::Delivered));
+    let text = metrics.render(&storage.read().unwrap());
+    assert!(text.contains("notifications_created_total{type=\"MESSAGE\",channel=\"mqtt\"} 1\n"));
+    assert!(text.contains("notifications_delivered_total{type=\"MESSAGE\",channel=\"mqtt\"} 1\n"));
+    runtime.shutdown_now().wait().unwrap();
+}
+
+#[test]
+fn test_create_payloads_become_events() {
+    let slap = MqttCreateRequest::from_payload(b"").unwrap().to_event().unwrap();
+    assert_eq!(slap, Event::new_slap());
+
+    let message = MqttCreateRequest::from_payload(br#"{"type_name":"MESSAGE","message_text":"hi"}"#).unwrap().to_event().unwrap();
+    assert_eq!(message, Event::new_message(String::from("hi")));
+
+    assert!(MqttCreateRequest::from_payload(br#"{"type_name":"MESSAGE"}"#).unwrap().to_event().is_err());
+    assert!(MqttCreateRequest::from_payload(br#"{"type_name":"POKE"}"#).unwrap().to_event().is_err());
+    assert!(MqttCreateRequest::from_payload(b"slap").is_err());
+}
+
+#[test]
+fn test_a_poisoned_storage_does_not_stop_the_bridge() {
+    // given
+    let storage = Arc::new(RwLock::new(Storage::new()));
+    let config = MqttConfig { broker: Some(String::from("127.0.0.1:1")), ..MqttConfig::default() };
+    let bridge = MqttBridge::new(storage.clone(), Arc::new(Metrics::new()), &config).unwrap();
+    let poisoning_storage = storage.clone();
+    let _ = std::thread::spawn(move || {
+        let _guard = poisoning_storage.write().unwrap();
+        panic!("poison the storage lock");
+    }).join();
+
+    // when
+    let (outgoing, packets) = unbounded();
+    bridge.attach(outgoing);
+    bridge.create(&Publish { topic: String::from("notifications/berlin/create"), packet_id: None, payload: Vec::new(), retain: false, dup: false });
+
+    // then the bridge still subscribes, but neither republishes sizes nor queues
+    bridge.session.lock().unwrap().outgoing = None;
+    let packets: Vec<Packet> = packets.collect().wait().unwrap();
+    assert!(matches!(packets.as_slice(), [Packet::Subscribe { .. }]));
+}
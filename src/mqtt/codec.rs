@@ -0,0 +1,301 @@
+use std::io;
+
+use bytes::{BufMut, BytesMut};
+use tokio::codec::{Decoder, Encoder};
+
+// the packets of MQTT 3.1.1 a client at QoS 1 needs, see
+// http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+const PROTOCOL_NAME: &str = "MQTT";
+const PROTOCOL_LEVEL: u8 = 4;
+// buzzers don't need more, anything bigger is refused
+const MAX_PACKET_SIZE: usize = 256 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Packet {
+    Connect(Connect),
+    ConnAck { session_present: bool, code: u8 },
+    Publish(Publish),
+    PubAck(u16),
+    Subscribe { packet_id: u16, filters: Vec<(String, u8)> },
+    SubAck { packet_id: u16, codes: Vec<u8> },
+    PingReq,
+    PingResp,
+    Disconnect
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Connect {
+    pub client_id: String,
+    pub keep_alive: u16,
+    pub clean_session: bool,
+    pub username: Option<String>,
+    pub password: Option<String>
+}
+
+// QoS 1 with a packet id, QoS 0 without; QoS 2 is not supported
+#[derive(Debug, Clone, PartialEq)]
+pub struct Publish {
+    pub topic: String,
+    pub packet_id: Option<u16>,
+    pub payload: Vec<u8>,
+    pub retain: bool,
+    pub dup: bool
+}
+
+#[derive(Default)]
+pub struct MqttCodec;
+
+impl Decoder for MqttCodec {
+    type Item = Packet;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Packet>, io::Error> {
+        // the remaining length follows the first byte in up to four 7-bit groups
+        let mut remaining = 0usize;
+        let mut header_length = 1;
+        loop {
+            let byte = match buf.get(header_length) {
+                Some(byte) => *byte,
+                None => return Ok(None)
+            };
+            remaining |= ((byte & 0x7f) as usize) << (7 * (header_length - 1));
+            header_length += 1;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            if header_length > 4 {
+                return Err(invalid("malformed remaining length"));
+            }
+        }
+        if remaining > MAX_PACKET_SIZE {
+            return Err(invalid("packet too large"));
+        }
+        if buf.len() < header_length + remaining {
+            buf.reserve(header_length + remaining - buf.len());
+            return Ok(None);
+        }
+
+        let packet = buf.split_to(header_length + remaining);
+        let (kind, flags) = (packet[0] >> 4, packet[0] & 0x0f);
+        let mut body = Reader { bytes: &packet[header_length..] };
+        let packet = match kind {
+            CONNECT => {
+                if body.string()? != PROTOCOL_NAME || body.u8()? != PROTOCOL_LEVEL {
+                    return Err(invalid("unsupported protocol"));
+                }
+                let connect_flags = body.u8()?;
+                let keep_alive = body.u16()?;
+                let client_id = body.string()?;
+                // a will is read past, the bridge never asks for one
+                if connect_flags & 0x04 != 0 {
+                    body.string()?;
+                    body.string()?;
+                }
+                let username = if connect_flags & 0x80 != 0 { Some(body.string()?) } else { None };
+                let password = if connect_flags & 0x40 != 0 { Some(body.string()?) } else { None };
+                Packet::Connect(Connect { client_id, keep_alive, clean_session: connect_flags & 0x02 != 0, username, password })
+            },
+            CONNACK => Packet::ConnAck { session_present: body.u8()? & 0x01 != 0, code: body.u8()? },
+            PUBLISH => {
+                let qos = (flags >> 1) & 0x03;
+                let topic = body.string()?;
+                let packet_id = match qos {
+                    0 => None,
+                    1 => Some(body.u16()?),
+                    _ => return Err(invalid("QoS 2 is not supported"))
+                };
+                Packet::Publish(Publish { topic, packet_id, payload: body.rest(), retain: flags & 0x01 != 0, dup: flags & 0x08 != 0 })
+            },
+            PUBACK => Packet::PubAck(body.u16()?),
+            SUBSCRIBE => {
+                let packet_id = body.u16()?;
+                let mut filters = Vec::new();
+                while !body.bytes.is_empty() {
+                    filters.push((body.string()?, body.u8()?));
+                }
+                Packet::Subscribe { packet_id, filters }
+            },
+            SUBACK => Packet::SubAck { packet_id: body.u16()?, codes: body.rest() },
+            PINGREQ => Packet::PingReq,
+            PINGRESP => Packet::PingResp,
+            DISCONNECT => Packet::Disconnect,
+            _ => return Err(invalid("unsupported packet type"))
+        };
+        Ok(Some(packet))
+    }
+}
+
+impl Encoder for MqttCodec {
+    type Item = Packet;
+    type Error = io::Error;
+
+    fn encode(&mut self, packet: Packet, buf: &mut BytesMut) -> Result<(), io::Error> {
+        let mut body = Vec::new();
+        let first_byte = match packet {
+            Packet::Connect(connect) => {
+                put_string(&mut body, PROTOCOL_NAME);
+                body.push(PROTOCOL_LEVEL);
+                let mut connect_flags = 0;
+                if connect.clean_session { connect_flags |= 0x02; }
+                if connect.username.is_some() { connect_flags |= 0x80; }
+                if connect.password.is_some() { connect_flags |= 0x40; }
+                body.push(connect_flags);
+                body.extend_from_slice(&connect.keep_alive.to_be_bytes());
+                put_string(&mut body, &connect.client_id);
+                for credential in connect.username.iter().chain(connect.password.iter()) {
+                    put_string(&mut body, credential);
+                }
+                CONNECT << 4
+            },
+            Packet::ConnAck { session_present, code } => {
+                body.push(session_present as u8);
+                body.push(code);
+                CONNACK << 4
+            },
+            Packet::Publish(publish) => {
+                put_string(&mut body, &publish.topic);
+                if let Some(packet_id) = publish.packet_id {
+                    body.extend_from_slice(&packet_id.to_be_bytes());
+                }
+                body.extend_from_slice(&publish.payload);
+                let qos = if publish.packet_id.is_some() { 1 } else { 0 };
+                PUBLISH << 4 | (publish.dup as u8) << 3 | qos << 1 | publish.retain as u8
+            },
+            Packet::PubAck(packet_id) => {
+                body.extend_from_slice(&packet_id.to_be_bytes());
+                PUBACK << 4
+            },
+            Packet::Subscribe { packet_id, filters } => {
+                body.extend_from_slice(&packet_id.to_be_bytes());
+                for (filter, qos) in filters {
+                    put_string(&mut body, &filter);
+                    body.push(qos);
+                }
+                SUBSCRIBE << 4 | 0x02
+            },
+            Packet::SubAck { packet_id, codes } => {
+                body.extend_from_slice(&packet_id.to_be_bytes());
+                body.extend_from_slice(&codes);
+                SUBACK << 4
+            },
+            Packet::PingReq => PINGREQ << 4,
+            Packet::PingResp => PINGRESP << 4,
+            Packet::Disconnect => DISCONNECT << 4
+        };
+        if body.len() > MAX_PACKET_SIZE {
+            return Err(invalid("packet too large"));
+        }
+
+        buf.reserve(body.len() + 5);
+        buf.put_u8(first_byte);
+        let mut remaining = body.len();
+        loop {
+            let byte = (remaining % 128) as u8;
+            remaining /= 128;
+            if remaining == 0 {
+                buf.put_u8(byte);
+                break;
+            }
+            buf.put_u8(byte | 0x80);
+        }
+        buf.extend_from_slice(&body);
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8]
+}
+
+impl<'a> Reader<'a> {
+    fn u8(&mut self) -> Result<u8, io::Error> {
+        let (byte, rest) = self.bytes.split_first().ok_or_else(|| invalid("packet ends early"))?;
+        self.bytes = rest;
+        Ok(*byte)
+    }
+
+    fn u16(&mut self) -> Result<u16, io::Error> {
+        Ok(u16::from(self.u8()?) << 8 | u16::from(self.u8()?))
+    }
+
+    // UTF-8 prefixed with its length
+    fn string(&mut self) -> Result<String, io::Error> {
+        let length = self.u16()? as usize;
+        if self.bytes.len() < length {
+            return Err(invalid("packet ends early"));
+        }
+        let (string, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        String::from_utf8(string.to_vec()).map_err(|_| invalid("string is not UTF-8"))
+    }
+
+    fn rest(&mut self) -> Vec<u8> {
+        let rest = self.bytes.to_vec();
+        self.bytes = &[];
+        rest
+    }
+}
+
+fn put_string(body: &mut Vec<u8>, string: &str) {
+    body.extend_from_slice(&(string.len() as u16).to_be_bytes());
+    body.extend_from_slice(string.as_bytes());
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+// ------------- there are tests only below this point ------------
+
+#[test]
+fn test_packets_survive_a_round_trip() {
+    let packets = vec![
+        Packet::Connect(Connect { client_id: String::from("danila"), keep_alive: 30, clean_session: true, username: Some(String::from("bridge")), password: Some(String::from("s3cr3t")) }),
+        Packet::ConnAck { session_present: false, code: 0 },
+        Packet::Publish(Publish { topic: String::from("notifications/BERLIN"), packet_id: Some(7), payload: vec![b'x'; 300], retain: false, dup: true }),
+        Packet::Publish(Publish { topic: String::from("notifications/BERLIN/queue_size"), packet_id: None, payload: b"1".to_vec(), retain: true, dup: false }),
+        Packet::PubAck(7),
+        Packet::Subscribe { packet_id: 1, filters: vec![(String::from("notifications/+/create"), 1)] },
+        Packet::SubAck { packet_id: 1, codes: vec![1] },
+        Packet::PingReq,
+        Packet::PingResp,
+        Packet::Disconnect
+    ];
+
+    let mut buf = BytesMut::new();
+    for packet in packets.iter() {
+        MqttCodec.encode(packet.clone(), &mut buf).unwrap();
+    }
+    let mut decoded = Vec::new();
+    while let Some(packet) = MqttCodec.decode(&mut buf).unwrap() {
+        decoded.push(packet);
+    }
+
+    assert_eq!(decoded, packets);
+}
+
+#[test]
+fn test_packets_are_decoded_once_complete() {
+    // PUBLISH at QoS 1 to "a/b" with id 10 and payload "hi", as in the specification
+    let bytes = [0x32, 0x09, 0x00, 0x03, b'a', b'/', b'b', 0x00, 0x0a, b'h', b'i'];
+    let mut buf = BytesMut::from(&bytes[..5]);
+
+    assert_eq!(MqttCodec.decode(&mut buf).unwrap(), None);
+    buf.extend_from_slice(&bytes[5..]);
+    let expected = Publish { topic: String::from("a/b"), packet_id: Some(10), payload: b"hi".to_vec(), retain: false, dup: false };
+    assert_eq!(MqttCodec.decode(&mut buf).unwrap(), Some(Packet::Publish(expected)));
+    assert!(buf.is_empty());
+
+    let mut qos_2 = BytesMut::from(&[0x34, 0x02, 0x00, 0x00][..]);
+    assert!(MqttCodec.decode(&mut qos_2).is_err());
+}
@@ -0,0 +1,405 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use futures::{future, Future, Stream};
+use hyper::{Body, Method, Request, StatusCode, Uri};
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+
+use crate::api::utils::JSON_CONTENT_TYPE;
+use crate::client::{send, with_retries, Failure, HttpClient};
+use crate::config::{ProactiveEventsConfig, ProactiveEventsStage};
+use crate::delivery::DeliveryChannel;
+use crate::feed::QueueChange;
+use crate::logging::rfc3339_timestamp;
+use crate::metrics::CHANNEL_PROACTIVE_EVENTS;
+use crate::storage::{Event, EventType};
+
+const FORM_CONTENT_TYPE: &str = "application/x-www-form-urlencoded";
+const SCOPE: &str = "alexa::proactive_events";
+const MESSAGE_ALERT: &str = "AMAZON.MessageAlert.Activated";
+// a token is renewed this long before it expires, so it doesn't run out in flight
+const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize, Debug)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub expires_in: u64
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ProactiveEvent {
+    pub timestamp: String,
+    pub reference_id: String,
+    pub expiry_time: String,
+    pub event: MessageAlert,
+    pub relevant_audience: RelevantAudience
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageAlert {
+    pub name: String,
+    pub payload: MessageAlertPayload
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageAlertPayload {
+    pub state: MessageAlertState,
+    pub message_group: MessageGroup
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MessageAlertState {
+    pub status: String,
+    pub freshness: String
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MessageGroup {
+    pub creator: Creator,
+    pub count: u32,
+    pub urgency: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Creator {
+    pub name: String
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RelevantAudience {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub payload: Audience
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Audience {
+    pub user: String
+}
+
+impl ProactiveEvent {
+    // a new unread message alert for one user, a slap is urgent
+    pub fn message_alert(reference_id: String, user: &str, event: &Event, expiry: Duration) -> ProactiveEvent {
+        let now = SystemTime::now();
+        ProactiveEvent {
+            timestamp: rfc3339_timestamp(now),
+            reference_id,
+            expiry_time: rfc3339_timestamp(now + expiry),
+            event: MessageAlert {
+                name: String::from(MESSAGE_ALERT),
+                payload: MessageAlertPayload {
+                    state: MessageAlertState { status: String::from("UNREAD"), freshness: String::from("NEW") },
+                    message_group: MessageGroup {
+                        creator: Creator { name: event.sender.clone().unwrap_or_else(|| String::from("Someone")) },
+                        count: event.count,
+                        urgency: match event.event_type {
+                            EventType::SLAP => Some(String::from("URGENT")),
+                            EventType::MESSAGE => None
+                        }
+                    }
+                }
+            },
+            relevant_audience: RelevantAudience {
+                kind: String::from("Unicast"),
+                payload: Audience { user: String::from(user) }
+            }
+        }
+    }
+}
+
+struct AccessToken {
+    value: String,
+    expires_at: Instant
+}
+
+// lights the notification indicator of the Echo devices of a city whenever a notification is added
+pub struct ProactiveEvents {
+    config: ProactiveEventsConfig,
+    token_uri: Uri,
+    events_uri: Uri,
+    initial_backoff: Duration,
+    timeout: Duration,
+    expiry: Duration,
+    client: HttpClient,
+    token: Mutex<Option<AccessToken>>
+}
+
+impl ProactiveEvents {
+
+    pub fn new(config: &ProactiveEventsConfig, client: HttpClient) -> Result<ProactiveEvents, String> {
+        let token_uri = config.token_url.parse::<Uri>()
+            .map_err(|err| format!("invalid token url of proactive events: {}", err))?;
+        let path = match config.stage {
+            ProactiveEventsStage::Development => "/v1/proactiveEvents/stages/development",
+            ProactiveEventsStage::Live => "/v1/proactiveEvents"
+        };
+        let events_uri = format!("{}{}", config.api_url.trim_end_matches('/'), path).parse::<Uri>()
+            .map_err(|err| format!("invalid api url of proactive events: {}", err))?;
+
+        Ok(ProactiveEvents {
+            config: config.clone(),
+            token_uri,
+            events_uri,
+            initial_backoff: Duration::from_millis(config.initial_backoff_millis),
+            timeout: Duration::from_secs(config.timeout_secs),
+            expiry: Duration::from_secs(config.expiry_minutes * 60),
+            client,
+            token: Mutex::new(None)
+        })
+    }
+
+    fn push(self: Arc<Self>, event: ProactiveEvent) -> impl Future<Item=(), Error=Failure> {
+        let body = serde_json::to_string(&event).unwrap_or_default();
+        let call = format!("proactive event {}", event.reference_id);
+        let events = self.clone();
+
+        with_retries(call, self.config.max_attempts.max(1), self.initial_backoff, move |_| events.clone().attempt(body.clone()))
+            .map(|_| ())
+            .map_err(|(_, failure)| failure)
+    }
+
+    fn attempt(self: Arc<Self>, body: String) -> impl Future<Item=(), Error=Failure> {
+        let events = self.clone();
+        self.access_token().and_then(move |token| {
+            let req = Request::builder()
+                .method(Method::POST)
+                .uri(events.events_uri.clone())
+                .header(CONTENT_TYPE, JSON_CONTENT_TYPE)
+                .header(AUTHORIZATION, format!("Bearer {}", token).as_str())
+                .body(Body::from(body))
+                .unwrap();
+
+            send(&events.client, req, events.timeout)
+                .map_err(Failure::retryable)
+                .and_then(move |rsp| match rsp.status() {
+                    status if status.is_success() => Ok(()),
+                    // the token was revoked or lost its scope, the next attempt fetches a fresh one
+                    status @ StatusCode::UNAUTHORIZED | status @ StatusCode::FORBIDDEN => {
+                        events.token.lock().unwrap().take();
+                        Err(Failure::retryable(format!("answered {}", status)))
+                    },
+                    status => Err(Failure::of_status(status))
+                })
+        })
+    }
+
+    // the cached token while it is valid, else a new one from the client credentials
+    fn access_token(self: Arc<Self>) -> Box<dyn Future<Item=String, Error=Failure> + Send> {
+        if let Some(token) = self.token.lock().unwrap().as_ref() {
+            if token.expires_at > Instant::now() {
+                return Box::new(future::ok(token.value.clone()));
+            }
+        }
+
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(self.token_uri.clone())
+            .header(CONTENT_TYPE, FORM_CONTENT_TYPE)
+            .body(Body::from(token_form(&self.config.client_id, &self.config.client_secret)))
+            .unwrap();
+
+        let events = self.clone();
+        Box::new(send(&self.client, req, self.timeout)
+            .map_err(Failure::retryable)
+            .and_then(|rsp| match rsp.status() {
+                status if status.is_success() => Ok(rsp),
+                // wrong client credentials stay wrong
+                StatusCode::BAD_REQUEST | StatusCode::UNAUTHORIZED => Err(Failure::permanent(String::from("token request was refused"))),
+                status => Err(Failure::of_status(status))
+            })
+            .and_then(|rsp| rsp.into_body().concat2().map_err(|err| Failure::retryable(err.to_string())))
+            .and_then(move |body| {
+                let token: TokenResponse = serde_json::from_slice(&body)
+                    .map_err(|err| Failure::retryable(format!("invalid token response: {}", err)))?;
+                let lifetime = Duration::from_secs(token.expires_in).checked_sub(TOKEN_EXPIRY_MARGIN).unwrap_or_default();
+                *events.token.lock().unwrap() = Some(AccessToken { value: token.access_token.clone(), expires_at: Instant::now() + lifetime });
+                Ok(token.access_token)
+            }))
+    }
+
+}
+
+// one push per user of the city, delivered once every user has been reached; a failing push
+// doesn't hold back the others
+impl DeliveryChannel for ProactiveEvents {
+    fn name(&self) -> &'static str {
+        CHANNEL_PROACTIVE_EVENTS
+    }
+
+    fn serves(&self, city: &str) -> bool {
+        self.config.users.get(city).is_some_and(|users| !users.is_empty())
+    }
+
+    fn deliver(self: Arc<Self>, change: &QueueChange) -> Box<dyn Future<Item=(), Error=Failure> + Send> {
+        let pushes: Vec<_> = self.config.users.get(&change.city).into_iter().flatten()
+            .map(|user| {
+                let event = ProactiveEvent::message_alert(format!("{}-{}", change.id, user), user, &change.event, self.expiry);
+                let user = user.clone();
+                self.clone().push(event)
+                    .map_err(move |failure| format!("user {}: {}", user, failure.reason))
+                    .then(Ok::<_, ()>)
+            })
+            .collect();
+
+        Box::new(future::join_all(pushes).then(|results| {
+            let failed: Vec<String> = results.unwrap_or_default().into_iter().filter_map(Result::err).collect();
+            match failed.is_empty() {
+                true => Ok(()),
+                false => Err(Failure::permanent(failed.join("; ")))
+            }
+        }))
+    }
+}
+
+fn token_form(client_id: &str, client_secret: &str) -> String {
+    [("grant_type", "client_credentials"), ("client_id", client_id), ("client_secret", client_secret), ("scope", SCOPE)]
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, form_encode(value)))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+// percent-encoding of application/x-www-form-urlencoded values
+fn form_encode(value: &str) -> String {
+    value.bytes().map(|byte| match byte {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'*' => (byte as char).to_string(),
+        b' ' => String::from("+"),
+        _ => format!("%{:02X}", byte)
+    }).collect()
+}
+
+// ------------- there are tests only below this point ------------
+
+#[cfg(test)]
+use std::collections::{BTreeMap, VecDeque};
+#[cfg(test)]
+use std::net::SocketAddr;
+#[cfg(test)]
+use hyper::{Response, Server};
+#[cfg(test)]
+use hyper::service::service_fn;
+#[cfg(test)]
+use crate::delivery::DeliveryRouter;
+#[cfg(test)]
+use crate::feed::{ChangeKind, Feed};
+#[cfg(test)]
+use crate::testing::wait_until;
+
+#[cfg(test)]
+type Received = Arc<Mutex<Vec<(String, String)>>>;
+
+// hands out the tokens t1, t2, .. and answers pushes with the given statuses in turn, 202 once they ran out;
+// records the token forms and the authorization and body of every push
+#[cfg(test)]
+fn mock_alexa(runtime: &mut tokio::runtime::Runtime, statuses: Vec<u16>) -> (SocketAddr, Arc<Mutex<Vec<String>>>, Received) {
+    let forms = Arc::new(Mutex::new(Vec::new()));
+    let pushes = Arc::new(Mutex::new(Vec::new()));
+    let statuses = Arc::new(Mutex::new(VecDeque::from(statuses)));
+    let (form_recorder, push_recorder) = (forms.clone(), pushes.clone());
+    let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(move || {
+        let (forms, pushes, statuses) = (form_recorder.clone(), push_recorder.clone(), statuses.clone());
+        service_fn(move |req: Request<Body>| {
+            let (forms, pushes, statuses) = (forms.clone(), pushes.clone(), statuses.clone());
+            let (parts, body) = req.into_parts();
+            body.concat2().map(move |body| {
+                let body = String::from_utf8_lossy(&body).into_owned();
+                if parts.uri.path() == "/auth/o2/token" {
+                    let mut forms = forms.lock().unwrap();
+                    forms.push(body);
+                    let token = format!(r#"{{"access_token":"t{}","expires_in":3600,"token_type":"bearer"}}"#, forms.len());
+                    return Response::new(Body::from(token));
+                }
+                let authorization = parts.headers.get(AUTHORIZATION).map(|value| value.to_str().unwrap().to_string()).unwrap_or_default();
+                pushes.lock().unwrap().push((authorization, body));
+                let status = statuses.lock().unwrap().pop_front().unwrap_or(202);
+                Response::builder().status(status).body(Body::empty()).unwrap()
+            })
+        })
+    });
+    let addr = server.local_addr();
+    runtime.spawn(server.map_err(|_| ()));
+    (addr, forms, pushes)
+}
+
+#[test]
+fn test_form_values_are_percent_encoded() {
+    assert_eq!(token_form("amzn1.client", "a+b/c d"), "grant_type=client_credentials&client_id=amzn1.client&client_secret=a%2Bb%2Fc+d&scope=alexa%3A%3Aproactive_events");
+}
+
+#[test]
+fn test_added_notifications_are_pushed_with_a_cached_token_and_retried() {
+    // given
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let (addr, forms, pushes) = mock_alexa(&mut runtime, vec![401, 503]);
+    let mut users = BTreeMap::new();
+    users.insert(String::from("BERLIN"), vec![String::from("amzn1.ask.account.berlin")]);
+    let config = ProactiveEventsConfig {
+        client_id: String::from("client"),
+        client_secret: String::from("secret"),
+        token_url: format!("http://{}/auth/o2/token", addr),
+        api_url: format!("http://{}", addr),
+        users,
+        initial_backoff_millis: 10,
+        ..ProactiveEventsConfig::default()
+    };
+    let proactive_events = Arc::new(ProactiveEvents::new(&config, crate::client::https_client().unwrap()).unwrap());
+    let feed = Feed::new();
+    runtime.spawn(Arc::new(DeliveryRouter::for_channel(proactive_events)).run(feed.subscribe_all()));
+
+    // when
+    feed.publish("KIEV", ChangeKind::Added, &Event::new_slap(), 1);
+    feed.publish("BERLIN", ChangeKind::Delivered, &Event::new_slap(), 0);
+    feed.publish("BERLIN", ChangeKind::Added, &Event::new_message(String::from("lunch is here")).sent_by(Some(String::from("anna"))), 1);
+
+    // then the revoked token was replaced and the unavailable API was asked again
+    wait_until(|| pushes.lock().unwrap().len() == 3);
+    let authorizations: Vec<String> = pushes.lock().unwrap().iter().map(|(authorization, _)| authorization.clone()).collect();
+    assert_eq!(authorizations, vec!["Bearer t1", "Bearer t2", "Bearer t2"]);
+    assert!(forms.lock().unwrap()[0].contains("client_id=client&client_secret=secret"));
+    let event: ProactiveEvent = serde_json::from_str(&pushes.lock().unwrap()[2].1).unwrap();
+    assert_eq!(event.event.name, MESSAGE_ALERT);
+    assert_eq!(event.relevant_audience.payload.user, "amzn1.ask.account.berlin");
+    assert_eq!((event.event.payload.message_group.creator.name.as_str(), event.event.payload.message_group.urgency), ("anna", None));
+
+    // when
+    feed.publish("BERLIN", ChangeKind::Added, &Event::new_slap(), 2);
+
+    // then the token is reused
+    wait_until(|| pushes.lock().unwrap().len() == 4);
+    let event: ProactiveEvent = serde_json::from_str(&pushes.lock().unwrap()[3].1).unwrap();
+    assert_eq!(pushes.lock().unwrap()[3].0, "Bearer t2");
+    assert_eq!(event.event.payload.message_group.urgency.as_deref(), Some("URGENT"));
+    assert_eq!(forms.lock().unwrap().len(), 2);
+
+    feed.close();
+    runtime.shutdown_now().wait().unwrap();
+}
+
+#[test]
+fn test_a_refused_push_does_not_hold_back_the_other_users() {
+    // given
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let (addr, _, pushes) = mock_alexa(&mut runtime, vec![400]);
+    let mut users = BTreeMap::new();
+    users.insert(String::from("KIEV"), vec![String::from("amzn1.ask.account.olga"), String::from("amzn1.ask.account.taras")]);
+    let config = ProactiveEventsConfig {
+        token_url: format!("http://{}/auth/o2/token", addr),
+        api_url: format!("http://{}", addr),
+        users,
+        ..ProactiveEventsConfig::default()
+    };
+    let proactive_events = Arc::new(ProactiveEvents::new(&config, crate::client::https_client().unwrap()).unwrap());
+    let change = QueueChange { id: crate::feed::ChangeId { epoch: 1, sequence: 1 }, city: String::from("KIEV"), kind: ChangeKind::Added, event: Event::new_slap(), queue_size: 1 };
+
+    // when
+    let result = runtime.block_on(proactive_events.deliver(&change));
+
+    // then both users were pushed to, the refusal fails the delivery
+    assert_eq!(pushes.lock().unwrap().len(), 2);
+    assert!(result.unwrap_err().reason.contains("answered 400 Bad Request"));
+
+    runtime.shutdown_now().wait().unwrap();
+}
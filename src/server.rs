@@ -0,0 +1,398 @@
+use std::fs;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::{Future, Stream};
+use futures::future::Shared;
+use hyper::{Body, Request, Response, Server, StatusCode};
+use hyper::header::{HOST, LOCATION};
+use hyper::http::uri::Authority;
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn, service_fn_ok};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::timer::{Delay, Timeout};
+use tokio_tls::{TlsAcceptor, TlsStream};
+
+use crate::api::middleware::Pipeline;
+use crate::api::ratelimit::RemoteAddr;
+use crate::config::{ServerConfig, TlsConfig};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_PENDING_HANDSHAKES: usize = 64;
+
+type ShutdownSignal = Shared<Box<dyn Future<Item=(), Error=()> + Send>>;
+
+// a listening server, nothing is served before the future is run. The future
+// completes once the shutdown signal fired and the in-flight requests drained.
+pub struct BoundServer {
+    pub local_addr: SocketAddr,
+    pub redirect_addr: Option<SocketAddr>,
+    pub future: Box<dyn Future<Item=(), Error=()> + Send>
+}
+
+impl BoundServer {
+
+    // gives up on requests still in flight once the timeout after the shutdown signal passed
+    fn with_drain_timeout(self, shutdown: &ShutdownSignal, timeout: Duration) -> BoundServer {
+        let deadline = on_shutdown(shutdown)
+            .and_then(move |_| Delay::new(Instant::now() + timeout).then(move |_| {
+                warn!(timeout_secs = timeout.as_secs(); "in-flight requests didn't finish in time, closing them");
+                Ok(())
+            }));
+
+        BoundServer {
+            future: Box::new(self.future.select(deadline).then(|_| Ok(()))),
+            ..self
+        }
+    }
+
+}
+
+pub fn bind<S>(config: &ServerConfig, pipeline: Arc<Pipeline>, shutdown: S) -> Result<BoundServer, String>
+    where S: Future<Item=(), Error=()> + Send + 'static {
+    let addr = parse_addr(&config.address)?;
+    let shutdown = (Box::new(shutdown) as Box<dyn Future<Item=(), Error=()> + Send>).shared();
+
+    let server = match &config.tls {
+        Some(tls) => bind_https(addr, tls, pipeline, &shutdown)?,
+        None => bind_http(addr, pipeline, &shutdown)?
+    };
+
+    Ok(server.with_drain_timeout(&shutdown, Duration::from_secs(config.shutdown_timeout_secs)))
+}
+
+// resolves on the first SIGINT or SIGTERM
+#[cfg(unix)]
+pub fn termination_signal() -> impl Future<Item=(), Error=()> + Send {
+    use tokio_signal::unix::{Signal, SIGTERM};
+
+    let interrupt = tokio_signal::ctrl_c().flatten_stream().into_future()
+        .map(|_| "SIGINT")
+        .map_err(|_| ());
+    let terminate = Signal::new(SIGTERM).flatten_stream().into_future()
+        .map(|_| "SIGTERM")
+        .map_err(|_| ());
+
+    interrupt.select(terminate)
+        .map(|(signal, _)| info!(signal = signal; "shutting down"))
+        .map_err(|_| error!("cannot listen for termination signals"))
+}
+
+#[cfg(not(unix))]
+pub fn termination_signal() -> impl Future<Item=(), Error=()> + Send {
+    tokio_signal::ctrl_c().flatten_stream().into_future()
+        .map(|_| info!(signal = "Ctrl-C"; "shutting down"))
+        .map_err(|_| error!("cannot listen for termination signals"))
+}
+
+fn on_shutdown(shutdown: &ShutdownSignal) -> impl Future<Item=(), Error=()> {
+    shutdown.clone().then(|_| Ok(()))
+}
+
+fn bind_http(addr: SocketAddr, pipeline: Arc<Pipeline>, shutdown: &ShutdownSignal) -> Result<BoundServer, String> {
+    let builder = Server::try_bind(&addr).map_err(|err| format!("cannot bind {}: {}", addr, err))?;
+
+    let server = builder.serve(make_service_fn(move |conn: &AddrStream| {
+        let pipeline = pipeline.clone();
+        let remote_addr = conn.remote_addr();
+        service_fn(move |req: Request<Body>| handle(&pipeline, remote_addr, req))
+    }));
+    let local_addr = server.local_addr();
+
+    Ok(BoundServer {
+        local_addr,
+        redirect_addr: None,
+        future: Box::new(server.with_graceful_shutdown(on_shutdown(shutdown)).map_err(|err| error!(error:% = err; "server failed")))
+    })
+}
+
+fn bind_https(addr: SocketAddr, tls: &TlsConfig, pipeline: Arc<Pipeline>, shutdown: &ShutdownSignal) -> Result<BoundServer, String> {
+    let acceptor = load_acceptor(tls)?;
+    let listener = TcpListener::bind(&addr).map_err(|err| format!("cannot bind {}: {}", addr, err))?;
+    let local_addr = listener.local_addr().map_err(|err| format!("cannot bind {}: {}", addr, err))?;
+
+    // handshakes run concurrently, so a stalled client cannot hold up the others
+    let incoming = listener.incoming()
+        .map(move |tcp| {
+            Timeout::new(acceptor.accept(tcp), HANDSHAKE_TIMEOUT).then(|result| match result {
+                Ok(stream) => Ok(Some(stream)),
+                Err(err) => {
+                    warn!(error:% = err; "TLS handshake failed");
+                    Ok(None)
+                }
+            })
+        })
+        .buffer_unordered(MAX_PENDING_HANDSHAKES)
+        .filter_map(|stream| stream);
+
+    let server = Server::builder(incoming).serve(make_service_fn(move |conn: &TlsStream<TcpStream>| {
+        let pipeline = pipeline.clone();
+        let remote_addr = conn.get_ref().get_ref().peer_addr().unwrap_or(local_addr);
+        service_fn(move |req: Request<Body>| handle(&pipeline, remote_addr, req))
+    }));
+    let server = server.with_graceful_shutdown(on_shutdown(shutdown)).map_err(|err| error!(error:% = err; "server failed"));
+
+    match &tls.redirect_from {
+        Some(redirect_from) => {
+            let redirect = bind_redirect(parse_addr(redirect_from)?, local_addr.port(), shutdown)?;
+            Ok(BoundServer {
+                local_addr,
+                redirect_addr: Some(redirect.local_addr),
+                future: Box::new(server.join(redirect.future).map(|_| ()))
+            })
+        },
+        None => Ok(BoundServer {
+            local_addr,
+            redirect_addr: None,
+            future: Box::new(server)
+        })
+    }
+}
+
+fn bind_redirect(addr: SocketAddr, https_port: u16, shutdown: &ShutdownSignal) -> Result<BoundServer, String> {
+    let builder = Server::try_bind(&addr).map_err(|err| format!("cannot bind {}: {}", addr, err))?;
+
+    let server = builder.serve(move || service_fn_ok(move |req: Request<Body>| redirect_to_https(&req, https_port)));
+    let local_addr = server.local_addr();
+
+    Ok(BoundServer {
+        local_addr,
+        redirect_addr: None,
+        future: Box::new(server.with_graceful_shutdown(on_shutdown(shutdown)).map_err(|err| error!(error:% = err; "redirect server failed")))
+    })
+}
+
+fn handle(pipeline: &Pipeline, remote_addr: SocketAddr, req: Request<Body>) -> Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send> {
+    let mut req = req;
+    req.extensions_mut().insert(RemoteAddr(remote_addr));
+    pipeline.handle(req)
+}
+
+fn redirect_to_https(req: &Request<Body>, https_port: u16) -> Response<Body> {
+    // parsed as an authority, the port of an IPv6 host follows its closing bracket
+    let authority = req.headers().get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| host.parse::<Authority>().ok());
+
+    let host = match &authority {
+        Some(authority) if !authority.host().is_empty() => authority.host(),
+        _ => return Response::builder().status(StatusCode::BAD_REQUEST).body(Body::empty()).unwrap()
+    };
+
+    let port = if https_port == 443 { String::new() } else { format!(":{}", https_port) };
+    let path = req.uri().path_and_query().map(|path| path.as_str()).unwrap_or("/");
+
+    Response::builder()
+        .status(StatusCode::PERMANENT_REDIRECT)
+        .header(LOCATION, format!("https://{}{}{}", host, port, path).as_str())
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn load_acceptor(tls: &TlsConfig) -> Result<TlsAcceptor, String> {
+    let cert = fs::read(&tls.cert_path).map_err(|err| format!("cannot read certificate {}: {}", &tls.cert_path, err))?;
+    let key = fs::read(&tls.key_path).map_err(|err| format!("cannot read private key {}: {}", &tls.key_path, err))?;
+
+    let identity = native_tls::Identity::from_pkcs8(&cert, &key)
+        .map_err(|err| format!("invalid certificate or key: {}", err))?;
+    let acceptor = native_tls::TlsAcceptor::new(identity)
+        .map_err(|err| format!("cannot set up TLS: {}", err))?;
+
+    Ok(TlsAcceptor::from(acceptor))
+}
+
+fn parse_addr(address: &str) -> Result<SocketAddr, String> {
+    address.parse().map_err(|err| format!("invalid address {}: {}", address, err))
+}
+
+// ------------- there are tests only below this point ------------
+
+#[cfg(test)]
+use std::io::{Read, Write};
+#[cfg(test)]
+use std::sync::RwLock;
+#[cfg(test)]
+use crate::config::Config;
+#[cfg(test)]
+use crate::storage::Storage;
+
+// returns the PEM encoded certificate and PKCS#8 key for "localhost"
+#[cfg(test)]
+fn self_signed_certificate() -> (Vec<u8>, Vec<u8>) {
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::x509::{X509, X509NameBuilder};
+    use openssl::x509::extension::SubjectAlternativeName;
+
+    let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", "localhost").unwrap();
+    let name = name.build();
+
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    builder.set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap()).unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_issuer_name(&name).unwrap();
+    builder.set_pubkey(&key).unwrap();
+    builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+    let alt_names = SubjectAlternativeName::new().dns("localhost").build(&builder.x509v3_context(None, None)).unwrap();
+    builder.append_extension(alt_names).unwrap();
+    builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+    (builder.build().to_pem().unwrap(), key.private_key_to_pem_pkcs8().unwrap())
+}
+
+#[cfg(test)]
+fn test_pipeline() -> Arc<Pipeline> {
+    let storage = Arc::new(RwLock::new(Storage::new()));
+    let dispatcher = crate::create_dispatcher(storage, Arc::new(crate::metrics::Metrics::new()), &Config::default());
+    Arc::new(Pipeline::new(dispatcher, Vec::new()))
+}
+
+#[cfg(test)]
+fn tls_config(cert: &[u8], key: &[u8]) -> ServerConfig {
+    let dir = std::env::temp_dir().join(format!("danila-tls-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let cert_path = dir.join("cert.pem");
+    let key_path = dir.join("key.pem");
+    fs::write(&cert_path, cert).unwrap();
+    fs::write(&key_path, key).unwrap();
+
+    ServerConfig {
+        address: String::from("127.0.0.1:0"),
+        tls: Some(TlsConfig {
+            cert_path: cert_path.to_string_lossy().into_owned(),
+            key_path: key_path.to_string_lossy().into_owned(),
+            redirect_from: Some(String::from("127.0.0.1:0"))
+        }),
+        ..ServerConfig::default()
+    }
+}
+
+#[test]
+fn test_serves_https_and_redirects_plain_http() {
+    let (cert, key) = self_signed_certificate();
+    let server = bind(&tls_config(&cert, &key), test_pipeline(), futures::future::empty()).unwrap();
+    let https_addr = server.local_addr;
+    let redirect_addr = server.redirect_addr.unwrap();
+
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.spawn(server.future);
+
+    // HTTPS request trusting only the generated certificate
+    let connector = native_tls::TlsConnector::builder()
+        .add_root_certificate(native_tls::Certificate::from_pem(&cert).unwrap())
+        .build()
+        .unwrap();
+    let tcp = std::net::TcpStream::connect(https_addr).unwrap();
+    let mut stream = connector.connect("localhost", tcp).unwrap();
+    stream.write_all(b"GET /rest-api/status?city=BERLIN HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 200"), "unexpected response: {}", response);
+    assert!(response.ends_with(r###"{"message_num":0}"###));
+
+    // plain HTTP request to the redirect listener
+    let mut plain = std::net::TcpStream::connect(redirect_addr).unwrap();
+    plain.write_all(b"GET /rest-api/status?city=BERLIN HTTP/1.1\r\nHost: localhost:8080\r\nConnection: close\r\n\r\n").unwrap();
+    let mut response = String::new();
+    plain.read_to_string(&mut response).unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 308"), "unexpected response: {}", response);
+    let location = format!("location: https://localhost:{}/rest-api/status?city=BERLIN", https_addr.port());
+    assert!(response.contains(&location), "unexpected response: {}", response);
+
+    runtime.shutdown_now().wait().unwrap();
+}
+
+#[test]
+fn test_invalid_certificate_is_reported() {
+    let config = ServerConfig {
+        tls: Some(TlsConfig {
+            cert_path: String::from("/nonexistent/cert.pem"),
+            key_path: String::from("/nonexistent/key.pem"),
+            redirect_from: None
+        }),
+        ..ServerConfig::default()
+    };
+
+    match bind(&config, test_pipeline(), futures::future::empty()) {
+        Err(err) => assert!(err.starts_with("cannot read certificate"), "unexpected error: {}", err),
+        Ok(_) => panic!("server started without a certificate")
+    }
+}
+
+#[cfg(test)]
+fn http_config(shutdown_timeout_secs: u64) -> ServerConfig {
+    ServerConfig {
+        address: String::from("127.0.0.1:0"),
+        shutdown_timeout_secs,
+        ..ServerConfig::default()
+    }
+}
+
+#[test]
+fn test_shutdown_stops_accepting_connections() {
+    let (trigger, shutdown) = futures::sync::oneshot::channel::<()>();
+    let server = bind(&http_config(30), test_pipeline(), shutdown.map_err(|_| ())).unwrap();
+    let addr = server.local_addr;
+
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let (done_tx, done) = futures::sync::oneshot::channel();
+    runtime.spawn(server.future.then(move |_| done_tx.send(()).map_err(|_| ())));
+
+    let mut stream = std::net::TcpStream::connect(addr).unwrap();
+    stream.write_all(b"GET /rest-api/status?city=BERLIN HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "unexpected response: {}", response);
+
+    trigger.send(()).unwrap();
+    done.wait().unwrap();
+
+    assert!(std::net::TcpStream::connect(addr).is_err());
+    runtime.shutdown_now().wait().unwrap();
+}
+
+#[test]
+fn test_shutdown_gives_up_on_stalled_requests() {
+    let (trigger, shutdown) = futures::sync::oneshot::channel::<()>();
+    let server = bind(&http_config(0), test_pipeline(), shutdown.map_err(|_| ())).unwrap();
+    let addr = server.local_addr;
+
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let (done_tx, done) = futures::sync::oneshot::channel();
+    runtime.spawn(server.future.then(move |_| done_tx.send(()).map_err(|_| ())));
+
+    // the announced body never arrives, so the request stays in flight
+    let mut stalled = std::net::TcpStream::connect(addr).unwrap();
+    stalled.write_all(b"POST /rest-api/notifications HTTP/1.1\r\nHost: localhost\r\nContent-Length: 100\r\n\r\n{").unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+
+    trigger.send(()).unwrap();
+    done.wait().unwrap();
+
+    runtime.shutdown_now().wait().unwrap();
+}
+
+#[test]
+fn test_redirect_keeps_ipv6_hosts() {
+    let redirected = |host: &str| {
+        let req = Request::builder().uri("/rest-api/status?city=BERLIN").header(HOST, host).body(Body::empty()).unwrap();
+        let rsp = redirect_to_https(&req, 8443);
+        (rsp.status(), rsp.headers().get(LOCATION).map(|location| location.to_str().unwrap().to_string()))
+    };
+
+    assert_eq!(redirected("[::1]:8080"), (StatusCode::PERMANENT_REDIRECT, Some(String::from("https://[::1]:8443/rest-api/status?city=BERLIN"))));
+    assert_eq!(redirected("[::1]"), (StatusCode::PERMANENT_REDIRECT, Some(String::from("https://[::1]:8443/rest-api/status?city=BERLIN"))));
+    assert_eq!(redirected("localhost:8080"), (StatusCode::PERMANENT_REDIRECT, Some(String::from("https://localhost:8443/rest-api/status?city=BERLIN"))));
+    assert_eq!(redirected("bad host").0, StatusCode::BAD_REQUEST);
+}
@@ -1,38 +1,120 @@
 use std::collections::HashSet;
 use std::collections::HashMap;
 use std::collections::VecDeque;
+use std::sync::Arc;
+
+use crate::delivery::{DeliveryLog, DeliveryRoutes, DeliveryStatus};
+use crate::feed::{ChangeKind, Feed};
 
 #[derive(Debug)]
 pub struct Storage {
     devices: HashSet<String>,
-    notifications: HashMap<String, VecDeque<Event>>
+    notifications: HashMap<String, VecDeque<Event>>,
+    max_queue_length: Option<usize>,
+    overflow_policy: OverflowPolicy,
+    slap_coalescing: SlapCoalescing,
+    feed: Arc<Feed>,
+    deliveries: Arc<DeliveryLog>,
+    routes: DeliveryRoutes
+}
+
+// what add_event does with a new event once the queue of the device is full
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    Reject,
+    DropOldest,
+    // merge into an identical event at the tail of the queue, reject otherwise
+    Coalesce
+}
+
+// whether a new SLAP is merged into a SLAP at the tail of the queue. Only consecutive
+// slaps are merged, a MESSAGE or another sender in between starts a new event.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SlapCoalescing {
+    Off,
+    // senders are not counted separately, the merged event keeps a sender only
+    // while all of its slaps came from it
+    All,
+    // a slap is merged only into a tail from the same sender
+    PerSender
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AddOutcome {
+    Queued,
+    // counted into the pending SLAP at the tail by the slap coalescing mode
+    Merged,
+    DroppedOldest(Event),
+    Coalesced,
+    Rejected,
+    UnknownDevice,
+    // not queued, its routes only name push channels
+    Pushed
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Event {
     pub event_type: EventType,
-    pub message: Option<String>
+    pub message: Option<String>,
+    pub sender: Option<String>,
+    pub count: u32
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum EventType {
     SLAP,
     MESSAGE
 }
 
+impl EventType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventType::SLAP => "SLAP",
+            EventType::MESSAGE => "MESSAGE"
+        }
+    }
+}
+
 impl Event {
     pub fn new_slap() -> Event {
         Event {
             event_type: EventType::SLAP,
-            message: None
+            message: None,
+            sender: None,
+            count: 1
         }
     }
 
     pub fn new_message(text: String) -> Event {
         Event {
             event_type: EventType::MESSAGE,
-            message: Some(text)
+            message: Some(text),
+            sender: None,
+            count: 1
+        }
+    }
+
+    pub fn sent_by(self, sender: Option<String>) -> Event {
+        Event {
+            sender,
+            ..self
+        }
+    }
+
+    // same notification, regardless of how many times it was counted
+    pub fn same_as(&self, other: &Event) -> bool {
+        self.event_type == other.event_type && self.message == other.message && self.sender == other.sender
+    }
+
+    // the sender is dropped once slaps of different senders are merged
+    fn absorb(&mut self, other: Event) {
+        if self.sender != other.sender {
+            self.sender = None;
         }
+        self.count += other.count;
     }
 }
 
@@ -40,7 +122,13 @@ impl Storage {
     pub fn new () -> Storage {
         let mut storage = Storage {
             devices: HashSet::new(),
-            notifications: HashMap::new()
+            notifications: HashMap::new(),
+            max_queue_length: None,
+            overflow_policy: OverflowPolicy::Reject,
+            slap_coalescing: SlapCoalescing::Off,
+            feed: Arc::new(Feed::new()),
+            deliveries: Arc::new(DeliveryLog::new()),
+            routes: DeliveryRoutes::default()
         };
 
         storage.devices.insert(String::from("MILAN"));
@@ -56,28 +144,166 @@ impl Storage {
         storage
     }
 
+    // None keeps the queues unbounded
+    pub fn with_queue_limit(self, max_queue_length: Option<usize>, overflow_policy: OverflowPolicy) -> Storage {
+        Storage {
+            max_queue_length,
+            overflow_policy,
+            ..self
+        }
+    }
+
+    pub fn with_slap_coalescing(self, slap_coalescing: SlapCoalescing) -> Storage {
+        Storage {
+            slap_coalescing,
+            ..self
+        }
+    }
+
+    // without routes every notification is queued
+    pub fn with_routes(self, routes: DeliveryRoutes) -> Storage {
+        Storage {
+            routes,
+            ..self
+        }
+    }
+
     pub fn is_registered(&self, device: &String) -> bool {
         self.devices.contains(device)
     }
 
+    pub fn get_devices(&self) -> Vec<String> {
+        let mut devices = self.devices.clone().into_iter().collect::<Vec<String>>();
+        devices.sort();
+        devices
+    }
+
     pub fn get_supported_cities_as_str(&self) -> String {
         self.devices.clone().into_iter().collect::<Vec<String>>().join(", ")
     }
 
-    pub fn add_event(&mut self, event: Event, to_device: String) {
-        if self.devices.contains(&to_device) {
-            match self.notifications.get_mut(&to_device) {
-                Some(queue) => queue.push_back(event),
-                _ => ()
+    // every change of a queue is published to the feed
+    pub fn feed(&self) -> Arc<Feed> {
+        self.feed.clone()
+    }
+
+    pub fn deliveries(&self) -> Arc<DeliveryLog> {
+        self.deliveries.clone()
+    }
+
+    pub fn add_event(&mut self, event: Event, to_device: String) -> AddOutcome {
+        if self.devices.contains(&to_device) && !self.routes.queues(&to_device, &event.event_type) {
+            // recorded before the router can set the status of a channel
+            let deliveries = &self.deliveries;
+            self.feed.publish_with(&to_device, ChangeKind::Pushed, &event, self.size(&to_device), |id| deliveries.pushed(id, &to_device, &event));
+            return AddOutcome::Pushed;
+        }
+
+        let added = event.clone();
+        let outcome = self.enqueue(event, &to_device);
+
+        match &outcome {
+            // a coalesced event was not queued, the queue didn't change
+            AddOutcome::Rejected | AddOutcome::UnknownDevice | AddOutcome::Pushed | AddOutcome::Coalesced => (),
+            AddOutcome::DroppedOldest(dropped) => {
+                let size = self.size(&to_device);
+                self.feed.publish(&to_device, ChangeKind::Expired, dropped, size - 1);
+                self.deliveries.dequeued(&to_device, DeliveryStatus::Failed(String::from("expired")), None);
+                let deliveries = &self.deliveries;
+                self.feed.publish_with(&to_device, ChangeKind::Added, &added, size, |id| deliveries.queued(id, &to_device, &added, false));
+            },
+            _ => {
+                let deliveries = &self.deliveries;
+                let merged = outcome != AddOutcome::Queued;
+                self.feed.publish_with(&to_device, ChangeKind::Added, &added, self.size(&to_device), |id| deliveries.queued(id, &to_device, &added, merged));
+            }
+        }
+
+        outcome
+    }
+
+    fn enqueue(&mut self, event: Event, to_device: &String) -> AddOutcome {
+        let queue = match self.notifications.get_mut(to_device) {
+            Some(queue) if self.devices.contains(to_device) => queue,
+            _ => return AddOutcome::UnknownDevice
+        };
+
+        if let Some(tail) = queue.back_mut() {
+            let mergeable = tail.event_type == EventType::SLAP && event.event_type == EventType::SLAP && match self.slap_coalescing {
+                SlapCoalescing::Off => false,
+                SlapCoalescing::All => true,
+                SlapCoalescing::PerSender => tail.sender == event.sender
+            };
+
+            if mergeable {
+                tail.absorb(event);
+                return AddOutcome::Merged;
             }
+        }
 
+        let is_full = match self.max_queue_length {
+            Some(max_queue_length) => queue.len() >= max_queue_length,
+            None => false
+        };
+
+        if !is_full {
+            queue.push_back(event);
+            return AddOutcome::Queued;
+        }
+
+        match self.overflow_policy {
+            OverflowPolicy::Reject => AddOutcome::Rejected,
+            OverflowPolicy::DropOldest => match queue.pop_front() {
+                Some(dropped) => {
+                    queue.push_back(event);
+                    AddOutcome::DroppedOldest(dropped)
+                },
+                None => AddOutcome::Rejected
+            },
+            OverflowPolicy::Coalesce => match queue.back() {
+                Some(tail) if tail.same_as(&event) => AddOutcome::Coalesced,
+                _ => AddOutcome::Rejected
+            }
         }
     }
 
-    pub fn pop_event(&mut self, for_device: &String) -> Option<Event> {
-        match self.notifications.get_mut(for_device) {
+    // the consumer is the channel taking the event, kept in the delivery status
+    pub fn pop_event(&mut self, for_device: &String, consumer: &str) -> Option<Event> {
+        let event = match self.notifications.get_mut(for_device) {
             Some(queue) => queue.pop_front(),
             _ => None
+        };
+
+        if let Some(event) = &event {
+            self.feed.publish(for_device, ChangeKind::Delivered, event, self.size(for_device));
+            self.deliveries.dequeued(for_device, DeliveryStatus::Delivered, Some(consumer));
+        }
+
+        event
+    }
+
+    // the next event without dequeuing it, channels that wait for an acknowledgement show it first
+    pub fn peek_event(&self, for_device: &String) -> Option<Event> {
+        self.notifications.get(for_device)?.front().cloned()
+    }
+
+    // dequeues the next event only while it is still the one a channel has shown,
+    // another channel may have delivered it or counted more SLAPs into it meanwhile
+    pub fn pop_event_if(&mut self, for_device: &String, shown: &Event, consumer: &str) -> Option<Event> {
+        if self.peek_event(for_device).as_ref() != Some(shown) {
+            return None;
+        }
+        self.pop_event(for_device, consumer)
+    }
+
+    // called once on shutdown. Queues only live in memory for now, so flushing
+    // reports what is about to be lost.
+    pub fn flush(&mut self) {
+        for device in self.get_devices() {
+            let pending = self.size(&device);
+            if pending > 0 {
+                warn!(device = device.as_str(), pending = pending; "pending notifications are discarded on exit");
+            }
         }
     }
 
@@ -91,6 +317,9 @@ impl Storage {
 }
 
 
+#[cfg(test)]
+use crate::metrics::{CHANNEL_REST, CHANNEL_WEBSOCKET};
+
 #[test]
 fn smoke_test_storage() {
     let mut storage = Storage::new();
@@ -99,7 +328,7 @@ fn smoke_test_storage() {
 
     storage.add_event(event.clone(), String::from("MILAN"));
 
-    match storage.pop_event(&String::from("MILAN")) {
+    match storage.pop_event(&String::from("MILAN"), CHANNEL_REST) {
         Some(_poped_event) => (),
         _ => panic!()
     }
@@ -107,6 +336,7 @@ fn smoke_test_storage() {
 }
 
 #[test]
+#[allow(clippy::assertions_on_constants)]
 fn smoke_test_empty_storage() {
     let mut storage = Storage::new();
 
@@ -116,22 +346,22 @@ fn smoke_test_empty_storage() {
     storage.add_event(event.clone(), String::from("MILAN"));
     println!("test debug {:?}", &storage);
 
-    match storage.pop_event(&String::from("BERLIN")) {
+    match storage.pop_event(&String::from("BERLIN"), CHANNEL_REST) {
         Some(_) => panic!(),
         _ => assert!(true)
     }
 
-    match storage.pop_event(&String::from("MILAN")) {
+    match storage.pop_event(&String::from("MILAN"), CHANNEL_REST) {
         Some(_poped_event) => (),
         _ => panic!()
     }
 
-    match storage.pop_event(&String::from("MILAN")) {
+    match storage.pop_event(&String::from("MILAN"), CHANNEL_REST) {
         Some(_poped_event) => (),
         _ => panic!()
     }
 
-    match storage.pop_event(&String::from("MILAN")) {
+    match storage.pop_event(&String::from("MILAN"), CHANNEL_REST) {
         Some(_) => panic!(),
         _ => assert!(true)
     }
@@ -151,3 +381,141 @@ fn test_size() {
     assert_eq!(storage.size(&String::from("MILAN")), 2);
 }
 
+
+#[test]
+fn test_full_queue_rejects_by_default() {
+    let mut storage = Storage::new().with_queue_limit(Some(1), OverflowPolicy::Reject);
+
+    assert_eq!(storage.add_event(Event::new_slap(), String::from("MILAN")), AddOutcome::Queued);
+    assert_eq!(storage.add_event(Event::new_slap(), String::from("MILAN")), AddOutcome::Rejected);
+    assert_eq!(storage.add_event(Event::new_slap(), String::from("PARIS")), AddOutcome::UnknownDevice);
+    assert_eq!(storage.size(&String::from("MILAN")), 1);
+}
+
+#[test]
+fn test_full_queue_drops_oldest() {
+    let mut storage = Storage::new().with_queue_limit(Some(2), OverflowPolicy::DropOldest);
+
+    storage.add_event(Event::new_message(String::from("first")), String::from("MILAN"));
+    storage.add_event(Event::new_message(String::from("second")), String::from("MILAN"));
+    let outcome = storage.add_event(Event::new_message(String::from("third")), String::from("MILAN"));
+
+    assert_eq!(outcome, AddOutcome::DroppedOldest(Event::new_message(String::from("first"))));
+    assert_eq!(storage.pop_event(&String::from("MILAN"), CHANNEL_REST).unwrap().message, Some(String::from("second")));
+    assert_eq!(storage.pop_event(&String::from("MILAN"), CHANNEL_REST).unwrap().message, Some(String::from("third")));
+}
+
+#[test]
+fn test_full_queue_coalesces_identical_events() {
+    let mut storage = Storage::new().with_queue_limit(Some(1), OverflowPolicy::Coalesce);
+
+    storage.add_event(Event::new_slap(), String::from("MILAN"));
+
+    assert_eq!(storage.add_event(Event::new_slap(), String::from("MILAN")), AddOutcome::Coalesced);
+    assert_eq!(storage.add_event(Event::new_message(String::from("hi")), String::from("MILAN")), AddOutcome::Rejected);
+    assert_eq!(storage.size(&String::from("MILAN")), 1);
+}
+
+#[test]
+fn test_consecutive_slaps_are_counted() {
+    let mut storage = Storage::new().with_slap_coalescing(SlapCoalescing::All);
+
+    storage.add_event(Event::new_slap().sent_by(Some(String::from("anna"))), String::from("MILAN"));
+    assert_eq!(storage.add_event(Event::new_slap().sent_by(Some(String::from("anna"))), String::from("MILAN")), AddOutcome::Merged);
+    assert_eq!(storage.add_event(Event::new_slap().sent_by(Some(String::from("boris"))), String::from("MILAN")), AddOutcome::Merged);
+    storage.add_event(Event::new_message(String::from("hi")), String::from("MILAN"));
+    storage.add_event(Event::new_slap(), String::from("MILAN"));
+
+    assert_eq!(storage.size(&String::from("MILAN")), 3);
+
+    let slaps = storage.pop_event(&String::from("MILAN"), CHANNEL_REST).unwrap();
+    assert_eq!(slaps.count, 3);
+    assert_eq!(slaps.sender, None);
+}
+
+#[test]
+fn test_slaps_are_counted_per_sender() {
+    let mut storage = Storage::new().with_slap_coalescing(SlapCoalescing::PerSender);
+
+    storage.add_event(Event::new_slap().sent_by(Some(String::from("anna"))), String::from("MILAN"));
+    storage.add_event(Event::new_slap().sent_by(Some(String::from("anna"))), String::from("MILAN"));
+    storage.add_event(Event::new_slap().sent_by(Some(String::from("boris"))), String::from("MILAN"));
+
+    let from_anna = storage.pop_event(&String::from("MILAN"), CHANNEL_REST).unwrap();
+    assert_eq!(from_anna.count, 2);
+    assert_eq!(from_anna.sender, Some(String::from("anna")));
+    assert_eq!(storage.pop_event(&String::from("MILAN"), CHANNEL_REST).unwrap().count, 1);
+}
+
+#[test]
+fn test_shown_event_is_only_dequeued_while_unchanged() {
+    let mut storage = Storage::new().with_slap_coalescing(SlapCoalescing::All);
+    let milan = String::from("MILAN");
+
+    storage.add_event(Event::new_slap(), milan.clone());
+    let shown = storage.peek_event(&milan).unwrap();
+    assert_eq!(storage.size(&milan), 1);
+
+    // a SLAP counted into the shown one must be shown again before it can go
+    storage.add_event(Event::new_slap(), milan.clone());
+    assert_eq!(storage.pop_event_if(&milan, &shown, CHANNEL_WEBSOCKET), None);
+
+    let shown = storage.peek_event(&milan).unwrap();
+    assert_eq!(storage.pop_event_if(&milan, &shown, CHANNEL_WEBSOCKET).unwrap().count, 2);
+    assert_eq!(storage.pop_event_if(&milan, &shown, CHANNEL_WEBSOCKET), None);
+    assert_eq!(storage.peek_event(&milan), None);
+}
+
+#[test]
+fn test_dropped_oldest_event_is_published_as_expired() {
+    use futures::{Future, Stream};
+
+    let mut storage = Storage::new().with_queue_limit(Some(1), OverflowPolicy::DropOldest);
+    let changes = storage.feed().subscribe_all();
+
+    storage.add_event(Event::new_message(String::from("first")), String::from("MILAN"));
+    storage.add_event(Event::new_message(String::from("second")), String::from("MILAN"));
+    storage.feed().close();
+
+    let changes: Vec<(ChangeKind, Option<String>)> = changes.map(|change| (change.kind, change.event.message)).collect().wait().unwrap();
+    assert_eq!(changes, vec![
+        (ChangeKind::Added, Some(String::from("first"))),
+        (ChangeKind::Expired, Some(String::from("first"))),
+        (ChangeKind::Added, Some(String::from("second")))
+    ]);
+}
+
+#[test]
+fn test_coalesced_event_is_not_published() {
+    use futures::{Future, Stream};
+
+    let mut storage = Storage::new().with_queue_limit(Some(1), OverflowPolicy::Coalesce);
+    let changes = storage.feed().subscribe_all();
+
+    storage.add_event(Event::new_slap(), String::from("MILAN"));
+    assert_eq!(storage.add_event(Event::new_slap(), String::from("MILAN")), AddOutcome::Coalesced);
+    storage.feed().close();
+
+    let kinds: Vec<ChangeKind> = changes.map(|change| change.kind).collect().wait().unwrap();
+    assert_eq!(kinds, vec![ChangeKind::Added]);
+}
+
+#[test]
+fn test_only_consecutive_slaps_of_a_sender_are_counted() {
+    let mut storage = Storage::new().with_slap_coalescing(SlapCoalescing::PerSender);
+
+    storage.add_event(Event::new_slap().sent_by(Some(String::from("anna"))), String::from("MILAN"));
+    storage.add_event(Event::new_slap().sent_by(Some(String::from("boris"))), String::from("MILAN"));
+    assert_eq!(storage.add_event(Event::new_slap().sent_by(Some(String::from("anna"))), String::from("MILAN")), AddOutcome::Queued);
+    assert_eq!(storage.add_event(Event::new_slap().sent_by(Some(String::from("anna"))), String::from("MILAN")), AddOutcome::Merged);
+
+    let senders: Vec<(Option<String>, u32)> = (0..3)
+        .map(|_| storage.pop_event(&String::from("MILAN"), CHANNEL_REST).unwrap())
+        .map(|event| (event.sender, event.count))
+        .collect();
+    assert_eq!(senders, vec![
+        (Some(String::from("anna")), 1),
+        (Some(String::from("boris")), 1),
+        (Some(String::from("anna")), 2)
+    ]);
+}
@@ -0,0 +1,54 @@
+// stand-ins shared by the tests of the delivery modules
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{Future, Stream};
+use hyper::{Body, HeaderMap, Request, Response, Server};
+use hyper::service::service_fn;
+
+#[derive(Clone, Debug)]
+pub struct Recorded {
+    pub path: String,
+    pub headers: HeaderMap,
+    pub body: String
+}
+
+pub type Received = Arc<Mutex<Vec<Recorded>>>;
+
+// answers with the given statuses in turn, 200 once they ran out, and records every request
+pub fn stand_in(runtime: &mut tokio::runtime::Runtime, statuses: Vec<u16>) -> (SocketAddr, Received) {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let statuses = Arc::new(Mutex::new(VecDeque::from(statuses)));
+    let recorder = received.clone();
+    let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(move || {
+        let recorder = recorder.clone();
+        let statuses = statuses.clone();
+        service_fn(move |req: Request<Body>| {
+            let recorder = recorder.clone();
+            let status = statuses.lock().unwrap().pop_front().unwrap_or(200);
+            let (parts, body) = req.into_parts();
+            body.concat2().map(move |body| {
+                recorder.lock().unwrap().push(Recorded {
+                    path: parts.uri.path().to_string(),
+                    headers: parts.headers,
+                    body: String::from_utf8_lossy(&body).into_owned()
+                });
+                Response::builder().status(status).body(Body::empty()).unwrap()
+            })
+        })
+    });
+    let addr = server.local_addr();
+    runtime.spawn(server.map_err(|_| ()));
+    (addr, received)
+}
+
+pub fn wait_until<F: Fn() -> bool>(condition: F) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !condition() {
+        assert!(Instant::now() < deadline, "condition not met in time");
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
@@ -0,0 +1,352 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::{future, Future};
+use hmac::{Hmac, Mac};
+use hyper::{Body, Method, Request, Uri};
+use hyper::header::CONTENT_TYPE;
+use sha2::Sha256;
+
+use crate::api::utils::JSON_CONTENT_TYPE;
+use crate::client::{send, with_retries, Failure, HttpClient};
+use crate::config::{WebhookConfig, WebhooksConfig};
+use crate::delivery::DeliveryChannel;
+use crate::feed::{ChangeKind, QueueChange};
+use crate::logging::rfc3339_timestamp;
+use crate::metrics::CHANNEL_WEBHOOKS;
+
+pub const EVENT_HEADER: &str = "x-danila-event";
+pub const DELIVERY_HEADER: &str = "x-danila-delivery";
+pub const TIMESTAMP_HEADER: &str = "x-danila-timestamp";
+// "sha256=" and the hex HMAC of "{timestamp}.{body}" keyed with the secret of the webhook
+pub const SIGNATURE_HEADER: &str = "x-danila-signature";
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    Created,
+    Delivered,
+    Expired
+}
+
+impl WebhookEvent {
+    pub fn of(kind: ChangeKind) -> WebhookEvent {
+        match kind {
+            ChangeKind::Added | ChangeKind::Pushed => WebhookEvent::Created,
+            ChangeKind::Delivered => WebhookEvent::Delivered,
+            ChangeKind::Expired => WebhookEvent::Expired
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            WebhookEvent::Created => "created",
+            WebhookEvent::Delivered => "delivered",
+            WebhookEvent::Expired => "expired"
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WebhookPayload {
+    pub id: String,
+    pub event: String,
+    pub city: String,
+    pub type_name: String,
+    pub message_text: Option<String>,
+    pub sender: Option<String>,
+    pub count: u32,
+    pub queue_size: usize,
+    pub timestamp: String
+}
+
+impl<'a> From<&'a QueueChange> for WebhookPayload {
+    fn from(change: &'a QueueChange) -> WebhookPayload {
+        WebhookPayload {
+            id: change.id.to_string(),
+            event: String::from(WebhookEvent::of(change.kind).name()),
+            city: change.city.clone(),
+            type_name: String::from(change.event.event_type.name()),
+            message_text: change.event.message.clone(),
+            sender: change.event.sender.clone(),
+            count: change.event.count,
+            queue_size: change.queue_size,
+            timestamp: rfc3339_timestamp(SystemTime::now())
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeadLetter {
+    pub webhook: String,
+    pub attempts: u32,
+    pub error: String,
+    pub failed_at: String,
+    pub payload: WebhookPayload
+}
+
+struct Endpoint {
+    config: WebhookConfig,
+    uri: Uri
+}
+
+impl Endpoint {
+    fn wants(&self, change: &QueueChange) -> bool {
+        self.wants_city(&change.city) && self.wants_kind(change.kind)
+    }
+
+    fn wants_city(&self, city: &str) -> bool {
+        self.config.cities.is_empty() || self.config.cities.iter().any(|wanted| wanted == city)
+    }
+
+    fn wants_kind(&self, kind: ChangeKind) -> bool {
+        self.config.events.is_empty() || self.config.events.contains(&WebhookEvent::of(kind))
+    }
+}
+
+// posts the changes of the notifications routed to it to the webhooks registered for their city and event
+pub struct Webhooks {
+    endpoints: Vec<Arc<Endpoint>>,
+    max_attempts: u32,
+    initial_backoff: Duration,
+    timeout: Duration,
+    dead_letter_path: Option<String>,
+    client: HttpClient
+}
+
+impl Webhooks {
+
+    pub fn new(config: &WebhooksConfig, client: HttpClient) -> Result<Webhooks, String> {
+        let endpoints = config.endpoints.iter()
+            .map(|endpoint| endpoint.url.parse::<Uri>()
+                .map(|uri| Arc::new(Endpoint { config: endpoint.clone(), uri }))
+                .map_err(|err| format!("invalid url of webhook {}: {}", endpoint.id, err)))
+            .collect::<Result<Vec<_>, String>>()?;
+
+        Ok(Webhooks {
+            endpoints,
+            max_attempts: config.max_attempts.max(1),
+            initial_backoff: Duration::from_millis(config.initial_backoff_millis),
+            timeout: Duration::from_secs(config.timeout_secs),
+            dead_letter_path: config.dead_letter_path.clone(),
+            client
+        })
+    }
+
+    // fails with the webhook once it is dead-lettered
+    fn post(self: Arc<Self>, endpoint: Arc<Endpoint>, payload: WebhookPayload) -> impl Future<Item=(), Error=String> {
+        let body = serde_json::to_string(&payload).unwrap_or_default();
+        let call = format!("webhook {}", endpoint.config.id);
+        let attempts = {
+            let webhooks = self.clone();
+            let endpoint = endpoint.clone();
+            let payload = payload.clone();
+            with_retries(call, self.max_attempts, self.initial_backoff, move |_| webhooks.attempt(&endpoint, &payload, &body))
+        };
+
+        attempts.then(move |result| match result {
+            Ok(attempts) => {
+                debug!(webhook = endpoint.config.id.as_str(), delivery = payload.id.as_str(), attempts = attempts; "webhook delivered");
+                Ok(())
+            },
+            Err((attempts, failure)) => {
+                let reason = format!("webhook {}: {}", endpoint.config.id, failure.reason);
+                self.dead_letter(DeadLetter {
+                    webhook: endpoint.config.id.clone(),
+                    attempts,
+                    error: failure.reason,
+                    failed_at: rfc3339_timestamp(SystemTime::now()),
+                    payload
+                });
+                Err(reason)
+            }
+        })
+    }
+
+    fn attempt(&self, endpoint: &Endpoint, payload: &WebhookPayload, body: &str) -> impl Future<Item=(), Error=Failure> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0).to_string();
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(endpoint.uri.clone())
+            .header(CONTENT_TYPE, JSON_CONTENT_TYPE)
+            .header(EVENT_HEADER, payload.event.as_str())
+            .header(DELIVERY_HEADER, payload.id.as_str())
+            .header(TIMESTAMP_HEADER, timestamp.as_str())
+            .header(SIGNATURE_HEADER, sign(&endpoint.config.secret, &timestamp, body).as_str())
+            .body(Body::from(String::from(body)))
+            .unwrap();
+
+        send(&self.client, req, self.timeout)
+            .map_err(Failure::retryable)
+            .and_then(|rsp| match rsp.status() {
+                status if status.is_success() => Ok(()),
+                status => Err(Failure::of_status(status))
+            })
+    }
+
+    fn dead_letter(&self, dead_letter: DeadLetter) {
+        error!(webhook = dead_letter.webhook.as_str(), delivery = dead_letter.payload.id.as_str(), attempts = dead_letter.attempts, error = dead_letter.error.as_str(); "webhook dead-lettered");
+
+        if let Some(path) = &self.dead_letter_path {
+            let line = serde_json::to_string(&dead_letter).unwrap_or_default();
+            let written = OpenOptions::new().create(true).append(true).open(path)
+                .and_then(|mut file| writeln!(file, "{}", line));
+            if let Err(err) = written {
+                error!(path = path.as_str(), error:% = err; "cannot write dead letter");
+            }
+        }
+    }
+
+}
+
+impl DeliveryChannel for Webhooks {
+    fn name(&self) -> &'static str {
+        CHANNEL_WEBHOOKS
+    }
+
+    fn serves(&self, city: &str) -> bool {
+        self.endpoints.iter().any(|endpoint| endpoint.wants_city(city))
+    }
+
+    fn follows(&self, kind: ChangeKind) -> bool {
+        self.endpoints.iter().any(|endpoint| endpoint.wants_kind(kind))
+    }
+
+    // posts to every webhook wanting the change, the delivery fails when any of them dead-lettered it
+    fn deliver(self: Arc<Self>, change: &QueueChange) -> Box<dyn Future<Item=(), Error=Failure> + Send> {
+        let payload = WebhookPayload::from(change);
+        let posts: Vec<_> = self.endpoints.iter()
+            .filter(|endpoint| endpoint.wants(change))
+            .map(|endpoint| self.clone().post(endpoint.clone(), payload.clone()).then(Ok::<_, ()>))
+            .collect();
+
+        Box::new(future::join_all(posts).then(|results| {
+            let failed: Vec<String> = results.unwrap_or_default().into_iter().filter_map(Result::err).collect();
+            match failed.is_empty() {
+                true => Ok(()),
+                false => Err(Failure::permanent(failed.join("; ")))
+            }
+        }))
+    }
+}
+
+pub fn sign(secret: &str, timestamp: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    let digest: String = mac.finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("sha256={}", digest)
+}
+
+// ------------- there are tests only below this point ------------
+
+#[cfg(test)]
+use std::net::SocketAddr;
+#[cfg(test)]
+use crate::delivery::DeliveryRouter;
+#[cfg(test)]
+use crate::feed::Feed;
+#[cfg(test)]
+use crate::storage::Event;
+#[cfg(test)]
+use crate::testing::{stand_in, wait_until, Recorded};
+
+#[cfg(test)]
+fn webhook(id: &str, addr: SocketAddr, cities: &[&str], events: Vec<WebhookEvent>) -> WebhookConfig {
+    WebhookConfig {
+        id: String::from(id),
+        url: format!("http://{}/{}", addr, id),
+        secret: format!("{}-secret", id),
+        cities: cities.iter().map(|city| String::from(*city)).collect(),
+        events
+    }
+}
+
+#[cfg(test)]
+fn dead_letter_path(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("danila-{}-{}.jsonl", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path.to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+fn dead_letters(path: &str) -> Vec<DeadLetter> {
+    std::fs::read_to_string(path).unwrap_or_default()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+#[test]
+fn test_signature_covers_timestamp_and_body() {
+    // printf '1538043174.{}' | openssl dgst -sha256 -hmac s3cr3t
+    let signature = sign("s3cr3t", "1538043174", "{}");
+
+    assert_eq!(signature, "sha256=20fdedf340c62237686e7412531ee57cd8afffbffb096a10bfefa7e47df8ef5f");
+    assert_ne!(signature, sign("s3cr3t", "1538043175", "{}"));
+    assert_ne!(signature, sign("other", "1538043174", "{}"));
+}
+
+#[test]
+fn test_changes_are_posted_signed_retried_and_dead_lettered() {
+    // given
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let (chat_addr, chat) = stand_in(&mut runtime, vec![503]);
+    let (audit_addr, audit) = stand_in(&mut runtime, vec![400]);
+    let config = WebhooksConfig {
+        endpoints: vec![
+            webhook("chat", chat_addr, &["BERLIN"], vec![WebhookEvent::Created]),
+            webhook("audit", audit_addr, &[], vec![])
+        ],
+        max_attempts: 3,
+        initial_backoff_millis: 10,
+        timeout_secs: 5,
+        dead_letter_path: Some(dead_letter_path("refused"))
+    };
+    let webhooks = Arc::new(Webhooks::new(&config, crate::client::https_client().unwrap()).unwrap());
+    let feed = Feed::new();
+    runtime.spawn(Arc::new(DeliveryRouter::for_channel(webhooks)).run(feed.subscribe_all()));
+
+    // when
+    feed.publish("BERLIN", ChangeKind::Added, &Event::new_message(String::from("lunch is here")).sent_by(Some(String::from("anna"))), 1);
+    feed.publish("KIEV", ChangeKind::Added, &Event::new_slap(), 1);
+
+    // then the chat got the BERLIN creation on the second attempt, the audit refused the first change for good
+    wait_until(|| chat.lock().unwrap().len() == 2 && audit.lock().unwrap().len() == 2);
+    let Recorded { path, headers, body } = chat.lock().unwrap()[1].clone();
+    assert_eq!(path, "/chat");
+    let payload: WebhookPayload = serde_json::from_str(&body).unwrap();
+    assert_eq!((payload.event.as_str(), payload.city.as_str(), payload.message_text.as_deref()), ("created", "BERLIN", Some("lunch is here")));
+    assert_eq!(headers[EVENT_HEADER], "created");
+    let timestamp = headers[TIMESTAMP_HEADER].to_str().unwrap();
+    assert_eq!(headers[SIGNATURE_HEADER].to_str().unwrap(), sign("chat-secret", timestamp, &body));
+
+    let refused = config.dead_letter_path.clone().unwrap();
+    wait_until(|| dead_letters(&refused).len() == 1);
+    assert_eq!((dead_letters(&refused)[0].webhook.as_str(), dead_letters(&refused)[0].attempts), ("audit", 1));
+
+    // when a receiver stays down
+    let unreachable = WebhooksConfig {
+        endpoints: vec![webhook("down", "127.0.0.1:1".parse().unwrap(), &[], vec![WebhookEvent::Expired])],
+        dead_letter_path: Some(dead_letter_path("unreachable")),
+        ..config
+    };
+    let down = Arc::new(Webhooks::new(&unreachable, crate::client::https_client().unwrap()).unwrap());
+    runtime.spawn(Arc::new(DeliveryRouter::for_channel(down)).run(feed.subscribe_all()));
+    feed.publish("KIEV", ChangeKind::Expired, &Event::new_slap(), 0);
+
+    // then
+    let path = unreachable.dead_letter_path.unwrap();
+    wait_until(|| dead_letters(&path).len() == 1);
+    assert_eq!(dead_letters(&path)[0].attempts, 3);
+    assert_eq!(dead_letters(&path)[0].payload.event, "expired");
+    assert_eq!(chat.lock().unwrap().len(), 2);
+
+    feed.close();
+    runtime.shutdown_now().wait().unwrap();
+    let _ = std::fs::remove_file(refused);
+    let _ = std::fs::remove_file(path);
+}